@@ -1,12 +1,14 @@
-use hyper;
 use luminal_router::{LuminalError, LuminalErrorKind};
+use luminal_server::{ServerError, ServerErrorKind};
 
-error_chain!{
+error_chain! {
     links {
         Luminal(LuminalError, LuminalErrorKind);
+        Server(ServerError, ServerErrorKind);
     }
 
     foreign_links {
-        Hyper(hyper::Error);
+        Io(::std::io::Error);
+        AddrParse(::std::net::AddrParseError);
     }
 }
@@ -1,50 +1,50 @@
 #[macro_use]
 extern crate error_chain;
-extern crate futures;
-extern crate http;
-extern crate hyper;
-extern crate luminal_handler;
-extern crate luminal_router;
-
-use futures::{Future, Stream};
-use hyper::{Body, Response};
-use hyper::server::Http;
-use luminal_router::{FnRouteBuilder, Router};
-use luminal_handler::LuminalFuture;
+
+use http::{Request, Response};
+use http_body_util::BodyExt;
+use luminal_handler::{full, LuminalBody, LuminalFuture};
+use luminal_router::{HandlerFnRouteBuilder, Router};
+use luminal_server::Server;
+
+use std::net::SocketAddr;
 
 mod error;
 
-use error::*;
+use crate::error::*;
 
 pub fn run() -> Result<()> {
-    let addr = "127.0.0.1:3000"
+    let addr: SocketAddr = "127.0.0.1:3000"
         .parse()
         .chain_err(|| "Could not parse address for binding server socket!")?;
-    let server = Http::new().bind(&addr, || Ok(routes().expect("Could not add all routes!")))?;
-    server.run()?;
+    let router = routes()?;
+    Server::new().bind(addr).run(move || Ok(router))?;
     Ok(())
 }
 
 fn routes() -> Result<Router> {
-    Ok(FnRouteBuilder::new()
+    Ok(HandlerFnRouteBuilder::new()
         .get("/echo", get_echo)?
         .post("/echo", post_echo)?
         .build())
 }
 
-fn get_echo(req: http::Request<Body>) -> ::std::result::Result<LuminalFuture, Response> {
+fn get_echo(
+    req: Request<LuminalBody>,
+) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>> {
     let (parts, ..) = req.into_parts();
-    let query = parts.uri.query().unwrap_or_else(|| "No query string");
-    Ok(Box::new(futures::future::ok(
-        Response::new().with_body(query.to_owned()),
-    )))
+    let query = parts.uri.query().unwrap_or("No query string").to_owned();
+    Ok(Box::pin(async move { Ok(Response::new(full(query))) }))
 }
 
-fn post_echo(req: http::Request<Body>) -> ::std::result::Result<LuminalFuture, Response> {
+fn post_echo(
+    req: Request<LuminalBody>,
+) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>> {
     let (.., body) = req.into_parts();
-    Ok(Box::new(
-        body.concat2().map(|b| Response::new().with_body(b)),
-    ))
+    Ok(Box::pin(async move {
+        let body = body.collect().await?.to_bytes();
+        Ok(Response::new(full(body)))
+    }))
 }
 
 #[cfg(test)]
@@ -1,25 +1,34 @@
-//! This create wraps `hyper::server::Service` with a slightly more convenient interface.
+//! This create wraps `hyper::service::Service` with a slightly more convenient interface.
 //!
-//! The request is wrapped with a new enum that helps provide additional information along with the
-//! request and uses a more liberal error type to allow users to map their own error to an actual
-//! http response.
-extern crate futures;
-extern crate http;
-extern crate hyper;
+//! The request is the plain `http::Request` and handlers use a more liberal error type to allow
+//! users to map their own error to an actual http response.
+use bytes::Bytes;
+use http::{Request, Response};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Body;
+use hyper::service::Service;
 
-use futures::future::{self, Future};
-use hyper::Body;
-use hyper::server::{Request, Response, Service};
+use std::any::Any;
+use std::future::{self, Future};
+use std::pin::Pin;
+
+/// The error type shared by bodies, futures and services.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The body type used for both requests and responses.
+pub type LuminalBody = UnsyncBoxBody<Bytes, BoxError>;
 
 // A convenience alias.
-pub type LuminalFuture = Box<Future<Item = Response, Error = hyper::Error>>;
+pub type LuminalFuture =
+    Pin<Box<dyn Future<Output = Result<Response<LuminalBody>, BoxError>> + Send>>;
 
 /// Trait for handling a request, returning either a success `Response` or an error `Response`.
 pub trait Handler {
-    fn handle(&self, req: http::Request<Body>) -> Result<LuminalFuture, Response>;
+    fn handle(&self, req: Request<LuminalBody>) -> Result<LuminalFuture, Response<LuminalBody>>;
 }
 
-/// An impl of `hyper::Service` that consumes an impl of `Handler`.
+/// An impl of `hyper::service::Service` that consumes an impl of `Handler`.
 pub struct HandlerService<H: Handler> {
     handler: H,
 }
@@ -30,55 +39,84 @@ impl<H: Handler> HandlerService<H> {
     }
 }
 
-impl<H: Handler> Service for HandlerService<H> {
-    type Request = Request;
-    type Response = Response;
-    type Error = hyper::Error;
+impl<H, B> Service<Request<B>> for HandlerService<H>
+where
+    H: Handler,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
     type Future = LuminalFuture;
 
     /// Dispatches to the owned `Handler`, marshalling success or error into the response.
-    fn call(&self, request: Request) -> Self::Future {
-        match self.handler.handle(request.into()) {
+    fn call(&self, request: Request<B>) -> Self::Future {
+        match self.handler.handle(request.map(boxed)) {
             Ok(response) => response,
-            Err(error) => Box::new(future::ok(error)),
+            Err(error) => Box::pin(future::ready(Ok(error))),
         }
     }
 }
 
-/// Accepts a function or closure that takes an `HttpRequest` and returns a compatible `Result`.
+/// Accepts a function or closure that takes an `http::Request` and returns a compatible `Result`.
 pub fn handler_fn<F>(func: F) -> HandlerFn<F>
 where
-    F: Fn(http::Request<Body>) -> Result<LuminalFuture, Response>,
+    F: Fn(Request<LuminalBody>) -> Result<LuminalFuture, Response<LuminalBody>>,
 {
     HandlerFn { func }
 }
 
-/// Holds a function to dispatch to via its impl of `Handler<E>`.
+/// Holds a function to dispatch to via its impl of `Handler`.
 pub struct HandlerFn<F>
 where
-    F: Fn(http::Request<Body>) -> Result<LuminalFuture, Response>,
+    F: Fn(Request<LuminalBody>) -> Result<LuminalFuture, Response<LuminalBody>>,
 {
     func: F,
 }
 
 impl<F> Handler for HandlerFn<F>
 where
-    F: Fn(http::Request<Body>) -> Result<LuminalFuture, Response>,
+    F: Fn(Request<LuminalBody>) -> Result<LuminalFuture, Response<LuminalBody>>,
 {
-    fn handle(&self, req: http::Request<Body>) -> Result<LuminalFuture, Response> {
+    fn handle(&self, req: Request<LuminalBody>) -> Result<LuminalFuture, Response<LuminalBody>> {
         (self.func)(req)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate tokio_core;
+/// Erase any compatible body, such as `hyper::body::Incoming`, into a `LuminalBody`.
+///
+/// A body that already is a `LuminalBody` is returned as it is, so services that box whatever
+/// body they are given don't stack another box on each layer.
+pub fn boxed<B>(body: B) -> LuminalBody
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let mut body = Some(body);
+    if let Some(boxed) = (&mut body as &mut dyn Any).downcast_mut::<Option<LuminalBody>>() {
+        return boxed.take().expect("Should have had the body");
+    }
+    body.expect("Should have had the body")
+        .map_err(Into::into)
+        .boxed_unsync()
+}
+
+/// A body holding a single, complete chunk.
+pub fn full<C: Into<Bytes>>(chunk: C) -> LuminalBody {
+    Full::new(chunk.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
 
-    use futures::Stream;
-    use hyper::Method;
-    use hyper::{Body, StatusCode};
+/// A body with no content.
+pub fn empty() -> LuminalBody {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
 
-    use self::tokio_core::reactor::Core;
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http::{Method, StatusCode};
 
     use super::*;
 
@@ -88,30 +126,27 @@ mod tests {
     }
 
     impl Handler for TestHandler {
-        fn handle(&self, _request: http::Request<Body>) -> Result<LuminalFuture, Response> {
+        fn handle(
+            &self,
+            _request: Request<LuminalBody>,
+        ) -> Result<LuminalFuture, Response<LuminalBody>> {
             match *self {
                 TestHandler::Success(ref body) => {
                     let body: String = body.clone();
-                    Ok(Box::new(futures::future::ok(
-                        Response::new().with_status(StatusCode::Ok).with_body(body),
-                    )))
+                    Ok(Box::pin(async move { Ok(Response::new(full(body))) }))
                 }
                 TestHandler::Failure(ref error) => {
                     let body: String = error.clone();
-                    Err(Response::new()
-                        .with_status(StatusCode::InternalServerError)
-                        .with_body(body))
+                    let mut response = Response::new(full(body));
+                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    Err(response)
                 }
             }
         }
     }
 
-    fn test_fn(_req: http::Request<Body>) -> Result<LuminalFuture, Response> {
-        Ok(Box::new(futures::future::ok(
-            Response::new()
-                .with_status(StatusCode::Ok)
-                .with_body(String::from("test")),
-        )))
+    fn test_fn(_req: Request<LuminalBody>) -> Result<LuminalFuture, Response<LuminalBody>> {
+        Ok(Box::pin(async { Ok(Response::new(full("test"))) }))
     }
 
     #[test]
@@ -119,7 +154,7 @@ mod tests {
         let handler = TestHandler::Success(String::from("Success"));
         let service = HandlerService::new(handler);
 
-        assert_call(&service, Method::Get, "/foo", (&StatusCode::Ok, "Success"));
+        assert_call(&service, Method::GET, "/foo", (&StatusCode::OK, "Success"));
     }
 
     #[test]
@@ -129,9 +164,9 @@ mod tests {
 
         assert_call(
             &service,
-            Method::Get,
+            Method::GET,
             "/foo",
-            (&StatusCode::InternalServerError, "Error"),
+            (&StatusCode::INTERNAL_SERVER_ERROR, "Error"),
         );
     }
 
@@ -140,7 +175,7 @@ mod tests {
         let handler = handler_fn(test_fn);
         let service = HandlerService::new(handler);
 
-        assert_call(&service, Method::Get, "/foo", (&StatusCode::Ok, "test"));
+        assert_call(&service, Method::GET, "/foo", (&StatusCode::OK, "test"));
     }
 
     fn assert_call<H>(
@@ -151,16 +186,15 @@ mod tests {
     ) where
         H: Handler,
     {
-        let uri = uri.parse()
-            .expect("Should have been able to convert to uri");
-        let req: Request<Body> = Request::new(method, uri);
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(empty())
+            .expect("Should have been able to build request");
 
         let work = service.call(req);
 
-        let mut core = Core::new().expect("Should have been able to create core");
-
-        let response = core.run(work)
-            .expect("Should have been able to run router call");
+        let response = block_on(work).expect("Should have been able to run handler call");
 
         assert_eq!(
             *expected.0,
@@ -169,13 +203,13 @@ mod tests {
             expected.0
         );
 
-        let body = core.run(response.body().concat2())
-            .expect("Should have been able to resolve body concat");
-        let body: &[u8] = &body.to_vec();
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
 
         assert_eq!(
             expected.1.as_bytes(),
-            body,
+            &body[..],
             "Should have received correct body content"
         );
     }
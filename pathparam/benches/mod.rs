@@ -1,102 +1,106 @@
-#![feature(test)]
 #![allow(dead_code)]
-extern crate test;
-extern crate url;
-
-extern crate luminal_pathparam;
-
+use criterion::{criterion_group, criterion_main, Criterion};
 use url::form_urlencoded;
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use test::Bencher;
 
 use luminal_pathparam::Parse;
 
-#[bench]
-fn bench_empty(bencher: &mut Bencher) {
-    bencher.iter(|| luminal_pathparam::parse("", ""));
+fn bench_empty(c: &mut Criterion) {
+    c.bench_function("bench_empty", |bencher| {
+        bencher.iter(|| luminal_pathparam::parse("", ""));
+    });
 }
 
-#[bench]
-fn bench_some(bencher: &mut Bencher) {
-    bencher.iter(|| {
-        luminal_pathparam::parse("/company/:comp_id/dept/:dept_id", "/company/123/dept/456")
+fn bench_some(c: &mut Criterion) {
+    c.bench_function("bench_some", |bencher| {
+        bencher.iter(|| {
+            luminal_pathparam::parse("/company/:comp_id/dept/:dept_id", "/company/123/dept/456")
+        });
     });
 }
 
-#[bench]
-fn bench_many(bencher: &mut Bencher) {
-    bencher.iter(|| {
-        luminal_pathparam::parse(
-            "/company/:comp_id/dept/:dept_id/user/:user_id/company/:comp2_id/dept/:dept2_id/user/:user2_id",
-            "/company/123/dept/456/user/789/company/123/dept/456/user/789",
-        )
+fn bench_many(c: &mut Criterion) {
+    c.bench_function("bench_many", |bencher| {
+        bencher.iter(|| {
+            luminal_pathparam::parse(
+                "/company/:comp_id/dept/:dept_id/user/:user_id/company/:comp2_id/dept/:dept2_id/user/:user2_id",
+                "/company/123/dept/456/user/789/company/123/dept/456/user/789",
+            )
+        });
     });
 }
 
-#[bench]
-fn bench_raw(bencher: &mut Bencher) {
-    bencher.iter(|| {
-        "/company/:comp_id/dept/:dept_id/user/:user_id/company/:comp2_id/dept/:dept2_id/user/:user2_id"
-            .split('/')
-            .zip("/company/123/dept/456/user/789/company/123/dept/456/user/789".split('/'))
-            .filter_map(|(key, value)| {
-                if key.starts_with(':') {
-                    Some((key.trim_left_matches(':'), value))
-                } else {
-                    None
-                }
-            })
+fn bench_raw(c: &mut Criterion) {
+    c.bench_function("bench_raw", |bencher| {
+        bencher.iter(|| {
+            "/company/:comp_id/dept/:dept_id/user/:user_id/company/:comp2_id/dept/:dept2_id/user/:user2_id"
+                .split('/')
+                .zip("/company/123/dept/456/user/789/company/123/dept/456/user/789".split('/'))
+                .filter_map(|(key, value)| {
+                    if key.starts_with(':') {
+                        Some((key.trim_start_matches(':'), value))
+                    } else {
+                        None
+                    }
+                })
+        });
     });
 }
 
-#[bench]
-fn test_from_some(bencher: &mut Bencher) {
-    bencher.iter(|| {
-        let _test: TestStruct = luminal_pathparam::from(
-            "/company/:company/dept/:dept/user/:user",
-            "/company/123/dept/456/user/789",
-        );
+fn test_from_some(c: &mut Criterion) {
+    c.bench_function("test_from_some", |bencher| {
+        bencher.iter(|| {
+            let _test: TestStruct = luminal_pathparam::from(
+                "/company/:company/dept/:dept/user/:user",
+                "/company/123/dept/456/user/789",
+            );
+        });
     });
 }
 
-#[bench]
-fn test_from_owned_some(bencher: &mut Bencher) {
-    bencher.iter(|| {
-        let _test: TestStructOwned = luminal_pathparam::from(
-            "/company/:company/dept/:dept/user/:user",
-            "/company/123/dept/456/user/789",
-        );
+fn test_from_owned_some(c: &mut Criterion) {
+    c.bench_function("test_from_owned_some", |bencher| {
+        bencher.iter(|| {
+            let _test: TestStructOwned = luminal_pathparam::from(
+                "/company/:company/dept/:dept/user/:user",
+                "/company/123/dept/456/user/789",
+            );
+        });
     });
 }
 
-#[bench]
-fn test_from_many(bencher: &mut Bencher) {
-    bencher.iter(|| {
-        let _test: TestStruct = luminal_pathparam::from(
-            "/company/:company/dept/:dept/user/:user/company/:company2/dept/:dept2/user2/:user",
-            "/company/123/dept/456/user/789/company2/123/dept2/456/user2/789",
-        );
+fn test_from_many(c: &mut Criterion) {
+    c.bench_function("test_from_many", |bencher| {
+        bencher.iter(|| {
+            let _test: TestStruct = luminal_pathparam::from(
+                "/company/:company/dept/:dept/user/:user/company/:company2/dept/:dept2/user2/:user",
+                "/company/123/dept/456/user/789/company2/123/dept2/456/user2/789",
+            );
+        });
     });
 }
 
-#[bench]
-fn test_form_many(bencher: &mut Bencher) {
-    bencher.iter(|| {
-        let _params: HashMap<Cow<str>, Cow<str>> = form_urlencoded::parse(
-            b"company=123&dept=456&user=789&company2=123&dept2=456&user2=789",
-        ).collect();
+fn test_form_many(c: &mut Criterion) {
+    c.bench_function("test_form_many", |bencher| {
+        bencher.iter(|| {
+            let _params: HashMap<Cow<str>, Cow<str>> = form_urlencoded::parse(
+                b"company=123&dept=456&user=789&company2=123&dept2=456&user2=789",
+            )
+            .collect();
+        });
     });
 }
 
-#[bench]
-fn test_from_owned_many(bencher: &mut Bencher) {
-    bencher.iter(|| {
-        let _test: TestStructOwned = luminal_pathparam::from(
-            "/company/:company/dept/:dept/user/:user/company/:company2/dept/:dept2/user2/:user",
-            "/company/123/dept/456/user/789/company2/123/dept2/456/user2/789",
-        );
+fn test_from_owned_many(c: &mut Criterion) {
+    c.bench_function("test_from_owned_many", |bencher| {
+        bencher.iter(|| {
+            let _test: TestStructOwned = luminal_pathparam::from(
+                "/company/:company/dept/:dept/user/:user/company/:company2/dept/:dept2/user2/:user",
+                "/company/123/dept/456/user/789/company2/123/dept2/456/user2/789",
+            );
+        });
     });
 }
 
@@ -161,3 +165,17 @@ impl<'a> From<Parse<'a>> for TestStructOwned {
         }
     }
 }
+
+criterion_group!(
+    benches,
+    bench_empty,
+    bench_some,
+    bench_many,
+    bench_raw,
+    test_from_some,
+    test_from_owned_some,
+    test_from_many,
+    test_form_many,
+    test_from_owned_many
+);
+criterion_main!(benches);
@@ -36,13 +36,9 @@ pub struct Parse<'a> {
 impl<'a> Iterator for Parse<'a> {
     type Item = (&'a str, &'a str);
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if let Some((key, value)) = self.source.next() {
-                if key.starts_with(':') {
-                    return Some((key, value));
-                }
-            } else {
-                break;
+        for (key, value) in self.source.by_ref() {
+            if key.starts_with(':') {
+                return Some((key, value));
             }
         }
         None
@@ -1,47 +1,39 @@
-#![feature(test)]
-extern crate futures;
-extern crate hyper;
-extern crate test;
-extern crate time;
+use criterion::{criterion_group, criterion_main, Criterion};
+use http::header::CONTENT_LENGTH;
+use http::{Method, Request, Response};
+use http_body_util::{BodyExt, Full};
+use hyper::service::Service;
 
-extern crate luminal_router;
+use luminal_router::{BoxError, FnRouteBuilder, LuminalBody, Metrics, Router};
 
-use futures::future;
-use hyper::header::ContentLength;
-use hyper::Method;
-use hyper::server::{Request, Response};
-use test::Bencher;
-use time::PreciseTime;
-
-use luminal_router::{FnRouteBuilder, LuminalFuture, Router};
-
-fn noop_handler(req: Request) -> LuminalFuture {
+async fn noop_handler(req: Request<LuminalBody>) -> Result<Response<LuminalBody>, BoxError> {
     // consume the request
-    ::std::mem::forget(req);
+    drop(req);
     let msg = String::from("No op");
-    Box::new(future::ok(
-        Response::new()
-            .with_header(ContentLength(msg.len() as u64))
-            .with_body(msg),
-    ))
+    Ok(Response::builder().header(CONTENT_LENGTH, msg.len()).body(
+        Full::from(msg)
+            .map_err(|never| match never {})
+            .boxed_unsync(),
+    )?)
 }
 
-#[bench]
-fn bench_empty(bencher: &mut Bencher) {
+fn bench_empty(c: &mut Criterion) {
     let router = FnRouteBuilder::new().build();
 
-    bencher.iter(|| router.dispatch(&Method::Get, "/"));
+    c.bench_function("bench_empty", |b| {
+        b.iter(|| router.dispatch(&Method::GET, "/").is_some())
+    });
 }
 
-#[bench]
-fn bench_broad(bencher: &mut Bencher) {
+fn bench_broad(c: &mut Criterion) {
     let router = permute_map(1000, 1);
 
-    bencher.iter(|| router.dispatch(&Method::Get, "/0"));
+    c.bench_function("bench_broad", |b| {
+        b.iter(|| router.dispatch(&Method::GET, "/0").is_some())
+    });
 }
 
-#[bench]
-fn bench_shallow(bencher: &mut Bencher) {
+fn bench_shallow(c: &mut Criterion) {
     let router = permute_map(5, 2);
 
     let mut to_find = String::from("/");
@@ -49,11 +41,12 @@ fn bench_shallow(bencher: &mut Bencher) {
         to_find += &format!("{}/", x);
     }
 
-    bencher.iter(|| router.dispatch(&Method::Get, &to_find));
+    c.bench_function("bench_shallow", |b| {
+        b.iter(|| router.dispatch(&Method::GET, &to_find).is_some())
+    });
 }
 
-#[bench]
-fn bench_deep(bencher: &mut Bencher) {
+fn bench_deep(c: &mut Criterion) {
     let router = permute_map(5, 10);
 
     let mut to_find = String::from("/");
@@ -61,11 +54,12 @@ fn bench_deep(bencher: &mut Bencher) {
         to_find += &format!("{}/", x);
     }
 
-    bencher.iter(|| router.dispatch(&Method::Get, &to_find));
+    c.bench_function("bench_deep", |b| {
+        b.iter(|| router.dispatch(&Method::GET, &to_find).is_some())
+    });
 }
 
-#[bench]
-fn bench_deep_path(bencher: &mut Bencher) {
+fn bench_deep_path(c: &mut Criterion) {
     let router = permute_map_path(5, 10);
 
     let mut to_find = String::from("/");
@@ -77,11 +71,12 @@ fn bench_deep_path(bencher: &mut Bencher) {
         }
     }
 
-    bencher.iter(|| router.dispatch(&Method::Get, &to_find));
+    c.bench_function("bench_deep_path", |b| {
+        b.iter(|| router.dispatch(&Method::GET, &to_find).is_some())
+    });
 }
 
-#[bench]
-fn bench_deeper(bencher: &mut Bencher) {
+fn bench_deeper(c: &mut Criterion) {
     let router = permute_map(5, 100);
 
     let mut to_find = String::from("/");
@@ -89,11 +84,12 @@ fn bench_deeper(bencher: &mut Bencher) {
         to_find += &format!("{}/", x);
     }
 
-    bencher.iter(|| router.dispatch(&Method::Get, &to_find));
+    c.bench_function("bench_deeper", |b| {
+        b.iter(|| router.dispatch(&Method::GET, &to_find).is_some())
+    });
 }
 
-#[bench]
-fn bench_deeper_path(bencher: &mut Bencher) {
+fn bench_deeper_path(c: &mut Criterion) {
     let router = permute_map_path(5, 100);
 
     let mut to_find = String::from("/");
@@ -105,11 +101,12 @@ fn bench_deeper_path(bencher: &mut Bencher) {
         }
     }
 
-    bencher.iter(|| router.dispatch(&Method::Get, &to_find));
+    c.bench_function("bench_deeper_path", |b| {
+        b.iter(|| router.dispatch(&Method::GET, &to_find).is_some())
+    });
 }
 
-#[bench]
-fn immediate_miss_deep(bencher: &mut Bencher) {
+fn immediate_miss_deep(c: &mut Criterion) {
     let router = permute_map(5, 100);
 
     let mut to_find = String::from("/a/");
@@ -117,27 +114,36 @@ fn immediate_miss_deep(bencher: &mut Bencher) {
         to_find += &format!("{}/", x);
     }
 
-    bencher.iter(|| router.dispatch(&Method::Get, &to_find));
+    c.bench_function("immediate_miss_deep", |b| {
+        b.iter(|| router.dispatch(&Method::GET, &to_find).is_some())
+    });
 }
 
-#[bench]
-fn dispatch_ms(_: &mut Bencher) {
-    let router = permute_map(5, 100);
+fn bench_call(c: &mut Criterion) {
+    let router = permute_map_path(10, 5);
 
-    let mut to_find = String::from("/");
-    for x in 0..100 {
-        to_find += &format!("{}/", x);
-    }
+    c.bench_function("bench_call", |b| {
+        b.iter(|| futures::executor::block_on(router.call(get("/0/1/2/3/9"))).is_ok())
+    });
+}
 
-    let n = 1_000_000;
-    let start = PreciseTime::now();
-    for _ in 0..n {
-        router.dispatch(&Method::Get, &to_find);
-    }
-    let end = PreciseTime::now();
-    let runtime = start.to(end).num_milliseconds() as f64;
-    println!("Took {:.2} MS to run.", runtime);
-    println!("{:.2} dispatches per MS", f64::from(n) / runtime);
+fn bench_call_metrics(c: &mut Criterion) {
+    let metrics = Metrics::new();
+    let router = metrics.wrap(permute_map_path(10, 5));
+
+    c.bench_function("bench_call_metrics", |b| {
+        b.iter(|| futures::executor::block_on(router.call(get("/0/1/2/3/9"))).is_ok())
+    });
+}
+
+fn get(uri: &str) -> Request<LuminalBody> {
+    Request::get(uri)
+        .body(
+            Full::default()
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+        .expect("Failed to build request")
 }
 
 fn permute_map(breadth: usize, depth: usize) -> Router {
@@ -173,3 +179,18 @@ fn permute_map_path(breadth: usize, depth: usize) -> Router {
     }
     builder.build()
 }
+
+criterion_group!(
+    benches,
+    bench_empty,
+    bench_broad,
+    bench_shallow,
+    bench_deep,
+    bench_deep_path,
+    bench_deeper,
+    bench_deeper_path,
+    immediate_miss_deep,
+    bench_call,
+    bench_call_metrics
+);
+criterion_main!(benches);
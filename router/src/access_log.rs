@@ -0,0 +1,554 @@
+//! Access logging through the `log` facade.
+//!
+//! Each request is logged once its response future resolves, with the latency up to that point.
+//! Besides what the classic formats record, every line carries the route template the request
+//! matched, such as `/user/:user_id`, so requests can be grouped by endpoint, and the request ID.
+//! The ID is the `RequestId` from the extensions when a `RequestIdService` is in play, whichever
+//! side of the access log it is on, and the `X-Request-Id` header otherwise. An ID from the
+//! header with spaces or quotes in it is quoted, so it can't pass for other fields of the line.
+use bytes::Bytes;
+use http::header::{HeaderName, CONTENT_LENGTH, REFERER, USER_AGENT};
+use http::{HeaderMap, Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use log::Level;
+use luminal_handler::boxed;
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::params::PathParams;
+use crate::peer::PeerAddr;
+use crate::proxy::client_ip;
+use crate::request_id::RequestId;
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The layout of each access log line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The Common Log Format, followed by the route, the request ID and the latency in
+    /// milliseconds.
+    Common,
+    /// The Common Log Format with the referer and user agent, then the same fields as `Common`.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// An access log policy, built fluently.
+///
+/// Lines go to the `luminal::access` target at `Level::Info` unless configured otherwise.
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    format: LogFormat,
+    target: String,
+    level: Level,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new(LogFormat::Common)
+    }
+}
+
+impl AccessLog {
+    pub fn new(format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            target: String::from("luminal::access"),
+            level: Level::Info,
+        }
+    }
+
+    /// Log to this target, so access logs can be routed apart from application logs.
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = target.to_owned();
+        self
+    }
+
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Put this policy in front of a service.
+    pub fn wrap<S>(self, inner: S) -> AccessLogService<S> {
+        AccessLogService {
+            log: Arc::new(self),
+            inner,
+        }
+    }
+
+    fn log(&self, entry: &Entry) {
+        if log::log_enabled!(target: &self.target, self.level) {
+            log::log!(target: &self.target, self.level, "{}", self.format(entry));
+        }
+    }
+
+    fn format(&self, entry: &Entry) -> String {
+        match self.format {
+            LogFormat::Common => format!(
+                "{} {} {}",
+                common(entry),
+                dash(entry.route.as_deref()),
+                extra(entry)
+            ),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\" {} {}",
+                common(entry),
+                quoted(entry.referer.as_deref()),
+                quoted(entry.user_agent.as_deref()),
+                dash(entry.route.as_deref()),
+                extra(entry)
+            ),
+            LogFormat::Json => json(entry),
+        }
+    }
+}
+
+// Everything about a request and its response that goes into a line.
+struct Entry {
+    time: SystemTime,
+    remote: Option<String>,
+    method: String,
+    target: String,
+    version: String,
+    route: Option<String>,
+    status: StatusCode,
+    bytes: Option<u64>,
+    latency: Duration,
+    request_id: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+fn header(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+fn dash(value: Option<&str>) -> &str {
+    value.unwrap_or("-")
+}
+
+// Quotes and backslashes would let a client forge the structure of a line.
+fn quoted(value: Option<&str>) -> String {
+    value
+        .unwrap_or("-")
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+}
+
+// A value logged without quotes, unless it has spaces or quotes of its own, such as a request ID
+// taken as the client sent it.
+fn bare(value: Option<&str>) -> String {
+    match value {
+        Some(value) if value.contains([' ', '\t', '"', '\\']) => {
+            format!("\"{}\"", quoted(Some(value)))
+        }
+        value => dash(value).to_owned(),
+    }
+}
+
+fn common(entry: &Entry) -> String {
+    format!(
+        "{} - - [{}] \"{} {} {}\" {} {}",
+        dash(entry.remote.as_deref()),
+        clf_time(entry.time),
+        entry.method,
+        quoted(Some(&entry.target)),
+        entry.version,
+        entry.status.as_u16(),
+        entry
+            .bytes
+            .map_or_else(|| String::from("-"), |bytes| bytes.to_string())
+    )
+}
+
+fn extra(entry: &Entry) -> String {
+    format!(
+        "{} {:.3}",
+        bare(entry.request_id.as_deref()),
+        entry.latency.as_secs_f64() * 1000.0
+    )
+}
+
+fn json(entry: &Entry) -> String {
+    let mut line = String::from("{");
+    let mut field = |name: &str, value: Option<String>| {
+        if line.len() > 1 {
+            line.push(',');
+        }
+        let _ = write!(line, "\"{}\":", name);
+        match value {
+            Some(value) => json_string(&mut line, &value),
+            None => line.push_str("null"),
+        }
+    };
+    field("time", Some(httpdate::fmt_http_date(entry.time)));
+    field("remote", entry.remote.clone());
+    field("method", Some(entry.method.clone()));
+    field("target", Some(entry.target.clone()));
+    field("version", Some(entry.version.clone()));
+    field("route", entry.route.clone());
+    field("request_id", entry.request_id.clone());
+    field("referer", entry.referer.clone());
+    field("user_agent", entry.user_agent.clone());
+    let bytes = entry
+        .bytes
+        .map_or_else(|| String::from("null"), |bytes| bytes.to_string());
+    let _ = write!(
+        line,
+        ",\"status\":{},\"bytes\":{},\"latency_ms\":{:.3}}}",
+        entry.status.as_u16(),
+        bytes,
+        entry.latency.as_secs_f64() * 1000.0
+    );
+    line
+}
+
+fn json_string(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+// The Common Log Format's timestamp, always in UTC, such as `10/Oct/2000:13:55:36 +0000`.
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // civil from days, after Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// A service behind an `AccessLog`, see `AccessLog::wrap`.
+pub struct AccessLogService<S> {
+    log: Arc<AccessLog>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for AccessLogService<S> {
+    fn clone(&self) -> Self {
+        AccessLogService {
+            log: Arc::clone(&self.log),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for AccessLogService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let start = Instant::now();
+        let req = req.map(boxed);
+        let headers = req.headers();
+        let mut entry = Entry {
+            time: SystemTime::now(),
+            remote: client_ip(req.extensions())
+                .map(|ip| ip.to_string())
+                .or_else(|| req.extensions().get::<PeerAddr>().map(PeerAddr::to_string)),
+            method: req.method().to_string(),
+            target: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| String::from("/"), |target| target.to_string()),
+            version: format!("{:?}", req.version()),
+            route: None,
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            bytes: None,
+            latency: Duration::default(),
+            request_id: req
+                .extensions()
+                .get::<RequestId>()
+                .map(|id| id.as_str().to_owned())
+                .or_else(|| header(headers, &REQUEST_ID)),
+            referer: header(headers, &REFERER),
+            user_agent: header(headers, &USER_AGENT),
+        };
+
+        let log = Arc::clone(&self.log);
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let result = response.await;
+            entry.latency = start.elapsed();
+            if let Ok(ref response) = result {
+                entry.status = response.status();
+                entry.route = response
+                    .extensions()
+                    .get::<PathParams>()
+                    .map(|params| params.route().to_owned());
+                entry.bytes = response
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+                    .or_else(|| response.body().size_hint().exact());
+                if let Some(id) = response.extensions().get::<RequestId>() {
+                    entry.request_id = Some(id.as_str().to_owned());
+                } else if entry.request_id.is_none() {
+                    entry.request_id = header(response.headers(), &REQUEST_ID);
+                }
+            }
+            log.log(&entry);
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use log::{LevelFilter, Log, Metadata, Record};
+    use luminal_handler::{empty, full};
+
+    use std::sync::{Mutex, Once};
+
+    use super::*;
+    use crate::{FnRouteBuilder, RequestIds, Router, TrustedProxies};
+
+    // Every test logs to a target of its own, since the logger is shared by the whole process.
+    struct Capture(Mutex<Vec<(String, String)>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0
+                .lock()
+                .expect("Should have locked the captured lines")
+                .push((record.target().to_owned(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+    static INIT: Once = Once::new();
+
+    fn lines(target: &str) -> Vec<String> {
+        CAPTURE
+            .0
+            .lock()
+            .expect("Should have locked the captured lines")
+            .iter()
+            .filter(|(logged, _)| logged == target)
+            .map(|(_, line)| line.clone())
+            .collect()
+    }
+
+    fn init() {
+        INIT.call_once(|| {
+            log::set_logger(&CAPTURE).expect("Should have been able to set the logger");
+            log::set_max_level(LevelFilter::Info);
+        });
+    }
+
+    fn call(format: LogFormat, target: &str, uri: &str) -> Vec<String> {
+        init();
+        let router = FnRouteBuilder::new()
+            .get("/user/:user_id", |_req| async {
+                Ok(Response::new(full("Hello")))
+            })
+            .expect("Should have been able to add route")
+            .build();
+        let service: AccessLogService<Router> = AccessLog::new(format).target(target).wrap(router);
+        let req = Request::builder()
+            .uri(uri)
+            .header(REQUEST_ID, "abc-123")
+            .header(REFERER, "https://example.com/")
+            .header(USER_AGENT, "curl/8.0 \"quoted\"")
+            .body(empty())
+            .expect("Should have been able to build request");
+        block_on(service.call(req)).expect("Should have been able to run call");
+        lines(target)
+    }
+
+    #[test]
+    fn test_common() {
+        let lines = call(LogFormat::Common, "test::common", "/user/42?verbose=1");
+        assert_eq!(1, lines.len());
+        let line = &lines[0];
+        assert!(line.starts_with("- - - ["), "{}", line);
+        assert!(
+            line.contains("] \"GET /user/42?verbose=1 HTTP/1.1\" 200 5 /user/:user_id abc-123 "),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_combined() {
+        let lines = call(LogFormat::Combined, "test::combined", "/user/42");
+        assert_eq!(1, lines.len());
+        assert!(
+            lines[0].contains(
+                "200 5 \"https://example.com/\" \"curl/8.0 \\\"quoted\\\"\" /user/:user_id abc-123 "
+            ),
+            "{}",
+            lines[0]
+        );
+    }
+
+    #[test]
+    fn test_request_id_quoted() {
+        init();
+        let service = AccessLog::new(LogFormat::Common)
+            .target("test::quoted")
+            .wrap(Router::new());
+        let req = Request::builder()
+            .uri("/")
+            .header(REQUEST_ID, "abc 200 \"forged\"")
+            .body(empty())
+            .expect("Should have been able to build request");
+        block_on(service.call(req)).expect("Should have been able to run call");
+
+        let lines = lines("test::quoted");
+        assert!(
+            lines[0].contains(" 404 0 - \"abc 200 \\\"forged\\\"\" "),
+            "{}",
+            lines[0]
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let lines = call(LogFormat::Json, "test::json", "/missing");
+        assert_eq!(1, lines.len());
+        let line = &lines[0];
+        assert!(line.starts_with("{\"time\":\""), "{}", line);
+        assert!(line.contains("\"remote\":null"), "{}", line);
+        assert!(line.contains("\"route\":null"), "{}", line);
+        assert!(line.contains("\"request_id\":\"abc-123\""), "{}", line);
+        assert!(
+            line.contains("\"user_agent\":\"curl/8.0 \\\"quoted\\\"\""),
+            "{}",
+            line
+        );
+        assert!(
+            line.contains(",\"status\":404,\"bytes\":0,\"latency_ms\":"),
+            "{}",
+            line
+        );
+        assert!(line.ends_with('}'), "{}", line);
+    }
+
+    #[test]
+    fn test_remote() {
+        init();
+        let router = FnRouteBuilder::new()
+            .get("/", |_req| async { Ok(Response::new(empty())) })
+            .expect("Should have been able to add route")
+            .build();
+        let service = TrustedProxies::local().wrap(
+            AccessLog::new(LogFormat::Common)
+                .target("test::remote")
+                .wrap(router),
+        );
+        let request = |peer: &str, forwarded_for: &str| {
+            let mut req = Request::builder()
+                .uri("/")
+                .header("x-forwarded-for", forwarded_for)
+                .body(empty())
+                .expect("Should have been able to build request");
+            req.extensions_mut().insert(PeerAddr::Tcp(
+                peer.parse().expect("Should have parsed address"),
+            ));
+            block_on(service.call(req)).expect("Should have been able to run call");
+        };
+        request("127.0.0.1:4711", "192.0.2.60");
+        request("198.51.100.7:4711", "192.0.2.60");
+
+        let lines = lines("test::remote");
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("192.0.2.60 - - ["), "{}", lines[0]);
+        assert!(lines[1].starts_with("198.51.100.7 - - ["), "{}", lines[1]);
+    }
+
+    #[test]
+    fn test_generated_request_id() {
+        init();
+        let service = AccessLog::new(LogFormat::Json)
+            .target("test::generated")
+            .wrap(
+                RequestIds::new()
+                    .generator(|| String::from("generated-1"))
+                    .wrap(Router::new()),
+            );
+        let req = Request::builder()
+            .uri("/")
+            .body(empty())
+            .expect("Should have been able to build request");
+        block_on(service.call(req)).expect("Should have been able to run call");
+
+        let lines = lines("test::generated");
+        assert!(
+            lines[0].contains("\"request_id\":\"generated-1\""),
+            "{}",
+            lines[0]
+        );
+    }
+
+    #[test]
+    fn test_clf_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!("10/Oct/2000:13:55:36 +0000", clf_time(time));
+        assert_eq!("01/Jan/1970:00:00:00 +0000", clf_time(UNIX_EPOCH));
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!("29/Feb/2000:00:00:00 +0000", clf_time(leap));
+    }
+}
@@ -0,0 +1,584 @@
+//! HTTP Basic and API key authentication, checked against a `CredentialStore`.
+//!
+//! Both services put the `Principal` the credentials belong to in the request extensions, and
+//! answer anything else with 401 and a `WWW-Authenticate` challenge. Secrets are compared in
+//! constant time, and the stores here check every API key rather than stopping at the first
+//! match, so response times don't tell how close a guess was.
+//!
+//! A layer can wrap a whole router, a router added under a catch-all route of another, or single
+//! routes with the `wrap_route` method of the router builders.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use http::header::{HeaderName, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use http::{Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::{boxed, empty};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::{self, Future};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::error::*;
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// Who a request authenticated as, from the request extensions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Principal(String);
+
+impl Principal {
+    pub fn new(name: &str) -> Principal {
+        Principal(name.to_owned())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A future resolving to the principal credentials belong to, if any.
+pub type CredentialFuture = Pin<Box<dyn Future<Output = Option<Principal>> + Send>>;
+
+/// Where credentials are verified.
+///
+/// Stores only need to implement the kinds of credentials they hold, the others verify nothing.
+pub trait CredentialStore: Send + Sync {
+    fn verify_password(&self, _user: &str, _password: &str) -> CredentialFuture {
+        Box::pin(future::ready(None))
+    }
+
+    fn verify_api_key(&self, _key: &str) -> CredentialFuture {
+        Box::pin(future::ready(None))
+    }
+}
+
+// Compares digests, which are always the same length, so the time taken doesn't give away the
+// length of the secret either.
+fn equal(left: &[u8], right: &[u8]) -> bool {
+    Sha256::digest(left).ct_eq(&Sha256::digest(right)).into()
+}
+
+/// Users and API keys held in memory, built fluently.
+#[derive(Clone, Default)]
+pub struct MemoryCredentials {
+    users: HashMap<String, String>,
+    api_keys: Vec<(String, Principal)>,
+}
+
+impl MemoryCredentials {
+    pub fn new() -> MemoryCredentials {
+        MemoryCredentials::default()
+    }
+
+    pub fn user(mut self, user: &str, password: &str) -> Self {
+        self.users.insert(user.to_owned(), password.to_owned());
+        self
+    }
+
+    /// Accept the API key for the principal.
+    pub fn api_key(mut self, key: &str, principal: &str) -> Self {
+        self.api_keys
+            .push((key.to_owned(), Principal::new(principal)));
+        self
+    }
+}
+
+impl CredentialStore for MemoryCredentials {
+    fn verify_password(&self, user: &str, password: &str) -> CredentialFuture {
+        // unknown users are compared against an empty password, so they take as long as known ones
+        let (known, expected) = match self.users.get(user) {
+            Some(expected) => (true, expected.as_str()),
+            None => (false, ""),
+        };
+        let matched = equal(expected.as_bytes(), password.as_bytes());
+        let verified = (known & matched).then(|| Principal::new(user));
+        Box::pin(future::ready(verified))
+    }
+
+    fn verify_api_key(&self, key: &str) -> CredentialFuture {
+        let mut verified = None;
+        for (expected, principal) in &self.api_keys {
+            if equal(expected.as_bytes(), key.as_bytes()) {
+                verified = Some(principal.clone());
+            }
+        }
+        Box::pin(future::ready(verified))
+    }
+}
+
+#[derive(Clone)]
+enum Hash {
+    Bcrypt(String),
+    Sha1(Vec<u8>),
+}
+
+/// Users from an Apache htpasswd file, with bcrypt (`htpasswd -B`) or SHA-1 (`htpasswd -s`)
+/// hashes.
+///
+/// bcrypt runs on tokio's blocking threads. Unknown users are checked against a dummy hash, at
+/// the cost of the file's bcrypt hashes, so they take as long as known ones.
+pub struct Htpasswd {
+    users: HashMap<String, Hash>,
+    dummy: Hash,
+}
+
+impl Htpasswd {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Htpasswd> {
+        let contents = std::fs::read_to_string(path.as_ref())
+            .map_err(|error| ErrorKind::InvalidCredentials(error.to_string()))?;
+        Htpasswd::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Htpasswd> {
+        let mut users = HashMap::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| ErrorKind::InvalidCredentials(format!("no hash in {}", line)))?;
+            let hash = if hash.starts_with("$2") {
+                Hash::Bcrypt(hash.to_owned())
+            } else if let Some(sha1) = hash.strip_prefix("{SHA}") {
+                Hash::Sha1(STANDARD.decode(sha1).map_err(|_| {
+                    ErrorKind::InvalidCredentials(format!("malformed SHA-1 for {}", user))
+                })?)
+            } else {
+                bail!(ErrorKind::InvalidCredentials(format!(
+                    "unsupported hash for {}, use bcrypt or SHA-1",
+                    user
+                )));
+            };
+            users.insert(user.to_owned(), hash);
+        }
+        let dummy = match users.values().find_map(bcrypt_cost) {
+            Some(cost) => bcrypt::hash("luminal dummy password", cost)
+                .map(Hash::Bcrypt)
+                .map_err(|error| ErrorKind::InvalidCredentials(error.to_string()))?,
+            None => Hash::Sha1(vec![0; 20]),
+        };
+        Ok(Htpasswd { users, dummy })
+    }
+}
+
+// The cost of a bcrypt hash, such as 5 for `$2y$05$...`.
+fn bcrypt_cost(hash: &Hash) -> Option<u32> {
+    match hash {
+        Hash::Bcrypt(hash) => hash.split('$').nth(2)?.parse().ok(),
+        Hash::Sha1(_) => None,
+    }
+}
+
+impl CredentialStore for Htpasswd {
+    fn verify_password(&self, user: &str, password: &str) -> CredentialFuture {
+        let (hash, known) = match self.users.get(user) {
+            Some(hash) => (hash.clone(), true),
+            None => (self.dummy.clone(), false),
+        };
+        let user = user.to_owned();
+        let password = password.to_owned();
+        Box::pin(async move {
+            let verified = match hash {
+                Hash::Bcrypt(hash) => {
+                    tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+                        .await
+                        .is_ok_and(|verified| verified.unwrap_or(false))
+                }
+                Hash::Sha1(hash) => equal(&hash, &Sha1::digest(password.as_bytes())),
+            };
+            (verified && known).then(|| Principal::new(&user))
+        })
+    }
+}
+
+fn unauthorized(challenge: String) -> ::std::result::Result<Response<LuminalBody>, BoxError> {
+    let mut response = Response::new(empty());
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_str(&challenge)?);
+    Ok(response)
+}
+
+fn quote(realm: &str) -> String {
+    realm.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// The user and password from `Authorization: Basic`.
+fn basic<B>(req: &Request<B>) -> Option<(String, String)> {
+    let authorization = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+#[derive(Clone)]
+struct Config {
+    store: Arc<dyn CredentialStore>,
+    realm: String,
+    header: HeaderName,
+}
+
+impl Config {
+    fn new<C: CredentialStore + 'static>(store: C, header: HeaderName) -> Config {
+        Config {
+            store: Arc::new(store),
+            realm: String::from("luminal"),
+            header,
+        }
+    }
+}
+
+// An auth already wrapped around a service, or cloned, gets a config of its own, leaving the
+// services it wraps as they were.
+fn configure<F: FnOnce(&mut Config)>(mut config: Arc<Config>, configure: F) -> Arc<Config> {
+    configure(Arc::make_mut(&mut config));
+    config
+}
+
+/// HTTP Basic authentication, shared by every service it wraps.
+#[derive(Clone)]
+pub struct BasicAuth {
+    config: Arc<Config>,
+}
+
+impl BasicAuth {
+    pub fn new<C: CredentialStore + 'static>(store: C) -> BasicAuth {
+        BasicAuth {
+            config: Arc::new(Config::new(store, AUTHORIZATION)),
+        }
+    }
+
+    /// The realm named in challenges, `luminal` unless set.
+    pub fn realm(self, realm: &str) -> Self {
+        BasicAuth {
+            config: configure(self.config, |config| config.realm = realm.to_owned()),
+        }
+    }
+
+    pub fn wrap<S>(&self, inner: S) -> AuthService<S> {
+        AuthService {
+            config: Arc::clone(&self.config),
+            scheme: Scheme::Basic,
+            inner,
+        }
+    }
+}
+
+/// API key authentication, from `X-Api-Key` unless another header is set, shared by every
+/// service it wraps.
+#[derive(Clone)]
+pub struct ApiKeyAuth {
+    config: Arc<Config>,
+}
+
+impl ApiKeyAuth {
+    pub fn new<C: CredentialStore + 'static>(store: C) -> ApiKeyAuth {
+        ApiKeyAuth {
+            config: Arc::new(Config::new(store, HeaderName::from_static("x-api-key"))),
+        }
+    }
+
+    pub fn header(self, header: HeaderName) -> Self {
+        ApiKeyAuth {
+            config: configure(self.config, |config| config.header = header),
+        }
+    }
+
+    /// The realm named in challenges, `luminal` unless set.
+    pub fn realm(self, realm: &str) -> Self {
+        ApiKeyAuth {
+            config: configure(self.config, |config| config.realm = realm.to_owned()),
+        }
+    }
+
+    pub fn wrap<S>(&self, inner: S) -> AuthService<S> {
+        AuthService {
+            config: Arc::clone(&self.config),
+            scheme: Scheme::ApiKey,
+            inner,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Scheme {
+    Basic,
+    ApiKey,
+}
+
+/// A service only passing on authenticated requests, see `BasicAuth` and `ApiKeyAuth`.
+pub struct AuthService<S> {
+    config: Arc<Config>,
+    scheme: Scheme,
+    inner: S,
+}
+
+impl<S: Clone> Clone for AuthService<S> {
+    fn clone(&self) -> Self {
+        AuthService {
+            config: Arc::clone(&self.config),
+            scheme: self.scheme,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for AuthService<S>
+where
+    S: Service<
+            Request<LuminalBody>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Clone
+        + Send
+        + 'static,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let verified = match self.scheme {
+            Scheme::Basic => basic(&req)
+                .map(|(user, password)| self.config.store.verify_password(&user, &password)),
+            Scheme::ApiKey => req
+                .headers()
+                .get(&self.config.header)
+                .and_then(|key| key.to_str().ok())
+                .map(|key| self.config.store.verify_api_key(key.trim())),
+        };
+        let challenge = match self.scheme {
+            Scheme::Basic => format!(
+                "Basic realm=\"{}\", charset=\"UTF-8\"",
+                quote(&self.config.realm)
+            ),
+            Scheme::ApiKey => format!(
+                "ApiKey realm=\"{}\", header=\"{}\"",
+                quote(&self.config.realm),
+                self.config.header
+            ),
+        };
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let principal = match verified {
+                Some(verified) => verified.await,
+                None => None,
+            };
+            match principal {
+                Some(principal) => {
+                    req.extensions_mut().insert(principal);
+                    inner.call(req).await
+                }
+                None => unauthorized(challenge),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http_body_util::BodyExt;
+    use luminal_handler::full;
+
+    use super::*;
+    use crate::{FnRouteBuilder, Router, ServiceRouteBuilder};
+
+    fn whoami(req: Request<LuminalBody>) -> LuminalFuture {
+        let principal = req
+            .extensions()
+            .get::<Principal>()
+            .map(Principal::to_string)
+            .unwrap_or_default();
+        Box::pin(async move { Ok(Response::new(full(principal))) })
+    }
+
+    fn call<S>(service: &S, uri: &str, header: Option<(HeaderName, &str)>) -> (StatusCode, String)
+    where
+        S: Service<Request<LuminalBody>, Response = Response<LuminalBody>, Error = BoxError>,
+    {
+        let mut req = Request::builder().uri(uri);
+        if let Some((name, value)) = header {
+            req = req.header(name, value);
+        }
+        let req = req
+            .body(empty())
+            .expect("Should have been able to build request");
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        let status = response.status();
+        let body = match status {
+            StatusCode::UNAUTHORIZED => response.headers()[WWW_AUTHENTICATE]
+                .to_str()
+                .expect("Should have been a string")
+                .to_owned(),
+            _ => {
+                let body = block_on(response.into_body().collect())
+                    .expect("Should have been able to resolve body concat")
+                    .to_bytes();
+                String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body")
+            }
+        };
+        (status, body)
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", user, password))
+        )
+    }
+
+    #[test]
+    fn test_basic() {
+        let auth = BasicAuth::new(MemoryCredentials::new().user("admin", "hunter2")).realm("admin");
+        let router = FnRouteBuilder::new()
+            .get("/admin/stats", whoami)
+            .expect("Should have been able to add route")
+            .get("/health", whoami)
+            .expect("Should have been able to add route")
+            .wrap_route("/admin/stats", |target| auth.wrap(target))
+            .expect("Should have been able to wrap route")
+            .build();
+
+        let good = basic("admin", "hunter2");
+        assert_eq!(
+            (StatusCode::OK, String::from("admin")),
+            call(&router, "/admin/stats", Some((AUTHORIZATION, &good)))
+        );
+        let bad = basic("admin", "hunter3");
+        assert_eq!(
+            (
+                StatusCode::UNAUTHORIZED,
+                String::from("Basic realm=\"admin\", charset=\"UTF-8\"")
+            ),
+            call(&router, "/admin/stats", Some((AUTHORIZATION, &bad)))
+        );
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            call(&router, "/admin/stats", None).0
+        );
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            call(
+                &router,
+                "/admin/stats",
+                Some((AUTHORIZATION, "Bearer token"))
+            )
+            .0
+        );
+        // other routes are left open
+        assert_eq!(StatusCode::OK, call(&router, "/health", None).0);
+    }
+
+    #[test]
+    fn test_memory_passwords() {
+        let credentials = MemoryCredentials::new().user("admin", "hunter2");
+        let verify =
+            |user: &str, password: &str| block_on(credentials.verify_password(user, password));
+        assert_eq!(Some(Principal::new("admin")), verify("admin", "hunter2"));
+        assert_eq!(None, verify("admin", "hunter"));
+        assert_eq!(None, verify("admin", "hunter22"));
+        assert_eq!(None, verify("admin", ""));
+        // the empty password unknown users are compared against lets nobody in
+        assert_eq!(None, verify("carol", ""));
+        assert_eq!(None, verify("carol", "hunter2"));
+    }
+
+    #[test]
+    fn test_api_key_sub_router() {
+        let auth = ApiKeyAuth::new(
+            MemoryCredentials::new()
+                .api_key("key-one", "deploy")
+                .api_key("key-two", "backup"),
+        );
+        let admin = FnRouteBuilder::new()
+            .get("/admin/keys/:id", whoami)
+            .expect("Should have been able to add route")
+            .build();
+        let router: Router = ServiceRouteBuilder::new()
+            .get("/admin/*rest", auth.wrap(admin))
+            .expect("Should have been able to add route")
+            .build();
+
+        let key = HeaderName::from_static("x-api-key");
+        assert_eq!(
+            (StatusCode::OK, String::from("backup")),
+            call(&router, "/admin/keys/1", Some((key.clone(), "key-two")))
+        );
+        assert_eq!(
+            (
+                StatusCode::UNAUTHORIZED,
+                String::from("ApiKey realm=\"luminal\", header=\"x-api-key\"")
+            ),
+            call(&router, "/admin/keys/1", Some((key, "key-three")))
+        );
+    }
+
+    #[test]
+    fn test_configure_clone() {
+        let auth = ApiKeyAuth::new(MemoryCredentials::new().api_key("key-one", "deploy"));
+        let service = auth.wrap(Router::new());
+        // configuring an auth already in use leaves the services it wraps alone
+        let custom = auth.clone().header(AUTHORIZATION).realm("custom");
+        let customized = custom.wrap(Router::new());
+        assert_eq!(
+            String::from("ApiKey realm=\"luminal\", header=\"x-api-key\""),
+            call(&service, "/", None).1
+        );
+        assert_eq!(
+            String::from("ApiKey realm=\"custom\", header=\"authorization\""),
+            call(&customized, "/", None).1
+        );
+    }
+
+    #[test]
+    fn test_htpasswd() {
+        let bcrypt = bcrypt::hash("hunter2", 4).expect("Should have been able to hash");
+        let htpasswd = Htpasswd::parse(&format!(
+            "# admins\nalice:{}\nbob:{{SHA}}{}\n",
+            bcrypt,
+            STANDARD.encode(Sha1::digest(b"swordfish"))
+        ))
+        .expect("Should have been able to parse htpasswd");
+        // bcrypt runs on the runtime's blocking threads
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("Should have been able to build a runtime");
+        let verify =
+            |user: &str, password: &str| runtime.block_on(htpasswd.verify_password(user, password));
+        assert_eq!(Some(Principal::new("alice")), verify("alice", "hunter2"));
+        assert_eq!(None, verify("alice", "swordfish"));
+        assert_eq!(Some(Principal::new("bob")), verify("bob", "swordfish"));
+        assert_eq!(None, verify("carol", "hunter2"));
+        assert!(matches!(&htpasswd.dummy, Hash::Bcrypt(dummy) if dummy.starts_with("$2b$04$")));
+        assert_eq!(None, block_on(htpasswd.verify_api_key("hunter2")));
+
+        match Htpasswd::parse("carol:$apr1$salt$hash") {
+            Err(Error(ErrorKind::InvalidCredentials(_), _)) => (),
+            _ => panic!("Should have refused MD5 hashes"),
+        }
+    }
+}
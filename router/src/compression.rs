@@ -0,0 +1,828 @@
+//! Response compression negotiated on `Accept-Encoding`, and decompression of encoded uploads.
+//!
+//! Bodies are coded as they stream through, a frame at a time, so a large or long running response
+//! never has to be buffered. Each frame is flushed out of the encoder as it arrives, so events and
+//! long polls aren't held back. Responses that are small, already encoded, or of a media type that
+//! is compressed anyway, such as images, are passed through as they are.
+//!
+//! Decoded uploads are capped, 16 MiB unless set, and answered with 413 once they go over.
+use brotli::{CompressorWriter, DecompressorWriter};
+use bytes::Bytes;
+use flate2::write::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder};
+use http::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_TYPE, ETAG, VARY,
+};
+use http::{Method, Request, Response, StatusCode};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::service::Service;
+use luminal_handler::boxed;
+
+use std::io::{self, Write};
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// The content codings `Compression` can apply and remove.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// The token for the coding in `Content-Encoding` and `Accept-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn parse(token: &str) -> Option<Encoding> {
+        match token.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// A compression policy, built fluently.
+///
+/// By default brotli, gzip and deflate are all offered, in that order of preference when a client
+/// weighs them equally, responses under 1 KiB are left alone and encoded uploads are decoded, up
+/// to 16 MiB.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: u64,
+    decompress_requests: bool,
+    max_decompressed_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+            decompress_requests: true,
+            max_decompressed_size: 16 * 1024 * 1024,
+        }
+    }
+
+    /// Offer only these encodings, in this order of preference.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Leave responses with fewer bytes than this uncompressed. Responses whose size isn't known
+    /// up front are always compressed.
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Whether to decode requests with a `Content-Encoding` before passing them on.
+    pub fn decompress_requests(mut self, decompress_requests: bool) -> Self {
+        self.decompress_requests = decompress_requests;
+        self
+    }
+
+    /// The most bytes an upload may decode to. Requests going over it get 413, however the
+    /// handler reading the body deals with the error it sees.
+    pub fn max_decompressed_size(mut self, max: u64) -> Self {
+        self.max_decompressed_size = max;
+        self
+    }
+
+    /// Put this policy in front of a service.
+    pub fn wrap<S>(self, inner: S) -> CompressionService<S> {
+        CompressionService {
+            compression: Arc::new(self),
+            inner,
+        }
+    }
+
+    // The offered encoding the client weighs highest, if it accepts any of them at all.
+    fn negotiate(&self, accept: &HeaderMap) -> Option<Encoding> {
+        let mut weights = vec![];
+        for value in accept.get_all(ACCEPT_ENCODING) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            for coding in value.split(',') {
+                let mut parts = coding.split(';');
+                let token = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+                let q = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .filter_map(|q| q.trim().parse::<f32>().ok())
+                    .next()
+                    .unwrap_or(1.0);
+                weights.push((token, q));
+            }
+        }
+        let weight = |encoding: &Encoding| {
+            let exact = weights
+                .iter()
+                .find(|(token, _)| Encoding::parse(token) == Some(*encoding));
+            let any = weights.iter().find(|(token, _)| token == "*");
+            exact.or(any).map_or(0.0, |(_, q)| *q)
+        };
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in &self.encodings {
+            let q = weight(encoding);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((*encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    // Whether compressing could help this response at all, regardless of what the client accepts.
+    fn compressible(&self, response: &Response<LuminalBody>) -> bool {
+        let status = response.status();
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::PARTIAL_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return false;
+        }
+        let headers = response.headers();
+        if headers.contains_key(CONTENT_ENCODING) {
+            return false;
+        }
+        let no_transform = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+        if no_transform {
+            return false;
+        }
+        let compressed_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(already_compressed);
+        if compressed_type {
+            return false;
+        }
+        let size = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .or_else(|| response.body().size_hint().exact());
+        size.is_none_or(|size| size >= self.min_size)
+    }
+
+    fn compress(
+        &self,
+        encoding: Option<Encoding>,
+        mut response: Response<LuminalBody>,
+    ) -> Response<LuminalBody> {
+        if !self.compressible(&response) {
+            return response;
+        }
+        add_vary(response.headers_mut());
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+
+        let headers = response.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        // the encoded representation is no longer byte for byte what a strong validator promised
+        let weak = headers
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .filter(|etag| !etag.starts_with("W/"))
+            .and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok());
+        if let Some(weak) = weak {
+            headers.insert(ETAG, weak);
+        }
+        response.map(|body| boxed(CodedBody::new(body, Coder::encoder(encoding))))
+    }
+
+    // Decode an encoded upload, along with the flag raised if it decodes to too much, or the
+    // status to refuse it with.
+    fn decompress(
+        &self,
+        mut req: Request<LuminalBody>,
+    ) -> ::std::result::Result<(Request<LuminalBody>, Option<Arc<AtomicBool>>), StatusCode> {
+        let encoding = match req.headers().get(CONTENT_ENCODING) {
+            None => return Ok((req, None)),
+            Some(value) => value
+                .to_str()
+                .map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)?,
+        };
+        if encoding.trim().eq_ignore_ascii_case("identity") {
+            req.headers_mut().remove(CONTENT_ENCODING);
+            return Ok((req, None));
+        }
+        let encoding = Encoding::parse(encoding).ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+        let headers = req.headers_mut();
+        headers.remove(CONTENT_ENCODING);
+        headers.remove(CONTENT_LENGTH);
+        let sink = Sink::limited(self.max_decompressed_size);
+        let exceeded = Arc::clone(&sink.exceeded);
+        let req = req.map(|body| boxed(CodedBody::new(body, Coder::decoder(encoding, sink))));
+        Ok((req, Some(exceeded)))
+    }
+
+    // The response for an upload in an encoding that can't be decoded, listing those that can.
+    fn unsupported(&self) -> Response<LuminalBody> {
+        let accepted = self
+            .encodings
+            .iter()
+            .map(Encoding::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut response = Response::new(luminal_handler::empty());
+        *response.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        if let Ok(accepted) = HeaderValue::from_str(&accepted) {
+            response.headers_mut().insert(ACCEPT_ENCODING, accepted);
+        }
+        response
+    }
+}
+
+// Media types that are compressed by their own format, where another pass only costs time.
+fn already_compressed(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let (kind, subtype) = essence.split_once('/').unwrap_or((&essence, ""));
+    match kind {
+        "image" => subtype != "svg+xml" && subtype != "bmp",
+        "audio" | "video" => true,
+        "font" => subtype == "woff" || subtype == "woff2",
+        "application" => matches!(
+            subtype,
+            "zip" | "gzip" | "x-gzip" | "zstd" | "x-bzip2" | "x-7z-compressed" | "x-xz"
+        ),
+        _ => false,
+    }
+}
+
+fn add_vary(headers: &mut HeaderMap) {
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| {
+            let value = value.trim();
+            value == "*" || value.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+/// A service behind a `Compression` policy, see `Compression::wrap`.
+pub struct CompressionService<S> {
+    compression: Arc<Compression>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for CompressionService<S> {
+    fn clone(&self) -> Self {
+        CompressionService {
+            compression: Arc::clone(&self.compression),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for CompressionService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let mut exceeded = None;
+        if self.compression.decompress_requests {
+            req = match self.compression.decompress(req) {
+                Ok((req, limit)) => {
+                    exceeded = limit;
+                    req
+                }
+                Err(_) => {
+                    let response = self.compression.unsupported();
+                    return Box::pin(async move { Ok(response) });
+                }
+            };
+        }
+        let encoding = if req.method() == Method::HEAD {
+            None
+        } else {
+            self.compression.negotiate(req.headers())
+        };
+
+        let compression = Arc::clone(&self.compression);
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let response = response.await;
+            if exceeded.is_some_and(|exceeded| exceeded.load(Ordering::Acquire)) {
+                let mut response = Response::new(luminal_handler::empty());
+                *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                return Ok(response);
+            }
+            Ok(compression.compress(encoding, response?))
+        })
+    }
+}
+
+// Where a coder writes its output, drained after every write. Decoders are limited in how much
+// they may write altogether, so a small upload can't expand without bound.
+struct Sink {
+    buffer: Vec<u8>,
+    remaining: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl Sink {
+    fn unlimited() -> Sink {
+        Sink::limited(u64::MAX)
+    }
+
+    fn limited(limit: u64) -> Sink {
+        Sink {
+            buffer: Vec::new(),
+            remaining: limit,
+            exceeded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.len() as u64 > self.remaining {
+            self.exceeded.store(true, Ordering::Release);
+            return Err(io::Error::other("The decoded body is larger than allowed"));
+        }
+        self.remaining -= data.len() as u64;
+        self.buffer.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Each coder writes into a `Sink`.
+enum Coder {
+    BrotliEncoder(Box<CompressorWriter<Sink>>),
+    GzipEncoder(GzEncoder<Sink>),
+    DeflateEncoder(ZlibEncoder<Sink>),
+    BrotliDecoder(Box<DecompressorWriter<Sink>>),
+    GzipDecoder(GzDecoder<Sink>),
+    DeflateDecoder(ZlibDecoder<Sink>),
+}
+
+impl Coder {
+    fn encoder(encoding: Encoding) -> Coder {
+        match encoding {
+            // quality 5 and a 4 MiB window keep brotli fast enough to run on every response
+            Encoding::Brotli => Coder::BrotliEncoder(Box::new(CompressorWriter::new(
+                Sink::unlimited(),
+                4096,
+                5,
+                22,
+            ))),
+            Encoding::Gzip => {
+                Coder::GzipEncoder(GzEncoder::new(Sink::unlimited(), Default::default()))
+            }
+            Encoding::Deflate => {
+                Coder::DeflateEncoder(ZlibEncoder::new(Sink::unlimited(), Default::default()))
+            }
+        }
+    }
+
+    fn decoder(encoding: Encoding, sink: Sink) -> Coder {
+        match encoding {
+            Encoding::Brotli => Coder::BrotliDecoder(Box::new(DecompressorWriter::new(sink, 4096))),
+            Encoding::Gzip => Coder::GzipDecoder(GzDecoder::new(sink)),
+            Encoding::Deflate => Coder::DeflateDecoder(ZlibDecoder::new(sink)),
+        }
+    }
+
+    // Code another chunk, returning whatever output is ready so far. Encoders sync flush, so the
+    // chunk is out before the next one comes, however long that takes.
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let sink = match *self {
+            Coder::BrotliEncoder(ref mut coder) => {
+                coder.write_all(data)?;
+                coder.flush()?;
+                coder.get_mut()
+            }
+            Coder::GzipEncoder(ref mut coder) => {
+                coder.write_all(data)?;
+                coder.flush()?;
+                coder.get_mut()
+            }
+            Coder::DeflateEncoder(ref mut coder) => {
+                coder.write_all(data)?;
+                coder.flush()?;
+                coder.get_mut()
+            }
+            Coder::BrotliDecoder(ref mut coder) => {
+                coder.write_all(data)?;
+                coder.get_mut()
+            }
+            Coder::GzipDecoder(ref mut coder) => {
+                coder.write_all(data)?;
+                coder.get_mut()
+            }
+            Coder::DeflateDecoder(ref mut coder) => {
+                coder.write_all(data)?;
+                coder.get_mut()
+            }
+        };
+        Ok(Bytes::from(mem::take(&mut sink.buffer)))
+    }
+
+    // End the stream, returning the last of the output.
+    fn finish(self) -> io::Result<Bytes> {
+        let sink = match self {
+            Coder::BrotliEncoder(coder) => coder.into_inner(),
+            Coder::GzipEncoder(coder) => coder.finish()?,
+            Coder::DeflateEncoder(coder) => coder.finish()?,
+            Coder::BrotliDecoder(coder) => coder.into_inner().map_err(|_| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "truncated brotli stream")
+            })?,
+            Coder::GzipDecoder(coder) => coder.finish()?,
+            Coder::DeflateDecoder(coder) => coder.finish()?,
+        };
+        Ok(Bytes::from(sink.buffer))
+    }
+}
+
+// A body passed through a `Coder`, trailers are held back until the coded data is all out.
+struct CodedBody {
+    inner: LuminalBody,
+    coder: Option<Coder>,
+    trailers: Option<HeaderMap>,
+}
+
+impl CodedBody {
+    fn new(inner: LuminalBody, coder: Coder) -> Self {
+        CodedBody {
+            inner,
+            coder: Some(coder),
+            trailers: None,
+        }
+    }
+}
+
+impl Body for CodedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<::std::result::Result<Frame<Bytes>, BoxError>>> {
+        let this = self.get_mut();
+        loop {
+            let coder = match this.coder.as_mut() {
+                Some(coder) => coder,
+                None => return Poll::Ready(this.trailers.take().map(|t| Ok(Frame::trailers(t)))),
+            };
+            let coded = match Pin::new(&mut this.inner).poll_frame(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Some(Err(error))),
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => coder.write(&data),
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            this.trailers = Some(trailers);
+                        }
+                        continue;
+                    }
+                },
+                Poll::Ready(None) => this
+                    .coder
+                    .take()
+                    .expect("Should have had a coder until the end")
+                    .finish(),
+            };
+            match coded {
+                Ok(data) if data.is_empty() => continue,
+                Ok(data) => return Poll::Ready(Some(Ok(Frame::data(data)))),
+                Err(error) => {
+                    this.coder = None;
+                    return Poll::Ready(Some(Err(error.into())));
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::stream::{self, StreamExt};
+    use http::header::HeaderName;
+    use http_body_util::{BodyExt, StreamBody};
+    use luminal_handler::{empty, full};
+
+    use std::io::Read;
+
+    use super::*;
+    use crate::{FnRouteBuilder, Router};
+
+    const TEXT: &str = "All work and no play makes Jack a dull boy. ";
+
+    fn text(repeat: usize) -> String {
+        TEXT.repeat(repeat)
+    }
+
+    fn router() -> Router {
+        FnRouteBuilder::new()
+            .get("/large", |_req| async {
+                Ok(Response::builder()
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ETAG, "\"large\"")
+                    .body(full(text(100)))?)
+            })
+            .expect("Should have been able to add route")
+            .get("/small", |_req| async { Ok(Response::new(full("Small"))) })
+            .expect("Should have been able to add route")
+            .get("/image", |_req| async {
+                Ok(Response::builder()
+                    .header(CONTENT_TYPE, "image/png")
+                    .body(full(text(100)))?)
+            })
+            .expect("Should have been able to add route")
+            .get("/encoded", |_req| async {
+                Ok(Response::builder()
+                    .header(CONTENT_ENCODING, "zstd")
+                    .body(full(text(100)))?)
+            })
+            .expect("Should have been able to add route")
+            .get("/stream", |_req| async {
+                let chunks = (0..50).map(|_| Ok::<_, BoxError>(Frame::data(Bytes::from(TEXT))));
+                Ok(Response::new(boxed(StreamBody::new(stream::iter(chunks)))))
+            })
+            .expect("Should have been able to add route")
+            .service_builder()
+            .post("/upload", EchoService)
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    fn service() -> CompressionService<Router> {
+        Compression::new().wrap(router())
+    }
+
+    struct EchoService;
+
+    impl Service<Request<LuminalBody>> for EchoService {
+        type Response = Response<LuminalBody>;
+        type Error = BoxError;
+        type Future = LuminalFuture;
+        fn call(&self, req: Request<LuminalBody>) -> Self::Future {
+            Box::pin(async move {
+                let body = req.into_body().collect().await?.to_bytes();
+                Ok(Response::new(full(body)))
+            })
+        }
+    }
+
+    fn call(
+        service: &CompressionService<Router>,
+        method: Method,
+        uri: &str,
+        headers: &[(HeaderName, &'static str)],
+        body: LuminalBody,
+    ) -> (Response<()>, Bytes) {
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        let req = builder
+            .body(body)
+            .expect("Should have been able to build request");
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        let (parts, body) = response.into_parts();
+        let body = block_on(body.collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        (Response::from_parts(parts, ()), body)
+    }
+
+    fn get(uri: &str, accept: &'static str) -> (Response<()>, Bytes) {
+        call(
+            &service(),
+            Method::GET,
+            uri,
+            &[(ACCEPT_ENCODING, accept)],
+            empty(),
+        )
+    }
+
+    fn header(response: &Response<()>, name: HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().expect("Should have been a string header"))
+    }
+
+    fn gunzip(body: &[u8]) -> String {
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(body)
+            .read_to_string(&mut decoded)
+            .expect("Should have been valid gzip");
+        decoded
+    }
+
+    #[test]
+    fn test_gzip() {
+        let (response, body) = get("/large", "gzip, deflate");
+        assert_eq!(Some("gzip"), header(&response, CONTENT_ENCODING));
+        assert_eq!(Some("Accept-Encoding"), header(&response, VARY));
+        assert_eq!(Some("W/\"large\""), header(&response, ETAG));
+        assert_eq!(None, header(&response, CONTENT_LENGTH));
+        assert!(body.len() < text(100).len());
+        assert_eq!(text(100), gunzip(&body));
+    }
+
+    #[test]
+    fn test_brotli() {
+        let (response, body) = get("/large", "gzip;q=0.8, br");
+        assert_eq!(Some("br"), header(&response, CONTENT_ENCODING));
+
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&body[..], 4096)
+            .read_to_string(&mut decoded)
+            .expect("Should have been valid brotli");
+        assert_eq!(text(100), decoded);
+    }
+
+    #[test]
+    fn test_deflate() {
+        let (response, body) = get("/large", "deflate, *;q=0.5");
+        assert_eq!(Some("deflate"), header(&response, CONTENT_ENCODING));
+
+        let mut decoded = String::new();
+        flate2::read::ZlibDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .expect("Should have been valid deflate");
+        assert_eq!(text(100), decoded);
+    }
+
+    #[test]
+    fn test_not_accepted() {
+        let (response, body) = get("/large", "identity, br;q=0");
+        assert_eq!(None, header(&response, CONTENT_ENCODING));
+        assert_eq!(Some("Accept-Encoding"), header(&response, VARY));
+        assert_eq!(text(100).as_bytes(), &body[..]);
+    }
+
+    #[test]
+    fn test_skipped() {
+        for uri in &["/small", "/image", "/encoded"] {
+            let (response, body) = get(uri, "gzip");
+            assert_ne!(Some("gzip"), header(&response, CONTENT_ENCODING));
+            assert_eq!(None, header(&response, VARY));
+            assert!(!body.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_stream() {
+        let (response, body) = get("/stream", "gzip");
+        assert_eq!(Some("gzip"), header(&response, CONTENT_ENCODING));
+        assert_eq!(text(50), gunzip(&body));
+    }
+
+    #[test]
+    fn test_gzip_upload() {
+        let mut encoder = GzEncoder::new(vec![], Default::default());
+        encoder
+            .write_all(text(10).as_bytes())
+            .expect("Should have been able to compress");
+        let upload = encoder.finish().expect("Should have been able to compress");
+
+        let (response, body) = call(
+            &service(),
+            Method::POST,
+            "/upload",
+            &[(CONTENT_ENCODING, "gzip")],
+            full(upload),
+        );
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(text(10).as_bytes(), &body[..]);
+    }
+
+    #[test]
+    fn test_upload_too_large() {
+        let mut encoder = GzEncoder::new(vec![], Default::default());
+        encoder
+            .write_all(&vec![0; 1024 * 1024])
+            .expect("Should have been able to compress");
+        let upload = encoder.finish().expect("Should have been able to compress");
+        let service = Compression::new()
+            .max_decompressed_size(64 * 1024)
+            .wrap(router());
+
+        let (response, _) = call(
+            &service,
+            Method::POST,
+            "/upload",
+            &[(CONTENT_ENCODING, "gzip")],
+            full(upload),
+        );
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+    }
+
+    #[test]
+    fn test_flush_frames() {
+        // the first frame comes out on its own, while the rest of the body is still pending
+        let chunks = stream::once(async { Ok::<_, BoxError>(Frame::data(Bytes::from(TEXT))) })
+            .chain(stream::pending());
+        let mut body = CodedBody::new(
+            boxed(StreamBody::new(chunks)),
+            Coder::encoder(Encoding::Gzip),
+        );
+        let frame = block_on(body.frame())
+            .expect("Should have had a frame")
+            .expect("Should have been able to compress")
+            .into_data()
+            .expect("Should have been a data frame");
+        let mut decoder = GzDecoder::new(vec![]);
+        decoder
+            .write_all(&frame)
+            .and_then(|()| decoder.flush())
+            .expect("Should have been able to decompress");
+        assert_eq!(TEXT.as_bytes(), &decoder.get_ref()[..]);
+    }
+
+    #[test]
+    fn test_corrupt_upload() {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/upload")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(full("not gzip at all"))
+            .expect("Should have been able to build request");
+        let result = block_on(service().call(req));
+        assert!(result.is_err(), "Should have failed to decode the upload");
+    }
+
+    #[test]
+    fn test_unsupported_upload() {
+        let (response, _) = call(
+            &service(),
+            Method::POST,
+            "/upload",
+            &[(CONTENT_ENCODING, "compress")],
+            full("Upload"),
+        );
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, response.status());
+        assert_eq!(
+            Some("br, gzip, deflate"),
+            header(&response, ACCEPT_ENCODING)
+        );
+    }
+}
@@ -0,0 +1,587 @@
+//! Admission control, capping the requests in flight globally and per route.
+//!
+//! Requests over a cap wait their turn in a bounded queue, first come first served, and are shed
+//! with 503 once the queue is full. With no queue, which is the default, they are shed right away.
+//!
+//! The global cap can be adaptive: it shrinks whenever requests take longer than a target latency
+//! or fail, and grows by one for each quick response while the cap is fully used.
+//!
+//! Requests take their place under the global cap before the service inside is called, and under
+//! the cap of their route once the `Router` inside, even behind other middleware, has matched it.
+//! Around any other service, only the global cap applies.
+use bytes::Bytes;
+use http::{Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::boxed;
+
+use std::collections::{HashMap, VecDeque};
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::route::RouteLayers;
+use crate::timeout::{Clock, TokioClock};
+use crate::{rejected, BoxError, LuminalBody, LuminalFuture};
+
+/// Bounds for an adaptive limit, and the latency above which it backs off.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adaptive {
+    min: usize,
+    max: usize,
+    latency: Duration,
+    backoff: f64,
+}
+
+impl Adaptive {
+    /// A limit between `min` and `max`, backing off when responses take longer than `latency`.
+    pub fn new(min: usize, max: usize, latency: Duration) -> Adaptive {
+        let min = min.max(1);
+        Adaptive {
+            min,
+            max: max.max(min),
+            latency,
+            backoff: 0.9,
+        }
+    }
+
+    /// The fraction the limit is multiplied by when backing off, 0.9 unless set.
+    pub fn backoff(mut self, backoff: f64) -> Self {
+        self.backoff = backoff.clamp(0.0, 1.0);
+        self
+    }
+}
+
+enum Slot {
+    Waiting(Option<Waker>),
+    Admitted,
+    Abandoned,
+}
+
+struct LimitState {
+    limit: usize,
+    in_flight: usize,
+    queue: VecDeque<Arc<Mutex<Slot>>>,
+}
+
+struct Limiter {
+    state: Mutex<LimitState>,
+    queue: usize,
+    adaptive: Option<Adaptive>,
+}
+
+// A place in a limiter, given back when it is dropped.
+struct Permit(Arc<Limiter>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.in_flight -= 1;
+        Limiter::admit(&mut state);
+    }
+}
+
+// A place in a limiter's queue, given up or passed on when it is dropped before it is used.
+struct Queued {
+    limiter: Arc<Limiter>,
+    slot: Arc<Mutex<Slot>>,
+}
+
+impl Queued {
+    fn poll_admitted(&mut self, cx: &mut Context) -> Poll<()> {
+        let mut slot = self
+            .slot
+            .lock()
+            .expect("Should not have poisoned the limiter");
+        match *slot {
+            Slot::Admitted => Poll::Ready(()),
+            _ => {
+                *slot = Slot::Waiting(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        let mut slot = self
+            .slot
+            .lock()
+            .expect("Should not have poisoned the limiter");
+        if let Slot::Admitted = *slot {
+            // admitted but never used, so the permit goes to whoever is next
+            *slot = Slot::Abandoned;
+            drop(slot);
+            drop(Permit(Arc::clone(&self.limiter)));
+        } else {
+            *slot = Slot::Abandoned;
+        }
+    }
+}
+
+enum Admission {
+    Now(Permit),
+    Queued(Queued),
+    Shed,
+}
+
+impl Limiter {
+    fn new(limit: usize, queue: usize, adaptive: Option<Adaptive>) -> Limiter {
+        Limiter {
+            state: Mutex::new(LimitState {
+                limit: limit.max(1),
+                in_flight: 0,
+                queue: VecDeque::new(),
+            }),
+            queue,
+            adaptive,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimitState> {
+        self.state
+            .lock()
+            .expect("Should not have poisoned the limiter")
+    }
+
+    fn try_acquire(self: &Arc<Self>) -> Admission {
+        let mut state = self.lock();
+        state.queue.retain(|slot| {
+            !matches!(
+                *slot.lock().expect("Should not have poisoned the limiter"),
+                Slot::Abandoned
+            )
+        });
+        if state.in_flight < state.limit && state.queue.is_empty() {
+            state.in_flight += 1;
+            return Admission::Now(Permit(Arc::clone(self)));
+        }
+        if state.queue.len() >= self.queue {
+            return Admission::Shed;
+        }
+        let slot = Arc::new(Mutex::new(Slot::Waiting(None)));
+        state.queue.push_back(Arc::clone(&slot));
+        Admission::Queued(Queued {
+            limiter: Arc::clone(self),
+            slot,
+        })
+    }
+
+    // Let in as many queued requests as the limit allows.
+    fn admit(state: &mut LimitState) {
+        while state.in_flight < state.limit {
+            let slot = match state.queue.pop_front() {
+                Some(slot) => slot,
+                None => return,
+            };
+            let mut slot = slot.lock().expect("Should not have poisoned the limiter");
+            if let Slot::Waiting(waker) = &mut *slot {
+                let waker = waker.take();
+                *slot = Slot::Admitted;
+                state.in_flight += 1;
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    async fn acquire(self: &Arc<Self>) -> Option<Permit> {
+        match self.try_acquire() {
+            Admission::Now(permit) => Some(permit),
+            Admission::Shed => None,
+            Admission::Queued(mut queued) => {
+                poll_fn(|cx| queued.poll_admitted(cx)).await;
+                // the permit is now held by this request rather than its place in the queue
+                *queued
+                    .slot
+                    .lock()
+                    .expect("Should not have poisoned the limiter") = Slot::Abandoned;
+                Some(Permit(Arc::clone(&queued.limiter)))
+            }
+        }
+    }
+
+    fn observe(&self, elapsed: Duration, failed: bool) {
+        let adaptive = match self.adaptive {
+            Some(adaptive) => adaptive,
+            None => return,
+        };
+        let mut state = self.lock();
+        if failed || elapsed > adaptive.latency {
+            let limit = (state.limit as f64 * adaptive.backoff) as usize;
+            state.limit = limit.max(adaptive.min);
+        } else if state.in_flight >= state.limit && state.limit < adaptive.max {
+            state.limit += 1;
+            Limiter::admit(&mut state);
+        }
+    }
+
+    fn limit(&self) -> usize {
+        self.lock().limit
+    }
+}
+
+/// A concurrency limit policy, built fluently.
+pub struct ConcurrencyLimit {
+    limit: Option<usize>,
+    adaptive: Option<Adaptive>,
+    routes: HashMap<String, usize>,
+    queue: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl ConcurrencyLimit {
+    /// Allow at most `limit` requests in flight at once.
+    pub fn new(limit: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            limit: Some(limit),
+            ..ConcurrencyLimit::per_route()
+        }
+    }
+
+    /// Adjust the global limit between the bounds, starting from their minimum.
+    pub fn adaptive(adaptive: Adaptive) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            limit: Some(adaptive.min),
+            adaptive: Some(adaptive),
+            ..ConcurrencyLimit::per_route()
+        }
+    }
+
+    /// Only limit routes given a limit with `route`.
+    pub fn per_route() -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            limit: None,
+            adaptive: None,
+            routes: HashMap::new(),
+            queue: 0,
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// Allow at most `limit` requests in flight at once to the route template, as it was added to
+    /// the router, whatever the method.
+    pub fn route(mut self, route: &str, limit: usize) -> Self {
+        self.routes.insert(route.to_owned(), limit);
+        self
+    }
+
+    /// Let up to `queue` requests over each limit wait for a place rather than shedding them.
+    pub fn queue(mut self, queue: usize) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// The clock timing responses for the adaptive limit, such as a `MockClock` in tests.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn wrap<S>(self, inner: S) -> ConcurrencyService<S> {
+        let global = self
+            .limit
+            .map(|limit| Arc::new(Limiter::new(limit, self.queue, self.adaptive)));
+        let routes = self
+            .routes
+            .iter()
+            .map(|(route, limit)| {
+                (
+                    route.clone(),
+                    Arc::new(Limiter::new(*limit, self.queue, None)),
+                )
+            })
+            .collect();
+        ConcurrencyService {
+            limits: Arc::new(Limits {
+                global,
+                routes,
+                clock: self.clock,
+            }),
+            inner,
+        }
+    }
+}
+
+struct Limits {
+    global: Option<Arc<Limiter>>,
+    routes: HashMap<String, Arc<Limiter>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Limits {
+    // Make the call once the request has a place under the global limit.
+    fn admit(
+        limits: Arc<Limits>,
+        call: Box<dyn FnOnce() -> LuminalFuture + Send>,
+    ) -> LuminalFuture {
+        let global = match limits.global.clone() {
+            Some(global) => global,
+            None => return call(),
+        };
+        Box::pin(async move {
+            let _global = match global.acquire().await {
+                Some(permit) => permit,
+                None => return rejected(StatusCode::SERVICE_UNAVAILABLE).await,
+            };
+
+            let start = limits.clock.now();
+            let result = call().await;
+            let failed = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+            global.observe(limits.clock.now().saturating_duration_since(start), failed);
+            result
+        })
+    }
+
+    // Make the call once the request also has a place under its route's limit.
+    fn admit_route(
+        route: Arc<Limiter>,
+        call: Box<dyn FnOnce() -> LuminalFuture + Send>,
+    ) -> LuminalFuture {
+        Box::pin(async move {
+            let _route = match route.acquire().await {
+                Some(permit) => permit,
+                None => return rejected(StatusCode::SERVICE_UNAVAILABLE).await,
+            };
+            call().await
+        })
+    }
+}
+
+/// A service capping the requests in flight to its inner service, see `ConcurrencyLimit`.
+pub struct ConcurrencyService<S> {
+    limits: Arc<Limits>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for ConcurrencyService<S> {
+    fn clone(&self) -> Self {
+        ConcurrencyService {
+            limits: Arc::clone(&self.limits),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S> ConcurrencyService<S> {
+    /// The global limit as it stands, which only changes when it is adaptive.
+    pub fn current_limit(&self) -> Option<usize> {
+        self.limits.global.as_ref().map(|global| global.limit())
+    }
+}
+
+impl<S, B> Service<Request<B>> for ConcurrencyService<S>
+where
+    S: Service<
+            Request<LuminalBody>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Clone
+        + Send
+        + 'static,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        if !self.limits.routes.is_empty() {
+            // the route's place is taken once the router matched the request
+            let limits = Arc::clone(&self.limits);
+            RouteLayers::push(&mut req, move |matched, req, next| {
+                match matched.route().and_then(|route| limits.routes.get(route)) {
+                    Some(route) => {
+                        Limits::admit_route(Arc::clone(route), Box::new(move || next(req)))
+                    }
+                    None => next(req),
+                }
+            });
+        }
+        let inner = self.inner.clone();
+        Limits::admit(Arc::clone(&self.limits), Box::new(move || inner.call(req)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker;
+    use luminal_handler::empty;
+
+    use std::future::pending;
+
+    use super::*;
+    use crate::timeout::MockClock;
+    use crate::{FnRouteBuilder, Router};
+
+    fn router(clock: MockClock) -> Router {
+        FnRouteBuilder::new()
+            .get("/hang", |_req| async {
+                pending::<()>().await;
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .get("/keys/:id", |_req| async {
+                pending::<()>().await;
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .get("/slow", move |_req| {
+                // every response takes a second
+                clock.advance(Duration::from_secs(1));
+                async { Ok(Response::new(empty())) }
+            })
+            .expect("Should have been able to add route")
+            .get("/fast", |_req| async { Ok(Response::new(empty())) })
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    fn get(uri: &str) -> Request<LuminalBody> {
+        Request::builder()
+            .uri(uri)
+            .body(empty())
+            .expect("Should have been able to build request")
+    }
+
+    fn poll(future: &mut LuminalFuture) -> Poll<StatusCode> {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(response) => Poll::Ready(
+                response
+                    .expect("Should have been able to run call")
+                    .status(),
+            ),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    #[test]
+    fn test_shed() {
+        let service = ConcurrencyLimit::new(2).wrap(router(MockClock::new()));
+        let mut first = service.call(get("/hang"));
+        let mut second = service.call(get("/hang"));
+        assert!(poll(&mut first).is_pending());
+        assert!(poll(&mut second).is_pending());
+        let mut third = service.call(get("/fast"));
+        assert_eq!(
+            Poll::Ready(StatusCode::SERVICE_UNAVAILABLE),
+            poll(&mut third)
+        );
+
+        // a place frees up as soon as a request is dropped
+        drop(first);
+        let mut fourth = service.call(get("/fast"));
+        assert_eq!(Poll::Ready(StatusCode::OK), poll(&mut fourth));
+    }
+
+    #[test]
+    fn test_queue() {
+        let service = ConcurrencyLimit::new(1)
+            .queue(1)
+            .wrap(router(MockClock::new()));
+        let mut first = service.call(get("/hang"));
+        assert!(poll(&mut first).is_pending());
+        let mut queued = service.call(get("/fast"));
+        assert!(poll(&mut queued).is_pending());
+        let mut shed = service.call(get("/fast"));
+        assert_eq!(
+            Poll::Ready(StatusCode::SERVICE_UNAVAILABLE),
+            poll(&mut shed)
+        );
+
+        drop(first);
+        assert_eq!(Poll::Ready(StatusCode::OK), poll(&mut queued));
+
+        // a request that gives up its place in the queue doesn't hold anyone else up
+        let mut first = service.call(get("/hang"));
+        assert!(poll(&mut first).is_pending());
+        let mut abandoned = service.call(get("/fast"));
+        assert!(poll(&mut abandoned).is_pending());
+        drop(abandoned);
+        let mut queued = service.call(get("/fast"));
+        assert!(poll(&mut queued).is_pending());
+        drop(first);
+        assert_eq!(Poll::Ready(StatusCode::OK), poll(&mut queued));
+    }
+
+    #[test]
+    fn test_per_route() {
+        let service = ConcurrencyLimit::per_route()
+            .route("/keys/:id", 1)
+            .wrap(router(MockClock::new()));
+        let mut first = service.call(get("/keys/1"));
+        assert!(poll(&mut first).is_pending());
+        let mut second = service.call(get("/keys/2"));
+        assert_eq!(
+            Poll::Ready(StatusCode::SERVICE_UNAVAILABLE),
+            poll(&mut second)
+        );
+        let mut other = service.call(get("/hang"));
+        assert!(poll(&mut other).is_pending());
+        assert_eq!(None, service.current_limit());
+    }
+
+    #[test]
+    fn test_per_route_deferred() {
+        // the inner limiter only calls the router from within its future
+        let service = ConcurrencyLimit::new(1)
+            .route("/keys/:id", 1)
+            .wrap(ConcurrencyLimit::new(5).wrap(router(MockClock::new())));
+        let mut first = service.call(get("/fast"));
+        assert_eq!(Poll::Ready(StatusCode::OK), poll(&mut first));
+
+        let mut first = service.call(get("/keys/1"));
+        assert!(poll(&mut first).is_pending());
+        // the global place is taken once, so nothing else fits beside it
+        let mut second = service.call(get("/fast"));
+        assert_eq!(
+            Poll::Ready(StatusCode::SERVICE_UNAVAILABLE),
+            poll(&mut second)
+        );
+        drop(first);
+        let mut third = service.call(get("/keys/2"));
+        assert!(poll(&mut third).is_pending());
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let clock = MockClock::new();
+        let service = ConcurrencyLimit::adaptive(Adaptive::new(2, 4, Duration::from_millis(100)))
+            .clock(clock.clone())
+            .wrap(router(clock));
+        assert_eq!(Some(2), service.current_limit());
+
+        // quick responses while the limit is used up raise it
+        let mut hang = service.call(get("/hang"));
+        assert!(poll(&mut hang).is_pending());
+        for expected in [3, 4, 4] {
+            let mut hangs = Vec::new();
+            while hangs.len() + 2 < service.current_limit().unwrap_or_default() {
+                let mut hang = service.call(get("/hang"));
+                assert!(poll(&mut hang).is_pending());
+                hangs.push(hang);
+            }
+            let mut fast = service.call(get("/fast"));
+            assert_eq!(Poll::Ready(StatusCode::OK), poll(&mut fast));
+            assert_eq!(Some(expected), service.current_limit());
+        }
+
+        // slow responses back it off, down to the minimum
+        for expected in [3, 2, 2] {
+            let mut slow = service.call(get("/slow"));
+            assert_eq!(Poll::Ready(StatusCode::OK), poll(&mut slow));
+            assert_eq!(Some(expected), service.current_limit());
+        }
+    }
+}
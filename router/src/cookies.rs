@@ -0,0 +1,301 @@
+//! Cookies, read from the request and written back to the response as `Set-Cookie`.
+//!
+//! `CookieService` puts a `Cookies` jar in the request extensions. Handlers read and change it
+//! there, and whatever they add or remove is sent with the response. Cookies can be plain, signed,
+//! so clients can read but not change them, or private, encrypted and authenticated so clients
+//! can do neither.
+//!
+//! Signed and private cookies are written with the first of the configured keys and read with
+//! any of them, so keys can be rotated without logging everyone out.
+use bytes::Bytes;
+use http::header::{HeaderValue, COOKIE, SET_COOKIE};
+use http::{Request, Response};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::boxed;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub use cookie::{Cookie, Key, SameSite};
+
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// The cookies of a request, from the request extensions.
+///
+/// Clones share the same jar, so changes made in a handler reach the `CookieService` that sends
+/// them on.
+#[derive(Clone)]
+pub struct Cookies {
+    jar: Arc<Mutex<cookie::CookieJar>>,
+    keys: Arc<Vec<Key>>,
+}
+
+impl Cookies {
+    fn jar(&self) -> MutexGuard<'_, cookie::CookieJar> {
+        self.jar
+            .lock()
+            .expect("Should not have poisoned the cookies")
+    }
+
+    pub fn get(&self, name: &str) -> Option<Cookie<'static>> {
+        self.jar().get(name).cloned()
+    }
+
+    pub fn add(&self, cookie: Cookie<'static>) {
+        self.jar().add(cookie);
+    }
+
+    /// Remove the cookie, which should have the same path and domain as when it was added.
+    pub fn remove(&self, cookie: Cookie<'static>) {
+        self.jar().remove(cookie);
+    }
+
+    /// The cookie, if it was signed by any of the keys, with its signature removed.
+    pub fn get_signed(&self, name: &str) -> Option<Cookie<'static>> {
+        let jar = self.jar();
+        self.keys.iter().find_map(|key| jar.signed(key).get(name))
+    }
+
+    pub fn add_signed(&self, cookie: Cookie<'static>) {
+        if let Some(key) = self.keys.first() {
+            self.jar().signed_mut(key).add(cookie);
+        }
+    }
+
+    // Whether there is a key to sign and encrypt cookies with.
+    pub(crate) fn can_sign(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// The cookie, if it was encrypted by any of the keys, decrypted.
+    pub fn get_private(&self, name: &str) -> Option<Cookie<'static>> {
+        let jar = self.jar();
+        self.keys.iter().find_map(|key| jar.private(key).get(name))
+    }
+
+    pub fn add_private(&self, cookie: Cookie<'static>) {
+        if let Some(key) = self.keys.first() {
+            self.jar().private_mut(key).add(cookie);
+        }
+    }
+}
+
+/// The keys for signed and private cookies, built fluently.
+///
+/// Without keys, signed and private cookies are never read and adding them does nothing.
+#[derive(Default)]
+pub struct CookieManager {
+    keys: Vec<Key>,
+}
+
+impl CookieManager {
+    pub fn new() -> CookieManager {
+        CookieManager::default()
+    }
+
+    /// Sign and encrypt with the key, and read cookies written with any key added before it.
+    pub fn key(mut self, key: Key) -> Self {
+        self.keys.insert(0, key);
+        self
+    }
+
+    pub fn wrap<S>(self, inner: S) -> CookieService<S> {
+        CookieService {
+            keys: Arc::new(self.keys),
+            inner,
+        }
+    }
+}
+
+/// A service managing the cookies of its requests, see `CookieManager`.
+pub struct CookieService<S> {
+    keys: Arc<Vec<Key>>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for CookieService<S> {
+    fn clone(&self) -> Self {
+        CookieService {
+            keys: Arc::clone(&self.keys),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for CookieService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let mut jar = cookie::CookieJar::new();
+        let headers = req.headers().get_all(COOKIE);
+        for pair in headers
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+        {
+            if let Ok(cookie) = Cookie::parse_encoded(pair.trim().to_owned()) {
+                jar.add_original(cookie);
+            }
+        }
+        let cookies = Cookies {
+            jar: Arc::new(Mutex::new(jar)),
+            keys: Arc::clone(&self.keys),
+        };
+        req.extensions_mut().insert(cookies.clone());
+
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            for cookie in cookies.jar().delta() {
+                let cookie = cookie.encoded().to_string();
+                response
+                    .headers_mut()
+                    .append(SET_COOKIE, HeaderValue::from_str(&cookie)?);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use luminal_handler::empty;
+
+    use super::*;
+    use crate::FnRouteBuilder;
+
+    fn cookies(req: &Request<LuminalBody>) -> Cookies {
+        req.extensions()
+            .get::<Cookies>()
+            .cloned()
+            .expect("Should have had cookies")
+    }
+
+    fn set_cookies(response: &Response<LuminalBody>) -> Vec<String> {
+        response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| {
+                value
+                    .to_str()
+                    .expect("Should have been a string")
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    fn call<S>(service: &S, cookie: &str) -> Response<LuminalBody>
+    where
+        S: Service<Request<LuminalBody>, Response = Response<LuminalBody>, Error = BoxError>,
+    {
+        let req = Request::builder()
+            .uri("/")
+            .header(COOKIE, cookie)
+            .body(empty())
+            .expect("Should have been able to build request");
+        block_on(service.call(req)).expect("Should have been able to run call")
+    }
+
+    #[test]
+    fn test_jar() {
+        let router = FnRouteBuilder::new()
+            .get("/", |req| async move {
+                let cookies = cookies(&req);
+                let visits = cookies
+                    .get("visits")
+                    .and_then(|cookie| cookie.value().parse::<u32>().ok())
+                    .unwrap_or_default();
+                assert_eq!(
+                    Some(String::from("a b")),
+                    cookies.get("name").map(|cookie| cookie.value().to_owned())
+                );
+                cookies.add(Cookie::new("visits", (visits + 1).to_string()));
+                cookies.remove(Cookie::from("theme"));
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .build();
+        let service = CookieManager::new().wrap(router);
+
+        let response = call(&service, "visits=2; name=a%20b; theme=dark");
+        let mut set = set_cookies(&response);
+        set.sort();
+        assert_eq!(2, set.len());
+        assert!(set[0].starts_with("theme=; "), "{:?}", set);
+        assert!(set[0].contains("Max-Age=0"));
+        assert_eq!("visits=3", set[1]);
+    }
+
+    #[test]
+    fn test_signed_private() {
+        let old = Key::generate();
+        let router = FnRouteBuilder::new()
+            .get("/", |req| async move {
+                let cookies = cookies(&req);
+                if let Some(user) = cookies.get_signed("user") {
+                    cookies.add_signed(user);
+                }
+                if let Some(cart) = cookies.get_private("cart") {
+                    cookies.add_private(cart);
+                }
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .build();
+
+        // cookies written with the old key
+        let mut jar = cookie::CookieJar::new();
+        jar.signed_mut(&old).add(Cookie::new("user", "alice"));
+        jar.private_mut(&old).add(Cookie::new("cart", "3 apples"));
+        let header = jar
+            .delta()
+            .map(|cookie| cookie.stripped().encoded().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        let service = CookieManager::new()
+            .key(old.clone())
+            .key(Key::generate())
+            .wrap(router);
+        let set = set_cookies(&call(&service, &header));
+        assert_eq!(2, set.len());
+        // read with the old key, written back with the new one
+        assert!(set.iter().all(|cookie| !header.contains(cookie.as_str())));
+        // signed cookies can still be read by the client, private ones can't
+        assert!(set.iter().any(|cookie| cookie.ends_with("alice")));
+        assert!(set.iter().all(|cookie| !cookie.contains("apples")));
+        let again = set.join("; ");
+        assert_eq!(2, set_cookies(&call(&service, &again)).len());
+
+        // a signed cookie that was changed is ignored
+        let forged = header.replace("alice", "mallory");
+        let service = CookieManager::new().key(old).wrap(
+            FnRouteBuilder::new()
+                .get("/", |req| async move {
+                    let cookies = cookies(&req);
+                    assert!(cookies.get_signed("user").is_none());
+                    assert!(cookies.get("user").is_some());
+                    assert!(cookies.get_private("cart").is_some());
+                    Ok(Response::new(empty()))
+                })
+                .expect("Should have been able to add route")
+                .build(),
+        );
+        assert!(set_cookies(&call(&service, &forged)).is_empty());
+    }
+}
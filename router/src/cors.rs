@@ -0,0 +1,588 @@
+//! Cross-Origin Resource Sharing for services built on a `Router`.
+//!
+//! `Cors` holds the policy and `wrap` puts it in front of a service. Preflight requests are
+//! answered by the wrapper itself, with the allowed methods taken from what is actually routed
+//! for the requested path, so the policy never has to repeat the route table. The `Router` inside,
+//! even behind other middleware, looks them up without calling the target. Around any other
+//! service, preflights are answered from the methods given to `allow_methods` alone.
+use bytes::Bytes;
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use http::{Method, Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::boxed;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::route::RouteLayers;
+use crate::{rejected, BoxError, LuminalBody, LuminalFuture};
+
+enum AllowOrigin {
+    Any,
+    List(Vec<String>),
+    Predicate(Box<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+/// A CORS policy, built fluently.
+///
+/// A new policy allows no origins at all. Requests from an allowed origin get the CORS response
+/// headers added, requests without an `Origin` or from any other origin pass through untouched.
+pub struct Cors {
+    origins: AllowOrigin,
+    methods: Option<Vec<Method>>,
+    headers: Option<Vec<HeaderName>>,
+    expose: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cors {
+    pub fn new() -> Cors {
+        Cors {
+            origins: AllowOrigin::List(vec![]),
+            methods: None,
+            headers: Some(vec![]),
+            expose: vec![],
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allow requests from every origin.
+    pub fn allow_any_origin(mut self) -> Self {
+        self.origins = AllowOrigin::Any;
+        self
+    }
+
+    /// Allow requests from an exact origin, such as `https://example.com`. Can be called for as
+    /// many origins as needed.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        match self.origins {
+            AllowOrigin::List(ref mut origins) => origins.push(origin.to_ascii_lowercase()),
+            _ => self.origins = AllowOrigin::List(vec![origin.to_ascii_lowercase()]),
+        }
+        self
+    }
+
+    /// Allow requests from any origin the function accepts.
+    pub fn allow_origin_fn<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.origins = AllowOrigin::Predicate(Box::new(predicate));
+        self
+    }
+
+    /// Limit cross-origin requests to these methods. Without a limit every method routed for the
+    /// path is allowed.
+    pub fn allow_methods(mut self, methods: &[Method]) -> Self {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    /// Allow these request headers beyond the CORS-safelisted ones.
+    pub fn allow_headers(mut self, headers: &[HeaderName]) -> Self {
+        if let Some(ref mut allowed) = self.headers {
+            allowed.extend_from_slice(headers);
+        }
+        self
+    }
+
+    /// Allow whatever request headers a preflight asks for.
+    pub fn allow_any_header(mut self) -> Self {
+        self.headers = None;
+        self
+    }
+
+    /// Let browsers read these response headers beyond the CORS-safelisted ones.
+    pub fn expose_headers(mut self, headers: &[HeaderName]) -> Self {
+        self.expose.extend_from_slice(headers);
+        self
+    }
+
+    /// Allow cookies and authorization on cross-origin requests.
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache the result of a preflight.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Put this policy in front of a service.
+    pub fn wrap<S>(self, inner: S) -> CorsService<S> {
+        CorsService {
+            cors: Arc::new(self),
+            inner,
+        }
+    }
+
+    fn allows_origin(&self, origin: &HeaderValue) -> bool {
+        let origin = match origin.to_str() {
+            Ok(origin) => origin,
+            Err(_) => return false,
+        };
+        match self.origins {
+            AllowOrigin::Any => true,
+            AllowOrigin::List(ref origins) => origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+            AllowOrigin::Predicate(ref predicate) => predicate(origin),
+        }
+    }
+
+    // A wildcard is only allowed when there are no credentials, otherwise the origin is echoed.
+    fn allow_origin_value(&self, origin: &HeaderValue) -> HeaderValue {
+        match self.origins {
+            AllowOrigin::Any if !self.credentials => HeaderValue::from_static("*"),
+            _ => origin.clone(),
+        }
+    }
+
+    fn allows_header(&self, header: &str) -> bool {
+        match self.headers {
+            None => true,
+            Some(ref allowed) => allowed
+                .iter()
+                .any(|name| name.as_str().eq_ignore_ascii_case(header)),
+        }
+    }
+
+    // The response to a preflight, or the status it was refused with.
+    fn preflight(
+        &self,
+        headers: &HeaderMap,
+        routed: &[Method],
+    ) -> Result<Response<LuminalBody>, StatusCode> {
+        let origin = headers.get(ORIGIN).ok_or(StatusCode::FORBIDDEN)?;
+        if !self.allows_origin(origin) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        if routed.is_empty() {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let methods = routed
+            .iter()
+            .filter(|method| {
+                self.methods
+                    .as_ref()
+                    .is_none_or(|limit| limit.contains(method))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let requested = headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Method>().ok())
+            .ok_or(StatusCode::FORBIDDEN)?;
+        if !methods.contains(&requested) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let requested_headers = headers.get(ACCESS_CONTROL_REQUEST_HEADERS);
+        if let Some(requested_headers) = requested_headers {
+            let headers = requested_headers
+                .to_str()
+                .map_err(|_| StatusCode::FORBIDDEN)?;
+            let all_allowed = headers
+                .split(',')
+                .map(str::trim)
+                .filter(|header| !header.is_empty())
+                .all(|header| self.allows_header(header));
+            if !all_allowed {
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+
+        let mut builder = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, self.allow_origin_value(origin))
+            .header(
+                VARY,
+                "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+            )
+            .header(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                methods
+                    .iter()
+                    .map(Method::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        if let Some(requested_headers) = requested_headers {
+            builder = builder.header(ACCESS_CONTROL_ALLOW_HEADERS, requested_headers);
+        }
+        if self.credentials {
+            builder = builder.header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if let Some(max_age) = self.max_age {
+            builder = builder.header(ACCESS_CONTROL_MAX_AGE, max_age.as_secs());
+        }
+        builder
+            .body(luminal_handler::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn respond(&self, headers: &HeaderMap, routed: &[Method]) -> LuminalFuture {
+        match self.preflight(headers, routed) {
+            Ok(response) => Box::pin(async move { Ok(response) }),
+            Err(status) => rejected(status),
+        }
+    }
+
+    // Add the headers for a cross-origin response that is not a preflight.
+    fn decorate(&self, origin: &HeaderValue, response: &mut Response<LuminalBody>) {
+        let allow_origin = self.allow_origin_value(origin);
+        let headers = response.headers_mut();
+        if allow_origin != "*" {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if !self.expose.is_empty() {
+            let expose = self
+                .expose
+                .iter()
+                .map(HeaderName::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+            if let Ok(expose) = HeaderValue::from_str(&expose) {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose);
+            }
+        }
+    }
+}
+
+/// A service behind a `Cors` policy, see `Cors::wrap`.
+pub struct CorsService<S> {
+    cors: Arc<Cors>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for CorsService<S> {
+    fn clone(&self) -> Self {
+        CorsService {
+            cors: Arc::clone(&self.cors),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for CorsService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let origin = match req.headers().get(ORIGIN) {
+            Some(origin) => origin.clone(),
+            None => return self.inner.call(req),
+        };
+        if req.method() == Method::OPTIONS
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            // answered from the methods the router has for the path, without calling the target
+            let headers = req.headers().clone();
+            let cors = Arc::clone(&self.cors);
+            let matched = RouteLayers::push(&mut req, move |matched, req, _| {
+                cors.respond(req.headers(), matched.allowed_methods())
+            });
+            let cors = Arc::clone(&self.cors);
+            let response = self.inner.call(req);
+            return Box::pin(async move {
+                let response = response.await?;
+                if matched.get().is_some() {
+                    return Ok(response);
+                }
+                // with no router inside there is no route table, only the methods of the policy
+                let methods = cors.methods.clone().unwrap_or_default();
+                cors.respond(&headers, &methods).await
+            });
+        }
+        if !self.cors.allows_origin(&origin) {
+            return self.inner.call(req);
+        }
+
+        let cors = Arc::clone(&self.cors);
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            cors.decorate(&origin, &mut response);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http::header::{CONTENT_TYPE, ETAG};
+    use http_body_util::BodyExt;
+    use luminal_handler::{empty, full};
+
+    use super::*;
+    use crate::{ConcurrencyLimit, Router, ServiceRouteBuilder};
+
+    fn router() -> Router {
+        ServiceRouteBuilder::new()
+            .fn_builder()
+            .get("/foo", |_req| async {
+                Ok(Response::builder()
+                    .header(ETAG, "\"foo\"")
+                    .body(full("Foo"))?)
+            })
+            .expect("Should have been able to add route")
+            .service_builder()
+            .post("/foo", Router::new())
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    fn service(cors: Cors) -> CorsService<Router> {
+        cors.wrap(router())
+    }
+
+    fn call(
+        service: &CorsService<Router>,
+        method: Method,
+        headers: &[(HeaderName, &'static str)],
+    ) -> Response<LuminalBody> {
+        let mut builder = Request::builder().method(method).uri("/foo");
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        let req = builder
+            .body(empty())
+            .expect("Should have been able to build request");
+        block_on(service.call(req)).expect("Should have been able to run call")
+    }
+
+    fn header(response: &Response<LuminalBody>, name: HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().expect("Should have been a string header"))
+    }
+
+    #[test]
+    fn test_preflight() {
+        let service = service(
+            Cors::new()
+                .allow_origin("https://example.com")
+                .allow_headers(&[CONTENT_TYPE])
+                .allow_credentials(true)
+                .max_age(Duration::from_secs(600)),
+        );
+        let response = call(
+            &service,
+            Method::OPTIONS,
+            &[
+                (ORIGIN, "https://example.com"),
+                (ACCESS_CONTROL_REQUEST_METHOD, "POST"),
+                (ACCESS_CONTROL_REQUEST_HEADERS, "Content-Type"),
+            ],
+        );
+
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert_eq!(
+            Some("https://example.com"),
+            header(&response, ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+        assert_eq!(
+            Some("GET, POST"),
+            header(&response, ACCESS_CONTROL_ALLOW_METHODS)
+        );
+        assert_eq!(
+            Some("Content-Type"),
+            header(&response, ACCESS_CONTROL_ALLOW_HEADERS)
+        );
+        assert_eq!(
+            Some("true"),
+            header(&response, ACCESS_CONTROL_ALLOW_CREDENTIALS)
+        );
+        assert_eq!(Some("600"), header(&response, ACCESS_CONTROL_MAX_AGE));
+    }
+
+    #[test]
+    fn test_preflight_deferred() {
+        // the limiter only calls the router from within its future
+        let service = Cors::new()
+            .allow_origin("https://example.com")
+            .wrap(ConcurrencyLimit::new(10).wrap(router()));
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/foo")
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert_eq!(
+            Some("GET, POST"),
+            header(&response, ACCESS_CONTROL_ALLOW_METHODS)
+        );
+
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/missing")
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[test]
+    fn test_preflight_refused() {
+        let service = service(
+            Cors::new()
+                .allow_origin_fn(|origin| origin.ends_with(".example.com"))
+                .allow_methods(&[Method::GET]),
+        );
+        let refused = |headers: &[(HeaderName, &'static str)]| {
+            call(&service, Method::OPTIONS, headers).status()
+        };
+
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            refused(&[
+                (ORIGIN, "https://example.org"),
+                (ACCESS_CONTROL_REQUEST_METHOD, "GET"),
+            ])
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            refused(&[
+                (ORIGIN, "https://app.example.com"),
+                (ACCESS_CONTROL_REQUEST_METHOD, "POST"),
+            ])
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            refused(&[
+                (ORIGIN, "https://app.example.com"),
+                (ACCESS_CONTROL_REQUEST_METHOD, "GET"),
+                (ACCESS_CONTROL_REQUEST_HEADERS, "x-custom"),
+            ])
+        );
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            refused(&[
+                (ORIGIN, "https://app.example.com"),
+                (ACCESS_CONTROL_REQUEST_METHOD, "GET"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_preflight_not_found() {
+        let service = Cors::new().allow_any_origin().wrap(Router::new());
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/missing")
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[test]
+    fn test_preflight_plain_service() {
+        let service = Cors::new()
+            .allow_any_origin()
+            .allow_methods(&[Method::GET, Method::PUT])
+            .wrap(hyper::service::service_fn(
+                |_req: Request<LuminalBody>| -> LuminalFuture {
+                    Box::pin(async { Ok(Response::new(empty())) })
+                },
+            ));
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/foo")
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert_eq!(
+            Some("GET, PUT"),
+            header(&response, ACCESS_CONTROL_ALLOW_METHODS)
+        );
+    }
+
+    #[test]
+    fn test_simple_request() {
+        let service = service(Cors::new().allow_any_origin().expose_headers(&[ETAG]));
+        let response = call(&service, Method::GET, &[(ORIGIN, "https://example.com")]);
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(Some("*"), header(&response, ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(
+            Some("etag"),
+            header(&response, ACCESS_CONTROL_EXPOSE_HEADERS)
+        );
+        assert_eq!(None, header(&response, VARY));
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        assert_eq!(&b"Foo"[..], &body[..]);
+    }
+
+    #[test]
+    fn test_credentials_echo_origin() {
+        let service = service(Cors::new().allow_any_origin().allow_credentials(true));
+        let response = call(&service, Method::GET, &[(ORIGIN, "https://example.com")]);
+
+        assert_eq!(
+            Some("https://example.com"),
+            header(&response, ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+        assert_eq!(Some("Origin"), header(&response, VARY));
+    }
+
+    #[test]
+    fn test_disallowed_origin() {
+        let service = service(Cors::new().allow_origin("https://example.com"));
+        let response = call(&service, Method::GET, &[(ORIGIN, "https://example.org")]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(None, header(&response, ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let response = call(&service, Method::GET, &[]);
+        assert_eq!(None, header(&response, ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,844 @@
+//! Cross-site request forgery protection.
+//!
+//! `CsrfService` gives every request a `CsrfToken` in its extensions, for handlers to put in
+//! their forms or pages, and makes sure requests with unsafe methods, POST, PUT, PATCH and
+//! DELETE, send it back, in the `X-CSRF-Token` header or the `csrf_token` field of a URL encoded
+//! form. The token is kept either in a signed cookie the request must match, the double-submit
+//! pattern, or in the `Session`, the synchronizer pattern. Signing keeps a cookie tossed in from
+//! a sibling subdomain from passing for one the site set.
+//!
+//! A client without a token only gets one once a handler reads the `CsrfToken`, so requests that
+//! never render a form don't set a cookie or touch the session.
+//!
+//! Unsafe requests must also come from the same origin, by `Origin`, or `Referer` when there is
+//! no `Origin`, unless the origin is trusted. Anything else gets 403.
+//!
+//! Routes can be exempted with the `csrf_exempt` method of the router builders. A refused request
+//! is handed to the `Router` inside, which lets it through once it has matched an exempt route.
+//! Only a router directly inside is found on its own, one behind other middleware needs
+//! `router_inside`. Around any other service nothing is exempt, and refused requests are turned
+//! away without calling it.
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, HOST, ORIGIN, REFERER};
+use http::uri::Authority;
+use http::{Method, Request, Response, StatusCode, Uri};
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use hyper::body::{Body, Frame};
+use hyper::service::Service;
+use luminal_handler::{boxed, full};
+use percent_encoding::percent_decode_str;
+use subtle::ConstantTimeEq;
+
+use std::any::TypeId;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, OnceLock};
+
+use crate::cookies::{Cookie, Cookies, SameSite};
+use crate::host::HostRouter;
+use crate::reload::ReloadableRouter;
+use crate::route::RouteLayers;
+use crate::service::Router;
+use crate::session::Session;
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// The session key the synchronizer pattern keeps its token under.
+const SESSION_KEY: &str = "csrf_token";
+
+/// The CSRF token of a request, from the request extensions.
+///
+/// A client that doesn't have a token yet is issued one the first time it is read.
+#[derive(Clone)]
+pub struct CsrfToken {
+    token: Arc<OnceLock<String>>,
+    issuer: Arc<Issuer>,
+}
+
+impl CsrfToken {
+    pub fn as_str(&self) -> &str {
+        self.token.get_or_init(|| self.issuer.issue())
+    }
+}
+
+impl fmt::Debug for CsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("CsrfToken").field(&self.token.get()).finish()
+    }
+}
+
+impl fmt::Display for CsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl PartialEq for CsrfToken {
+    fn eq(&self, other: &CsrfToken) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for CsrfToken {}
+
+impl Hash for CsrfToken {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+// Where a new token is kept once it is issued.
+enum Issuer {
+    Cookie(Cookies, Cookie<'static>),
+    Session(Session),
+}
+
+impl Issuer {
+    fn issue(&self) -> String {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        match self {
+            Issuer::Cookie(cookies, cookie) => {
+                let mut cookie = cookie.clone();
+                cookie.set_value(token.clone());
+                cookies.add_signed(cookie);
+            }
+            Issuer::Session(session) => {
+                // a string always serializes
+                let _ = session.insert(SESSION_KEY, &token);
+            }
+        }
+        token
+    }
+}
+
+enum Pattern {
+    // the cookie holding the token
+    DoubleSubmit(Cookie<'static>),
+    Synchronizer,
+}
+
+/// A CSRF policy, built fluently.
+///
+/// The double-submit pattern needs a `CookieService` with a key wrapped around the `CsrfService`,
+/// the synchronizer pattern a `SessionService`.
+pub struct Csrf {
+    pattern: Pattern,
+    header: HeaderName,
+    field: String,
+    trusted: Vec<String>,
+    form_limit: usize,
+    router_inside: bool,
+    body: Bytes,
+}
+
+impl Csrf {
+    /// Keep the token in the signed `luminal.csrf` cookie. The cookie isn't `HttpOnly`, but
+    /// scripts sending the token as a header should take it from the page, where it is without
+    /// the signature.
+    pub fn double_submit() -> Csrf {
+        let cookie = Cookie::build(("luminal.csrf", ""))
+            .path("/")
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .build();
+        Csrf::with_pattern(Pattern::DoubleSubmit(cookie))
+    }
+
+    /// Keep the token in the session.
+    pub fn synchronizer() -> Csrf {
+        Csrf::with_pattern(Pattern::Synchronizer)
+    }
+
+    fn with_pattern(pattern: Pattern) -> Csrf {
+        Csrf {
+            pattern,
+            header: HeaderName::from_static("x-csrf-token"),
+            field: String::from("csrf_token"),
+            trusted: Vec::new(),
+            form_limit: 64 * 1024,
+            router_inside: false,
+            body: Bytes::new(),
+        }
+    }
+
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// The form field the token is read from when it isn't in the header.
+    pub fn field(mut self, field: &str) -> Self {
+        self.field = field.to_owned();
+        self
+    }
+
+    /// Accept requests from another origin, such as `https://admin.example.com`.
+    pub fn trust_origin(mut self, origin: &str) -> Self {
+        self.trusted
+            .push(origin.trim_end_matches('/').to_ascii_lowercase());
+        self
+    }
+
+    /// The largest form body read looking for the token, 64KB unless set. Larger forms have to
+    /// send it in the header.
+    pub fn form_limit(mut self, form_limit: usize) -> Self {
+        self.form_limit = form_limit;
+        self
+    }
+
+    /// Whether the double-submit cookie is only sent over HTTPS, turned off for local
+    /// development.
+    pub fn secure(mut self, secure: bool) -> Self {
+        if let Pattern::DoubleSubmit(cookie) = &mut self.pattern {
+            cookie.set_secure(secure);
+        }
+        self
+    }
+
+    /// Answer rejected requests with this plain text body.
+    pub fn body<T: Into<Bytes>>(mut self, body: T) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Hand refused requests on to the service inside, for a `Router` behind other middleware to
+    /// let those to exempt routes through.
+    ///
+    /// Refused requests go through the middleware in between, and reach a service inside that
+    /// isn't a router, before they are turned away, so only set this when there is a router.
+    pub fn router_inside(mut self) -> Self {
+        self.router_inside = true;
+        self
+    }
+
+    pub fn wrap<S: 'static>(self, inner: S) -> CsrfService<S> {
+        let routed = self.router_inside || is_router::<S>();
+        CsrfService {
+            csrf: Arc::new(self),
+            routed,
+            inner,
+        }
+    }
+
+    fn forbidden(&self) -> Response<LuminalBody> {
+        let mut response = Response::new(full(self.body.clone()));
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        if !self.body.is_empty() {
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+        }
+        response
+    }
+
+    // The token the request has to match, if it has one yet, and the token for handlers, issued
+    // when they first read it otherwise.
+    fn token(&self, req: &Request<LuminalBody>) -> Result<(Option<String>, CsrfToken), BoxError> {
+        let (existing, issuer) = match &self.pattern {
+            Pattern::DoubleSubmit(cookie) => {
+                let cookies = req
+                    .extensions()
+                    .get::<Cookies>()
+                    .ok_or("Double-submit CSRF needs a CookieService wrapped around it")?;
+                if !cookies.can_sign() {
+                    return Err("Double-submit CSRF needs a key to sign its cookie with".into());
+                }
+                let existing = cookies
+                    .get_signed(cookie.name())
+                    .map(|cookie| cookie.value().to_owned());
+                (existing, Issuer::Cookie(cookies.clone(), cookie.clone()))
+            }
+            Pattern::Synchronizer => {
+                let session = req
+                    .extensions()
+                    .get::<Session>()
+                    .ok_or("Synchronizer CSRF needs a SessionService wrapped around it")?;
+                let existing = session.get::<String>(SESSION_KEY);
+                (existing, Issuer::Session(session.clone()))
+            }
+        };
+        let existing = existing.filter(|token| !token.is_empty());
+        let token = OnceLock::new();
+        if let Some(existing) = &existing {
+            let _ = token.set(existing.clone());
+        }
+        let token = CsrfToken {
+            token: Arc::new(token),
+            issuer: Arc::new(issuer),
+        };
+        Ok((existing, token))
+    }
+
+    // Whether the request comes from the same origin, or a trusted one.
+    fn same_origin(&self, req: &Request<LuminalBody>) -> bool {
+        let origin = match (req.headers().get(ORIGIN), req.headers().get(REFERER)) {
+            (Some(origin), _) => origin,
+            (None, Some(referer)) => referer,
+            // requests without either come from outside browsers, which don't send cookies
+            // along for another site
+            (None, None) => return true,
+        };
+        allowed_origin(origin, req.uri(), req.headers(), &self.trusted)
+    }
+}
+
+impl Csrf {
+    // Whether the request comes from the same origin and sends back its token, along with the
+    // request, its body put back together if the token was looked for in a form.
+    async fn verify(
+        &self,
+        req: Request<LuminalBody>,
+        existing: Option<String>,
+    ) -> Result<(Request<LuminalBody>, bool), BoxError> {
+        if !self.same_origin(&req) {
+            return Ok((req, false));
+        }
+        let mut req = req;
+        let mut submitted = req
+            .headers()
+            .get(&self.header)
+            .and_then(|token| token.to_str().ok())
+            .map(str::to_owned);
+        if submitted.is_none() && is_form(&req) {
+            let (parts, body) = req.into_parts();
+            let (form, rest) = read_form(body, self.form_limit).await?;
+            if let Some(rest) = rest {
+                return Ok((Request::from_parts(parts, prefixed(form, rest)), false));
+            }
+            submitted = form_field(&form, &self.field);
+            req = Request::from_parts(parts, full(form));
+        }
+        let matched = match (existing, submitted) {
+            (Some(existing), Some(submitted)) => equal(&existing, &submitted),
+            _ => false,
+        };
+        Ok((req, matched))
+    }
+}
+
+// Whether an `Origin` or `Referer` names the host the request was sent to, by its URI or `Host`,
+// or one of the trusted origins, lowercase and without a trailing slash.
+pub(crate) fn allowed_origin(
+    origin: &HeaderValue,
+    uri: &Uri,
+    headers: &HeaderMap,
+    trusted: &[String],
+) -> bool {
+    let origin = match origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.parse::<Uri>().ok())
+    {
+        Some(origin) => origin,
+        None => return false,
+    };
+    let (scheme, authority) = match (origin.scheme_str(), origin.authority()) {
+        (Some(scheme), Some(authority)) => (scheme, authority),
+        _ => return false,
+    };
+    let full = format!("{}://{}", scheme, authority).to_ascii_lowercase();
+    if trusted.contains(&full) {
+        return true;
+    }
+    let host = uri.authority().cloned().or_else(|| {
+        headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+    });
+    host.is_some_and(|host| {
+        host.host().eq_ignore_ascii_case(authority.host())
+            && host.port_u16().or(default_port(scheme))
+                == authority.port_u16().or(default_port(scheme))
+    })
+}
+
+// Whether the service is one of the routers, which know whether a route is exempt.
+fn is_router<S: 'static>() -> bool {
+    let service = TypeId::of::<S>();
+    service == TypeId::of::<Router>()
+        || service == TypeId::of::<HostRouter>()
+        || service == TypeId::of::<ReloadableRouter>()
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+fn equal(left: &str, right: &str) -> bool {
+    left.as_bytes().ct_eq(right.as_bytes()).into()
+}
+
+fn unsafe_method(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    )
+}
+
+fn is_form(req: &Request<LuminalBody>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|mime| {
+            mime.trim()
+                .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        })
+}
+
+// The value of a field in a URL encoded form.
+fn form_field(form: &[u8], field: &str) -> Option<String> {
+    let form = std::str::from_utf8(form).ok()?;
+    form.split('&').find_map(|pair| {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decode = |part: &str| {
+            percent_decode_str(&part.replace('+', " "))
+                .decode_utf8()
+                .ok()
+                .map(|decoded| decoded.into_owned())
+        };
+        match decode(name) {
+            Some(name) if name == field => decode(value),
+            _ => None,
+        }
+    })
+}
+
+// The whole form, or as much of it as was read before it went over the limit along with the rest
+// of the body.
+async fn read_form(
+    mut body: LuminalBody,
+    limit: usize,
+) -> Result<(Bytes, Option<LuminalBody>), BoxError> {
+    let mut form = Vec::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            form.extend_from_slice(&data);
+            if form.len() > limit {
+                return Ok((Bytes::from(form), Some(body)));
+            }
+        }
+    }
+    Ok((Bytes::from(form), None))
+}
+
+// A body made whole again from the part already read and the rest.
+fn prefixed(read: Bytes, rest: LuminalBody) -> LuminalBody {
+    let read = stream::once(async move { Ok::<_, BoxError>(Frame::data(read)) });
+    boxed(StreamBody::new(read.chain(BodyStream::new(rest))))
+}
+
+/// A service protecting its inner service from forged requests, see `Csrf`.
+pub struct CsrfService<S> {
+    csrf: Arc<Csrf>,
+    routed: bool,
+    inner: S,
+}
+
+impl<S: Clone> Clone for CsrfService<S> {
+    fn clone(&self) -> Self {
+        CsrfService {
+            csrf: Arc::clone(&self.csrf),
+            routed: self.routed,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for CsrfService<S>
+where
+    S: Service<
+            Request<LuminalBody>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Clone
+        + Send
+        + 'static,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let (existing, token) = match self.csrf.token(&req) {
+            Ok(token) => token,
+            Err(error) => return Box::pin(async move { Err(error) }),
+        };
+        req.extensions_mut().insert(token);
+        if !unsafe_method(req.method()) {
+            return self.inner.call(req);
+        }
+
+        let csrf = Arc::clone(&self.csrf);
+        let routed = self.routed;
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let (mut req, verified) = csrf.verify(req, existing).await?;
+            if verified {
+                return inner.call(req).await;
+            }
+            if !routed {
+                return Ok(csrf.forbidden());
+            }
+            // refused, unless the router inside finds the route is exempt
+            let refusal = Arc::clone(&csrf);
+            let matched = RouteLayers::push(&mut req, move |matched, req, next| {
+                if matched.is_csrf_exempt() {
+                    return next(req);
+                }
+                let forbidden = refusal.forbidden();
+                Box::pin(async move { Ok(forbidden) })
+            });
+            let response = inner.call(req).await?;
+            // no router ran the layer, so nothing let the request through
+            if matched.get().is_none() {
+                return Ok(csrf.forbidden());
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http::header::{COOKIE, SET_COOKIE};
+    use luminal_handler::empty;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::cookies::{CookieManager, CookieService, Key};
+    use crate::session::{CookieSessions, SessionService, Sessions};
+    use crate::{ConcurrencyLimit, FnRouteBuilder, LuminalErrorKind, Router};
+
+    fn router() -> Router {
+        FnRouteBuilder::new()
+            .get("/form", |req| async move {
+                let token = req
+                    .extensions()
+                    .get::<CsrfToken>()
+                    .map(CsrfToken::to_string)
+                    .unwrap_or_default();
+                Ok(Response::new(full(token)))
+            })
+            .expect("Should have been able to add route")
+            .get("/plain", |_req| async { Ok(Response::new(empty())) })
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    fn with_posts(mut router: Router) -> Router {
+        for route in ["/comment", "/webhook"] {
+            router
+                .add(
+                    Method::POST,
+                    route,
+                    hyper::service::service_fn(|req: Request<LuminalBody>| -> LuminalFuture {
+                        Box::pin(async move {
+                            // the form is still there for the handler
+                            let body = req.into_body().collect().await?.to_bytes();
+                            Ok(Response::new(full(body)))
+                        })
+                    }),
+                )
+                .expect("Should have been able to add route");
+        }
+        router
+            .csrf_exempt("/webhook")
+            .expect("Should have been able to exempt route");
+        router
+    }
+
+    struct Client<S> {
+        service: S,
+        cookies: Vec<String>,
+    }
+
+    impl<S> Client<S>
+    where
+        S: Service<Request<LuminalBody>, Response = Response<LuminalBody>, Error = BoxError>,
+    {
+        fn send(&mut self, req: http::request::Builder, body: &str) -> (StatusCode, String) {
+            let mut req = req.header(HOST, "luminal.example");
+            if !self.cookies.is_empty() {
+                req = req.header(COOKIE, self.cookies.join("; "));
+            }
+            let req = req
+                .body(full(body.to_owned()))
+                .expect("Should have been able to build request");
+            let response =
+                block_on(self.service.call(req)).expect("Should have been able to run call");
+            for cookie in response.headers().get_all(SET_COOKIE) {
+                let cookie = cookie.to_str().expect("Should have been a string");
+                let pair = cookie.split(';').next().unwrap_or_default().to_owned();
+                let name = pair.split('=').next().unwrap_or_default().to_owned();
+                self.cookies
+                    .retain(|kept| !kept.starts_with(&format!("{}=", name)));
+                self.cookies.push(pair);
+            }
+            let status = response.status();
+            let body = block_on(response.into_body().collect())
+                .expect("Should have been able to resolve body concat")
+                .to_bytes();
+            (
+                status,
+                String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body"),
+            )
+        }
+
+        fn token(&mut self) -> String {
+            self.send(Request::get("/form"), "").1
+        }
+    }
+
+    fn post(uri: &str) -> http::request::Builder {
+        Request::post(uri)
+    }
+
+    fn form(uri: &str) -> http::request::Builder {
+        post(uri).header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+    }
+
+    #[test]
+    fn test_double_submit() {
+        let service: CookieService<CsrfService<Router>> =
+            CookieManager::new().key(Key::generate()).wrap(
+                Csrf::double_submit()
+                    .body("Forbidden")
+                    .wrap(with_posts(router())),
+            );
+        let mut client = Client {
+            service,
+            cookies: Vec::new(),
+        };
+
+        // nothing to match before a token has been issued
+        let (status, body) = client.send(post("/comment").header("x-csrf-token", "guess"), "");
+        assert_eq!(
+            (StatusCode::FORBIDDEN, "Forbidden"),
+            (status, body.as_str())
+        );
+
+        // the token is only issued once a handler reads it
+        assert_eq!(StatusCode::OK, client.send(Request::get("/plain"), "").0);
+        assert!(client.cookies.is_empty());
+        let token = client.token();
+        assert_eq!(32, token.len());
+        let cookie = client
+            .cookies
+            .iter()
+            .find(|cookie| cookie.starts_with("luminal.csrf="));
+        assert!(cookie.is_some_and(|cookie| cookie.ends_with(&token)));
+        assert_ne!(Some(&format!("luminal.csrf={}", token)), cookie);
+        assert_eq!(token, client.token());
+
+        let header = post("/comment").header("x-csrf-token", token.as_str());
+        assert_eq!(StatusCode::OK, client.send(header, "").0);
+        let body = format!("text=hello+there&csrf_token={}", token);
+        assert_eq!(
+            (StatusCode::OK, body.clone()),
+            client.send(form("/comment"), &body)
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            client.send(form("/comment"), "text=hi&csrf_token=wrong").0
+        );
+        assert_eq!(StatusCode::FORBIDDEN, client.send(post("/comment"), "").0);
+
+        // without the cookie the token alone isn't enough
+        client.cookies.clear();
+        let header = post("/comment").header("x-csrf-token", token.as_str());
+        assert_eq!(StatusCode::FORBIDDEN, client.send(header, "").0);
+
+        // nor is a cookie the site didn't sign, such as one tossed in from a subdomain
+        client.cookies.push(format!("luminal.csrf={}", token));
+        let header = post("/comment").header("x-csrf-token", token.as_str());
+        assert_eq!(StatusCode::FORBIDDEN, client.send(header, "").0);
+
+        // exempt routes skip the checks
+        assert_eq!(StatusCode::OK, client.send(post("/webhook"), "{}").0);
+    }
+
+    #[test]
+    fn test_origin() {
+        let service = CookieManager::new().key(Key::generate()).wrap(
+            Csrf::double_submit()
+                .trust_origin("https://admin.example")
+                .wrap(with_posts(router())),
+        );
+        let mut client = Client {
+            service,
+            cookies: Vec::new(),
+        };
+        let token = client.token();
+        let send = |client: &mut Client<_>, header: HeaderName, origin: &str| {
+            let req = post("/comment")
+                .header("x-csrf-token", token.as_str())
+                .header(header, origin);
+            client.send(req, "").0
+        };
+        assert_eq!(
+            StatusCode::OK,
+            send(&mut client, ORIGIN, "https://luminal.example")
+        );
+        assert_eq!(
+            StatusCode::OK,
+            send(&mut client, ORIGIN, "https://admin.example")
+        );
+        assert_eq!(
+            StatusCode::OK,
+            send(&mut client, REFERER, "https://luminal.example/form")
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            send(&mut client, ORIGIN, "https://evil.example")
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            send(&mut client, REFERER, "https://evil.example/luminal.example")
+        );
+        assert_eq!(StatusCode::FORBIDDEN, send(&mut client, ORIGIN, "null"));
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            send(&mut client, ORIGIN, "https://luminal.example:8443")
+        );
+    }
+
+    #[test]
+    fn test_synchronizer() {
+        let service: CookieService<SessionService<CsrfService<Router>>> =
+            CookieManager::new().key(Key::generate()).wrap(
+                Sessions::new(CookieSessions).wrap(Csrf::synchronizer().wrap(with_posts(router()))),
+            );
+        let mut client = Client {
+            service,
+            cookies: Vec::new(),
+        };
+        // the session isn't touched until a handler reads the token
+        assert_eq!(StatusCode::OK, client.send(Request::get("/plain"), "").0);
+        assert!(client.cookies.is_empty());
+        let token = client.token();
+        assert!(client.cookies.iter().all(|cookie| !cookie.contains(&token)));
+        assert_eq!(token, client.token());
+        let body = format!("csrf_token={}", token);
+        assert_eq!(StatusCode::OK, client.send(form("/comment"), &body).0);
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            client.send(form("/comment"), "csrf_token=other").0
+        );
+    }
+
+    #[test]
+    fn test_exempt_large_form() {
+        let service = CookieManager::new().key(Key::generate()).wrap(
+            Csrf::double_submit()
+                .form_limit(16)
+                .wrap(with_posts(router())),
+        );
+        let mut client = Client {
+            service,
+            cookies: Vec::new(),
+        };
+        // the part read looking for the token is put back in front of the rest
+        let body = "text=a+form+longer+than+the+limit";
+        assert_eq!(
+            (StatusCode::OK, body.to_owned()),
+            client.send(form("/webhook"), body)
+        );
+        assert_eq!(StatusCode::FORBIDDEN, client.send(form("/comment"), body).0);
+    }
+
+    #[test]
+    fn test_exempt_deferred() {
+        // the limiter only calls the router from within its future
+        let service = CookieManager::new().key(Key::generate()).wrap(
+            Csrf::double_submit()
+                .router_inside()
+                .wrap(ConcurrencyLimit::new(10).wrap(with_posts(router()))),
+        );
+        let mut client = Client {
+            service,
+            cookies: Vec::new(),
+        };
+        assert_eq!(
+            (StatusCode::OK, String::from("text=hook")),
+            client.send(form("/webhook"), "text=hook")
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            client.send(form("/comment"), "text=hi").0
+        );
+        let token = client.token();
+        assert_eq!(
+            StatusCode::OK,
+            client
+                .send(form("/comment"), &format!("csrf_token={}", token))
+                .0
+        );
+
+        // without being told, a router behind other middleware isn't trusted with refusals
+        let service = CookieManager::new()
+            .key(Key::generate())
+            .wrap(Csrf::double_submit().wrap(ConcurrencyLimit::new(10).wrap(with_posts(router()))));
+        let mut client = Client {
+            service,
+            cookies: Vec::new(),
+        };
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            client.send(form("/webhook"), "text=hook").0
+        );
+    }
+
+    #[test]
+    fn test_plain_service() {
+        let called = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&called);
+        let service = CookieManager::new()
+            .key(Key::generate())
+            .wrap(Csrf::double_submit().wrap(hyper::service::service_fn(
+                move |_req: Request<LuminalBody>| -> LuminalFuture {
+                    flag.store(true, Ordering::SeqCst);
+                    Box::pin(async { Ok(Response::new(empty())) })
+                },
+            )));
+        let mut client = Client {
+            service,
+            cookies: Vec::new(),
+        };
+        assert_eq!(StatusCode::FORBIDDEN, client.send(post("/webhook"), "").0);
+        // refused without ever reaching the service
+        assert!(!called.load(Ordering::SeqCst));
+        assert_eq!(StatusCode::OK, client.send(Request::get("/"), "").0);
+        assert!(called.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_exempt_unknown_route() {
+        match FnRouteBuilder::new().csrf_exempt("/missing") {
+            Err(crate::LuminalError(LuminalErrorKind::UnknownRoute(_), _)) => (),
+            _ => panic!("Should have refused to exempt a missing route"),
+        }
+        let req = Request::get("/form")
+            .body(empty())
+            .expect("Should have been able to build request");
+        // the double-submit pattern can't work without cookies, or a key to sign them
+        assert!(block_on(Csrf::double_submit().wrap(router()).call(req)).is_err());
+        let req = Request::get("/form")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let unsigned = CookieManager::new().wrap(Csrf::double_submit().wrap(router()));
+        assert!(block_on(unsigned.call(req)).is_err());
+    }
+}
@@ -1 +1,44 @@
-error_chain!{}
+error_chain! {
+    errors {
+        /// A route that doesn't start with a slash.
+        InvalidRoute(route: String) {
+            description("invalid route")
+            display("Paths must start with a slash (/), got {}", route)
+        }
+        /// A catch-all parameter anywhere but at the end of a route.
+        CatchAll(route: String) {
+            description("misplaced catch-all")
+            display("Catch-all parameters (*) must be the last segment, got {}", route)
+        }
+        /// The internal tree could not be connected for a new route.
+        Wiring(route: String) {
+            description("could not wire route")
+            display("Could not fully wire up route {}", route)
+        }
+        /// A host pattern that is empty or mixes a wildcard with captures.
+        InvalidHost(host: String) {
+            description("invalid host")
+            display("Hosts must be names, :captures or a leading * wildcard, got {}", host)
+        }
+        /// A JWK set that could not be read or parsed.
+        InvalidKeys(reason: String) {
+            description("invalid JWK set")
+            display("Could not load JWK set: {}", reason)
+        }
+        /// A credentials file that could not be read or has entries that can't be verified.
+        InvalidCredentials(reason: String) {
+            description("invalid credentials")
+            display("Could not load credentials: {}", reason)
+        }
+        /// A trusted proxy that is neither an IP address nor a CIDR block.
+        InvalidCidr(cidr: String) {
+            description("invalid CIDR block")
+            display("Trusted proxies must be IP addresses or CIDR blocks, got {}", cidr)
+        }
+        /// A route with no targets to wrap.
+        UnknownRoute(route: String) {
+            description("unknown route")
+            display("No targets have been added at route {}", route)
+        }
+    }
+}
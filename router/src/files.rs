@@ -0,0 +1,842 @@
+//! A service serving files from a directory, for documentation and front-end bundles.
+//!
+//! The file is named by a catch-all route parameter, so `StaticFiles` is meant to be added at a
+//! route such as `/assets/*path`. Files are streamed from disk a chunk at a time and the usual
+//! cache validators are supported: `ETag` with `If-None-Match`, `Last-Modified` with
+//! `If-Modified-Since`, and single byte `Range` requests, guarded by `If-Range`.
+use bytes::Bytes;
+use http::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE,
+};
+use http::request::Parts;
+use http::{Method, Request, Response, StatusCode};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::service::Service;
+use luminal_handler::{boxed, empty, full};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncSeekExt, ReadBuf};
+
+use std::fs::Metadata;
+use std::io::{self, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::params::PathParams;
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+// Everything that can't appear as is in a single path segment of a link.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Serves the files below a root directory, see the module documentation.
+///
+/// Only `GET` and `HEAD` are served, any other method is answered with 405 Method Not Allowed.
+/// Paths that try to climb out of the root, directly or through a symlink, are refused with
+/// 403 Forbidden, and hidden files, those starting with a dot, are not found unless
+/// `serve_hidden` is set.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+    param: String,
+    index: Option<String>,
+    listing: bool,
+    hidden: bool,
+    chunk_size: usize,
+}
+
+impl StaticFiles {
+    /// Serve the files below the root, named by a catch-all route parameter called `path`, with
+    /// `index.html` as the index of each directory.
+    pub fn new<P: Into<PathBuf>>(root: P) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            param: String::from("path"),
+            index: Some(String::from("index.html")),
+            listing: false,
+            hidden: false,
+            chunk_size: 64 * 1024,
+        }
+    }
+
+    /// Name the file with this route parameter instead of `path`. When the request didn't come
+    /// through a `Router` the whole request path is used.
+    pub fn param(mut self, param: &str) -> Self {
+        self.param = param.to_owned();
+        self
+    }
+
+    /// Serve this file for a directory, or nothing with `None`.
+    pub fn index(mut self, index: Option<&str>) -> Self {
+        self.index = index.map(str::to_owned);
+        self
+    }
+
+    /// Whether to render a page linking the entries of a directory that has no index file.
+    pub fn list_directories(mut self, listing: bool) -> Self {
+        self.listing = listing;
+        self
+    }
+
+    /// Whether to serve files and directories whose names start with a dot.
+    pub fn serve_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// How many bytes to read from disk for each chunk of a response body.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    async fn serve(self, req: Request<LuminalBody>) -> Result<Response<LuminalBody>, BoxError> {
+        // the body is never read, and holding on to it would keep the future from being `Send`
+        let (req, _) = req.into_parts();
+        if req.method != Method::GET && req.method != Method::HEAD {
+            let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Ok(response);
+        }
+        let requested = match req.extensions.get::<PathParams>() {
+            Some(params) => params.get(&self.param).unwrap_or("").to_owned(),
+            None => req.uri.path().to_owned(),
+        };
+        let segments = match self.segments(&requested) {
+            Ok(segments) => segments,
+            Err(code) => return Ok(status(code)),
+        };
+        let mut path = self.root.clone();
+        path.extend(&segments);
+
+        let (resolved, metadata) = match self.resolve(&path).await {
+            Ok(resolved) => resolved,
+            Err(code) => return Ok(status(code)),
+        };
+        if !metadata.is_dir() {
+            return self.file(&req, &path, &resolved, &metadata).await;
+        }
+
+        // relative links in an index only resolve against a path ending in a slash
+        if !req.uri.path().ends_with('/') {
+            let location = match req.uri.query() {
+                Some(query) => format!("{}/?{}", req.uri.path(), query),
+                None => format!("{}/", req.uri.path()),
+            };
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(LOCATION, location)
+                .body(empty())?);
+        }
+        if let Some(ref index) = self.index {
+            let index = path.join(index);
+            match self.resolve(&index).await {
+                Ok((resolved, metadata)) if metadata.is_file() => {
+                    return self.file(&req, &index, &resolved, &metadata).await;
+                }
+                Err(StatusCode::FORBIDDEN) => return Ok(status(StatusCode::FORBIDDEN)),
+                _ => {}
+            }
+        }
+        if self.listing {
+            return self.listing(&req, &path, segments.is_empty()).await;
+        }
+        Ok(status(StatusCode::NOT_FOUND))
+    }
+
+    // The decoded segments of the requested path, or the status to refuse it with.
+    fn segments(&self, requested: &str) -> Result<Vec<String>, StatusCode> {
+        let decoded = percent_decode_str(requested)
+            .decode_utf8()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let mut segments = vec![];
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return Err(StatusCode::FORBIDDEN),
+                _ if segment.contains('\\') || segment.contains('\0') => {
+                    return Err(StatusCode::FORBIDDEN)
+                }
+                _ if segment.starts_with('.') && !self.hidden => return Err(StatusCode::NOT_FOUND),
+                _ => segments.push(segment.to_owned()),
+            }
+        }
+        Ok(segments)
+    }
+
+    // The path with symlinks resolved, once it is confirmed to be inside the root, and its metadata.
+    async fn resolve(&self, path: &Path) -> Result<(PathBuf, Metadata), StatusCode> {
+        let (root, resolved) = match (
+            fs::canonicalize(&self.root).await,
+            fs::canonicalize(path).await,
+        ) {
+            (Ok(root), Ok(resolved)) => (root, resolved),
+            (_, Err(error)) => return Err(io_status(&error)),
+            (Err(_), _) => return Err(StatusCode::NOT_FOUND),
+        };
+        if !resolved.starts_with(&root) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        match fs::metadata(&resolved).await {
+            Ok(metadata) => Ok((resolved, metadata)),
+            Err(error) => Err(io_status(&error)),
+        }
+    }
+
+    async fn file(
+        &self,
+        req: &Parts,
+        path: &Path,
+        resolved: &Path,
+        metadata: &Metadata,
+    ) -> Result<Response<LuminalBody>, BoxError> {
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let etag = entity_tag(len, modified);
+        let last_modified = modified.map(httpdate::fmt_http_date);
+
+        let mut builder = Response::builder()
+            .header(ETAG, etag.as_str())
+            .header(ACCEPT_RANGES, "bytes");
+        if let Some(ref last_modified) = last_modified {
+            builder = builder.header(LAST_MODIFIED, last_modified.as_str());
+        }
+        if not_modified(&req.headers, &etag, modified) {
+            return Ok(builder.status(StatusCode::NOT_MODIFIED).body(empty())?);
+        }
+
+        let range = if if_range(&req.headers, &etag, modified) {
+            req.headers
+                .get(RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_range(value, len))
+        } else {
+            None
+        };
+        let (start, end) = match range {
+            None => (0, len),
+            Some(Ok((start, end))) => {
+                builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end - 1, len),
+                );
+                (start, end)
+            }
+            Some(Err(())) => {
+                return Ok(builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", len))
+                    .body(empty())?);
+            }
+        };
+
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+        let content_type = if content_type.type_() == mime_guess::mime::TEXT {
+            format!("{}; charset=utf-8", content_type)
+        } else {
+            content_type.to_string()
+        };
+        builder = builder
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, end - start);
+        if req.method == Method::HEAD {
+            return Ok(builder.body(empty())?);
+        }
+
+        // the path that was checked, so a symlink swapped in since can't point it elsewhere
+        let mut file = File::open(resolved).await?;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+        Ok(builder.body(boxed(FileBody {
+            file,
+            remaining: end - start,
+            buffer: vec![0; self.chunk_size],
+        }))?)
+    }
+
+    async fn listing(
+        &self,
+        req: &Parts,
+        path: &Path,
+        root: bool,
+    ) -> Result<Response<LuminalBody>, BoxError> {
+        let mut entries = vec![];
+        let mut dir = fs::read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') && !self.hidden {
+                continue;
+            }
+            let is_dir = entry
+                .file_type()
+                .await
+                .map(|kind| kind.is_dir())
+                .unwrap_or(false);
+            entries.push((name, is_dir));
+        }
+        entries.sort();
+
+        let title = escape(&percent_decode_str(req.uri.path()).decode_utf8_lossy());
+        let mut page = format!(
+            concat!(
+                "<!DOCTYPE html>\n<html>\n",
+                "<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n",
+                "<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+            ),
+            title
+        );
+        if !root {
+            page.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+        for (name, is_dir) in entries {
+            let slash = if is_dir { "/" } else { "" };
+            page.push_str(&format!(
+                "<li><a href=\"{}{}\">{}{}</a></li>\n",
+                utf8_percent_encode(&name, SEGMENT),
+                slash,
+                escape(&name),
+                slash
+            ));
+        }
+        page.push_str("</ul>\n</body>\n</html>\n");
+
+        let builder = Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(CONTENT_LENGTH, page.len());
+        if req.method == Method::HEAD {
+            return Ok(builder.body(empty())?);
+        }
+        Ok(builder.body(full(page))?)
+    }
+}
+
+impl Service<Request<LuminalBody>> for StaticFiles {
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<LuminalBody>) -> Self::Future {
+        Box::pin(self.clone().serve(req))
+    }
+}
+
+fn status(code: StatusCode) -> Response<LuminalBody> {
+    let mut response = Response::new(empty());
+    *response.status_mut() = code;
+    response
+}
+
+fn io_status(error: &io::Error) -> StatusCode {
+    match error.kind() {
+        ErrorKind::NotFound | ErrorKind::NotADirectory => StatusCode::NOT_FOUND,
+        ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Strong as long as a file isn't rewritten with the same size within the clock's resolution.
+fn entity_tag(len: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", len, modified.as_nanos())
+}
+
+// HTTP dates have a resolution of a second, so compare at that resolution.
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+// Whether the client's copy is current. `If-Modified-Since` only counts without `If-None-Match`.
+fn not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    let mut none_match = headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .peekable();
+    if none_match.peek().is_some() {
+        let etag = etag.trim_start_matches("W/");
+        return none_match.any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    let since = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    match (since, modified) {
+        (Some(since), Some(modified)) => seconds(modified) <= seconds(since),
+        _ => false,
+    }
+}
+
+// Whether a range can be served, a stale `If-Range` means the whole file has to be sent instead.
+fn if_range(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    let value = match headers.get(IF_RANGE).and_then(|value| value.to_str().ok()) {
+        Some(value) => value.trim(),
+        None => return true,
+    };
+    if value.starts_with('"') {
+        return value == etag;
+    }
+    match (httpdate::parse_http_date(value), modified) {
+        (Ok(date), Some(modified)) => seconds(date) == seconds(modified),
+        _ => false,
+    }
+}
+
+// Parse a single byte range into a half open interval. `None` when the header should be ignored,
+// including requests for several ranges, which are served the whole file instead.
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.trim().split_once('-')?;
+    let range = match (first.trim(), last.trim()) {
+        ("", "") => return None,
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(suffix), len)
+        }
+        (first, last) => {
+            let first = first.parse::<u64>().ok()?;
+            let end = if last.is_empty() {
+                len
+            } else {
+                let last = last.parse::<u64>().ok()?;
+                if last < first {
+                    return None;
+                }
+                last.saturating_add(1).min(len)
+            };
+            (first, end)
+        }
+    };
+    if range.0 >= len {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+// Streams a file, or the requested part of it, a chunk at a time.
+struct FileBody {
+    file: File,
+    remaining: u64,
+    buffer: Vec<u8>,
+}
+
+impl Body for FileBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = self.get_mut();
+        if this.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        let size = this.buffer.len().min(this.remaining as usize);
+        let mut read = ReadBuf::new(&mut this.buffer[..size]);
+        match Pin::new(&mut this.file).poll_read(cx, &mut read) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(error)) => Poll::Ready(Some(Err(error.into()))),
+            Poll::Ready(Ok(())) if read.filled().is_empty() => {
+                // the file shrank since its length went out in the headers
+                this.remaining = 0;
+                let error = io::Error::new(ErrorKind::UnexpectedEof, "file was truncated");
+                Poll::Ready(Some(Err(error.into())))
+            }
+            Poll::Ready(Ok(())) => {
+                let data = Bytes::copy_from_slice(read.filled());
+                this.remaining -= data.len() as u64;
+                Poll::Ready(Some(Ok(Frame::data(data))))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::HeaderName;
+    use http_body_util::BodyExt;
+    use tempfile::TempDir;
+    use tokio::runtime::{Builder, Runtime};
+
+    use std::fs;
+
+    use super::*;
+    use crate::{Router, ServiceRouteBuilder};
+
+    const CONTENT: &str = "0123456789abcdef";
+
+    struct Fixture {
+        dir: TempDir,
+        runtime: Runtime,
+        router: Router,
+    }
+
+    fn fixture(files: StaticFiles) -> Fixture {
+        let dir = TempDir::new().expect("Should have been able to create a directory");
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("docs/guide")).expect("Should have created directories");
+        fs::create_dir_all(root.join("empty")).expect("Should have created directories");
+        fs::write(root.join("hex.txt"), CONTENT).expect("Should have written file");
+        fs::write(root.join("docs/index.html"), "<h1>Docs</h1>").expect("Should have written file");
+        fs::write(root.join("docs/a b.css"), "body {}").expect("Should have written file");
+        fs::write(root.join(".secret"), "hidden").expect("Should have written file");
+        fs::write(dir.path().join("outside.txt"), "outside").expect("Should have written file");
+
+        let files = StaticFiles { root, ..files };
+        let mut router = ServiceRouteBuilder::new()
+            .get("/static/*path", files.clone())
+            .expect("Should have been able to add route")
+            .build();
+        router
+            .add(Method::HEAD, "/static/*path", files)
+            .expect("Should have been able to add route");
+
+        let runtime = Builder::new_current_thread()
+            .build()
+            .expect("Should have been able to build a runtime");
+        Fixture {
+            dir,
+            runtime,
+            router,
+        }
+    }
+
+    impl Fixture {
+        fn call(
+            &self,
+            method: Method,
+            uri: &str,
+            headers: &[(HeaderName, &str)],
+        ) -> (Response<()>, Bytes) {
+            let mut builder = Request::builder().method(method).uri(uri);
+            for (name, value) in headers {
+                builder = builder.header(name, *value);
+            }
+            let req = builder
+                .body(empty())
+                .expect("Should have been able to build request");
+            self.runtime.block_on(async {
+                let response = self
+                    .router
+                    .call(req)
+                    .await
+                    .expect("Should have been able to run router call");
+                let (parts, body) = response.into_parts();
+                let body = body
+                    .collect()
+                    .await
+                    .expect("Should have been able to resolve body concat")
+                    .to_bytes();
+                (Response::from_parts(parts, ()), body)
+            })
+        }
+
+        fn get(&self, uri: &str, headers: &[(HeaderName, &str)]) -> (Response<()>, Bytes) {
+            self.call(Method::GET, uri, headers)
+        }
+    }
+
+    fn header(response: &Response<()>, name: HeaderName) -> &str {
+        response
+            .headers()
+            .get(&name)
+            .unwrap_or_else(|| panic!("Should have had a {} header", name))
+            .to_str()
+            .expect("Should have been a string header")
+    }
+
+    #[test]
+    fn test_file() {
+        let fixture = fixture(StaticFiles::new(""));
+        let (response, body) = fixture.get("/static/hex.txt", &[]);
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(CONTENT.as_bytes(), &body[..]);
+        assert_eq!("text/plain; charset=utf-8", header(&response, CONTENT_TYPE));
+        assert_eq!("16", header(&response, CONTENT_LENGTH));
+        assert_eq!("bytes", header(&response, ACCEPT_RANGES));
+        assert!(header(&response, ETAG).starts_with('"'));
+        assert!(httpdate::parse_http_date(header(&response, LAST_MODIFIED)).is_ok());
+
+        let (response, body) = fixture.get("/static/docs/a%20b.css", &[]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("text/css; charset=utf-8", header(&response, CONTENT_TYPE));
+        assert_eq!(&b"body {}"[..], &body[..]);
+    }
+
+    #[test]
+    fn test_streamed_in_chunks() {
+        let fixture = fixture(StaticFiles::new("").chunk_size(3));
+        let (response, body) = fixture.get("/static/hex.txt", &[]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(CONTENT.as_bytes(), &body[..]);
+    }
+
+    #[test]
+    fn test_head() {
+        let fixture = fixture(StaticFiles::new(""));
+        let (response, body) = fixture.call(Method::HEAD, "/static/hex.txt", &[]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("16", header(&response, CONTENT_LENGTH));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_traversal() {
+        let fixture = fixture(StaticFiles::new(""));
+        for uri in &[
+            "/static/../outside.txt",
+            "/static/docs/../../outside.txt",
+            "/static/%2e%2e/outside.txt",
+            "/static/..%2Foutside.txt",
+            "/static/docs%5C..%5C..%5Coutside.txt",
+        ] {
+            let (response, body) = fixture.get(uri, &[]);
+            assert_eq!(StatusCode::FORBIDDEN, response.status(), "{}", uri);
+            assert!(body.is_empty());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_escape() {
+        let fixture = fixture(StaticFiles::new(""));
+        std::os::unix::fs::symlink(
+            fixture.dir.path().join("outside.txt"),
+            fixture.dir.path().join("root/link.txt"),
+        )
+        .expect("Should have been able to link");
+        let (response, _) = fixture.get("/static/link.txt", &[]);
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_index_escape() {
+        let fixture = fixture(StaticFiles::new(""));
+        std::os::unix::fs::symlink(
+            fixture.dir.path().join("outside.txt"),
+            fixture.dir.path().join("root/empty/index.html"),
+        )
+        .expect("Should have been able to link");
+        let (response, body) = fixture.get("/static/empty/", &[]);
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_missing_and_hidden() {
+        let fixture = fixture(StaticFiles::new(""));
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            fixture.get("/static/missing.txt", &[]).0.status()
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            fixture.get("/static/.secret", &[]).0.status()
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            fixture.get("/static/hex.txt/more", &[]).0.status()
+        );
+
+        let fixture = self::fixture(StaticFiles::new("").serve_hidden(true));
+        let (response, body) = fixture.get("/static/.secret", &[]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(&b"hidden"[..], &body[..]);
+    }
+
+    #[test]
+    fn test_conditional() {
+        let fixture = fixture(StaticFiles::new(""));
+        let (response, _) = fixture.get("/static/hex.txt", &[]);
+        let etag = header(&response, ETAG).to_owned();
+        let last_modified = header(&response, LAST_MODIFIED).to_owned();
+
+        let (response, body) = fixture.get("/static/hex.txt", &[(IF_NONE_MATCH, &etag)]);
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+        assert_eq!(etag, header(&response, ETAG));
+        assert!(body.is_empty());
+
+        let listed = format!("\"other\", W/{}", etag);
+        let (response, _) = fixture.get("/static/hex.txt", &[(IF_NONE_MATCH, &listed)]);
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+
+        let (response, _) = fixture.get("/static/hex.txt", &[(IF_MODIFIED_SINCE, &last_modified)]);
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+
+        // a mismatched entity tag wins over a date that would otherwise be current
+        let (response, _) = fixture.get(
+            "/static/hex.txt",
+            &[
+                (IF_NONE_MATCH, "\"other\""),
+                (IF_MODIFIED_SINCE, &last_modified),
+            ],
+        );
+        assert_eq!(StatusCode::OK, response.status());
+
+        let (response, _) = fixture.get(
+            "/static/hex.txt",
+            &[(IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")],
+        );
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[test]
+    fn test_range() {
+        let fixture = fixture(StaticFiles::new(""));
+
+        let (response, body) = fixture.get("/static/hex.txt", &[(RANGE, "bytes=2-5")]);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!("bytes 2-5/16", header(&response, CONTENT_RANGE));
+        assert_eq!("4", header(&response, CONTENT_LENGTH));
+        assert_eq!(&b"2345"[..], &body[..]);
+
+        let (response, body) = fixture.get("/static/hex.txt", &[(RANGE, "bytes=-3")]);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!(&b"def"[..], &body[..]);
+
+        let (response, body) = fixture.get("/static/hex.txt", &[(RANGE, "bytes=12-99")]);
+        assert_eq!("bytes 12-15/16", header(&response, CONTENT_RANGE));
+        assert_eq!(&b"cdef"[..], &body[..]);
+
+        let (response, _) = fixture.get("/static/hex.txt", &[(RANGE, "bytes=16-")]);
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, response.status());
+        assert_eq!("bytes */16", header(&response, CONTENT_RANGE));
+
+        let (response, body) = fixture.get("/static/hex.txt", &[(RANGE, "bytes=0-1,4-5")]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(CONTENT.as_bytes(), &body[..]);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let fixture = fixture(StaticFiles::new(""));
+        let (response, _) = fixture.get("/static/hex.txt", &[]);
+        let etag = header(&response, ETAG).to_owned();
+
+        let (response, body) = fixture.get(
+            "/static/hex.txt",
+            &[(RANGE, "bytes=0-1"), (IF_RANGE, &etag)],
+        );
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!(&b"01"[..], &body[..]);
+
+        let (response, body) = fixture.get(
+            "/static/hex.txt",
+            &[(RANGE, "bytes=0-1"), (IF_RANGE, "\"stale\"")],
+        );
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(CONTENT.as_bytes(), &body[..]);
+    }
+
+    #[test]
+    fn test_directory() {
+        let fixture = fixture(StaticFiles::new(""));
+
+        let (response, _) = fixture.get("/static/docs?v=1", &[]);
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, response.status());
+        assert_eq!("/static/docs/?v=1", header(&response, LOCATION));
+
+        let (response, body) = fixture.get("/static/docs/", &[]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("text/html; charset=utf-8", header(&response, CONTENT_TYPE));
+        assert_eq!(&b"<h1>Docs</h1>"[..], &body[..]);
+
+        let (response, _) = fixture.get("/static/empty/", &[]);
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[test]
+    fn test_listing() {
+        let fixture = fixture(StaticFiles::new("").index(None).list_directories(true));
+        let (response, body) = fixture.get("/static/docs/", &[]);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("text/html; charset=utf-8", header(&response, CONTENT_TYPE));
+
+        let page = String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body");
+        assert!(page.contains("<a href=\"../\">../</a>"));
+        assert!(page.contains("<a href=\"a%20b.css\">a b.css</a>"));
+        assert!(page.contains("<a href=\"guide/\">guide/</a>"));
+        assert!(page.contains("<a href=\"index.html\">index.html</a>"));
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let fixture = fixture(StaticFiles::new(""));
+        let req = Request::builder()
+            .method(Method::DELETE)
+            .uri("/hex.txt")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let files = StaticFiles::new(fixture.dir.path().join("root"));
+        let response = fixture
+            .runtime
+            .block_on(files.call(req))
+            .expect("Should have been able to run call");
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+        assert_eq!(
+            "GET, HEAD",
+            header(&Response::from_parts(response.into_parts().0, ()), ALLOW)
+        );
+    }
+
+    #[test]
+    fn test_without_router() {
+        let fixture = fixture(StaticFiles::new(""));
+        let req = Request::builder()
+            .uri("/docs/a%20b.css")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let files = StaticFiles::new(fixture.dir.path().join("root"));
+        let response = fixture
+            .runtime
+            .block_on(files.call(req))
+            .expect("Should have been able to run call");
+        assert_eq!(StatusCode::OK, response.status());
+    }
+}
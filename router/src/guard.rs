@@ -0,0 +1,323 @@
+//! Guards narrow a route down to the requests it should serve, beyond method and path.
+//!
+//! Several targets can be added for the same method and route, each with its own guard. The
+//! `Router` dispatches to the first one, in the order they were added, whose guard passes. When
+//! none of them pass, the response status comes from the guards that rejected the request, so a
+//! failed `Accept` guard answers with 406 Not Acceptable and a failed `ContentType` guard with
+//! 415 Unsupported Media Type rather than a plain 404.
+use http::header::{HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use http::{Request, StatusCode};
+
+use std::fmt;
+
+use crate::LuminalBody;
+
+/// A check a request has to pass before it is dispatched to a route.
+pub trait Guard: Send + Sync {
+    /// Whether the request passes, or the status to respond with when it doesn't.
+    fn check(&self, req: &Request<LuminalBody>) -> Result<(), StatusCode>;
+
+    /// A guard that passes only when both this and the other guard pass.
+    fn and<G>(self, other: G) -> And<Self, G>
+    where
+        Self: Sized,
+        G: Guard,
+    {
+        And(self, other)
+    }
+}
+
+/// Any function over the request is a guard, rejecting with 404 Not Found.
+impl<F> Guard for F
+where
+    F: Fn(&Request<LuminalBody>) -> bool + Send + Sync,
+{
+    fn check(&self, req: &Request<LuminalBody>) -> Result<(), StatusCode> {
+        if self(req) {
+            Ok(())
+        } else {
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+/// Two guards that must both pass, see `Guard::and`.
+pub struct And<A, B>(A, B);
+
+impl<A: Guard, B: Guard> Guard for And<A, B> {
+    fn check(&self, req: &Request<LuminalBody>) -> Result<(), StatusCode> {
+        self.0.check(req)?;
+        self.1.check(req)
+    }
+}
+
+impl<A, B> fmt::Debug for And<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("And")
+    }
+}
+
+/// Requires a header to be present, optionally with an exact value.
+#[derive(Clone, Debug)]
+pub struct Header {
+    name: HeaderName,
+    value: Option<HeaderValue>,
+}
+
+impl Header {
+    pub fn exists(name: HeaderName) -> Self {
+        Header { name, value: None }
+    }
+
+    pub fn equals(name: HeaderName, value: HeaderValue) -> Self {
+        Header {
+            name,
+            value: Some(value),
+        }
+    }
+}
+
+impl Guard for Header {
+    fn check(&self, req: &Request<LuminalBody>) -> Result<(), StatusCode> {
+        let found = req
+            .headers()
+            .get_all(&self.name)
+            .iter()
+            .any(|value| self.value.as_ref().is_none_or(|expected| expected == value));
+        if found {
+            Ok(())
+        } else {
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+/// Requires a query parameter to be present, optionally with an exact raw value.
+#[derive(Clone, Debug)]
+pub struct Query {
+    name: String,
+    value: Option<String>,
+}
+
+impl Query {
+    pub fn exists(name: &str) -> Self {
+        Query {
+            name: name.to_owned(),
+            value: None,
+        }
+    }
+
+    pub fn equals(name: &str, value: &str) -> Self {
+        Query {
+            name: name.to_owned(),
+            value: Some(value.to_owned()),
+        }
+    }
+}
+
+impl Guard for Query {
+    fn check(&self, req: &Request<LuminalBody>) -> Result<(), StatusCode> {
+        let query = req.uri().query().unwrap_or("");
+        let found = query.split('&').any(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            let name = pair.next().unwrap_or("");
+            let value = pair.next().unwrap_or("");
+            name == self.name && self.value.as_ref().is_none_or(|expected| expected == value)
+        });
+        if found {
+            Ok(())
+        } else {
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+/// Requires the client to accept a media type, such as `application/vnd.luminal.v2+json`.
+///
+/// A request without an `Accept` header accepts anything. Otherwise a matching media range,
+/// including `type/*` and `*/*`, has to be listed without `q=0`, or the request is rejected with
+/// 406 Not Acceptable.
+#[derive(Clone, Debug)]
+pub struct Accept {
+    media_type: String,
+}
+
+impl Accept {
+    pub fn new(media_type: &str) -> Self {
+        Accept {
+            media_type: media_type.to_ascii_lowercase(),
+        }
+    }
+}
+
+impl Guard for Accept {
+    fn check(&self, req: &Request<LuminalBody>) -> Result<(), StatusCode> {
+        let mut values = req.headers().get_all(ACCEPT).iter().peekable();
+        if values.peek().is_none() {
+            return Ok(());
+        }
+        let (kind, _) = split_media_type(&self.media_type);
+        let accepted = values
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|range| {
+                let mut parts = range.split(';');
+                let range = essence(parts.next().unwrap_or(""));
+                let refused = parts.any(|param| {
+                    let param = param.trim().replace(' ', "");
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                });
+                !refused
+                    && (range == "*/*"
+                        || range == self.media_type
+                        || range.strip_suffix("/*") == Some(kind))
+            });
+        if accepted {
+            Ok(())
+        } else {
+            Err(StatusCode::NOT_ACCEPTABLE)
+        }
+    }
+}
+
+/// Requires the request body to be of a media type, ignoring parameters such as `charset`.
+///
+/// A request without a matching `Content-Type` is rejected with 415 Unsupported Media Type.
+#[derive(Clone, Debug)]
+pub struct ContentType {
+    media_type: String,
+}
+
+impl ContentType {
+    pub fn new(media_type: &str) -> Self {
+        ContentType {
+            media_type: media_type.to_ascii_lowercase(),
+        }
+    }
+}
+
+impl Guard for ContentType {
+    fn check(&self, req: &Request<LuminalBody>) -> Result<(), StatusCode> {
+        let matches = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| essence(value.split(';').next().unwrap_or("")))
+            .is_some_and(|found| found == self.media_type);
+        if matches {
+            Ok(())
+        } else {
+            Err(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        }
+    }
+}
+
+// The media type without parameters or surrounding whitespace, in lower case.
+fn essence(media_type: &str) -> String {
+    media_type.trim().to_ascii_lowercase()
+}
+
+fn split_media_type(media_type: &str) -> (&str, &str) {
+    let mut parts = media_type.splitn(2, '/');
+    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::HOST;
+    use luminal_handler::empty;
+
+    use super::*;
+
+    fn request(headers: &[(HeaderName, &'static str)], uri: &str) -> Request<LuminalBody> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        builder
+            .body(empty())
+            .expect("Should have been able to build request")
+    }
+
+    #[test]
+    fn test_header() {
+        let req = request(&[(HOST, "example.com")], "/");
+        assert!(Header::exists(HOST).check(&req).is_ok());
+        assert!(
+            Header::equals(HOST, HeaderValue::from_static("example.com"))
+                .check(&req)
+                .is_ok()
+        );
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            Header::equals(HOST, HeaderValue::from_static("example.org")).check(&req)
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let req = request(&[], "/?debug&version=2");
+        assert!(Query::exists("debug").check(&req).is_ok());
+        assert!(Query::equals("version", "2").check(&req).is_ok());
+        assert!(Query::equals("version", "1").check(&req).is_err());
+        assert!(Query::exists("missing").check(&req).is_err());
+    }
+
+    #[test]
+    fn test_accept() {
+        let guard = Accept::new("application/vnd.luminal.v2+json");
+        assert!(guard.check(&request(&[], "/")).is_ok());
+        assert!(guard
+            .check(&request(&[(ACCEPT, "text/html, application/*;q=0.5")], "/"))
+            .is_ok());
+        assert!(guard
+            .check(&request(
+                &[(ACCEPT, "Application/Vnd.Luminal.V2+JSON")],
+                "/"
+            ))
+            .is_ok());
+        assert_eq!(
+            Err(StatusCode::NOT_ACCEPTABLE),
+            guard.check(&request(
+                &[(ACCEPT, "application/vnd.luminal.v1+json")],
+                "/"
+            ))
+        );
+        assert_eq!(
+            Err(StatusCode::NOT_ACCEPTABLE),
+            guard.check(&request(&[(ACCEPT, "*/*; q=0.0")], "/"))
+        );
+    }
+
+    #[test]
+    fn test_content_type() {
+        let guard = ContentType::new("application/json");
+        assert!(guard
+            .check(&request(
+                &[(CONTENT_TYPE, "application/json; charset=utf-8")],
+                "/"
+            ))
+            .is_ok());
+        assert_eq!(
+            Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            guard.check(&request(&[(CONTENT_TYPE, "text/plain")], "/"))
+        );
+        assert_eq!(
+            Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            guard.check(&request(&[], "/"))
+        );
+    }
+
+    #[test]
+    fn test_and() {
+        let guard = ContentType::new("application/json").and(Accept::new("application/json"));
+        let req = request(
+            &[(CONTENT_TYPE, "application/json"), (ACCEPT, "text/html")],
+            "/",
+        );
+        assert_eq!(Err(StatusCode::NOT_ACCEPTABLE), guard.check(&req));
+
+        let function = |req: &Request<LuminalBody>| req.uri().path() == "/";
+        assert!(function.check(&req).is_ok());
+    }
+}
@@ -1,14 +1,16 @@
 //! Builders to add implementations of `Handler` and functions for specific methods and routes.
-use http;
-use hyper::{Body, Method};
-use hyper::server::Response;
+use http::{Method, Request, Response};
+use hyper::service::Service;
 use luminal_handler::{self, Handler};
 
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use error::*;
-use LuminalFuture;
-use super::Router;
+use crate::error::*;
+use crate::guard::Guard;
+use crate::ratelimit::RateLimiter;
+use crate::service::{FnRouteBuilder, Router, ServiceRouteBuilder};
+use crate::websocket::WebSocketUpgrade;
+use crate::{BoxError, LuminalBody, LuminalFuture, LuminalService};
 
 /// Fluent builder, takes ownership of a `Router` while adding routes.
 ///
@@ -17,35 +19,125 @@ pub struct HandlerRouteBuilder {
     pub router: Router,
 }
 
+impl Default for HandlerRouteBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HandlerRouteBuilder {
     /// Create a new instance with a `Router` with empty routes.
     pub fn new() -> HandlerRouteBuilder {
         HandlerRouteBuilder {
-            router: Router {
-                routes: HashMap::new(),
-            },
+            router: Router::new(),
         }
     }
 
-    /// Add a service for `Method::Get` at the specified route.
-    pub fn get<H: Handler + 'static>(mut self, route: &str, handler: H) -> Result<Self> {
+    /// Add a `Handler` for `Method::GET` at the specified route.
+    pub fn get<H: Handler + Send + Sync + 'static>(
+        mut self,
+        route: &str,
+        handler: H,
+    ) -> Result<Self> {
         {
-            self.router.add(Method::Get, route, handler)?;
+            self.router.add_handler(Method::GET, route, handler)?;
         }
         Ok(self)
     }
 
-    /// Add a `Handler` for `Method::Post` at the specified route.
-    pub fn post<H: Handler + 'static>(mut self, route: &str, handler: H) -> Result<Self> {
+    /// Add a `Handler` for `Method::GET` at the specified route that only serves requests passing
+    /// the guard.
+    pub fn get_guarded<G, H>(mut self, route: &str, guard: G, handler: H) -> Result<Self>
+    where
+        G: Guard + 'static,
+        H: Handler + Send + Sync + 'static,
+    {
         {
-            self.router.add(Method::Post, route, handler)?;
+            self.router
+                .add_guarded_handler(Method::GET, route, guard, handler)?;
         }
         Ok(self)
     }
 
-    /// Return a new `FnRouteBuilder` that now owns the router being contructed.
-    pub fn fn_builder(self) -> FnRouteBuilder {
-        FnRouteBuilder {
+    /// Add a `Handler` for `Method::POST` at the specified route.
+    pub fn post<H: Handler + Send + Sync + 'static>(
+        mut self,
+        route: &str,
+        handler: H,
+    ) -> Result<Self> {
+        {
+            self.router.add_handler(Method::POST, route, handler)?;
+        }
+        Ok(self)
+    }
+
+    /// Add a `Handler` for `Method::POST` at the specified route that only serves requests passing
+    /// the guard.
+    pub fn post_guarded<G, H>(mut self, route: &str, guard: G, handler: H) -> Result<Self>
+    where
+        G: Guard + 'static,
+        H: Handler + Send + Sync + 'static,
+    {
+        {
+            self.router
+                .add_guarded_handler(Method::POST, route, guard, handler)?;
+        }
+        Ok(self)
+    }
+
+    /// Wrap every target at the specified route, whatever the method, in another service, such as
+    /// `BasicAuth::wrap`.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn wrap_route<F, S>(mut self, route: &str, wrap: F) -> Result<Self>
+    where
+        F: Fn(Arc<LuminalService>) -> S,
+        S: Service<
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        self.router.wrap_route(route, wrap)?;
+        Ok(self)
+    }
+
+    /// Exempt every target at the specified route from CSRF checks.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn csrf_exempt(mut self, route: &str) -> Result<Self> {
+        self.router.csrf_exempt(route)?;
+        Ok(self)
+    }
+
+    /// Add a WebSocket route, answering upgrade requests for `Method::GET` at the specified route.
+    pub fn websocket(mut self, route: &str, upgrade: WebSocketUpgrade) -> Result<Self> {
+        self.router.add(Method::GET, route, upgrade)?;
+        Ok(self)
+    }
+
+    /// Limit the rate of requests to every target at the specified route, whatever the method.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn rate_limited(mut self, route: &str, limiter: &RateLimiter) -> Result<Self> {
+        self.router
+            .wrap_route(route, |target| limiter.wrap(target))?;
+        Ok(self)
+    }
+
+    /// Return a new `HandlerFnRouteBuilder` that now owns the router being contructed.
+    pub fn fn_builder(self) -> HandlerFnRouteBuilder {
+        HandlerFnRouteBuilder {
+            router: self.router,
+        }
+    }
+
+    /// Return a new `ServiceRouteBuilder` that now owns the router being contructed.
+    pub fn service_builder(self) -> ServiceRouteBuilder {
+        ServiceRouteBuilder {
             router: self.router,
         }
     }
@@ -56,50 +148,152 @@ impl HandlerRouteBuilder {
     }
 }
 
-pub struct FnRouteBuilder {
+pub struct HandlerFnRouteBuilder {
     pub router: Router,
 }
 
-impl FnRouteBuilder {
-    pub fn new() -> FnRouteBuilder {
-        FnRouteBuilder {
-            router: Router {
-                routes: HashMap::new(),
-            },
+impl Default for HandlerFnRouteBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HandlerFnRouteBuilder {
+    pub fn new() -> HandlerFnRouteBuilder {
+        HandlerFnRouteBuilder {
+            router: Router::new(),
         }
     }
 
-    /// Add a `Handler` for `Method::Post` at the specified route.
+    /// Add a `Handler` for `Method::GET` at the specified route.
     pub fn get<F>(mut self, route: &str, function: F) -> Result<Self>
     where
-        F: Fn(http::Request<Body>) -> ::std::result::Result<LuminalFuture, Response> + 'static,
+        F: Fn(Request<LuminalBody>) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>>
+            + Send
+            + Sync
+            + 'static,
     {
         {
             self.router
-                .add(Method::Get, route, luminal_handler::handler_fn(function))?;
+                .add_handler(Method::GET, route, luminal_handler::handler_fn(function))?;
+        }
+        Ok(self)
+    }
+
+    /// Add a `Handler` for `Method::GET` at the specified route that only serves requests passing
+    /// the guard.
+    pub fn get_guarded<G, F>(mut self, route: &str, guard: G, function: F) -> Result<Self>
+    where
+        G: Guard + 'static,
+        F: Fn(Request<LuminalBody>) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        {
+            self.router.add_guarded_handler(
+                Method::GET,
+                route,
+                guard,
+                luminal_handler::handler_fn(function),
+            )?;
         }
         Ok(self)
     }
 
-    /// Add a `Handler` for `Method::Post` at the specified route.
+    /// Add a `Handler` for `Method::POST` at the specified route.
     pub fn post<F>(mut self, route: &str, function: F) -> Result<Self>
     where
-        F: Fn(http::Request<Body>) -> ::std::result::Result<LuminalFuture, Response> + 'static,
+        F: Fn(Request<LuminalBody>) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>>
+            + Send
+            + Sync
+            + 'static,
     {
         {
             self.router
-                .add(Method::Post, route, luminal_handler::handler_fn(function))?;
+                .add_handler(Method::POST, route, luminal_handler::handler_fn(function))?;
         }
         Ok(self)
     }
 
-    /// Return a new `FnRouteBuilder` that now owns the router being contructed.
+    /// Add a `Handler` for `Method::POST` at the specified route that only serves requests passing
+    /// the guard.
+    pub fn post_guarded<G, F>(mut self, route: &str, guard: G, function: F) -> Result<Self>
+    where
+        G: Guard + 'static,
+        F: Fn(Request<LuminalBody>) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        {
+            self.router.add_guarded_handler(
+                Method::POST,
+                route,
+                guard,
+                luminal_handler::handler_fn(function),
+            )?;
+        }
+        Ok(self)
+    }
+
+    /// Wrap every target at the specified route, whatever the method, in another service, such as
+    /// `BasicAuth::wrap`.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn wrap_route<F, S>(mut self, route: &str, wrap: F) -> Result<Self>
+    where
+        F: Fn(Arc<LuminalService>) -> S,
+        S: Service<
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        self.router.wrap_route(route, wrap)?;
+        Ok(self)
+    }
+
+    /// Exempt every target at the specified route from CSRF checks.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn csrf_exempt(mut self, route: &str) -> Result<Self> {
+        self.router.csrf_exempt(route)?;
+        Ok(self)
+    }
+
+    /// Add a WebSocket route, answering upgrade requests for `Method::GET` at the specified route.
+    pub fn websocket(mut self, route: &str, upgrade: WebSocketUpgrade) -> Result<Self> {
+        self.router.add(Method::GET, route, upgrade)?;
+        Ok(self)
+    }
+
+    /// Limit the rate of requests to every target at the specified route, whatever the method.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn rate_limited(mut self, route: &str, limiter: &RateLimiter) -> Result<Self> {
+        self.router
+            .wrap_route(route, |target| limiter.wrap(target))?;
+        Ok(self)
+    }
+
+    /// Return a new `HandlerRouteBuilder` that now owns the router being contructed.
     pub fn handler_builder(self) -> HandlerRouteBuilder {
         HandlerRouteBuilder {
             router: self.router,
         }
     }
 
+    /// Return a new `FnRouteBuilder` that now owns the router being contructed.
+    pub fn service_fn_builder(self) -> FnRouteBuilder {
+        FnRouteBuilder {
+            router: self.router,
+        }
+    }
+
     pub fn build(self) -> Router {
         self.router
     }
@@ -1,117 +1,26 @@
-//! Router for mapping `hyper::Method` and a request path to a `luminal_handler::Handler`.
+//! Builders for adding `luminal_handler::Handler` implementations to a `Router`.
 //!
-//! Use the feature, "handler", to build this alternative router that is able to work with the
-//! luminal-handler create directly.
-use futures::future;
-use hyper::{self, Method, StatusCode};
-use hyper::server::{Request, Response, Service};
-use luminal_handler::Handler;
-
-use std::collections::HashMap;
-
+//! Each handler is wrapped in a `HandlerService`, so handlers and plain services can be mixed
+//! freely within the same router.
 mod builder;
 
-use LuminalFuture;
-use error::*;
-use tree::RouteTree;
-use route::Route;
-pub use self::builder::{FnRouteBuilder, HandlerRouteBuilder};
-
-/// Router for luminal-handler.
-#[derive(Default)]
-pub struct Router {
-    routes: HashMap<Method, RouteTree<Route<Box<Handler>>>>,
-}
-
-impl Service for Router {
-    type Request = Request;
-    type Response = Response;
-    type Error = hyper::Error;
-    type Future = LuminalFuture;
-
-    fn call(&self, req: Request) -> Self::Future {
-        let route = self.dispatch(req.method(), req.path());
-        if let Some(&Some(ref route)) = route {
-            match route.target.handle(req.into()) {
-                Ok(response) => response,
-                Err(error) => Box::new(future::ok(
-                    Response::new()
-                        .with_status(error.status())
-                        .with_body(error.body()),
-                )),
-            }
-        } else {
-            let mut response = Response::new();
-            response.set_status(StatusCode::NotFound);
-            Box::new(future::ok(response))
-        }
-    }
-}
-
-impl Router {
-    /// Add a handler at the specific route path for the given `Method`.
-    pub fn add<H: Handler + 'static>(
-        &mut self,
-        method: Method,
-        route: &str,
-        handler: H,
-    ) -> Result<()> {
-        {
-            let routing = self.routes
-                .entry(method)
-                .or_insert_with(RouteTree::empty_root);
-            routing.add(route, Route::new(route, Box::new(handler)))?;
-        }
-        Ok(())
-    }
-
-    pub fn dispatch<'a>(
-        &'a self,
-        method: &Method,
-        route_path: &str,
-    ) -> Option<&'a Option<Route<Box<Handler>>>> {
-        if let Some(routing) = self.routes.get(method) {
-            if let Some(route) = routing.dispatch(route_path) {
-                Some(route)
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    }
-}
+pub use self::builder::{HandlerFnRouteBuilder, HandlerRouteBuilder};
 
 #[cfg(test)]
 mod tests {
-    extern crate tokio_core;
-
-    use futures;
-    use http;
-    use hyper::{self, Body};
-    use hyper::header::ContentLength;
-    use futures::Stream;
-
-    use self::tokio_core::reactor::Core;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use http::header::CONTENT_LENGTH;
+    use http::{Method, Request, Response, StatusCode};
+    use http_body_util::{BodyExt, Empty};
+    use hyper::service::Service;
+    use luminal_handler::{full, Handler};
 
     use super::*;
+    use crate::{LuminalBody, LuminalFuture, Router};
 
     struct StringHandler(String);
 
-    impl Service for StringHandler {
-        type Request = Request;
-        type Response = Response;
-        type Error = hyper::Error;
-        type Future = LuminalFuture;
-        fn call(&self, _req: Request) -> Self::Future {
-            Box::new(future::ok(
-                Response::new()
-                    .with_header(ContentLength(self.0.len() as u64))
-                    .with_body(self.0.clone()),
-            ))
-        }
-    }
-
     impl StringHandler {
         fn new(msg: &str) -> Self {
             StringHandler(msg.to_owned())
@@ -121,30 +30,44 @@ mod tests {
     impl Handler for StringHandler {
         fn handle(
             &self,
-            _req: http::Request<Body>,
-        ) -> ::std::result::Result<LuminalFuture, Response> {
-            Ok(Box::new(futures::future::ok(
-                Response::new()
-                    .with_header(ContentLength(self.0.len() as u64))
-                    .with_body(self.0.clone()),
-            )))
+            _req: Request<LuminalBody>,
+        ) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>> {
+            let mut response = Response::new(full(self.0.clone()));
+            response
+                .headers_mut()
+                .insert(CONTENT_LENGTH, self.0.len().into());
+            Ok(Box::pin(async move { Ok(response) }))
         }
     }
 
     fn get_bar_handler(
-        _req: http::Request<Body>,
-    ) -> ::std::result::Result<LuminalFuture, Response> {
+        _req: Request<LuminalBody>,
+    ) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>> {
         let msg = String::from("Get bar");
-        Ok(Box::new(futures::future::ok(
-            Response::new()
-                .with_header(ContentLength(msg.len() as u64))
-                .with_body(msg),
-        )))
+        let mut response = Response::new(full(msg.clone()));
+        response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, msg.len().into());
+        Ok(Box::pin(async move { Ok(response) }))
+    }
+
+    fn teapot_handler(
+        _req: Request<LuminalBody>,
+    ) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>> {
+        let mut response = Response::new(full("Short and stout"));
+        *response.status_mut() = StatusCode::IM_A_TEAPOT;
+        Err(response)
+    }
+
+    async fn get_qux_service(
+        _req: Request<LuminalBody>,
+    ) -> ::std::result::Result<Response<LuminalBody>, crate::BoxError> {
+        Ok(Response::new(full("Qux")))
     }
 
     #[test]
     fn test_router() {
-        let router = FnRouteBuilder::new()
+        let router = HandlerFnRouteBuilder::new()
             .get("/foo/bar", get_bar_handler)
             .expect("Should have been able to add route")
             .handler_builder()
@@ -154,61 +77,85 @@ mod tests {
             .expect("Should have been able to add route")
             .build();
 
-        assert_call(&router, Method::Get, "/foo/bar", "Get bar");
-        assert_call(&router, Method::Post, "/foo/bar", "Post bar");
-        assert_call(&router, Method::Get, "/foo/baz", "Baz");
+        assert_call(
+            &router,
+            Method::GET,
+            "/foo/bar",
+            (StatusCode::OK, "Get bar"),
+        );
+        assert_call(
+            &router,
+            Method::POST,
+            "/foo/bar",
+            (StatusCode::OK, "Post bar"),
+        );
+        assert_call(&router, Method::GET, "/foo/baz", (StatusCode::OK, "Baz"));
     }
 
     #[test]
-    fn test_not_found() {
-        let router = Router {
-            ..Default::default()
-        };
-
-        let uri = "/foo"
-            .parse()
-            .expect("Should have been able to convert to uri");
-        let req: Request<Body> = Request::new(Method::Get, uri);
-
-        let work = router.call(req);
+    fn test_handler_error() {
+        let router = HandlerFnRouteBuilder::new()
+            .get("/teapot", teapot_handler)
+            .expect("Should have been able to add route")
+            .build();
 
-        let mut core = Core::new().expect("Should have been able to create core");
+        assert_call(
+            &router,
+            Method::GET,
+            "/teapot",
+            (StatusCode::IM_A_TEAPOT, "Short and stout"),
+        );
+    }
 
-        let response = core.run(work)
-            .expect("Should have been able to run router call");
+    #[test]
+    fn test_mixed() {
+        let router = HandlerRouteBuilder::new()
+            .get("/foo/baz", StringHandler::new("Baz"))
+            .expect("Should have been able to add route")
+            .service_builder()
+            .fn_builder()
+            .get("/foo/qux", get_qux_service)
+            .expect("Should have been able to add route")
+            .handler_fn_builder()
+            .get("/foo/bar", get_bar_handler)
+            .expect("Should have been able to add route")
+            .build();
 
-        assert_eq!(
-            StatusCode::NotFound,
-            response.status(),
-            "Should have received not found status."
+        assert_call(
+            &router,
+            Method::GET,
+            "/foo/bar",
+            (StatusCode::OK, "Get bar"),
         );
+        assert_call(&router, Method::GET, "/foo/baz", (StatusCode::OK, "Baz"));
+        assert_call(&router, Method::GET, "/foo/qux", (StatusCode::OK, "Qux"));
     }
 
-    fn assert_call(router: &Router, method: Method, uri: &str, expected: &str) {
-        let uri = uri.parse()
-            .expect("Should have been able to convert to uri");
-        let req: Request<Body> = Request::new(method, uri);
+    fn assert_call(router: &Router, method: Method, uri: &str, expected: (StatusCode, &str)) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
 
         let work = router.call(req);
 
-        let mut core = Core::new().expect("Should have been able to create core");
-
-        let response = core.run(work)
-            .expect("Should have been able to run router call");
+        let response = block_on(work).expect("Should have been able to run router call");
 
         assert_eq!(
-            StatusCode::Ok,
+            expected.0,
             response.status(),
-            "Should have received Ok status."
+            "Should have received {} status.",
+            expected.0
         );
 
-        let body = core.run(response.body().concat2())
-            .expect("Should have been able to resolve body concat");
-        let body: &[u8] = &body.to_vec();
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
 
         assert_eq!(
-            expected.as_bytes(),
-            body,
+            expected.1.as_bytes(),
+            &body[..],
             "Should have received correct body content"
         );
     }
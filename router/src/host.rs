@@ -0,0 +1,377 @@
+//! Virtual hosting, picking a `Router` by the host a request was sent to.
+//!
+//! Hosts are matched label by label. A pattern is either an exact host such as `example.com`, a
+//! host with `:name` labels that each capture a single label such as `:tenant.example.com`, or a
+//! host with a leading `*` that stands for one or more labels such as `*.example.com`. Exact hosts
+//! win over patterns, and patterns with more literal labels win over those with fewer.
+use bytes::Bytes;
+use http::header::HOST;
+use http::uri::Authority;
+use http::{Request, Response};
+use hyper::body::Body;
+use hyper::service::Service;
+
+use std::collections::HashMap;
+
+use crate::error::*;
+use crate::params::HostParams;
+use crate::service::Router;
+use crate::{not_found, BoxError, LuminalBody, LuminalFuture};
+
+/// Router for Hyper that dispatches to a `Router` per host.
+///
+/// The matched pattern and any captured labels are added to the request extensions as
+/// `HostParams` before the request is handed to the host's `Router`.
+#[derive(Clone, Default)]
+pub struct HostRouter {
+    exact: HashMap<String, Router>,
+    patterns: Vec<(HostPattern, Router)>,
+    default: Option<Router>,
+}
+
+impl<B> Service<Request<B>> for HostRouter
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, mut req: Request<B>) -> Self::Future {
+        let host = request_host(&req).unwrap_or_default();
+        match self.dispatch(&host) {
+            Some((pattern, router)) => {
+                req.extensions_mut().insert(HostParams::new(pattern, &host));
+                router.call(req)
+            }
+            None => not_found(),
+        }
+    }
+}
+
+impl HostRouter {
+    pub fn new() -> Self {
+        HostRouter {
+            ..Default::default()
+        }
+    }
+
+    /// Add a `Router` for every host matching the pattern, replacing any router already added for
+    /// the same pattern.
+    pub fn add(&mut self, pattern: &str, router: Router) -> Result<()> {
+        let pattern = HostPattern::parse(pattern)?;
+        if pattern.is_exact() {
+            self.exact.insert(pattern.source, router);
+        } else if let Some(existing) = self
+            .patterns
+            .iter_mut()
+            .find(|(existing, _)| existing.source == pattern.source)
+        {
+            existing.1 = router;
+        } else {
+            self.patterns.push((pattern, router));
+            // stable, so patterns that are equally specific keep the order they were added in
+            self.patterns
+                .sort_by_key(|(pattern, _)| (usize::MAX - pattern.literals(), pattern.wildcard));
+        }
+        Ok(())
+    }
+
+    /// Set the `Router` for requests whose host matches nothing else, or that have no host.
+    pub fn set_default(&mut self, router: Router) {
+        self.default = Some(router);
+    }
+
+    /// Remove the `Router` added for the pattern, returning it.
+    pub fn remove(&mut self, pattern: &str) -> Option<Router> {
+        let pattern = normalize(pattern);
+        if let Some(router) = self.exact.remove(&pattern) {
+            return Some(router);
+        }
+        let index = self
+            .patterns
+            .iter()
+            .position(|(existing, _)| existing.source == pattern)?;
+        Some(self.patterns.remove(index).1)
+    }
+
+    /// Find the `Router` for a normalized host, along with the pattern it was added with. The
+    /// default router is returned with an empty pattern.
+    pub fn dispatch<'a>(&'a self, host: &str) -> Option<(&'a str, &'a Router)> {
+        if let Some((pattern, router)) = self.exact.get_key_value(host) {
+            return Some((pattern, router));
+        }
+        self.patterns
+            .iter()
+            .find(|(pattern, _)| pattern.matches(host))
+            .map(|(pattern, router)| (pattern.source.as_str(), router))
+            .or_else(|| self.default.as_ref().map(|router| ("", router)))
+    }
+}
+
+/// Fluent builder, takes ownership of a `HostRouter` while adding hosts.
+///
+/// Call `build` to move ownership of the host router back out.
+pub struct HostRouteBuilder {
+    pub router: HostRouter,
+}
+
+impl Default for HostRouteBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostRouteBuilder {
+    /// Create a new instance with a `HostRouter` with no hosts.
+    pub fn new() -> HostRouteBuilder {
+        HostRouteBuilder {
+            router: HostRouter::new(),
+        }
+    }
+
+    /// Add a `Router` for every host matching the pattern.
+    pub fn host(mut self, pattern: &str, router: Router) -> Result<Self> {
+        {
+            self.router.add(pattern, router)?;
+        }
+        Ok(self)
+    }
+
+    /// Use a `Router` for any host that matches nothing else.
+    pub fn default_router(mut self, router: Router) -> Self {
+        self.router.set_default(router);
+        self
+    }
+
+    /// Call to gain/regain ownership of the `HostRouter`.
+    pub fn build(self) -> HostRouter {
+        self.router
+    }
+}
+
+// A parsed host pattern, the source is kept normalized for `HostParams`.
+#[derive(Clone, Debug)]
+struct HostPattern {
+    source: String,
+    wildcard: bool,
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Result<HostPattern> {
+        let source = normalize(pattern);
+        let labels = source.split('.').collect::<Vec<_>>();
+        let wildcard = labels[0] == "*";
+        let captures = labels.iter().any(|label| label.starts_with(':'));
+        let valid = labels.iter().enumerate().all(|(index, label)| {
+            let wild = *label == "*" && index == 0;
+            !label.is_empty() && *label != ":" && (wild || !label.contains('*'))
+        }) && !(wildcard && (captures || labels.len() == 1));
+        if !valid {
+            bail!(ErrorKind::InvalidHost(pattern.to_owned()));
+        }
+        Ok(HostPattern { source, wildcard })
+    }
+
+    fn is_exact(&self) -> bool {
+        !self.wildcard && !self.source.split('.').any(|label| label.starts_with(':'))
+    }
+
+    // The number of labels that must match literally.
+    fn literals(&self) -> usize {
+        self.source
+            .split('.')
+            .filter(|label| *label != "*" && !label.starts_with(':'))
+            .count()
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        if self.wildcard {
+            // at least one label has to stand in for the `*`
+            let suffix = &self.source[1..];
+            return host.len() > suffix.len() && host.ends_with(suffix) && !host.starts_with('.');
+        }
+        let mut host_labels = host.split('.');
+        let all = self
+            .source
+            .split('.')
+            .all(|label| match host_labels.next() {
+                Some(host_label) => {
+                    !host_label.is_empty() && (label.starts_with(':') || label == host_label)
+                }
+                None => false,
+            });
+        all && host_labels.next().is_none()
+    }
+}
+
+// Hosts are case insensitive and may be written fully qualified with a trailing dot.
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// The host a request was sent to, from an absolute URI or else the Host header, without the port.
+pub(crate) fn request_host<B>(req: &Request<B>) -> Option<String> {
+    if let Some(host) = req.uri().host() {
+        return Some(normalize(host));
+    }
+    let authority = req
+        .headers()
+        .get(HOST)?
+        .to_str()
+        .ok()?
+        .parse::<Authority>()
+        .ok()?;
+    Some(normalize(authority.host()))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http::{Method, StatusCode};
+    use http_body_util::{BodyExt, Empty};
+    use luminal_handler::full;
+
+    use super::*;
+    use crate::FnRouteBuilder;
+
+    fn router(msg: &'static str) -> Router {
+        FnRouteBuilder::new()
+            .get("/", move |req: Request<LuminalBody>| async move {
+                let params = req
+                    .extensions()
+                    .get::<HostParams>()
+                    .expect("Should have had host params");
+                let captured = params
+                    .params()
+                    .iter()
+                    .map(|(name, value)| format!(" {}={}", name, value))
+                    .collect::<String>();
+                Ok(Response::new(full(format!("{}{}", msg, captured))))
+            })
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    fn host_router() -> HostRouter {
+        HostRouteBuilder::new()
+            .host("example.com", router("Apex"))
+            .expect("Should have been able to add host")
+            .host("*.example.com", router("Wildcard"))
+            .expect("Should have been able to add host")
+            .host(":tenant.example.com", router("Tenant"))
+            .expect("Should have been able to add host")
+            .host("api.:region.example.com", router("Api"))
+            .expect("Should have been able to add host")
+            .build()
+    }
+
+    #[test]
+    fn test_exact() {
+        let router = host_router();
+        assert_call(&router, "example.com", (StatusCode::OK, "Apex"));
+        assert_call(&router, "Example.COM.:8080", (StatusCode::OK, "Apex"));
+    }
+
+    #[test]
+    fn test_captures() {
+        let router = host_router();
+        assert_call(
+            &router,
+            "acme.example.com",
+            (StatusCode::OK, "Tenant tenant=acme"),
+        );
+        assert_call(
+            &router,
+            "api.eu.example.com:443",
+            (StatusCode::OK, "Api region=eu"),
+        );
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let router = host_router();
+        assert_call(&router, "a.b.c.example.com", (StatusCode::OK, "Wildcard"));
+        assert_call(&router, "badexample.com", (StatusCode::NOT_FOUND, ""));
+    }
+
+    #[test]
+    fn test_default() {
+        let mut router = host_router();
+        assert_call(&router, "example.org", (StatusCode::NOT_FOUND, ""));
+
+        router.set_default(self::router("Default"));
+        assert_call(&router, "example.org", (StatusCode::OK, "Default"));
+
+        let req = Request::builder()
+            .uri("/")
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+        let response = block_on(router.call(req)).expect("Should have been able to run call");
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[test]
+    fn test_absolute_uri() {
+        let router = host_router();
+        let req = Request::builder()
+            .uri("http://acme.example.com/")
+            .header(HOST, "example.com")
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+        let response = block_on(router.call(req)).expect("Should have been able to run call");
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        assert_eq!(&b"Tenant tenant=acme"[..], &body[..]);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut router = host_router();
+        assert!(router.remove(":tenant.example.com").is_some());
+        assert!(router.remove(":tenant.example.com").is_none());
+        assert_call(&router, "acme.example.com", (StatusCode::OK, "Wildcard"));
+    }
+
+    #[test]
+    fn test_invalid_host() {
+        for pattern in &["", "a..com", "*.:x.com", "a.*.com", "*", "w*.com", ":.com"] {
+            let result = HostRouter::new().add(pattern, Router::new());
+            match result {
+                Err(Error(ErrorKind::InvalidHost(_), _)) => (),
+                _ => panic!("Should have rejected host pattern {:?}", pattern),
+            }
+        }
+    }
+
+    fn assert_call(router: &HostRouter, host: &str, expected: (StatusCode, &str)) {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/")
+            .header(HOST, host)
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+
+        let response = block_on(router.call(req)).expect("Should have been able to run call");
+
+        assert_eq!(
+            expected.0,
+            response.status(),
+            "Should have received {} status for {}.",
+            expected.0,
+            host
+        );
+
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+
+        assert_eq!(
+            expected.1.as_bytes(),
+            &body[..],
+            "Should have received correct body content"
+        );
+    }
+}
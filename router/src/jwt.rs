@@ -0,0 +1,606 @@
+//! Bearer token authentication with JSON Web Tokens, verified against a JWK set.
+//!
+//! `JwtAuthService` takes the token from `Authorization: Bearer`, picks the key from the set by
+//! the token's `kid`, or tries every key suited to its algorithm when there is none, and checks
+//! the signature along with `exp`, `nbf` and, when configured, `aud` and `iss`. The claims of a
+//! valid token are put in the request extensions as `Claims`.
+//!
+//! Requests without a valid token get 401 with a `WWW-Authenticate` challenge as described in
+//! RFC 6750. Keys come from a `JwkSource`, so a file or an in-memory set can stand in for a
+//! remote JWKS endpoint. While the source fails, requests with a token get 503.
+use bytes::Bytes;
+use http::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use http::{Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use jsonwebtoken::errors::ErrorKind as JwtErrorKind;
+use jsonwebtoken::jwk::{Jwk, JwkSet, KeyAlgorithm, PublicKeyUse};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use luminal_handler::{boxed, empty};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use std::future::{self, Future};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::error::*;
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// The claims of a verified token, from the request extensions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claims(Map<String, Value>);
+
+impl Claims {
+    pub fn get(&self, claim: &str) -> Option<&Value> {
+        self.0.get(claim)
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.0.get("sub").and_then(Value::as_str)
+    }
+
+    pub fn claims(&self) -> &Map<String, Value> {
+        &self.0
+    }
+
+    /// The claims as an application's own type.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_value(Value::Object(self.0.clone()))
+    }
+}
+
+/// A future resolving to the current JWK set, from `JwkSource::jwks`.
+pub type JwkFuture =
+    Pin<Box<dyn Future<Output = ::std::result::Result<Arc<JwkSet>, BoxError>> + Send>>;
+
+/// Where the keys for verifying tokens come from.
+///
+/// Sources backed by a remote endpoint should cache the set, it is asked for on every request.
+pub trait JwkSource: Send + Sync {
+    fn jwks(&self) -> JwkFuture;
+}
+
+/// A fixed JWK set held in memory.
+#[derive(Clone, Debug)]
+pub struct StaticJwks(Arc<JwkSet>);
+
+impl StaticJwks {
+    pub fn new(jwks: JwkSet) -> StaticJwks {
+        StaticJwks(Arc::new(jwks))
+    }
+
+    pub fn from_json(json: &str) -> Result<StaticJwks> {
+        let jwks = serde_json::from_str(json)
+            .map_err(|error| ErrorKind::InvalidKeys(error.to_string()))?;
+        Ok(StaticJwks::new(jwks))
+    }
+}
+
+impl JwkSource for StaticJwks {
+    fn jwks(&self) -> JwkFuture {
+        Box::pin(future::ready(Ok(Arc::clone(&self.0))))
+    }
+}
+
+// The last set read from a file, with the time the file was modified.
+type Cached = Option<(SystemTime, Arc<JwkSet>)>;
+
+/// A JWK set read from a file, and read again whenever the file is modified.
+pub struct JwkFile {
+    path: PathBuf,
+    cached: Arc<Mutex<Cached>>,
+}
+
+impl JwkFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> JwkFile {
+        JwkFile {
+            path: path.as_ref().to_owned(),
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl JwkSource for JwkFile {
+    fn jwks(&self) -> JwkFuture {
+        let path = self.path.clone();
+        let cached = Arc::clone(&self.cached);
+        Box::pin(async move {
+            let modified = tokio::fs::metadata(&path).await?.modified()?;
+            if let Some((read, jwks)) = &*cached.lock().expect("Should not have poisoned the keys")
+            {
+                if *read == modified {
+                    return Ok(Arc::clone(jwks));
+                }
+            }
+            let json = tokio::fs::read(&path).await?;
+            let jwks = Arc::new(serde_json::from_slice::<JwkSet>(&json)?);
+            *cached.lock().expect("Should not have poisoned the keys") =
+                Some((modified, Arc::clone(&jwks)));
+            Ok(jwks)
+        })
+    }
+}
+
+// Whether a key marked for an algorithm can verify tokens signed with another.
+fn suits(key: &Jwk, algorithm: Algorithm) -> bool {
+    if let Some(PublicKeyUse::Encryption) = key.common.public_key_use {
+        return false;
+    }
+    let marked = match key.common.key_algorithm {
+        Some(marked) => marked,
+        None => return true,
+    };
+    matches!(
+        (marked, algorithm),
+        (KeyAlgorithm::HS256, Algorithm::HS256)
+            | (KeyAlgorithm::HS384, Algorithm::HS384)
+            | (KeyAlgorithm::HS512, Algorithm::HS512)
+            | (KeyAlgorithm::ES256, Algorithm::ES256)
+            | (KeyAlgorithm::ES384, Algorithm::ES384)
+            | (KeyAlgorithm::RS256, Algorithm::RS256)
+            | (KeyAlgorithm::RS384, Algorithm::RS384)
+            | (KeyAlgorithm::RS512, Algorithm::RS512)
+            | (KeyAlgorithm::PS256, Algorithm::PS256)
+            | (KeyAlgorithm::PS384, Algorithm::PS384)
+            | (KeyAlgorithm::PS512, Algorithm::PS512)
+            | (KeyAlgorithm::EdDSA, Algorithm::EdDSA)
+    )
+}
+
+/// A bearer token policy, built fluently.
+///
+/// Tokens signed with RS256, ES256, EdDSA or HS256 are accepted by default, and must have an
+/// `exp` claim. A key is only used for the algorithm its `alg` names, if it names one, and never
+/// for an algorithm of another family, so a public key can't double as an HMAC secret.
+pub struct JwtAuth {
+    source: Box<dyn JwkSource>,
+    algorithms: Vec<Algorithm>,
+    validation: Validation,
+    realm: String,
+}
+
+impl JwtAuth {
+    pub fn new<J: JwkSource + 'static>(source: J) -> JwtAuth {
+        let mut validation = Validation::default();
+        validation.validate_nbf = true;
+        validation.validate_aud = false;
+        JwtAuth {
+            source: Box::new(source),
+            algorithms: vec![
+                Algorithm::RS256,
+                Algorithm::ES256,
+                Algorithm::EdDSA,
+                Algorithm::HS256,
+            ],
+            validation,
+            realm: String::from("luminal"),
+        }
+    }
+
+    pub fn algorithms(mut self, algorithms: &[Algorithm]) -> Self {
+        self.algorithms = algorithms.to_vec();
+        self
+    }
+
+    /// Require tokens to be issued by one of the issuers.
+    pub fn issuers(mut self, issuers: &[&str]) -> Self {
+        self.validation.set_issuer(issuers);
+        self.require("iss");
+        self
+    }
+
+    /// Require tokens to be meant for one of the audiences.
+    pub fn audiences(mut self, audiences: &[&str]) -> Self {
+        self.validation.set_audience(audiences);
+        self.validation.validate_aud = true;
+        self.require("aud");
+        self
+    }
+
+    /// Allow for clocks that disagree by this many seconds when checking `exp` and `nbf`, 60
+    /// unless set.
+    pub fn leeway(mut self, seconds: u64) -> Self {
+        self.validation.leeway = seconds;
+        self
+    }
+
+    /// The realm named in challenges, `luminal` unless set.
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = realm.to_owned();
+        self
+    }
+
+    pub fn wrap<S>(self, inner: S) -> JwtAuthService<S> {
+        JwtAuthService {
+            auth: Arc::new(self),
+            inner,
+        }
+    }
+
+    fn require(&mut self, claim: &str) {
+        self.validation
+            .required_spec_claims
+            .insert(claim.to_owned());
+    }
+
+    async fn verify(&self, token: &str) -> ::std::result::Result<Claims, Challenge> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| Challenge::invalid("The token is malformed"))?;
+        if !self.algorithms.contains(&header.alg) {
+            return Err(Challenge::invalid(
+                "The token is signed with an unsupported algorithm",
+            ));
+        }
+        let jwks = self.source.jwks().await.map_err(|_| Challenge::Keys)?;
+
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![header.alg];
+        let mut failure = Challenge::invalid("No key matches the token");
+        let keys = jwks.keys.iter().filter(|key| match &header.kid {
+            Some(kid) => key.common.key_id.as_ref() == Some(kid),
+            None => true,
+        });
+        for key in keys.filter(|key| suits(key, header.alg)) {
+            let key = match DecodingKey::from_jwk(key) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            match jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation) {
+                Ok(data) => return Ok(Claims(data.claims)),
+                Err(error) => failure = Challenge::from(error.into_kind()),
+            }
+        }
+        Err(failure)
+    }
+}
+
+enum Challenge {
+    Missing,
+    Invalid(&'static str),
+    // the keys couldn't be had from the source
+    Keys,
+}
+
+impl Challenge {
+    fn invalid(description: &'static str) -> Challenge {
+        Challenge::Invalid(description)
+    }
+
+    fn respond(self, realm: &str) -> ::std::result::Result<Response<LuminalBody>, BoxError> {
+        let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let challenge = match self {
+            Challenge::Missing => format!("Bearer realm=\"{}\"", realm),
+            Challenge::Invalid(description) => format!(
+                "Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"",
+                realm, description
+            ),
+            // the token can't be checked either way, so this is on the server rather than the
+            // client
+            Challenge::Keys => {
+                let mut response = Response::new(empty());
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                return Ok(response);
+            }
+        };
+        let mut response = Response::new(empty());
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_str(&challenge)?);
+        Ok(response)
+    }
+}
+
+impl From<JwtErrorKind> for Challenge {
+    fn from(kind: JwtErrorKind) -> Challenge {
+        Challenge::invalid(match kind {
+            JwtErrorKind::ExpiredSignature => "The token has expired",
+            JwtErrorKind::ImmatureSignature => "The token is not valid yet",
+            JwtErrorKind::InvalidAudience => "The token is meant for another audience",
+            JwtErrorKind::InvalidIssuer => "The token is from an untrusted issuer",
+            JwtErrorKind::MissingRequiredClaim(_) => "The token is missing a required claim",
+            JwtErrorKind::InvalidSignature => "The token signature is invalid",
+            _ => "The token is invalid",
+        })
+    }
+}
+
+fn bearer<B>(req: &Request<B>) -> Option<&str> {
+    let authorization = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = authorization.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// A service only passing on requests with a valid bearer token, see `JwtAuth`.
+pub struct JwtAuthService<S> {
+    auth: Arc<JwtAuth>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for JwtAuthService<S> {
+    fn clone(&self) -> Self {
+        JwtAuthService {
+            auth: Arc::clone(&self.auth),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for JwtAuthService<S>
+where
+    S: Service<
+            Request<LuminalBody>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Clone
+        + Send
+        + 'static,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let auth = Arc::clone(&self.auth);
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let verified = match bearer(&req) {
+                Some(token) => auth.verify(token).await,
+                None => Err(Challenge::Missing),
+            };
+            match verified {
+                Ok(claims) => {
+                    req.extensions_mut().insert(claims);
+                    inner.call(req).await
+                }
+                Err(challenge) => challenge.respond(&auth.realm),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use futures::executor::block_on;
+    use jsonwebtoken::{EncodingKey, Header};
+    use luminal_handler::full;
+    use serde_json::json;
+
+    use super::*;
+    use crate::FnRouteBuilder;
+
+    const JWKS: &str = include_str!("../testdata/jwt/jwks.json");
+    const SECRET: &[u8] = b"luminal test secret, not for production";
+
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
+
+    fn sign(algorithm: Algorithm, kid: Option<&str>, claims: Value) -> String {
+        let key = match algorithm {
+            Algorithm::RS256 => {
+                EncodingKey::from_rsa_pem(include_bytes!("../testdata/jwt/rsa.pem"))
+            }
+            Algorithm::ES256 => EncodingKey::from_ec_pem(include_bytes!("../testdata/jwt/ec.pem")),
+            Algorithm::EdDSA => {
+                EncodingKey::from_ed_pem(include_bytes!("../testdata/jwt/ed25519.pem"))
+            }
+            _ => Ok(EncodingKey::from_secret(SECRET)),
+        }
+        .expect("Should have been able to read the signing key");
+        let mut header = Header::new(algorithm);
+        header.kid = kid.map(str::to_owned);
+        jsonwebtoken::encode(&header, &claims, &key).expect("Should have been able to sign")
+    }
+
+    fn service(auth: JwtAuth) -> JwtAuthService<crate::Router> {
+        let router = FnRouteBuilder::new()
+            .get("/whoami", |req| async move {
+                let claims = req
+                    .extensions()
+                    .get::<Claims>()
+                    .expect("Should have had claims");
+                Ok(Response::new(full(
+                    claims.subject().unwrap_or_default().to_owned(),
+                )))
+            })
+            .expect("Should have been able to add route")
+            .build();
+        auth.wrap(router)
+    }
+
+    fn call(service: &JwtAuthService<crate::Router>, token: Option<&str>) -> Response<LuminalBody> {
+        let mut req = Request::builder().uri("/whoami");
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let req = req
+            .body(empty())
+            .expect("Should have been able to build request");
+        block_on(service.call(req)).expect("Should have been able to run call")
+    }
+
+    fn challenge(response: &Response<LuminalBody>) -> &str {
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        response.headers()[WWW_AUTHENTICATE]
+            .to_str()
+            .expect("Should have been a string")
+    }
+
+    #[test]
+    fn test_algorithms() {
+        let service = service(JwtAuth::new(
+            StaticJwks::from_json(JWKS).expect("Should have been able to parse keys"),
+        ));
+        let claims = json!({"sub": "alice", "exp": now() + 300});
+        for (algorithm, kid) in [
+            (Algorithm::RS256, Some("rsa")),
+            (Algorithm::ES256, Some("ec")),
+            (Algorithm::EdDSA, Some("ed25519")),
+            (Algorithm::HS256, Some("hmac")),
+            // without a kid every suitable key is tried
+            (Algorithm::ES256, None),
+        ] {
+            let response = call(&service, Some(&sign(algorithm, kid, claims.clone())));
+            assert_eq!(StatusCode::OK, response.status(), "{:?}", algorithm);
+            let body = block_on(http_body_util::BodyExt::collect(response.into_body()))
+                .expect("Should have been able to resolve body concat")
+                .to_bytes();
+            assert_eq!("alice", body);
+        }
+
+        // a key is only used for its own algorithm
+        let response = call(&service, Some(&sign(Algorithm::HS256, Some("rsa"), claims)));
+        assert!(challenge(&response).contains("error=\"invalid_token\""));
+    }
+
+    #[test]
+    fn test_challenge() {
+        let service = service(
+            JwtAuth::new(StaticJwks::from_json(JWKS).expect("Should have been able to parse keys"))
+                .realm("chatelaine"),
+        );
+        assert_eq!(
+            "Bearer realm=\"chatelaine\"",
+            challenge(&call(&service, None))
+        );
+        assert!(challenge(&call(&service, Some("not.a.token")))
+            .contains("error_description=\"The token is malformed\""));
+
+        let expired = sign(
+            Algorithm::RS256,
+            Some("rsa"),
+            json!({"sub": "alice", "exp": now() - 600}),
+        );
+        assert!(challenge(&call(&service, Some(&expired))).contains("The token has expired"));
+        let early = sign(
+            Algorithm::RS256,
+            Some("rsa"),
+            json!({"sub": "alice", "exp": now() + 1200, "nbf": now() + 600}),
+        );
+        assert!(challenge(&call(&service, Some(&early))).contains("The token is not valid yet"));
+        let forever = sign(Algorithm::RS256, Some("rsa"), json!({"sub": "alice"}));
+        assert!(challenge(&call(&service, Some(&forever))).contains("missing a required claim"));
+
+        let signed = sign(
+            Algorithm::RS256,
+            Some("rsa"),
+            json!({"sub": "alice", "exp": now() + 300}),
+        );
+        let parts = signed.split('.').collect::<Vec<_>>();
+        let forged = json!({"sub": "mallory", "exp": now() + 300}).to_string();
+        let tampered = format!(
+            "{}.{}.{}",
+            parts[0],
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(forged),
+            parts[2]
+        );
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            call(&service, Some(&tampered)).status()
+        );
+    }
+
+    #[test]
+    fn test_issuer_audience() {
+        let service = service(
+            JwtAuth::new(StaticJwks::from_json(JWKS).expect("Should have been able to parse keys"))
+                .issuers(&["https://chatelaine.example"])
+                .audiences(&["luminal"]),
+        );
+        let token = |claims| sign(Algorithm::EdDSA, Some("ed25519"), claims);
+        let exp = now() + 300;
+        let ok = token(
+            json!({"sub": "alice", "exp": exp, "iss": "https://chatelaine.example", "aud": "luminal"}),
+        );
+        assert_eq!(StatusCode::OK, call(&service, Some(&ok)).status());
+
+        let issuer = token(
+            json!({"sub": "alice", "exp": exp, "iss": "https://other.example", "aud": "luminal"}),
+        );
+        assert!(challenge(&call(&service, Some(&issuer))).contains("untrusted issuer"));
+        let audience = token(
+            json!({"sub": "alice", "exp": exp, "iss": "https://chatelaine.example", "aud": "other"}),
+        );
+        assert!(challenge(&call(&service, Some(&audience))).contains("another audience"));
+        let missing =
+            token(json!({"sub": "alice", "exp": exp, "iss": "https://chatelaine.example"}));
+        assert!(challenge(&call(&service, Some(&missing))).contains("missing a required claim"));
+    }
+
+    struct FailingJwks;
+
+    impl JwkSource for FailingJwks {
+        fn jwks(&self) -> JwkFuture {
+            Box::pin(future::ready(Err(BoxError::from(
+                "The keys are unreachable",
+            ))))
+        }
+    }
+
+    #[test]
+    fn test_failing_source() {
+        let service = service(JwtAuth::new(FailingJwks));
+        let token = sign(
+            Algorithm::HS256,
+            Some("hmac"),
+            json!({"sub": "alice", "exp": now() + 300}),
+        );
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            call(&service, Some(&token)).status()
+        );
+        // requests without a token are still told to authenticate
+        assert_eq!(StatusCode::UNAUTHORIZED, call(&service, None).status());
+    }
+
+    #[test]
+    fn test_jwk_file() {
+        let dir = tempfile::tempdir().expect("Should have been able to create a directory");
+        let path = dir.path().join("jwks.json");
+        std::fs::write(&path, r#"{"keys": []}"#).expect("Should have been able to write keys");
+        let service = service(JwtAuth::new(JwkFile::new(&path)));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("Should have been able to build a runtime");
+        let token = sign(
+            Algorithm::HS256,
+            Some("hmac"),
+            json!({"sub": "alice", "exp": now() + 300}),
+        );
+        let call = |token: &str| {
+            let req = Request::builder()
+                .uri("/whoami")
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .body(empty())
+                .expect("Should have been able to build request");
+            runtime
+                .block_on(service.call(req))
+                .expect("Should have been able to run call")
+                .status()
+        };
+        assert_eq!(StatusCode::UNAUTHORIZED, call(&token));
+
+        // the file is read again once it changes
+        std::fs::write(&path, JWKS).expect("Should have been able to write keys");
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(later))
+            .expect("Should have been able to touch keys");
+        assert_eq!(StatusCode::OK, call(&token));
+    }
+}
@@ -1,44 +1,96 @@
-//! Router for mapping `hyper::Method` and a request path to something that will response.
+//! Router for mapping `http::Method` and a request path to something that will response.
 //!
-//! luminal's router uses a simplified radix tree for speedy lookups. `cargo +nightly bench` to see
-//! relative performance across some contrived examples.
+//! luminal's router uses a simplified radix tree for speedy lookups. `cargo bench` to see relative
+//! performance across some contrived examples.
 //!
-//! The actual router implementation depends on the features used to build the crate. By default,
-//! the `Router` implementation works with `hyper::server::Service`. Using the "handler" feature
-//! switches to a `Router` that is aware of the luminal-handler create.
+//! There is a single `Router` whose routes are `hyper::service::Service` implementations. Plain
+//! services and functions are added with `ServiceRouteBuilder` and `FnRouteBuilder`. Implementations
+//! of the luminal-handler crate's `Handler` are added to the very same `Router` with
+//! `HandlerRouteBuilder` and `HandlerFnRouteBuilder`, each wrapped in a `HandlerService`.
 #[macro_use]
 extern crate error_chain;
-extern crate futures;
-#[cfg(feature = "handler")]
-extern crate http;
-extern crate hyper;
-#[cfg(feature = "handler")]
-extern crate luminal_handler;
 
+pub mod access_log;
+pub mod auth;
+pub mod compression;
+pub mod concurrency;
+pub mod cookies;
+pub mod cors;
+pub mod csrf;
 mod error;
-mod route;
-mod tree;
-#[cfg(feature = "handler")]
+pub mod files;
+pub mod guard;
 mod handler;
-#[cfg(not(feature = "handler"))]
+mod host;
+pub mod jwt;
+pub mod metrics;
+mod params;
+pub mod peer;
+pub mod proxy;
+pub mod ratelimit;
+mod reload;
+pub mod request_id;
+mod route;
+pub mod security;
 mod service;
+pub mod session;
+pub mod timeout;
+pub mod tree;
+pub mod websocket;
 
-use futures::future::Future;
-use hyper::server::Response;
-#[cfg(not(feature = "handler"))]
-use hyper::server::{Request, Service};
+use http::{Request, Response, StatusCode};
+use hyper::service::Service;
 
-#[cfg(feature = "handler")]
-pub use handler::{FnRouteBuilder, HandlerRouteBuilder, Router};
-#[cfg(not(feature = "handler"))]
+use std::future;
+
+pub use access_log::{AccessLog, AccessLogService, LogFormat};
+pub use auth::{ApiKeyAuth, AuthService, BasicAuth, CredentialStore, Principal};
+pub use compression::{Compression, CompressionService};
+pub use concurrency::{Adaptive, ConcurrencyLimit, ConcurrencyService};
+pub use cookies::{CookieManager, CookieService, Cookies};
+pub use cors::{Cors, CorsService};
+pub use csrf::{Csrf, CsrfService, CsrfToken};
+pub use files::StaticFiles;
+pub use guard::Guard;
+pub use handler::{HandlerFnRouteBuilder, HandlerRouteBuilder};
+pub use host::{HostRouteBuilder, HostRouter};
+pub use jwt::{Claims, JwkSource, JwtAuth, JwtAuthService};
+pub use metrics::{Metrics, MetricsHandler, MetricsService};
+pub use params::{HostParams, Params, ParamsIter, PathParams};
+pub use peer::{PeerAddr, Secure};
+pub use proxy::{ClientInfo, TrustedProxies, TrustedProxiesService};
+pub use ratelimit::{Quota, RateLimitService, RateLimiter};
+pub use reload::ReloadableRouter;
+pub use request_id::{RequestId, RequestIdService, RequestIds};
+pub use route::{MatchedRoute, Route};
+pub use security::{CspNonce, SecurityHeaders, SecurityHeadersService};
 pub use service::{FnRouteBuilder, Router, ServiceRouteBuilder};
+pub use session::{Session, SessionService, SessionStore, Sessions};
+pub use timeout::{Deadline, Timeout, TimeoutService};
+pub use tree::{Match, RouteTree};
+pub use websocket::{Message, WebSocket, WebSocketUpgrade};
 
 pub use error::Error as LuminalError;
 pub use error::ErrorKind as LuminalErrorKind;
+pub use luminal_handler::{BoxError, LuminalBody, LuminalFuture};
+
+/// A route target as the router holds it, handed to the functions given to `wrap_route`.
+pub type LuminalService = dyn Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    > + Send
+    + Sync;
 
-/// Convenience, especially for `hyper::service::service_fn`.
-pub type LuminalFuture = Box<Future<Item = Response, Error = hyper::Error>>;
+// The response for any method and path that has no route.
+fn not_found() -> LuminalFuture {
+    rejected(StatusCode::NOT_FOUND)
+}
 
-#[cfg(not(feature = "handler"))]
-type LuminalService =
-    Service<Request = Request, Response = Response, Error = hyper::Error, Future = LuminalFuture>;
+// An empty response with the status a request was turned away with.
+fn rejected(status: StatusCode) -> LuminalFuture {
+    let mut response = Response::new(luminal_handler::empty());
+    *response.status_mut() = status;
+    Box::pin(future::ready(Ok(response)))
+}
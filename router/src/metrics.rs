@@ -0,0 +1,640 @@
+//! Request metrics, rendered in the Prometheus text exposition format.
+//!
+//! Three families are recorded, labelled by method, or `OTHER` for anything but the standard
+//! ones, the route template a request matched and, once there is a response, the class of its
+//! status:
+//!
+//! * `luminal_requests_total`, a counter of responses
+//! * `luminal_requests_in_flight`, a gauge of requests still waiting on a response
+//! * `luminal_request_duration_seconds`, a histogram of the time until the response was ready
+//!
+//! The route is the one the `Router` inside matched, even behind other middleware, as the
+//! response carries it. Requests to any other service are labelled `unmatched`, and so are those
+//! in flight until the router matched them.
+//!
+//! Every series is a set of atomics. The maps holding them are only locked for writing the first
+//! time a series is seen, so recording is a shared lock and a few atomic adds.
+use bytes::Bytes;
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::{Method, Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::{boxed, full};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::route::{MatchedRoute, RouteLayers};
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// The label for requests that matched no route.
+const UNMATCHED: &str = "unmatched";
+
+/// The Prometheus client defaults, from 5ms to 10s.
+const DEFAULT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Buckets are counted individually and only made cumulative when rendered.
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: usize) -> Histogram {
+        Histogram {
+            buckets: (0..bounds).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, bounds: &[f64], elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = bounds.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct RouteKey {
+    method: String,
+    route: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct ResponseKey {
+    route: RouteKey,
+    status: &'static str,
+}
+
+struct Registry {
+    namespace: String,
+    bounds: Vec<f64>,
+    responses: RwLock<HashMap<ResponseKey, Arc<Histogram>>>,
+    in_flight: RwLock<HashMap<RouteKey, Arc<AtomicI64>>>,
+}
+
+impl Registry {
+    // The series for the key, created on first use.
+    fn series<K, V, F>(map: &RwLock<HashMap<K, Arc<V>>>, key: K, create: F) -> Arc<V>
+    where
+        K: Eq + Hash,
+        F: FnOnce() -> V,
+    {
+        if let Some(series) = map
+            .read()
+            .expect("Should not have poisoned the metrics")
+            .get(&key)
+        {
+            return Arc::clone(series);
+        }
+        let mut map = map.write().expect("Should not have poisoned the metrics");
+        Arc::clone(map.entry(key).or_insert_with(|| Arc::new(create())))
+    }
+
+    fn in_flight(&self, key: RouteKey) -> InFlight {
+        let gauge = Registry::series(&self.in_flight, key, || AtomicI64::new(0));
+        gauge.fetch_add(1, Ordering::Relaxed);
+        InFlight(gauge)
+    }
+
+    fn observe(&self, key: ResponseKey, elapsed: Duration) {
+        let bounds = self.bounds.len();
+        Registry::series(&self.responses, key, || Histogram::new(bounds))
+            .observe(&self.bounds, elapsed);
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let namespace = &self.namespace;
+
+        let responses = self
+            .responses
+            .read()
+            .expect("Should not have poisoned the metrics");
+        let mut keys = responses.keys().collect::<Vec<_>>();
+        keys.sort();
+
+        let _ = writeln!(
+            text,
+            "# HELP {}_requests_total Requests that received a response.\n# TYPE {}_requests_total counter",
+            namespace, namespace
+        );
+        for key in &keys {
+            let _ = writeln!(
+                text,
+                "{}_requests_total{{{}}} {}",
+                namespace,
+                labels(key),
+                responses[*key].count.load(Ordering::Relaxed)
+            );
+        }
+
+        let in_flight = self
+            .in_flight
+            .read()
+            .expect("Should not have poisoned the metrics");
+        let mut routes = in_flight.keys().collect::<Vec<_>>();
+        routes.sort();
+        let _ = writeln!(
+            text,
+            "# HELP {}_requests_in_flight Requests waiting on a response.\n# TYPE {}_requests_in_flight gauge",
+            namespace, namespace
+        );
+        for route in routes {
+            let _ = writeln!(
+                text,
+                "{}_requests_in_flight{{method=\"{}\",route=\"{}\"}} {}",
+                namespace,
+                escape(&route.method),
+                escape(&route.route),
+                in_flight[route].load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(
+            text,
+            "# HELP {}_request_duration_seconds Time until a response was ready.\n# TYPE {}_request_duration_seconds histogram",
+            namespace, namespace
+        );
+        for key in &keys {
+            let histogram = &responses[*key];
+            let labels = labels(key);
+            let mut cumulative = 0;
+            for (bound, bucket) in self.bounds.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    text,
+                    "{}_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    namespace, labels, bound, cumulative
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(
+                text,
+                "{}_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n{}_request_duration_seconds_sum{{{}}} {}\n{}_request_duration_seconds_count{{{}}} {}",
+                namespace, labels, count, namespace, labels, sum, namespace, labels, count
+            );
+        }
+        text
+    }
+}
+
+fn labels(key: &ResponseKey) -> String {
+    format!(
+        "method=\"{}\",route=\"{}\",status=\"{}\"",
+        escape(&key.route.method),
+        escape(&key.route.route),
+        key.status
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// The standard methods label themselves, anything else a client sends is `OTHER` so it can't add
+// series without bound.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => "OTHER",
+    }
+}
+
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+// Counts a request as in flight until it is dropped, so a request abandoned before its response
+// is ready, by a timeout or a closed connection, still leaves the gauge.
+struct InFlight(Arc<AtomicI64>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A set of request metrics, shared by the services it wraps and the handler that renders it.
+///
+/// Cloning is cheap and every clone records into, and renders, the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::with_config("luminal", &DEFAULT_BUCKETS)
+    }
+
+    /// Metrics whose names start with the namespace instead of `luminal`, with the upper bounds,
+    /// in seconds, of the latency histogram buckets.
+    pub fn with_config(namespace: &str, buckets: &[f64]) -> Metrics {
+        let mut bounds = buckets.to_vec();
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+        Metrics {
+            registry: Arc::new(Registry {
+                namespace: namespace.to_owned(),
+                bounds,
+                responses: RwLock::new(HashMap::new()),
+                in_flight: RwLock::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Record the requests a service handles.
+    pub fn wrap<S>(&self, inner: S) -> MetricsService<S> {
+        MetricsService {
+            metrics: self.clone(),
+            inner,
+        }
+    }
+
+    /// A service rendering these metrics, to be added at a route such as `/metrics`.
+    pub fn handler(&self) -> MetricsHandler {
+        MetricsHandler {
+            metrics: self.clone(),
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        self.registry.render()
+    }
+}
+
+/// A service recording into `Metrics`, see `Metrics::wrap`.
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    metrics: Metrics,
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for MetricsService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let start = Instant::now();
+        let mut req = req.map(boxed);
+        let method = method_label(req.method());
+        let registry = Arc::clone(&self.metrics.registry);
+        let unmatched = RouteKey {
+            method: method.to_owned(),
+            route: UNMATCHED.to_owned(),
+        };
+        let in_flight = Arc::new(Mutex::new(Some((
+            unmatched.clone(),
+            registry.in_flight(unmatched),
+        ))));
+        // the request is counted under its route from when the router inside matched it
+        let moved = Arc::clone(&in_flight);
+        RouteLayers::push(&mut req, move |matched, req, next| {
+            if let Some(route) = matched.route() {
+                let route = RouteKey {
+                    method: method.to_owned(),
+                    route: route.to_owned(),
+                };
+                let gauge = registry.in_flight(route.clone());
+                *moved.lock().expect("Should not have poisoned the metrics") = Some((route, gauge));
+            }
+            next(req)
+        });
+        let response = self.inner.call(req);
+
+        let registry = Arc::clone(&self.metrics.registry);
+        Box::pin(async move {
+            let result = response.await;
+            let elapsed = start.elapsed();
+            // taken out, so the gauge goes down now rather than once the router lets go of the layer
+            let (route, _) = in_flight
+                .lock()
+                .expect("Should not have poisoned the metrics")
+                .take()
+                .expect("Should have been in flight");
+            let (route, status) = match result {
+                Ok(ref response) => {
+                    let route = match response.extensions().get::<MatchedRoute>() {
+                        Some(matched) => RouteKey {
+                            method: route.method,
+                            route: matched.route().unwrap_or(UNMATCHED).to_owned(),
+                        },
+                        None => route,
+                    };
+                    (route, status_class(response.status()))
+                }
+                Err(_) => (route, "5xx"),
+            };
+            registry.observe(ResponseKey { route, status }, elapsed);
+            result
+        })
+    }
+}
+
+/// Renders `Metrics` for Prometheus to scrape, see `Metrics::handler`.
+#[derive(Clone)]
+pub struct MetricsHandler {
+    metrics: Metrics,
+}
+
+impl Service<Request<LuminalBody>> for MetricsHandler {
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, _req: Request<LuminalBody>) -> Self::Future {
+        let mut response = Response::new(full(self.metrics.render()));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        );
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::future::poll_fn;
+    use http_body_util::BodyExt;
+    use luminal_handler::empty;
+
+    use std::task::Poll;
+
+    use super::*;
+    use crate::{ConcurrencyLimit, FnRouteBuilder, Router};
+
+    fn router() -> Router {
+        FnRouteBuilder::new()
+            .get("/user/:user_id", |_req| async {
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .get("/fail", |_req| async {
+                Err::<Response<LuminalBody>, BoxError>(BoxError::from("Failed"))
+            })
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    fn call(service: &MetricsService<Router>, uri: &str) {
+        let req = Request::builder()
+            .uri(uri)
+            .body(empty())
+            .expect("Should have been able to build request");
+        let _ = block_on(service.call(req));
+    }
+
+    #[test]
+    fn test_counts() {
+        let metrics = Metrics::with_config("test", &[0.1, 1.0]);
+        let service = metrics.wrap(router());
+        call(&service, "/user/1");
+        call(&service, "/user/2");
+        call(&service, "/missing");
+        call(&service, "/fail");
+
+        let text = metrics.render();
+        assert!(
+            text.contains(
+                "test_requests_total{method=\"GET\",route=\"/user/:user_id\",status=\"2xx\"} 2\n"
+            ),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(
+                "test_requests_total{method=\"GET\",route=\"unmatched\",status=\"4xx\"} 1\n"
+            ),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("test_requests_total{method=\"GET\",route=\"/fail\",status=\"5xx\"} 1\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("test_request_duration_seconds_bucket{method=\"GET\",route=\"/user/:user_id\",status=\"2xx\",le=\"0.1\"} 2\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("test_request_duration_seconds_bucket{method=\"GET\",route=\"/user/:user_id\",status=\"2xx\",le=\"+Inf\"} 2\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("test_request_duration_seconds_count{method=\"GET\",route=\"/user/:user_id\",status=\"2xx\"} 2\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("test_requests_in_flight{method=\"GET\",route=\"/user/:user_id\"} 0\n"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_other_methods() {
+        let metrics = Metrics::new();
+        let service = metrics.wrap(router());
+        for method in ["PURGE", "X-RANDOM-1", "X-RANDOM-2"] {
+            let req = Request::builder()
+                .method(method)
+                .uri("/user/1")
+                .body(empty())
+                .expect("Should have been able to build request");
+            let _ = block_on(service.call(req));
+        }
+        let text = metrics.render();
+        assert!(
+            text.contains(
+                "luminal_requests_total{method=\"OTHER\",route=\"unmatched\",status=\"4xx\"} 3\n"
+            ),
+            "{}",
+            text
+        );
+        assert!(!text.contains("PURGE"));
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let metrics = Metrics::new();
+        let router = FnRouteBuilder::new()
+            .get("/slow", |_req| async {
+                let mut polled = false;
+                // pending once, so the gauge can be read while the request is in flight
+                poll_fn(|cx| {
+                    if polled {
+                        Poll::Ready(())
+                    } else {
+                        polled = true;
+                        cx.waker().wake_by_ref();
+                        Poll::Pending
+                    }
+                })
+                .await;
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .build();
+        let service = metrics.wrap(router);
+        let req = Request::builder()
+            .uri("/slow")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let mut pending = service.call(req);
+
+        let waker = futures::task::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(pending.as_mut().poll(&mut cx).is_pending());
+        assert!(metrics
+            .render()
+            .contains("luminal_requests_in_flight{method=\"GET\",route=\"/slow\"} 1\n"));
+
+        // abandoning the request takes it out of flight without counting a response
+        drop(pending);
+        let text = metrics.render();
+        assert!(text.contains("luminal_requests_in_flight{method=\"GET\",route=\"/slow\"} 0\n"));
+        assert!(!text.contains("luminal_requests_total{"));
+    }
+
+    #[test]
+    fn test_deferred() {
+        let metrics = Metrics::new();
+        // the limiter only calls the router from within its future
+        let service = metrics.wrap(ConcurrencyLimit::new(10).wrap(router()));
+        for uri in ["/user/1", "/fail", "/missing"] {
+            let req = Request::builder()
+                .uri(uri)
+                .body(empty())
+                .expect("Should have been able to build request");
+            let _ = block_on(service.call(req));
+        }
+        let text = metrics.render();
+        assert!(
+            text.contains(
+                "luminal_requests_total{method=\"GET\",route=\"/user/:user_id\",status=\"2xx\"} 1\n"
+            ),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(
+                "luminal_requests_total{method=\"GET\",route=\"unmatched\",status=\"4xx\"} 1\n"
+            ),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(
+                "luminal_requests_total{method=\"GET\",route=\"/fail\",status=\"5xx\"} 1\n"
+            ),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(
+                "luminal_requests_in_flight{method=\"GET\",route=\"/user/:user_id\"} 0\n"
+            ),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_plain_service() {
+        let metrics = Metrics::new();
+        let service = metrics.wrap(hyper::service::service_fn(
+            |_req: Request<LuminalBody>| -> LuminalFuture {
+                Box::pin(async { Ok(Response::new(empty())) })
+            },
+        ));
+        let req = Request::builder()
+            .uri("/user/1")
+            .body(empty())
+            .expect("Should have been able to build request");
+        block_on(service.call(req)).expect("Should have been able to run call");
+        assert!(metrics.render().contains(
+            "luminal_requests_total{method=\"GET\",route=\"unmatched\",status=\"2xx\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_handler() {
+        let metrics = Metrics::new();
+        let router = metrics.wrap(router());
+        call(&router, "/user/1");
+
+        let req = Request::builder()
+            .uri("/metrics")
+            .body(empty())
+            .expect("Should have been able to build request");
+        let response =
+            block_on(metrics.handler().call(req)).expect("Should have been able to run call");
+        assert_eq!(
+            "text/plain; version=0.0.4; charset=utf-8",
+            response.headers()[CONTENT_TYPE]
+        );
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        let text = String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body");
+        assert!(text.contains("# TYPE luminal_requests_total counter\n"));
+        assert!(text.contains("# TYPE luminal_request_duration_seconds histogram\n"));
+        assert!(text.contains(
+            "luminal_requests_total{method=\"GET\",route=\"/user/:user_id\",status=\"2xx\"} 1\n"
+        ));
+    }
+}
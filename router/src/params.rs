@@ -0,0 +1,176 @@
+//! Parameters captured while routing a request.
+//!
+//! `Params` borrows the route and the path it matched, which is all `RouteTree::dispatch` needs.
+//! `Router` and `HostRouter` store owned copies in the request extensions instead, `PathParams`
+//! for the path and `HostParams` for the host, so handlers can reach them from the request.
+use std::str::Split;
+
+/// The parameters captured by a route, by name without the leading colon or asterisk.
+///
+/// A `:name` parameter captures a single segment, a `*name` catch-all captures everything that is
+/// left of the path, slashes and all. Nothing is parsed ahead of time, the route and the requested
+/// path are walked side by side whenever a parameter is looked up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params<'a, 'p> {
+    route: &'a str,
+    path: &'p str,
+    separator: char,
+}
+
+impl<'a, 'p> Params<'a, 'p> {
+    /// Pair a route with a path that it matched.
+    pub fn new(route: &'a str, path: &'p str) -> Self {
+        Params::with_separator(route, path, '/')
+    }
+
+    /// Pair a route with a value it matched, where components are split on something other than
+    /// a slash, such as the dots of a host name.
+    pub fn with_separator(route: &'a str, path: &'p str, separator: char) -> Self {
+        Params {
+            route,
+            path,
+            separator,
+        }
+    }
+
+    /// Get the raw value captured for the named parameter.
+    pub fn get(&self, name: &str) -> Option<&'p str> {
+        self.iter()
+            .find(|&(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Iterate over the parameter names and their raw values, in route order.
+    pub fn iter(&self) -> ParamsIter<'a, 'p> {
+        ParamsIter {
+            route: self
+                .route
+                .trim_start_matches(self.separator)
+                .split(self.separator),
+            path: Some(self.path.trim_start_matches(self.separator)),
+            separator: self.separator,
+        }
+    }
+
+    /// Whether the route captured no parameters.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+impl<'a, 'p> IntoIterator for Params<'a, 'p> {
+    type Item = (&'a str, &'p str);
+    type IntoIter = ParamsIter<'a, 'p>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the captured parameters, see `Params::iter`.
+pub struct ParamsIter<'a, 'p> {
+    route: Split<'a, char>,
+    // What is left of the path, `None` once it has all been matched.
+    path: Option<&'p str>,
+    separator: char,
+}
+
+impl<'a, 'p> Iterator for ParamsIter<'a, 'p> {
+    type Item = (&'a str, &'p str);
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in self.route.by_ref() {
+            let path = self.path?;
+            if let Some(name) = key.strip_prefix('*') {
+                // a bare wildcard, such as in a host pattern, matches without capturing
+                self.path = None;
+                if name.is_empty() {
+                    return None;
+                }
+                return Some((name, path));
+            }
+            let value = match path.split_once(self.separator) {
+                Some((value, rest)) => {
+                    self.path = Some(rest);
+                    value
+                }
+                None => {
+                    self.path = None;
+                    path
+                }
+            };
+            if let Some(name) = key.strip_prefix(':') {
+                return Some((name, value));
+            }
+        }
+        None
+    }
+}
+
+/// The route a request matched and the path parameters it captured.
+///
+/// `Router` adds this to the extensions of every request it dispatches to a route, and to the
+/// extensions of the response that comes back, for middleware wrapped around the router.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathParams {
+    route: String,
+    path: String,
+}
+
+impl PathParams {
+    pub fn new(route: &str, path: &str) -> Self {
+        PathParams {
+            route: route.to_owned(),
+            path: path.to_owned(),
+        }
+    }
+
+    /// The route the matching target was added with, such as `/user/:user_id`.
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    /// Get the raw value captured for the named parameter.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params().get(name)
+    }
+
+    pub fn params(&self) -> Params<'_, '_> {
+        Params::new(&self.route, &self.path)
+    }
+}
+
+/// The host pattern a request matched and the host parameters it captured.
+///
+/// `HostRouter` adds this to the extensions of every request it dispatches to a host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostParams {
+    pattern: String,
+    host: String,
+}
+
+impl HostParams {
+    pub fn new(pattern: &str, host: &str) -> Self {
+        HostParams {
+            pattern: pattern.to_owned(),
+            host: host.to_owned(),
+        }
+    }
+
+    /// The host pattern the matching router was added with, such as `:tenant.example.com`.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// The normalized host of the request, without any port.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Get the raw value captured for the named parameter.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params().get(name)
+    }
+
+    pub fn params(&self) -> Params<'_, '_> {
+        Params::with_separator(&self.pattern, &self.host, '.')
+    }
+}
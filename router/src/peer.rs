@@ -0,0 +1,83 @@
+//! What servers know about the connection a request came in on, which they put in the extensions
+//! of every request on it.
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+/// The peer of the connection a request came in on, from the request extensions.
+///
+/// This is whoever opened the connection, a reverse proxy just as well as a browser.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, with the path the peer bound, if any, and its credentials where the
+    /// platform provides them.
+    Unix {
+        path: Option<PathBuf>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+}
+
+/// Marks requests that came in over TLS, in the request extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Secure;
+
+impl PeerAddr {
+    /// The IP address of a TCP peer.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            PeerAddr::Unix { .. } => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix {
+                path: Some(path), ..
+            } => write!(f, "unix:{}", path.display()),
+            PeerAddr::Unix { uid: Some(uid), .. } => write!(f, "unix:uid={}", uid),
+            PeerAddr::Unix { .. } => f.write_str("unix"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let tcp = PeerAddr::from(
+            "192.0.2.7:4711"
+                .parse::<SocketAddr>()
+                .expect("Should have parsed address"),
+        );
+        assert_eq!("192.0.2.7:4711", tcp.to_string());
+        assert_eq!(Some(IpAddr::from([192, 0, 2, 7])), tcp.ip());
+
+        let unix = PeerAddr::Unix {
+            path: None,
+            uid: Some(1000),
+            gid: Some(1000),
+        };
+        assert_eq!("unix:uid=1000", unix.to_string());
+        assert_eq!(None, unix.ip());
+        let unix = PeerAddr::Unix {
+            path: Some(PathBuf::from("/run/proxy.sock")),
+            uid: None,
+            gid: None,
+        };
+        assert_eq!("unix:/run/proxy.sock", unix.to_string());
+    }
+}
@@ -0,0 +1,655 @@
+//! Who the client is when requests come through reverse proxies.
+//!
+//! Behind a proxy, the peer of every connection is the proxy. `TrustedProxiesService` reads either
+//! `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`, or the `Forwarded` header,
+//! whichever the proxies set, and walks back through the hops only as far as they are proxies it
+//! trusts, so clients can't pass themselves off as someone else by sending the headers themselves.
+//! The other headers are ignored, since proxies pass on those a client sent untouched. The
+//! result is a `ClientInfo` in the request extensions with the client's IP and the scheme and host
+//! it asked for, enough to build absolute URLs.
+use bytes::Bytes;
+use http::header::{HeaderName, FORWARDED, HOST};
+use http::uri::{Authority, Scheme};
+use http::{Extensions, HeaderMap, Request, Response};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::boxed;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use crate::error::*;
+use crate::peer::{PeerAddr, Secure};
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// The client behind a request, from the request extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientInfo {
+    ip: Option<IpAddr>,
+    scheme: Scheme,
+    host: Option<Authority>,
+}
+
+impl ClientInfo {
+    /// What the connection and the request itself tell about the client, trusting no proxies:
+    /// the peer's IP, `https` for requests over TLS and the host from the URI or `Host` header.
+    pub fn direct<B>(req: &Request<B>) -> ClientInfo {
+        let scheme = match req.uri().scheme() {
+            Some(scheme) => scheme.clone(),
+            None if req.extensions().get::<Secure>().is_some() => Scheme::HTTPS,
+            None => Scheme::HTTP,
+        };
+        let host = req.uri().authority().cloned().or_else(|| {
+            req.headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .and_then(|host| host.parse().ok())
+        });
+        ClientInfo {
+            ip: peer_ip(req.extensions()),
+            scheme,
+            host,
+        }
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    pub fn scheme(&self) -> &str {
+        self.scheme.as_str()
+    }
+
+    /// The host the client asked for, with the port if it gave one.
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_ref().map(Authority::as_str)
+    }
+
+    /// The URL of a path on the host the client asked for, such as `https://example.com/login`.
+    pub fn absolute_url(&self, path_and_query: &str) -> Option<String> {
+        let host = self.host.as_ref()?;
+        Some(format!("{}://{}{}", self.scheme, host, path_and_query))
+    }
+}
+
+fn peer_ip(extensions: &Extensions) -> Option<IpAddr> {
+    match extensions.get::<PeerAddr>() {
+        Some(peer) => peer.ip(),
+        None => extensions.get::<SocketAddr>().map(SocketAddr::ip),
+    }
+}
+
+/// The client's IP from the extensions, from the `ClientInfo` or else the peer of the connection.
+pub(crate) fn client_ip(extensions: &Extensions) -> Option<IpAddr> {
+    match extensions.get::<ClientInfo>() {
+        Some(client) => client.ip(),
+        None => peer_ip(extensions),
+    }
+}
+
+// An IP address with a prefix length, or a single address at full length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(cidr: &str) -> Result<Cidr> {
+        let invalid = || ErrorKind::InvalidCidr(cidr.to_owned());
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+        let addr: IpAddr = addr.trim().parse().chain_err(invalid)?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().chain_err(invalid)?,
+            None => bits,
+        };
+        if prefix > bits {
+            bail!(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// One hop a request took, as a proxy recorded it.
+#[derive(Debug, Default)]
+struct Hop {
+    addr: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+// The headers the trusted proxies set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Headers {
+    #[default]
+    XForwarded,
+    Forwarded,
+}
+
+/// The proxies to believe the forwarding headers of, built fluently.
+///
+/// Nothing is trusted by default, so the headers are ignored and the `ClientInfo` is what the
+/// connection tells. The proxies are taken to set the `X-Forwarded-*` headers unless they are
+/// said to set `Forwarded`.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<Cidr>,
+    unix: bool,
+    headers: Headers,
+}
+
+impl TrustedProxies {
+    pub fn new() -> TrustedProxies {
+        TrustedProxies::default()
+    }
+
+    /// Trust proxies on loopback and private networks, the usual place of a load balancer or
+    /// an ingress: `127.0.0.0/8`, `10.0.0.0/8`, `172.16.0.0/12`, `192.168.0.0/16`, `::1` and
+    /// `fc00::/7`, and Unix domain sockets.
+    pub fn local() -> TrustedProxies {
+        let cidrs = [
+            Cidr {
+                addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)),
+                prefix: 8,
+            },
+            Cidr {
+                addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)),
+                prefix: 8,
+            },
+            Cidr {
+                addr: IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)),
+                prefix: 12,
+            },
+            Cidr {
+                addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)),
+                prefix: 16,
+            },
+            Cidr {
+                addr: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                prefix: 128,
+            },
+            Cidr {
+                addr: IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)),
+                prefix: 7,
+            },
+        ];
+        TrustedProxies {
+            cidrs: cidrs.to_vec(),
+            unix: true,
+            headers: Headers::default(),
+        }
+    }
+
+    /// Trust proxies at an IP address or in a CIDR block, such as `10.1.0.0/16`.
+    pub fn trust(mut self, cidr: &str) -> Result<Self> {
+        self.cidrs.push(Cidr::parse(cidr)?);
+        Ok(self)
+    }
+
+    /// Trust whatever connects over a Unix domain socket.
+    pub fn trust_unix(mut self) -> Self {
+        self.unix = true;
+        self
+    }
+
+    /// Read the `Forwarded` header of RFC 7239, and ignore `X-Forwarded-*`.
+    pub fn forwarded(mut self) -> Self {
+        self.headers = Headers::Forwarded;
+        self
+    }
+
+    /// Read `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`, and ignore
+    /// `Forwarded`, which is the default.
+    pub fn x_forwarded(mut self) -> Self {
+        self.headers = Headers::XForwarded;
+        self
+    }
+
+    pub fn wrap<S>(self, inner: S) -> TrustedProxiesService<S> {
+        TrustedProxiesService {
+            proxies: Arc::new(self),
+            inner,
+        }
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn trusts_peer(&self, extensions: &Extensions) -> bool {
+        match extensions.get::<PeerAddr>() {
+            Some(PeerAddr::Tcp(addr)) => self.trusts(addr.ip()),
+            Some(PeerAddr::Unix { .. }) => self.unix,
+            None => extensions
+                .get::<SocketAddr>()
+                .is_some_and(|addr| self.trusts(addr.ip())),
+        }
+    }
+
+    /// Work out the client, believing the forwarding headers as far as the proxies that set them
+    /// are trusted.
+    pub fn resolve<B>(&self, req: &Request<B>) -> ClientInfo {
+        let mut client = ClientInfo::direct(req);
+        if !self.trusts_peer(req.extensions()) {
+            return client;
+        }
+
+        // walk back from the nearest proxy until a hop isn't one of ours
+        let hops = match self.headers {
+            Headers::XForwarded => x_forwarded_hops(req.headers()),
+            Headers::Forwarded => forwarded_hops(req.headers()),
+        };
+        let mut first = None;
+        for hop in hops.iter().rev() {
+            first = Some(hop);
+            match hop.addr {
+                Some(addr) => {
+                    client.ip = Some(addr);
+                    if !self.trusts(addr) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        if let Some(hop) = first {
+            if let Some(proto) = &hop.proto {
+                if proto.eq_ignore_ascii_case("https") {
+                    client.scheme = Scheme::HTTPS;
+                } else if proto.eq_ignore_ascii_case("http") {
+                    client.scheme = Scheme::HTTP;
+                }
+            }
+            if let Some(host) = hop.host.as_ref().and_then(|host| host.parse().ok()) {
+                client.host = Some(host);
+            }
+        }
+        client
+    }
+}
+
+// The hops from the Forwarded header, nearest proxy last.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    values(headers, &FORWARDED)
+        .iter()
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in split_unquoted(element, ';') {
+                let (key, value) = match pair.split_once('=') {
+                    Some((key, value)) => (key.trim(), unquote(value.trim())),
+                    None => continue,
+                };
+                if key.eq_ignore_ascii_case("for") {
+                    hop.addr = node_ip(&value);
+                } else if key.eq_ignore_ascii_case("proto") {
+                    hop.proto = Some(value);
+                } else if key.eq_ignore_ascii_case("host") {
+                    hop.host = Some(value);
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+// The hops from the X-Forwarded headers, nearest proxy last. Proxies tend to set
+// X-Forwarded-Proto and X-Forwarded-Host rather than add to them, so unless they have a value for
+// every hop, the nearest proxy's value goes for all of them.
+fn x_forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    let addrs = values(headers, &X_FORWARDED_FOR);
+    let protos = values(headers, &X_FORWARDED_PROTO);
+    let hosts = values(headers, &X_FORWARDED_HOST);
+    let len = if addrs.is_empty() && !(protos.is_empty() && hosts.is_empty()) {
+        1
+    } else {
+        addrs.len()
+    };
+    let nth = |values: &[String], hop: usize| {
+        if values.len() == len {
+            values.get(hop).cloned()
+        } else {
+            values.last().cloned()
+        }
+    };
+    (0..len)
+        .map(|hop| Hop {
+            addr: addrs.get(hop).and_then(|addr| node_ip(addr)),
+            proto: nth(&protos, hop),
+            host: nth(&hosts, hop),
+        })
+        .collect()
+}
+
+// Every comma separated value of every instance of a header.
+fn values(headers: &HeaderMap, name: &HeaderName) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| split_unquoted(value, ','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (index, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && c == separator {
+            parts.push(&value[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(quoted) => {
+            let mut unquoted = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_owned(),
+    }
+}
+
+// The IP of a node, `192.0.2.1`, `192.0.2.1:80`, `[2001:db8::1]:80` or a bare IPv6 address, but
+// not `unknown` or an obfuscated `_name`.
+fn node_ip(node: &str) -> Option<IpAddr> {
+    if let Some(bracketed) = node.strip_prefix('[') {
+        let (ip, _) = bracketed.split_once(']')?;
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    let (ip, _port) = node.rsplit_once(':')?;
+    ip.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
+/// A service putting the `ClientInfo` in the request extensions, see `TrustedProxies`.
+pub struct TrustedProxiesService<S> {
+    proxies: Arc<TrustedProxies>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for TrustedProxiesService<S> {
+    fn clone(&self) -> Self {
+        TrustedProxiesService {
+            proxies: Arc::clone(&self.proxies),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for TrustedProxiesService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let client = self.proxies.resolve(&req);
+        req.extensions_mut().insert(client);
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http_body_util::BodyExt;
+    use luminal_handler::{empty, full};
+
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::FnRouteBuilder;
+
+    fn request(peer: PeerAddr, headers: &[(&str, &str)]) -> Request<LuminalBody> {
+        let mut req = Request::builder()
+            .uri("/whoami?full=1")
+            .header(HOST, "internal:8080");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let mut req = req
+            .body(empty())
+            .expect("Should have been able to build request");
+        req.extensions_mut().insert(peer);
+        req
+    }
+
+    fn tcp(addr: &str) -> PeerAddr {
+        PeerAddr::Tcp(addr.parse().expect("Should have parsed address"))
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().expect("Should have parsed IP"))
+    }
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new()
+            .trust("10.0.0.0/8")
+            .expect("Should have parsed CIDR")
+            .trust("2001:db8::/32")
+            .expect("Should have parsed CIDR")
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr = Cidr::parse("10.1.0.0/16").expect("Should have parsed CIDR");
+        assert!(cidr.contains("10.1.200.3".parse().expect("Should have parsed IP")));
+        assert!(cidr.contains("::ffff:10.1.0.1".parse().expect("Should have parsed IP")));
+        assert!(!cidr.contains("10.2.0.1".parse().expect("Should have parsed IP")));
+        let all = Cidr::parse("::/0").expect("Should have parsed CIDR");
+        assert!(all.contains("2001:db8::1".parse().expect("Should have parsed IP")));
+        assert!(!all.contains("192.0.2.1".parse().expect("Should have parsed IP")));
+        let single = Cidr::parse("192.0.2.1").expect("Should have parsed CIDR");
+        assert!(single.contains("192.0.2.1".parse().expect("Should have parsed IP")));
+        assert!(!single.contains("192.0.2.2".parse().expect("Should have parsed IP")));
+
+        for invalid in ["10.0.0.0/33", "proxy", "10.0.0.0/x", "::/129"] {
+            let error = TrustedProxies::new()
+                .trust(invalid)
+                .expect_err("Should have refused CIDR");
+            assert!(matches!(error.kind(), ErrorKind::InvalidCidr(_)));
+        }
+    }
+
+    #[test]
+    fn test_direct() {
+        let mut req = request(tcp("203.0.113.9:4711"), &[("x-forwarded-for", "1.2.3.4")]);
+        let client = proxies().resolve(&req);
+        assert_eq!(ip("203.0.113.9"), client.ip());
+        assert_eq!("http", client.scheme());
+        assert_eq!(Some("internal:8080"), client.host());
+
+        req.extensions_mut().insert(Secure);
+        assert_eq!(
+            Some(String::from("https://internal:8080/login")),
+            ClientInfo::direct(&req).absolute_url("/login")
+        );
+    }
+
+    #[test]
+    fn test_x_forwarded() {
+        let req = request(
+            tcp("10.0.0.1:4711"),
+            &[
+                ("x-forwarded-for", "1.2.3.4, 203.0.113.9"),
+                ("x-forwarded-for", "10.0.0.2"),
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "example.com"),
+            ],
+        );
+        let client = proxies().resolve(&req);
+        // 1.2.3.4 is whatever 203.0.113.9 claimed, and it isn't a proxy
+        assert_eq!(ip("203.0.113.9"), client.ip());
+        assert_eq!("https", client.scheme());
+        assert_eq!(
+            Some(String::from("https://example.com/whoami")),
+            client.absolute_url("/whoami")
+        );
+
+        // the proxy only told the scheme
+        let req = request(tcp("10.0.0.1:4711"), &[("x-forwarded-proto", "https")]);
+        let client = proxies().resolve(&req);
+        assert_eq!(ip("10.0.0.1"), client.ip());
+        assert_eq!("https", client.scheme());
+        assert_eq!(Some("internal:8080"), client.host());
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let req = request(
+            tcp("10.0.0.1:4711"),
+            &[
+                (
+                    "forwarded",
+                    "for=192.0.2.60;proto=https;host=\"example.com:8443\", for=\"[2001:db8::17]:80\"",
+                ),
+                ("x-forwarded-for", "198.51.100.1"),
+            ],
+        );
+        let client = proxies().forwarded().resolve(&req);
+        assert_eq!(ip("192.0.2.60"), client.ip());
+        assert_eq!("https", client.scheme());
+        assert_eq!(Some("example.com:8443"), client.host());
+
+        // a hop hiding the client stops the walk at the proxy that knew it
+        let req = request(
+            tcp("10.0.0.1:4711"),
+            &[("forwarded", "for=unknown;proto=https, for=10.0.0.3")],
+        );
+        let client = proxies().forwarded().resolve(&req);
+        assert_eq!(ip("10.0.0.3"), client.ip());
+        assert_eq!("https", client.scheme());
+
+        let unix = PeerAddr::Unix {
+            path: Some(PathBuf::from("/run/proxy.sock")),
+            uid: None,
+            gid: None,
+        };
+        let req = request(unix.clone(), &[("forwarded", "for=192.0.2.60")]);
+        assert_eq!(None, proxies().forwarded().resolve(&req).ip());
+        assert_eq!(
+            ip("192.0.2.60"),
+            TrustedProxies::local().forwarded().resolve(&req).ip()
+        );
+    }
+
+    #[test]
+    fn test_spoofed_headers() {
+        // a proxy appending to X-Forwarded-For passes on the Forwarded header the client sent
+        let req = request(
+            tcp("10.0.0.1:4711"),
+            &[
+                ("forwarded", "for=6.6.6.6;proto=https;host=evil.example"),
+                ("x-forwarded-for", "203.0.113.9"),
+            ],
+        );
+        let client = TrustedProxies::local().resolve(&req);
+        assert_eq!(ip("203.0.113.9"), client.ip());
+        assert_eq!("http", client.scheme());
+        assert_eq!(Some("internal:8080"), client.host());
+
+        // and one setting Forwarded passes on the X-Forwarded-For
+        let req = request(
+            tcp("10.0.0.1:4711"),
+            &[
+                ("forwarded", "for=203.0.113.9"),
+                ("x-forwarded-for", "6.6.6.6"),
+                ("x-forwarded-proto", "https"),
+            ],
+        );
+        let client = TrustedProxies::local().forwarded().resolve(&req);
+        assert_eq!(ip("203.0.113.9"), client.ip());
+        assert_eq!("http", client.scheme());
+    }
+
+    #[test]
+    fn test_service() {
+        let router = FnRouteBuilder::new()
+            .get("/whoami", |req| async move {
+                let client = req
+                    .extensions()
+                    .get::<ClientInfo>()
+                    .cloned()
+                    .expect("Should have client info");
+                Ok(Response::new(full(
+                    client
+                        .absolute_url(&format!("/from/{}", client.ip().expect("Should have IP")))
+                        .expect("Should have host"),
+                )))
+            })
+            .expect("Should have been able to add route")
+            .build();
+        let service = TrustedProxies::local().wrap(router);
+        let req = request(
+            tcp("127.0.0.1:4711"),
+            &[
+                ("x-forwarded-for", "192.0.2.60"),
+                ("x-forwarded-proto", "https"),
+            ],
+        );
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        assert_eq!("https://internal:8080/from/192.0.2.60", body);
+    }
+}
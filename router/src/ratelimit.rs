@@ -0,0 +1,538 @@
+//! Rate limiting with the generic cell rate algorithm, a token bucket that only keeps the time the
+//! bucket will next be full.
+//!
+//! `RateLimiter` keys each request, by default by client IP, and checks it against a `Quota` in a
+//! `RateLimitStore`. Requests over the limit get 429 with `Retry-After`, and every response carries
+//! the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers.
+//!
+//! Limiters can wrap a whole router, or be attached to single routes with the `rate_limited`
+//! method of the router builders, which is where keys from path parameters are available.
+use bytes::Bytes;
+use http::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use http::{HeaderMap, Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::{boxed, empty};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::params::PathParams;
+use crate::proxy::client_ip;
+use crate::timeout::{Clock, TokioClock};
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+/// How many requests a key may make in a period, and how many of those may come at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    limit: u32,
+    period: Duration,
+    burst: u32,
+}
+
+impl Quota {
+    /// `limit` requests every `period`, all of which may be made at once.
+    pub fn new(limit: u32, period: Duration) -> Quota {
+        let limit = limit.max(1);
+        Quota {
+            limit,
+            period,
+            burst: limit,
+        }
+    }
+
+    pub fn per_second(limit: u32) -> Quota {
+        Quota::new(limit, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(limit: u32) -> Quota {
+        Quota::new(limit, Duration::from_secs(60))
+    }
+
+    /// Only allow this many requests at once, with the rest spread over the period.
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    // The time it takes for one request to be allowed again.
+    fn interval(&self) -> Duration {
+        self.period / self.limit
+    }
+
+    /// Apply the algorithm to a key whose bucket is next full at `tat`, or already is with `None`,
+    /// returning the decision and the new `tat` to store.
+    ///
+    /// Times are measured from any fixed point, so stores can use whatever clock they share.
+    pub fn check(&self, tat: Option<Duration>, now: Duration) -> (Decision, Duration) {
+        let interval = self.interval();
+        let tolerance = interval * self.burst;
+        let tat = tat.map_or(now, |tat| tat.max(now));
+        let next = tat + interval;
+        if next > now + tolerance {
+            let decision = Decision {
+                allowed: false,
+                limit: self.limit,
+                remaining: 0,
+                reset: tat - now,
+                retry_after: Some(next - (now + tolerance)),
+            };
+            return (decision, tat);
+        }
+        let remaining = (now + tolerance - next).as_nanos() / interval.as_nanos().max(1);
+        let decision = Decision {
+            allowed: true,
+            limit: self.limit,
+            remaining: remaining as u32,
+            reset: next - now,
+            retry_after: None,
+        };
+        (decision, next)
+    }
+}
+
+/// The outcome of checking a request against its `Quota`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the key's bucket is full again.
+    pub reset: Duration,
+    /// Until a rejected request would be allowed.
+    pub retry_after: Option<Duration>,
+}
+
+/// A future deciding whether a request is allowed, from `RateLimitStore::check`.
+pub type DecisionFuture = Pin<Box<dyn Future<Output = Result<Decision, BoxError>> + Send>>;
+
+/// Where the state of each key is kept, such as the `MemoryStore` or a store shared between
+/// instances.
+///
+/// Stores shared between instances should apply `Quota::check` atomically, for example in a
+/// script run by the store.
+pub trait RateLimitStore: Send + Sync {
+    fn check(&self, key: &str, quota: &Quota) -> DecisionFuture;
+}
+
+/// A `RateLimitStore` in this process, split into shards locked separately.
+///
+/// Keys whose buckets have filled up again are pruned as the shards grow.
+pub struct MemoryStore {
+    shards: Vec<Mutex<Shard>>,
+    clock: Arc<dyn Clock>,
+    epoch: Instant,
+}
+
+#[derive(Default)]
+struct Shard {
+    tats: HashMap<String, Duration>,
+    prune_at: usize,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::with_clock(TokioClock)
+    }
+
+    /// A store taking the time from the clock, such as a `MockClock` in tests.
+    pub fn with_clock<C: Clock + 'static>(clock: C) -> MemoryStore {
+        let epoch = clock.now();
+        MemoryStore {
+            shards: (0..16).map(|_| Mutex::new(Shard::default())).collect(),
+            clock: Arc::new(clock),
+            epoch,
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    fn decide(&self, key: &str, quota: &Quota) -> Decision {
+        let now = self.clock.now().saturating_duration_since(self.epoch);
+        let mut shard = self
+            .shard(key)
+            .lock()
+            .expect("Should not have poisoned the rate limits");
+        let (decision, tat) = quota.check(shard.tats.get(key).copied(), now);
+        if decision.allowed {
+            shard.tats.insert(key.to_owned(), tat);
+        }
+        if shard.tats.len() > shard.prune_at {
+            shard.tats.retain(|_, tat| *tat > now);
+            shard.prune_at = (shard.tats.len() * 2).max(1024);
+        }
+        decision
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn check(&self, key: &str, quota: &Quota) -> DecisionFuture {
+        Box::pin(future::ready(Ok(self.decide(key, quota))))
+    }
+}
+
+type KeyFn = dyn Fn(&Request<LuminalBody>) -> Option<String> + Send + Sync;
+
+#[derive(Clone)]
+struct Config {
+    quota: Quota,
+    key: Arc<KeyFn>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+/// A rate limit, built fluently and shared by every service it wraps.
+///
+/// Requests are keyed by the client's IP unless another key is chosen, from the `ClientInfo` in
+/// their extensions when they come through `TrustedProxies`, or the connection's `PeerAddr`.
+/// Requests without a key, such as those missing the header they are keyed by, share a single
+/// bucket, so leaving the key out doesn't get around the limit.
+///
+/// Clones share the store, so its buckets, until one of them is given a store of its own.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<Config>,
+}
+
+impl RateLimiter {
+    pub fn new(quota: Quota) -> RateLimiter {
+        RateLimiter {
+            config: Arc::new(Config {
+                quota,
+                key: Arc::new(|req| client_ip(req.extensions()).map(|ip| ip.to_string())),
+                store: Arc::new(MemoryStore::new()),
+            }),
+        }
+    }
+
+    // A limiter already wrapped around a service, or cloned, gets a config of its own, leaving the
+    // services it wraps as they were.
+    fn configure<F: FnOnce(&mut Config)>(mut self, configure: F) -> Self {
+        configure(Arc::make_mut(&mut self.config));
+        self
+    }
+
+    /// Key requests by the value of a header, such as an API key.
+    pub fn key_header(self, header: HeaderName) -> Self {
+        self.key_fn(move |req| {
+            req.headers()
+                .get(&header)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        })
+    }
+
+    /// Key requests by a parameter of the route they matched, for limiters attached to routes.
+    pub fn key_param(self, param: &str) -> Self {
+        let param = param.to_owned();
+        self.key_fn(move |req| {
+            req.extensions()
+                .get::<PathParams>()
+                .and_then(|params| params.get(&param).map(str::to_owned))
+        })
+    }
+
+    pub fn key_fn<F>(self, key: F) -> Self
+    where
+        F: Fn(&Request<LuminalBody>) -> Option<String> + Send + Sync + 'static,
+    {
+        self.configure(|config| config.key = Arc::new(key))
+    }
+
+    pub fn store<S: RateLimitStore + 'static>(self, store: S) -> Self {
+        self.configure(|config| config.store = Arc::new(store))
+    }
+
+    pub fn wrap<S>(&self, inner: S) -> RateLimitService<S> {
+        RateLimitService {
+            config: Arc::clone(&self.config),
+            inner,
+        }
+    }
+}
+
+fn add_headers(headers: &mut HeaderMap, decision: &Decision) {
+    let seconds = |duration: Duration| {
+        // whole seconds, rounded up so clients don't come back early
+        let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+        HeaderValue::from(seconds)
+    };
+    headers
+        .entry(RATELIMIT_LIMIT)
+        .or_insert_with(|| HeaderValue::from(decision.limit));
+    headers
+        .entry(RATELIMIT_REMAINING)
+        .or_insert_with(|| HeaderValue::from(decision.remaining));
+    headers
+        .entry(RATELIMIT_RESET)
+        .or_insert_with(|| seconds(decision.reset));
+    if let Some(retry_after) = decision.retry_after {
+        headers.insert(RETRY_AFTER, seconds(retry_after));
+    }
+}
+
+/// A service only passing requests within their rate limit on, see `RateLimiter`.
+pub struct RateLimitService<S> {
+    config: Arc<Config>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for RateLimitService<S> {
+    fn clone(&self) -> Self {
+        RateLimitService {
+            config: Arc::clone(&self.config),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for RateLimitService<S>
+where
+    S: Service<
+            Request<LuminalBody>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Clone
+        + Send
+        + 'static,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let req = req.map(boxed);
+        let key = (self.config.key)(&req).unwrap_or_default();
+        let decision = self.config.store.check(&key, &self.config.quota);
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let decision = decision.await?;
+            let mut response = if decision.allowed {
+                inner.call(req).await?
+            } else {
+                let mut response = Response::new(empty());
+                *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                response
+            };
+            add_headers(response.headers_mut(), &decision);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http::header::AUTHORIZATION;
+    use http::Method;
+
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::timeout::MockClock;
+    use crate::{FnRouteBuilder, LuminalErrorKind, PeerAddr, TrustedProxies};
+
+    fn router(limiter: &RateLimiter) -> crate::Router {
+        FnRouteBuilder::new()
+            .get("/token", |_req| async { Ok(Response::new(empty())) })
+            .expect("Should have been able to add route")
+            .get("/user/:user_id", |_req| async {
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .get("/open", |_req| async { Ok(Response::new(empty())) })
+            .expect("Should have been able to add route")
+            .rate_limited("/token", limiter)
+            .expect("Should have been able to limit route")
+            .rate_limited("/user/:user_id", limiter)
+            .expect("Should have been able to limit route")
+            .build()
+    }
+
+    fn request(method: Method, uri: &str, ip: [u8; 4]) -> Request<LuminalBody> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(empty())
+            .expect("Should have been able to build request");
+        req.extensions_mut()
+            .insert(PeerAddr::Tcp(SocketAddr::from((ip, 40000))));
+        req
+    }
+
+    #[test]
+    fn test_gcra() {
+        let quota = Quota::new(2, Duration::from_secs(1));
+        let now = Duration::from_secs(10);
+        let (first, tat) = quota.check(None, now);
+        assert!(first.allowed);
+        assert_eq!(1, first.remaining);
+        let (second, tat) = quota.check(Some(tat), now);
+        assert!(second.allowed);
+        assert_eq!(0, second.remaining);
+        assert_eq!(Duration::from_secs(1), second.reset);
+        let (third, tat) = quota.check(Some(tat), now);
+        assert!(!third.allowed);
+        assert_eq!(Some(Duration::from_millis(500)), third.retry_after);
+
+        // one request's worth of the bucket refills every half second
+        let (fourth, _) = quota.check(Some(tat), now + Duration::from_millis(500));
+        assert!(fourth.allowed);
+        assert_eq!(0, fourth.remaining);
+
+        let smooth = Quota::per_second(10).burst(1);
+        let (first, tat) = smooth.check(None, now);
+        assert!(first.allowed);
+        let (second, _) = smooth.check(Some(tat), now + Duration::from_millis(50));
+        assert_eq!(Some(Duration::from_millis(50)), second.retry_after);
+    }
+
+    #[test]
+    fn test_limit_by_ip() {
+        let clock = MockClock::new();
+        let limiter =
+            RateLimiter::new(Quota::per_minute(2)).store(MemoryStore::with_clock(clock.clone()));
+        let router = router(&limiter);
+
+        for remaining in ["1", "0"] {
+            let response = block_on(router.call(request(Method::GET, "/token", [10, 0, 0, 1])))
+                .expect("Should have been able to run call");
+            assert_eq!(StatusCode::OK, response.status());
+            assert_eq!("2", response.headers()[RATELIMIT_LIMIT]);
+            assert_eq!(remaining, response.headers()[RATELIMIT_REMAINING]);
+        }
+        let response = block_on(router.call(request(Method::GET, "/token", [10, 0, 0, 1])))
+            .expect("Should have been able to run call");
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("30", response.headers()[RETRY_AFTER]);
+        assert_eq!("60", response.headers()[RATELIMIT_RESET]);
+
+        // other clients and unlimited routes are unaffected
+        let response = block_on(router.call(request(Method::GET, "/token", [10, 0, 0, 2])))
+            .expect("Should have been able to run call");
+        assert_eq!(StatusCode::OK, response.status());
+        let response = block_on(router.call(request(Method::GET, "/open", [10, 0, 0, 1])))
+            .expect("Should have been able to run call");
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(!response.headers().contains_key(RATELIMIT_LIMIT));
+
+        clock.advance(Duration::from_secs(30));
+        let response = block_on(router.call(request(Method::GET, "/token", [10, 0, 0, 1])))
+            .expect("Should have been able to run call");
+        assert_eq!(StatusCode::OK, response.status());
+
+        // behind a trusted proxy, clients are told apart by the address it forwards
+        let proxied = TrustedProxies::local().wrap(router);
+        let forwarded = |client: &'static str| {
+            let mut req = request(Method::GET, "/token", [10, 0, 0, 1]);
+            req.headers_mut()
+                .insert("x-forwarded-for", HeaderValue::from_static(client));
+            block_on(proxied.call(req)).expect("Should have been able to run call")
+        };
+        assert_eq!(StatusCode::OK, forwarded("192.0.2.60").status());
+        assert_eq!(StatusCode::OK, forwarded("192.0.2.60").status());
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            forwarded("192.0.2.60").status()
+        );
+        assert_eq!(StatusCode::OK, forwarded("192.0.2.61").status());
+    }
+
+    #[test]
+    fn test_limit_by_key() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(Quota::per_minute(1))
+            .key_param("user_id")
+            .store(MemoryStore::with_clock(clock.clone()));
+        let router = router(&limiter);
+
+        let status = |uri: &str, ip| {
+            block_on(router.call(request(Method::GET, uri, ip)))
+                .expect("Should have been able to run call")
+                .status()
+        };
+        assert_eq!(StatusCode::OK, status("/user/1", [10, 0, 0, 1]));
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            status("/user/1", [10, 0, 0, 2])
+        );
+        assert_eq!(StatusCode::OK, status("/user/2", [10, 0, 0, 1]));
+
+        let limiter = RateLimiter::new(Quota::per_minute(1))
+            .key_header(AUTHORIZATION)
+            .store(MemoryStore::with_clock(clock));
+        let service = limiter.wrap(router);
+        let call = |key: Option<&str>| {
+            let mut req = request(Method::GET, "/open", [10, 0, 0, 1]);
+            if let Some(key) = key {
+                req.headers_mut().insert(
+                    AUTHORIZATION,
+                    HeaderValue::from_str(key).expect("Should have been a header"),
+                );
+            }
+            block_on(service.call(req))
+                .expect("Should have been able to run call")
+                .status()
+        };
+        assert_eq!(StatusCode::OK, call(Some("a")));
+        assert_eq!(StatusCode::OK, call(Some("b")));
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, call(Some("a")));
+        // requests without a key share a bucket
+        assert_eq!(StatusCode::OK, call(None));
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, call(None));
+    }
+
+    #[test]
+    fn test_configure_clone() {
+        let limiter = RateLimiter::new(Quota::per_minute(1));
+        let open = limiter.wrap(router(&limiter));
+        // configuring a limiter already in use leaves the services it wraps alone
+        let keyed = limiter.clone().key_header(AUTHORIZATION);
+        let service = keyed.wrap(router(&keyed));
+        let status = |service: &RateLimitService<crate::Router>, key: &'static str| {
+            let mut req = request(Method::GET, "/open", [10, 0, 0, 1]);
+            req.headers_mut()
+                .insert(AUTHORIZATION, HeaderValue::from_static(key));
+            block_on(service.call(req))
+                .expect("Should have been able to run call")
+                .status()
+        };
+        assert_eq!(StatusCode::OK, status(&service, "a"));
+        assert_eq!(StatusCode::OK, status(&service, "b"));
+        assert_eq!(StatusCode::OK, status(&open, "c"));
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, status(&open, "d"));
+    }
+
+    #[test]
+    fn test_unknown_route() {
+        let limiter = RateLimiter::new(Quota::per_second(1));
+        match FnRouteBuilder::new().rate_limited("/missing", &limiter) {
+            Err(crate::LuminalError(LuminalErrorKind::UnknownRoute(_), _)) => (),
+            _ => panic!("Should have refused to limit a missing route"),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! A `Router` whose route table can be replaced while it is serving requests.
+//!
+//! Every request is dispatched against a snapshot of the table that was current when the request
+//! arrived. Swapping in a new table never waits on or disturbs requests that are in flight, they
+//! finish against the table they started with, which is dropped once the last of them is done.
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http::{Request, Response};
+use hyper::body::Body;
+use hyper::service::Service;
+
+use std::sync::Arc;
+
+use crate::error::*;
+use crate::service::Router;
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// Wraps a `Router` so that its routes can be changed at runtime.
+pub struct ReloadableRouter {
+    current: ArcSwap<Router>,
+}
+
+impl<B> Service<Request<B>> for ReloadableRouter
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        self.current.load().call(req)
+    }
+}
+
+impl ReloadableRouter {
+    pub fn new(router: Router) -> Self {
+        ReloadableRouter {
+            current: ArcSwap::from_pointee(router),
+        }
+    }
+
+    /// A snapshot of the route table currently being served.
+    pub fn load(&self) -> Arc<Router> {
+        self.current.load_full()
+    }
+
+    /// Atomically replace the route table, returning the previous one.
+    pub fn swap(&self, router: Router) -> Arc<Router> {
+        self.current.swap(Arc::new(router))
+    }
+
+    /// Derive a new route table from the current one and swap it in.
+    ///
+    /// The closure works on a copy of the current table, so nothing is served from a partially
+    /// updated table and an error leaves the current table untouched. If another update lands
+    /// first, the closure is run again against the newer table.
+    pub fn update<F>(&self, mut update: F) -> Result<()>
+    where
+        F: FnMut(&mut Router) -> Result<()>,
+    {
+        let mut error = None;
+        self.current.rcu(|current| {
+            let mut next = Router::clone(current);
+            match update(&mut next) {
+                Ok(()) => {
+                    error = None;
+                    Arc::new(next)
+                }
+                Err(failed) => {
+                    error = Some(failed);
+                    Arc::clone(current)
+                }
+            }
+        });
+        match error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<Router> for ReloadableRouter {
+    fn from(router: Router) -> Self {
+        ReloadableRouter::new(router)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http::{Method, StatusCode};
+    use http_body_util::{BodyExt, Empty};
+    use luminal_handler::{full, handler_fn};
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use super::*;
+    use crate::FnRouteBuilder;
+
+    fn router(msg: &'static str) -> Router {
+        FnRouteBuilder::new()
+            .get(
+                "/foo",
+                move |_req| async move { Ok(Response::new(full(msg))) },
+            )
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    fn bar_handler(
+        _req: Request<LuminalBody>,
+    ) -> ::std::result::Result<LuminalFuture, Response<LuminalBody>> {
+        Ok(Box::pin(async { Ok(Response::new(full("Bar"))) }))
+    }
+
+    fn call(router: &ReloadableRouter, uri: &str) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(uri)
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+
+        let response =
+            block_on(router.call(req)).expect("Should have been able to run router call");
+        let status = response.status();
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        (
+            status,
+            String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body"),
+        )
+    }
+
+    #[test]
+    fn test_swap() {
+        let reloadable = ReloadableRouter::new(router("Old"));
+        assert_eq!(
+            (StatusCode::OK, String::from("Old")),
+            call(&reloadable, "/foo")
+        );
+
+        let previous = reloadable.swap(router("New"));
+        assert!(previous.dispatch(&Method::GET, "/foo").is_some());
+        assert_eq!(
+            (StatusCode::OK, String::from("New")),
+            call(&reloadable, "/foo")
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        let reloadable = ReloadableRouter::from(router("Foo"));
+        reloadable
+            .update(|router| {
+                router.add_handler(Method::GET, "/bar", handler_fn(bar_handler))?;
+                router.remove(&Method::GET, "/foo");
+                Ok(())
+            })
+            .expect("Should have been able to update routes");
+
+        assert_eq!(StatusCode::NOT_FOUND, call(&reloadable, "/foo").0);
+        assert_eq!(
+            (StatusCode::OK, String::from("Bar")),
+            call(&reloadable, "/bar")
+        );
+    }
+
+    #[test]
+    fn test_update_error() {
+        let reloadable = ReloadableRouter::new(router("Foo"));
+        let snapshot = reloadable.load();
+
+        let result = reloadable.update(|router| {
+            router.remove(&Method::GET, "/foo");
+            router.add_handler(Method::GET, "bar", handler_fn(bar_handler))
+        });
+
+        assert!(
+            result.is_err(),
+            "Should have failed to add an invalid route"
+        );
+        assert!(Arc::ptr_eq(&snapshot, &reloadable.load()));
+        assert_eq!(
+            (StatusCode::OK, String::from("Foo")),
+            call(&reloadable, "/foo")
+        );
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let reloadable = ReloadableRouter::new(router("Old"));
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/foo")
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+
+        // dispatched but not yet polled when the table changes underneath it
+        let in_flight = reloadable.call(req);
+        reloadable.swap(Router::new());
+
+        let response = block_on(in_flight).expect("Should have been able to run router call");
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        assert_eq!(&b"Old"[..], &body[..]);
+        assert_eq!(StatusCode::NOT_FOUND, call(&reloadable, "/foo").0);
+    }
+
+    #[test]
+    fn test_concurrent_dispatch() {
+        let reloadable = Arc::new(ReloadableRouter::new(router("A")));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let workers = (0..4)
+            .map(|_| {
+                let reloadable = Arc::clone(&reloadable);
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    let mut calls = 0;
+                    while !done.load(Ordering::Acquire) || calls == 0 {
+                        let (status, body) = call(&reloadable, "/foo");
+                        assert_eq!(StatusCode::OK, status);
+                        assert!(body == "A" || body == "B", "Unexpected body {}", body);
+                        calls += 1;
+                    }
+                    calls
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for swap in 0..200 {
+            let msg = if swap % 2 == 0 { "B" } else { "A" };
+            reloadable.swap(router(msg));
+        }
+        done.store(true, Ordering::Release);
+
+        for worker in workers {
+            let calls = worker.join().expect("Worker should not have panicked");
+            assert!(calls > 0);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Request IDs, read from the incoming request or generated, to correlate requests across
+//! services.
+//!
+//! `RequestIdService` puts a `RequestId` in the extensions of every request, so handlers can pass
+//! it on to the services they call, sets it in the request's header for anything further in, and
+//! echoes it on the response, where it is also added to the extensions for logging.
+use bytes::Bytes;
+use http::header::{HeaderName, HeaderValue};
+use http::{Request, Response};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::boxed;
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// The ID of a request, from the extensions of a request or its response.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A request ID policy, built fluently.
+///
+/// By default the ID is read from `X-Request-Id`, and a random UUID is generated when there is
+/// none, or when the incoming one is longer than 128 characters or has characters other than
+/// ASCII letters, digits and `-_.:@+=/`.
+pub struct RequestIds {
+    header: HeaderName,
+    generator: Box<dyn Fn() -> String + Send + Sync>,
+    max_len: usize,
+}
+
+impl Default for RequestIds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestIds {
+    pub fn new() -> RequestIds {
+        RequestIds {
+            header: HeaderName::from_static("x-request-id"),
+            generator: Box::new(|| uuid::Uuid::new_v4().to_string()),
+            max_len: 128,
+        }
+    }
+
+    /// Read and echo the ID in this header instead of `X-Request-Id`.
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Generate new IDs with this function. An ID it generates that fails validation is used
+    /// anyway, as long as it is a valid header value.
+    pub fn generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.generator = Box::new(generator);
+        self
+    }
+
+    /// Replace incoming IDs longer than this.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Put this policy in front of a service.
+    pub fn wrap<S>(self, inner: S) -> RequestIdService<S> {
+        RequestIdService {
+            ids: Arc::new(self),
+            inner,
+        }
+    }
+
+    fn valid(&self, id: &str) -> bool {
+        !id.is_empty()
+            && id.len() <= self.max_len
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.:@+=/".contains(&b))
+    }
+
+    // The incoming ID when it is acceptable, otherwise a new one.
+    fn id<B>(&self, req: &Request<B>) -> (RequestId, HeaderValue) {
+        let incoming = req
+            .headers()
+            .get(&self.header)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| self.valid(value));
+        if let Some(incoming) = incoming {
+            if let Ok(value) = HeaderValue::from_str(incoming) {
+                return (RequestId(incoming.to_owned()), value);
+            }
+        }
+        let generated = (self.generator)();
+        match HeaderValue::from_str(&generated) {
+            Ok(value) => (RequestId(generated), value),
+            Err(_) => {
+                // a generator producing unusable IDs is a bug, but not one to fail requests over
+                let fallback = uuid::Uuid::new_v4().to_string();
+                let value = HeaderValue::from_str(&fallback)
+                    .expect("Should always have a valid header value for a UUID");
+                (RequestId(fallback), value)
+            }
+        }
+    }
+}
+
+/// A service behind a `RequestIds` policy, see `RequestIds::wrap`.
+pub struct RequestIdService<S> {
+    ids: Arc<RequestIds>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for RequestIdService<S> {
+    fn clone(&self) -> Self {
+        RequestIdService {
+            ids: Arc::clone(&self.ids),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for RequestIdService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let (id, value) = self.ids.id(&req);
+        req.headers_mut()
+            .insert(self.ids.header.clone(), value.clone());
+        req.extensions_mut().insert(id.clone());
+
+        let header = self.ids.header.clone();
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            response.headers_mut().entry(header).or_insert(value);
+            response.extensions_mut().insert(id);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use luminal_handler::{empty, full};
+
+    use super::*;
+    use crate::{FnRouteBuilder, Router};
+
+    // Echoes the ID from the request extensions and the header, to show what the handler saw.
+    fn service(ids: RequestIds) -> RequestIdService<Router> {
+        let router = FnRouteBuilder::new()
+            .get("/", |req: Request<LuminalBody>| async move {
+                let id = req
+                    .extensions()
+                    .get::<RequestId>()
+                    .expect("Should have had a request ID");
+                let header = req
+                    .headers()
+                    .values()
+                    .next()
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("");
+                Ok(Response::new(full(format!("{} {}", id, header))))
+            })
+            .expect("Should have been able to add route")
+            .build();
+        ids.wrap(router)
+    }
+
+    fn call(
+        service: &RequestIdService<Router>,
+        header: Option<(&str, &str)>,
+    ) -> (Response<()>, String) {
+        let mut builder = Request::builder().uri("/");
+        if let Some((name, value)) = header {
+            builder = builder.header(name, value);
+        }
+        let req = builder
+            .body(empty())
+            .expect("Should have been able to build request");
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        let (parts, body) = response.into_parts();
+        let body = block_on(http_body_util::BodyExt::collect(body))
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        (
+            Response::from_parts(parts, ()),
+            String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body"),
+        )
+    }
+
+    fn echoed<'a>(response: &'a Response<()>, name: &str) -> &'a str {
+        response
+            .headers()
+            .get(name)
+            .expect("Should have echoed the request ID")
+            .to_str()
+            .expect("Should have been a string header")
+    }
+
+    #[test]
+    fn test_incoming() {
+        let service = service(RequestIds::new());
+        let (response, body) = call(&service, Some(("x-request-id", "abc-123")));
+
+        assert_eq!("abc-123 abc-123", body);
+        assert_eq!("abc-123", echoed(&response, "x-request-id"));
+        assert_eq!(
+            Some("abc-123"),
+            response
+                .extensions()
+                .get::<RequestId>()
+                .map(RequestId::as_str)
+        );
+    }
+
+    #[test]
+    fn test_generated() {
+        let service = service(RequestIds::new());
+        let (response, body) = call(&service, None);
+
+        let id = echoed(&response, "x-request-id");
+        assert!(uuid::Uuid::parse_str(id).is_ok(), "{}", id);
+        assert_eq!(format!("{} {}", id, id), body);
+
+        let (other, _) = call(&service, None);
+        assert_ne!(id, echoed(&other, "x-request-id"));
+    }
+
+    #[test]
+    fn test_invalid_replaced() {
+        let service = service(
+            RequestIds::new()
+                .max_len(8)
+                .generator(|| String::from("fresh")),
+        );
+        for invalid in &["", "way-too-long-id", "semi;colon", "space d"] {
+            let (response, _) = call(&service, Some(("x-request-id", invalid)));
+            assert_eq!("fresh", echoed(&response, "x-request-id"), "{:?}", invalid);
+        }
+        let (response, _) = call(&service, Some(("x-request-id", "a1:b2/c3")));
+        assert_eq!("a1:b2/c3", echoed(&response, "x-request-id"));
+    }
+
+    #[test]
+    fn test_header() {
+        let service =
+            service(RequestIds::new().header(HeaderName::from_static("x-correlation-id")));
+        let (response, body) = call(&service, Some(("x-correlation-id", "corr-1")));
+        assert_eq!("corr-1 corr-1", body);
+        assert_eq!("corr-1", echoed(&response, "x-correlation-id"));
+        assert!(response.headers().get("x-request-id").is_none());
+    }
+}
@@ -1,13 +1,174 @@
+use http::{Method, Request, StatusCode};
+
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use crate::guard::Guard;
+use crate::{LuminalBody, LuminalFuture};
+
+/// A route target along with the route it was added at, and the guard requests must pass for it.
+#[derive(Clone)]
 pub struct Route<T> {
     pub route_path: String,
+    pub guard: Option<Arc<dyn Guard>>,
     pub target: T,
+    /// Whether requests to this target skip the checks of a `CsrfService`, see `csrf_exempt` on
+    /// the router builders.
+    pub csrf_exempt: bool,
 }
 
 impl<T> Route<T> {
     pub fn new(route_path: &str, target: T) -> Self {
         Route {
             route_path: route_path.to_owned(),
+            guard: None,
             target,
+            csrf_exempt: false,
+        }
+    }
+
+    pub fn guarded(route_path: &str, guard: Arc<dyn Guard>, target: T) -> Self {
+        Route {
+            route_path: route_path.to_owned(),
+            guard: Some(guard),
+            target,
+            csrf_exempt: false,
+        }
+    }
+
+    /// Whether the request passes this route's guard, if it has one.
+    pub fn check(&self, req: &Request<LuminalBody>) -> Result<(), StatusCode> {
+        match self.guard {
+            Some(ref guard) => guard.check(req),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Route<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Route")
+            .field("route_path", &self.route_path)
+            .field("guarded", &self.guard.is_some())
+            .field("target", &self.target)
+            .field("csrf_exempt", &self.csrf_exempt)
+            .finish()
+    }
+}
+
+/// What the `Router` matched for a request.
+///
+/// `Router` puts it in the request extensions before calling the target and in the response
+/// extensions afterwards, so middleware on either side of the router can tell which route served
+/// a request. Requests no route matched get one too, without a route.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MatchedRoute {
+    route: Option<String>,
+    allowed: Vec<Method>,
+    csrf_exempt: bool,
+}
+
+impl MatchedRoute {
+    pub(crate) fn new(route: Option<String>, allowed: Vec<Method>, csrf_exempt: bool) -> Self {
+        MatchedRoute {
+            route,
+            allowed,
+            csrf_exempt,
         }
     }
+
+    /// The route template the request matched, such as `/user/:user_id`.
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    /// The methods with a route for the requested path, in alphabetical order.
+    ///
+    /// These are only looked up for `OPTIONS` requests and requests no route matched, for every
+    /// other request this is empty.
+    pub fn allowed_methods(&self) -> &[Method] {
+        &self.allowed
+    }
+
+    /// Whether the route is exempt from CSRF checks.
+    pub fn is_csrf_exempt(&self) -> bool {
+        self.csrf_exempt
+    }
+}
+
+/// The rest of the chain a `RouteLayers` layer hands the request on to.
+pub(crate) type Next = Box<dyn FnOnce(Request<LuminalBody>) -> LuminalFuture + Send>;
+
+type Layer = Arc<dyn Fn(&MatchedRoute, Request<LuminalBody>, Next) -> LuminalFuture + Send + Sync>;
+
+// Work that middleware wrapped around a router defers until the router has matched the request,
+// such as a per-route timeout or a CSRF exemption, run before the target is called.
+//
+// The layers travel in the request extensions and the router inside takes them out, so a nested
+// router doesn't run them again. Each middleware gets back a cell the router fills in with what
+// it matched. Middleware between it and the router may only call on from within their futures,
+// so the cell is only looked at once the inner service's response has resolved: still empty then,
+// no router ran the layers.
+#[derive(Clone, Default)]
+pub(crate) struct RouteLayers {
+    layers: Vec<Layer>,
+    matched: Arc<OnceLock<MatchedRoute>>,
+}
+
+impl RouteLayers {
+    // Defer a layer to the router, after any layers of the middleware outside this one.
+    pub(crate) fn push<F>(req: &mut Request<LuminalBody>, layer: F) -> Arc<OnceLock<MatchedRoute>>
+    where
+        F: Fn(&MatchedRoute, Request<LuminalBody>, Next) -> LuminalFuture + Send + Sync + 'static,
+    {
+        let layers = RouteLayers::get_or_insert(req);
+        layers.layers.push(Arc::new(layer));
+        Arc::clone(&layers.matched)
+    }
+
+    fn get_or_insert(req: &mut Request<LuminalBody>) -> &mut RouteLayers {
+        let extensions = req.extensions_mut();
+        if extensions.get::<RouteLayers>().is_none() {
+            extensions.insert(RouteLayers::default());
+        }
+        extensions
+            .get_mut::<RouteLayers>()
+            .expect("Should have had route layers")
+    }
+
+    // Run the layers around the call to the matched target.
+    pub(crate) fn run(
+        self,
+        matched: &MatchedRoute,
+        req: Request<LuminalBody>,
+        target: Next,
+    ) -> LuminalFuture {
+        let _ = self.matched.set(matched.clone());
+        RouteLayers::chain(
+            Arc::new(self.layers),
+            0,
+            Arc::new(matched.clone()),
+            req,
+            target,
+        )
+    }
+
+    fn chain(
+        layers: Arc<Vec<Layer>>,
+        index: usize,
+        matched: Arc<MatchedRoute>,
+        req: Request<LuminalBody>,
+        target: Next,
+    ) -> LuminalFuture {
+        let layer = match layers.get(index) {
+            Some(layer) => Arc::clone(layer),
+            None => return target(req),
+        };
+        let rest = Arc::clone(&matched);
+        layer(
+            &matched,
+            req,
+            Box::new(move |req| RouteLayers::chain(layers, index + 1, rest, req, target)),
+        )
+    }
 }
@@ -0,0 +1,387 @@
+//! Hardening headers: HSTS, Content-Security-Policy, X-Content-Type-Options, X-Frame-Options,
+//! Referrer-Policy and Permissions-Policy.
+//!
+//! `SecurityHeadersService` adds the headers of its policy to every response, leaving alone any a
+//! handler set itself. A policy containing `{nonce}` in its CSP gets a fresh nonce for each
+//! request, available to handlers as a `CspNonce` in the request extensions for their inline
+//! scripts and styles.
+//!
+//! A policy wrapped around single routes, with `wrap_route` on the router builders, replaces the
+//! one wrapped around the router for those routes entirely.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use http::header::{
+    HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use http::{Request, Response};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::boxed;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+const PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+
+/// The CSP nonce of a request, from the request extensions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Marks responses a policy has already been applied to, so outer policies leave them be.
+#[derive(Clone, Copy)]
+struct Secured;
+
+// A CSP, ready to send unless it has a nonce to fill in for each request.
+#[derive(Clone, Debug)]
+enum Csp {
+    Fixed(HeaderValue),
+    Nonce(String),
+}
+
+/// A policy for security headers, built fluently from a preset or from nothing.
+///
+/// Values are checked as they are set, so the methods taking one panic if it isn't a valid header
+/// value.
+#[derive(Clone, Debug, Default)]
+pub struct SecurityHeaders {
+    hsts: Option<HeaderValue>,
+    csp: Option<Csp>,
+    content_type_options: bool,
+    frame_options: Option<HeaderValue>,
+    referrer_policy: Option<HeaderValue>,
+    permissions_policy: Option<HeaderValue>,
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value)
+        .unwrap_or_else(|_| panic!("Should have been a valid header value: {:?}", value))
+}
+
+impl SecurityHeaders {
+    /// A policy setting no headers.
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders::default()
+    }
+
+    /// A policy for pages: HSTS for a year including subdomains, a CSP only allowing the site's
+    /// own resources and scripts with the request's nonce, `nosniff`, `DENY` framing,
+    /// `strict-origin-when-cross-origin` referrers, and no camera, microphone or geolocation.
+    pub fn recommended() -> SecurityHeaders {
+        SecurityHeaders::new()
+            .hsts(Duration::from_secs(365 * 24 * 60 * 60), true, false)
+            .content_security_policy(
+                "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+                 style-src 'self' 'nonce-{nonce}'; object-src 'none'; base-uri 'self'; \
+                 frame-ancestors 'none'",
+            )
+            .content_type_options(true)
+            .frame_options("DENY")
+            .referrer_policy("strict-origin-when-cross-origin")
+            .permissions_policy("camera=(), microphone=(), geolocation=()")
+    }
+
+    /// A policy for APIs that never serve pages: the recommended headers, with a CSP that allows
+    /// nothing at all and no referrers.
+    pub fn api() -> SecurityHeaders {
+        SecurityHeaders::recommended()
+            .content_security_policy("default-src 'none'; frame-ancestors 'none'")
+            .referrer_policy("no-referrer")
+    }
+
+    pub fn hsts(mut self, max_age: Duration, include_subdomains: bool, preload: bool) -> Self {
+        let mut hsts = format!("max-age={}", max_age.as_secs());
+        if include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        if preload {
+            hsts.push_str("; preload");
+        }
+        self.hsts = Some(header_value(&hsts));
+        self
+    }
+
+    pub fn no_hsts(mut self) -> Self {
+        self.hsts = None;
+        self
+    }
+
+    /// Set the CSP, with `{nonce}` standing for the nonce of each request.
+    pub fn content_security_policy(mut self, policy: &str) -> Self {
+        let value = header_value(policy);
+        self.csp = Some(if policy.contains("{nonce}") {
+            // nonces are base64, so filling them in keeps the value valid
+            Csp::Nonce(policy.to_owned())
+        } else {
+            Csp::Fixed(value)
+        });
+        self
+    }
+
+    pub fn no_content_security_policy(mut self) -> Self {
+        self.csp = None;
+        self
+    }
+
+    /// Whether to send `X-Content-Type-Options: nosniff`.
+    pub fn content_type_options(mut self, nosniff: bool) -> Self {
+        self.content_type_options = nosniff;
+        self
+    }
+
+    /// Set `X-Frame-Options`, `DENY` or `SAMEORIGIN`.
+    pub fn frame_options(mut self, frame_options: &str) -> Self {
+        self.frame_options = Some(header_value(frame_options));
+        self
+    }
+
+    pub fn no_frame_options(mut self) -> Self {
+        self.frame_options = None;
+        self
+    }
+
+    pub fn referrer_policy(mut self, policy: &str) -> Self {
+        self.referrer_policy = Some(header_value(policy));
+        self
+    }
+
+    pub fn no_referrer_policy(mut self) -> Self {
+        self.referrer_policy = None;
+        self
+    }
+
+    pub fn permissions_policy(mut self, policy: &str) -> Self {
+        self.permissions_policy = Some(header_value(policy));
+        self
+    }
+
+    pub fn no_permissions_policy(mut self) -> Self {
+        self.permissions_policy = None;
+        self
+    }
+
+    pub fn wrap<S>(self, inner: S) -> SecurityHeadersService<S> {
+        SecurityHeadersService {
+            policy: Arc::new(self),
+            inner,
+        }
+    }
+
+    fn headers(&self, nonce: Option<&CspNonce>) -> Vec<(HeaderName, HeaderValue)> {
+        let mut headers = Vec::new();
+        if let Some(hsts) = &self.hsts {
+            headers.push((STRICT_TRANSPORT_SECURITY, hsts.clone()));
+        }
+        match (&self.csp, nonce) {
+            (Some(Csp::Fixed(csp)), _) => headers.push((CONTENT_SECURITY_POLICY, csp.clone())),
+            (Some(Csp::Nonce(csp)), Some(nonce)) => headers.push((
+                CONTENT_SECURITY_POLICY,
+                header_value(&csp.replace("{nonce}", nonce.as_str())),
+            )),
+            _ => (),
+        }
+        if self.content_type_options {
+            headers.push((X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")));
+        }
+        if let Some(frame_options) = &self.frame_options {
+            headers.push((X_FRAME_OPTIONS, frame_options.clone()));
+        }
+        if let Some(referrer_policy) = &self.referrer_policy {
+            headers.push((REFERRER_POLICY, referrer_policy.clone()));
+        }
+        if let Some(permissions_policy) = &self.permissions_policy {
+            headers.push((PERMISSIONS_POLICY, permissions_policy.clone()));
+        }
+        headers
+    }
+}
+
+/// A service adding security headers to its responses, see `SecurityHeaders`.
+pub struct SecurityHeadersService<S> {
+    policy: Arc<SecurityHeaders>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for SecurityHeadersService<S> {
+    fn clone(&self) -> Self {
+        SecurityHeadersService {
+            policy: Arc::clone(&self.policy),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for SecurityHeadersService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let nonce = match &self.policy.csp {
+            Some(Csp::Nonce(_)) => {
+                let nonce = CspNonce(STANDARD.encode(uuid::Uuid::new_v4().as_bytes()));
+                req.extensions_mut().insert(nonce.clone());
+                Some(nonce)
+            }
+            _ => None,
+        };
+
+        let policy = Arc::clone(&self.policy);
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            if response.extensions().get::<Secured>().is_some() {
+                return Ok(response);
+            }
+            for (name, value) in policy.headers(nonce.as_ref()) {
+                if !response.headers().contains_key(&name) {
+                    response.headers_mut().insert(name, value);
+                }
+            }
+            response.extensions_mut().insert(Secured);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use luminal_handler::{empty, full};
+
+    use super::*;
+    use crate::{FnRouteBuilder, Router};
+
+    fn router() -> Router {
+        FnRouteBuilder::new()
+            .get("/page", |req| async move {
+                let nonce = req
+                    .extensions()
+                    .get::<CspNonce>()
+                    .map(CspNonce::to_string)
+                    .unwrap_or_default();
+                Ok(Response::new(full(nonce)))
+            })
+            .expect("Should have been able to add route")
+            .get("/widget", |_req| async { Ok(Response::new(empty())) })
+            .expect("Should have been able to add route")
+            .get("/legacy", |_req| async {
+                let mut response = Response::new(empty());
+                response
+                    .headers_mut()
+                    .insert(X_FRAME_OPTIONS, HeaderValue::from_static("SAMEORIGIN"));
+                Ok(response)
+            })
+            .expect("Should have been able to add route")
+            .wrap_route("/widget", |target| {
+                SecurityHeaders::recommended()
+                    .no_frame_options()
+                    .content_security_policy("frame-ancestors https://partner.example")
+                    .wrap(target)
+            })
+            .expect("Should have been able to wrap route")
+            .build()
+    }
+
+    fn call<S>(service: &S, uri: &str) -> Response<LuminalBody>
+    where
+        S: Service<Request<LuminalBody>, Response = Response<LuminalBody>, Error = BoxError>,
+    {
+        let req = Request::builder()
+            .uri(uri)
+            .body(empty())
+            .expect("Should have been able to build request");
+        block_on(service.call(req)).expect("Should have been able to run call")
+    }
+
+    #[test]
+    fn test_recommended() {
+        let service = SecurityHeaders::recommended().wrap(router());
+        let response = call(&service, "/page");
+        let headers = response.headers().clone();
+        assert_eq!(
+            "max-age=31536000; includeSubDomains",
+            headers[STRICT_TRANSPORT_SECURITY]
+        );
+        assert_eq!("nosniff", headers[X_CONTENT_TYPE_OPTIONS]);
+        assert_eq!("DENY", headers[X_FRAME_OPTIONS]);
+        assert_eq!("strict-origin-when-cross-origin", headers[REFERRER_POLICY]);
+        assert_eq!(
+            "camera=(), microphone=(), geolocation=()",
+            headers[PERMISSIONS_POLICY]
+        );
+
+        // the nonce handed to the handler is the one in the policy, and new for every request
+        let body = block_on(http_body_util::BodyExt::collect(response.into_body()))
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        let nonce = String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body");
+        assert_eq!(24, nonce.len());
+        let csp = headers[CONTENT_SECURITY_POLICY]
+            .to_str()
+            .expect("Should have been a string");
+        assert!(csp.contains(&format!("script-src 'self' 'nonce-{}'", nonce)));
+        assert_ne!(
+            headers[CONTENT_SECURITY_POLICY],
+            call(&service, "/page").headers()[CONTENT_SECURITY_POLICY]
+        );
+    }
+
+    #[test]
+    fn test_overrides() {
+        let service = SecurityHeaders::api().wrap(router());
+
+        // a handler's own header stays
+        let response = call(&service, "/legacy");
+        assert_eq!("SAMEORIGIN", response.headers()[X_FRAME_OPTIONS]);
+        assert_eq!("no-referrer", response.headers()[REFERRER_POLICY]);
+
+        // the route's own policy replaces the router's
+        let response = call(&service, "/widget");
+        assert!(!response.headers().contains_key(X_FRAME_OPTIONS));
+        assert_eq!(
+            "frame-ancestors https://partner.example",
+            response.headers()[CONTENT_SECURITY_POLICY]
+        );
+        assert_eq!(
+            "strict-origin-when-cross-origin",
+            response.headers()[REFERRER_POLICY]
+        );
+
+        let bare = SecurityHeaders::new().wrap(router());
+        assert!(call(&bare, "/page").headers().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Should have been a valid header value")]
+    fn test_invalid_value() {
+        let _ = SecurityHeaders::new().referrer_policy("no-referrer\r\nX-Injected: 1");
+    }
+}
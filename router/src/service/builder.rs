@@ -1,12 +1,17 @@
 //! Builders to add implementations of `Service` and functions for specific methods and reoutes.
-use hyper::{self, Method};
-use hyper::server::{self, Request, Response, Service};
+use http::{Method, Request, Response};
+use hyper::service::{self, Service};
 
-use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
 
-use LuminalFuture;
-use error::*;
 use super::Router;
+use crate::error::*;
+use crate::guard::Guard;
+use crate::handler::{HandlerFnRouteBuilder, HandlerRouteBuilder};
+use crate::ratelimit::RateLimiter;
+use crate::websocket::WebSocketUpgrade;
+use crate::{BoxError, LuminalBody, LuminalFuture, LuminalService};
 
 /// Fluent builder, takes ownership of a `Router` while adding routes.
 ///
@@ -15,62 +20,154 @@ pub struct ServiceRouteBuilder {
     pub router: Router,
 }
 
+impl Default for ServiceRouteBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ServiceRouteBuilder {
     /// Create a new instance with a `Router` with empty routes.
     pub fn new() -> ServiceRouteBuilder {
         ServiceRouteBuilder {
-            router: Router {
-                routes: HashMap::new(),
-            },
+            router: Router::new(),
         }
     }
 
-    /// Add a service for `Method::Get` at the specified route.
-    pub fn get<
+    /// Add a service for `Method::GET` at the specified route.
+    pub fn get<S>(mut self, route: &str, service: S) -> Result<Self>
+    where
         S: Service<
-            Request = Request,
-            Response = Response,
-            Error = hyper::Error,
-            Future = LuminalFuture,
-        >
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
             + 'static,
-    >(
-        mut self,
-        route: &str,
-        service: S,
-    ) -> Result<Self> {
+    {
         {
-            self.router.add(Method::Get, route, Box::new(service))?;
+            self.router.add(Method::GET, route, service)?;
         }
         Ok(self)
     }
 
-    /// Add a service for `Method::Post` at the specified route.
-    pub fn post<
+    /// Add a service for `Method::GET` at the specified route that only serves requests passing
+    /// the guard.
+    pub fn get_guarded<G, S>(mut self, route: &str, guard: G, service: S) -> Result<Self>
+    where
+        G: Guard + 'static,
         S: Service<
-            Request = Request,
-            Response = Response,
-            Error = hyper::Error,
-            Future = LuminalFuture,
-        >
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
             + 'static,
-    >(
-        mut self,
-        route: &str,
-        service: S,
-    ) -> Result<Self> {
+    {
         {
-            self.router.add(Method::Post, route, Box::new(service))?;
+            self.router
+                .add_guarded(Method::GET, route, guard, service)?;
         }
         Ok(self)
     }
 
+    /// Add a service for `Method::POST` at the specified route.
+    pub fn post<S>(mut self, route: &str, service: S) -> Result<Self>
+    where
+        S: Service<
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        {
+            self.router.add(Method::POST, route, service)?;
+        }
+        Ok(self)
+    }
+
+    /// Add a service for `Method::POST` at the specified route that only serves requests passing
+    /// the guard.
+    pub fn post_guarded<G, S>(mut self, route: &str, guard: G, service: S) -> Result<Self>
+    where
+        G: Guard + 'static,
+        S: Service<
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        {
+            self.router
+                .add_guarded(Method::POST, route, guard, service)?;
+        }
+        Ok(self)
+    }
+
+    /// Wrap every target at the specified route, whatever the method, in another service, such as
+    /// `BasicAuth::wrap`.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn wrap_route<F, S>(mut self, route: &str, wrap: F) -> Result<Self>
+    where
+        F: Fn(Arc<LuminalService>) -> S,
+        S: Service<
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        self.router.wrap_route(route, wrap)?;
+        Ok(self)
+    }
+
+    /// Exempt every target at the specified route from CSRF checks.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn csrf_exempt(mut self, route: &str) -> Result<Self> {
+        self.router.csrf_exempt(route)?;
+        Ok(self)
+    }
+
+    /// Add a WebSocket route, answering upgrade requests for `Method::GET` at the specified route.
+    pub fn websocket(mut self, route: &str, upgrade: WebSocketUpgrade) -> Result<Self> {
+        self.router.add(Method::GET, route, upgrade)?;
+        Ok(self)
+    }
+
+    /// Limit the rate of requests to every target at the specified route, whatever the method.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn rate_limited(mut self, route: &str, limiter: &RateLimiter) -> Result<Self> {
+        self.router
+            .wrap_route(route, |target| limiter.wrap(target))?;
+        Ok(self)
+    }
+
     pub fn fn_builder(self) -> FnRouteBuilder {
         FnRouteBuilder {
             router: self.router,
         }
     }
 
+    /// Return a new `HandlerRouteBuilder` that now owns the router being contructed.
+    pub fn handler_builder(self) -> HandlerRouteBuilder {
+        HandlerRouteBuilder {
+            router: self.router,
+        }
+    }
+
     /// Call to gain/regain ownership of the `Router`.
     pub fn build(self) -> Router {
         self.router
@@ -81,34 +178,110 @@ pub struct FnRouteBuilder {
     pub router: Router,
 }
 
+impl Default for FnRouteBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FnRouteBuilder {
     pub fn new() -> FnRouteBuilder {
         FnRouteBuilder {
-            router: Router {
-                routes: HashMap::new(),
-            },
+            router: Router::new(),
         }
     }
 
-    /// Add a service for `Method::Get` at the specified route.
-    pub fn get<F: Fn(Request) -> LuminalFuture + 'static>(
-        mut self,
-        route: &str,
-        function: F,
-    ) -> Result<Self> {
+    /// Add a function or `async fn` for `Method::GET` at the specified route.
+    pub fn get<F, R>(mut self, route: &str, function: F) -> Result<Self>
+    where
+        F: Fn(Request<LuminalBody>) -> R + Send + Sync + 'static,
+        R: Future<Output = ::std::result::Result<Response<LuminalBody>, BoxError>> + Send + 'static,
+    {
         {
-            self.router
-                .add(Method::Get, route, Box::new(server::service_fn(function)))?;
+            self.router.add(
+                Method::GET,
+                route,
+                service::service_fn(move |req| -> LuminalFuture { Box::pin(function(req)) }),
+            )?;
         }
         Ok(self)
     }
 
+    /// Add a function or `async fn` for `Method::GET` at the specified route that only serves
+    /// requests passing the guard.
+    pub fn get_guarded<G, F, R>(mut self, route: &str, guard: G, function: F) -> Result<Self>
+    where
+        G: Guard + 'static,
+        F: Fn(Request<LuminalBody>) -> R + Send + Sync + 'static,
+        R: Future<Output = ::std::result::Result<Response<LuminalBody>, BoxError>> + Send + 'static,
+    {
+        {
+            self.router.add_guarded(
+                Method::GET,
+                route,
+                guard,
+                service::service_fn(move |req| -> LuminalFuture { Box::pin(function(req)) }),
+            )?;
+        }
+        Ok(self)
+    }
+
+    /// Wrap every target at the specified route, whatever the method, in another service, such as
+    /// `BasicAuth::wrap`.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn wrap_route<F, S>(mut self, route: &str, wrap: F) -> Result<Self>
+    where
+        F: Fn(Arc<LuminalService>) -> S,
+        S: Service<
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        self.router.wrap_route(route, wrap)?;
+        Ok(self)
+    }
+
+    /// Exempt every target at the specified route from CSRF checks.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn csrf_exempt(mut self, route: &str) -> Result<Self> {
+        self.router.csrf_exempt(route)?;
+        Ok(self)
+    }
+
+    /// Add a WebSocket route, answering upgrade requests for `Method::GET` at the specified route.
+    pub fn websocket(mut self, route: &str, upgrade: WebSocketUpgrade) -> Result<Self> {
+        self.router.add(Method::GET, route, upgrade)?;
+        Ok(self)
+    }
+
+    /// Limit the rate of requests to every target at the specified route, whatever the method.
+    ///
+    /// Add the targets first, the route must already be in the router.
+    pub fn rate_limited(mut self, route: &str, limiter: &RateLimiter) -> Result<Self> {
+        self.router
+            .wrap_route(route, |target| limiter.wrap(target))?;
+        Ok(self)
+    }
+
     pub fn service_builder(self) -> ServiceRouteBuilder {
         ServiceRouteBuilder {
             router: self.router,
         }
     }
 
+    /// Return a new `HandlerFnRouteBuilder` that now owns the router being contructed.
+    pub fn handler_fn_builder(self) -> HandlerFnRouteBuilder {
+        HandlerFnRouteBuilder {
+            router: self.router,
+        }
+    }
+
     pub fn build(self) -> Router {
         self.router
     }
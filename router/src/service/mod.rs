@@ -1,42 +1,98 @@
-//! Router for mapping `hyper::Method` and a request path to a `hyper::Service`.
+//! Router for mapping `http::Method` and a request path to a `hyper::service::Service`.
 //!
-//! luminal's router uses a simplified radix tree for speedy lookups. `cargo +nightly bench` to see
-//! relative performance across some contrived examples.
-use futures::future;
-use hyper::{self, Method, StatusCode};
-use hyper::server::{Request, Response, Service};
+//! Implementations of `luminal_handler::Handler` are added to the same router, wrapped in a
+//! `HandlerService`.
+//!
+//! luminal's router uses a simplified radix tree for speedy lookups. `cargo bench` to see relative
+//! performance across some contrived examples.
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::{boxed, Handler, HandlerService};
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 mod builder;
 
-use {LuminalFuture, LuminalService};
-use error::*;
-use tree::RouteTree;
-use route::Route;
 pub use self::builder::{FnRouteBuilder, ServiceRouteBuilder};
-
-/// Router for Hyper.
-#[derive(Default)]
+use crate::error::*;
+use crate::guard::Guard;
+use crate::params::PathParams;
+use crate::route::{MatchedRoute, Route, RouteLayers};
+use crate::tree::{Match, RouteTree};
+use crate::{rejected, BoxError, LuminalBody, LuminalFuture, LuminalService};
+
+/// Router for Hyper, dispatching to services and handlers alike.
+///
+/// Route targets are shared, so cloning a `Router` only copies the route tables. That makes it
+/// cheap to derive a new table from the one being served, see `ReloadableRouter`.
+#[derive(Clone, Default)]
 pub struct Router {
-    routes: HashMap<Method, RouteTree<Route<Box<LuminalService>>>>,
+    routes: HashMap<Method, RouteTree<Vec<Route<Arc<LuminalService>>>>>,
 }
 
-impl Service for Router {
-    type Request = Request;
-    type Response = Response;
-    type Error = hyper::Error;
+impl<B> Service<Request<B>> for Router
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
     type Future = LuminalFuture;
 
-    fn call(&self, req: Request) -> Self::Future {
-        let route = self.dispatch(req.method(), req.path());
-        if let Some(&Some(ref route)) = route {
-            route.target.call(req)
-        } else {
-            let mut response = Response::new();
-            response.set_status(StatusCode::NotFound);
-            Box::new(future::ok(response))
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let layers = req.extensions_mut().remove::<RouteLayers>();
+        let path = req.uri().path();
+        let (target, matched, params) = match self.route(&req) {
+            Ok(route) => {
+                let allowed = if req.method() == Method::OPTIONS {
+                    self.allowed_methods(path)
+                } else {
+                    Vec::new()
+                };
+                (
+                    Ok(Arc::clone(&route.target)),
+                    MatchedRoute::new(Some(route.route_path.clone()), allowed, route.csrf_exempt),
+                    Some(PathParams::new(&route.route_path, path)),
+                )
+            }
+            Err(status) => (
+                Err(status),
+                MatchedRoute::new(None, self.allowed_methods(path), false),
+                None,
+            ),
+        };
+        req.extensions_mut().insert(matched.clone());
+        if let Some(params) = &params {
+            req.extensions_mut().insert(params.clone());
         }
+        let response = match layers {
+            Some(layers) => layers.run(&matched, req, Box::new(move |req| call(target, req))),
+            None => call(target, req),
+        };
+        // middleware wrapped around the router only sees the response, so it carries the match too
+        Box::pin(async move {
+            let mut response = response.await?;
+            response.extensions_mut().insert(matched);
+            if let Some(params) = params {
+                response.extensions_mut().insert(params);
+            }
+            Ok(response)
+        })
+    }
+}
+
+// Call the target a request was routed to, or turn it away.
+fn call(
+    target: ::std::result::Result<Arc<LuminalService>, StatusCode>,
+    req: Request<LuminalBody>,
+) -> LuminalFuture {
+    match target {
+        Ok(target) => target.call(req),
+        Err(status) => rejected(status),
     }
 }
 
@@ -47,74 +103,232 @@ impl Router {
         }
     }
 
+    /// Add a service at the specific route path for the given `Method`.
     ///
-    /// Add a handler at the specific route path for the given `Method`.
-    pub fn add<
+    /// An unguarded service replaces any other unguarded target at the same route, guarded
+    /// targets keep their place ahead of it.
+    pub fn add<S>(&mut self, method: Method, route: &str, service: S) -> Result<()>
+    where
         S: Service<
-            Request = Request,
-            Response = Response,
-            Error = hyper::Error,
-            Future = LuminalFuture,
-        >
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
             + 'static,
-    >(
+    {
+        self.insert(method, Route::new(route, Arc::new(service)))
+    }
+
+    /// Add a service that only serves requests passing the guard.
+    ///
+    /// Guarded targets are tried in the order they were added, ahead of any unguarded target at
+    /// the same route.
+    pub fn add_guarded<G, S>(
         &mut self,
         method: Method,
         route: &str,
+        guard: G,
         service: S,
-    ) -> Result<()> {
-        {
-            let routing = self.routes
-                .entry(method)
-                .or_insert_with(RouteTree::empty_root);
-            routing.add(route, Route::new(route, Box::new(service)))?;
+    ) -> Result<()>
+    where
+        G: Guard + 'static,
+        S: Service<
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        self.insert(
+            method,
+            Route::guarded(route, Arc::new(guard), Arc::new(service)),
+        )
+    }
+
+    /// Add a `Handler` at the specific route path for the given `Method`.
+    pub fn add_handler<H>(&mut self, method: Method, route: &str, handler: H) -> Result<()>
+    where
+        H: Handler + Send + Sync + 'static,
+    {
+        self.add(method, route, HandlerService::new(handler))
+    }
+
+    /// Add a `Handler` that only serves requests passing the guard.
+    pub fn add_guarded_handler<G, H>(
+        &mut self,
+        method: Method,
+        route: &str,
+        guard: G,
+        handler: H,
+    ) -> Result<()>
+    where
+        G: Guard + 'static,
+        H: Handler + Send + Sync + 'static,
+    {
+        self.add_guarded(method, route, guard, HandlerService::new(handler))
+    }
+
+    /// Wrap every target at the specific route path, for all methods, in another service, such
+    /// as a `RateLimitService`.
+    ///
+    /// Guards stay with their targets. Only the targets already added are wrapped.
+    pub fn wrap_route<F, S>(&mut self, route: &str, wrap: F) -> Result<()>
+    where
+        F: Fn(Arc<LuminalService>) -> S,
+        S: Service<
+                Request<LuminalBody>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        let mut wrapped = false;
+        for routing in self.routes.values_mut() {
+            if let Some(routes) = routing.get_mut(route) {
+                for target in routes.iter_mut() {
+                    target.target = Arc::new(wrap(Arc::clone(&target.target)));
+                    wrapped = true;
+                }
+            }
+        }
+        if !wrapped {
+            bail!(ErrorKind::UnknownRoute(route.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Exempt every target at the specific route path, for all methods, from the checks of a
+    /// `CsrfService` wrapped around the router, such as for a webhook that authenticates its own
+    /// requests.
+    pub fn csrf_exempt(&mut self, route: &str) -> Result<()> {
+        let mut exempted = false;
+        for routing in self.routes.values_mut() {
+            if let Some(routes) = routing.get_mut(route) {
+                for target in routes.iter_mut() {
+                    target.csrf_exempt = true;
+                    exempted = true;
+                }
+            }
+        }
+        if !exempted {
+            bail!(ErrorKind::UnknownRoute(route.to_owned()));
         }
         Ok(())
     }
 
-    pub fn dispatch<'a>(
+    fn insert(&mut self, method: Method, target: Route<Arc<LuminalService>>) -> Result<()> {
+        let routing = self
+            .routes
+            .entry(method)
+            .or_insert_with(RouteTree::empty_root);
+        let route_path = target.route_path.clone();
+        let routes = match routing.get_mut(&route_path) {
+            Some(routes) => routes,
+            None => {
+                routing.add(&route_path, vec![target])?;
+                return Ok(());
+            }
+        };
+        if target.guard.is_some() {
+            let unguarded = routes.iter().position(|route| route.guard.is_none());
+            routes.insert(unguarded.unwrap_or(routes.len()), target);
+        } else if let Some(existing) = routes.iter_mut().find(|route| route.guard.is_none()) {
+            *existing = target;
+        } else {
+            routes.push(target);
+        }
+        Ok(())
+    }
+
+    /// Remove every target at the specific route path for the given `Method`, returning them.
+    pub fn remove(
+        &mut self,
+        method: &Method,
+        route: &str,
+    ) -> Option<Vec<Route<Arc<LuminalService>>>> {
+        let routing = self.routes.get_mut(method)?;
+        let removed = routing.remove(route);
+        if routing.is_empty() {
+            self.routes.remove(method);
+        }
+        removed
+    }
+
+    /// Find the targets for the method and path, along with the path parameters they capture.
+    pub fn dispatch<'a, 'p>(
         &'a self,
         method: &Method,
-        route_path: &str,
-    ) -> Option<&'a Option<Route<Box<LuminalService>>>> {
-        if let Some(routing) = self.routes.get(method) {
-            if let Some(route) = routing.dispatch(route_path) {
-                Some(route)
-            } else {
-                None
+        route_path: &'p str,
+    ) -> Option<Match<'a, 'p, Vec<Route<Arc<LuminalService>>>>> {
+        self.routes.get(method)?.dispatch(route_path)
+    }
+
+    /// The methods with a route for the path, in alphabetical order.
+    pub fn allowed_methods(&self, route_path: &str) -> Vec<Method> {
+        let mut methods = self
+            .routes
+            .keys()
+            .filter(|method| self.routes[*method].dispatch(route_path).is_some())
+            .cloned()
+            .collect::<Vec<_>>();
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        methods
+    }
+
+    /// Pick the first target for the request whose guard passes.
+    ///
+    /// When nothing passes, the status to respond with is the first rejection that is more
+    /// specific than 404 Not Found, such as 406 Not Acceptable from an `Accept` guard.
+    pub fn route(
+        &self,
+        req: &Request<LuminalBody>,
+    ) -> ::std::result::Result<&Route<Arc<LuminalService>>, StatusCode> {
+        let routes = self
+            .routes
+            .get(req.method())
+            .and_then(|routing| routing.dispatch(req.uri().path()))
+            .ok_or(StatusCode::NOT_FOUND)?
+            .value;
+        let mut status = StatusCode::NOT_FOUND;
+        for route in routes {
+            match route.check(req) {
+                Ok(()) => return Ok(route),
+                Err(rejection) if status == StatusCode::NOT_FOUND => status = rejection,
+                Err(_) => (),
             }
-        } else {
-            None
         }
+        Err(status)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    extern crate tokio_core;
-
-    use hyper::Body;
-    use hyper::header::ContentLength;
-    use futures::Stream;
-    use futures::future::Future;
-
-    use self::tokio_core::reactor::Core;
+    use futures::executor::block_on;
+    use http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+    use http_body_util::{BodyExt, Empty};
+    use luminal_handler::full;
 
     use super::*;
+    use crate::guard::{Accept, ContentType};
 
     struct StringHandler(String);
 
-    impl Service for StringHandler {
-        type Request = Request;
-        type Response = Response;
-        type Error = hyper::Error;
-        type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
-        fn call(&self, _req: Request) -> Self::Future {
-            Box::new(future::ok(
-                Response::new()
-                    .with_header(ContentLength(self.0.len() as u64))
-                    .with_body(self.0.clone()),
-            ))
+    impl Service<Request<LuminalBody>> for StringHandler {
+        type Response = Response<LuminalBody>;
+        type Error = BoxError;
+        type Future = LuminalFuture;
+        fn call(&self, _req: Request<LuminalBody>) -> Self::Future {
+            let response = Response::builder()
+                .header(CONTENT_LENGTH, self.0.len())
+                .body(full(self.0.clone()))
+                .map_err(BoxError::from);
+            Box::pin(async move { response })
         }
     }
 
@@ -124,13 +338,13 @@ mod tests {
         }
     }
 
-    fn get_bar_handler(_req: Request) -> LuminalFuture {
+    async fn get_bar_handler(
+        _req: Request<LuminalBody>,
+    ) -> ::std::result::Result<Response<LuminalBody>, BoxError> {
         let msg = String::from("Get bar");
-        Box::new(future::ok(
-            Response::new()
-                .with_header(ContentLength(msg.len() as u64))
-                .with_body(msg),
-        ))
+        Ok(Response::builder()
+            .header(CONTENT_LENGTH, msg.len())
+            .body(full(msg))?)
     }
 
     #[test]
@@ -145,9 +359,169 @@ mod tests {
             .expect("Should have been able to add route")
             .build();
 
-        assert_call(&router, Method::Get, "/foo/bar", "Get bar");
-        assert_call(&router, Method::Post, "/foo/bar", "Post bar");
-        assert_call(&router, Method::Get, "/foo/baz", "Baz");
+        assert_call(&router, Method::GET, "/foo/bar", "Get bar");
+        assert_call(&router, Method::POST, "/foo/bar", "Post bar");
+        assert_call(&router, Method::GET, "/foo/baz", "Baz");
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut router = FnRouteBuilder::new()
+            .get("/foo/bar", get_bar_handler)
+            .expect("Should have been able to add route")
+            .service_builder()
+            .get("/foo/baz", StringHandler::new("Baz"))
+            .expect("Should have been able to add route")
+            .build();
+
+        let removed = router
+            .remove(&Method::GET, "/foo/bar")
+            .expect("Should have removed route");
+        assert_eq!("/foo/bar", removed[0].route_path);
+        assert!(router.remove(&Method::POST, "/foo/baz").is_none());
+
+        assert_status(&router, Method::GET, "/foo/bar", StatusCode::NOT_FOUND);
+        assert_call(&router, Method::GET, "/foo/baz", "Baz");
+    }
+
+    #[test]
+    fn test_path_params() {
+        let router = FnRouteBuilder::new()
+            .get("/user/:user_id", |req: Request<LuminalBody>| async move {
+                let params = req
+                    .extensions()
+                    .get::<PathParams>()
+                    .expect("Should have had path params");
+                let msg = format!("{} {}", params.route(), params.get("user_id").unwrap_or(""));
+                Ok(Response::new(full(msg)))
+            })
+            .expect("Should have been able to add route")
+            .build();
+
+        assert_call(&router, Method::GET, "/user/42", "/user/:user_id 42");
+
+        let req = Request::builder()
+            .uri("/user/7")
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+        let response =
+            block_on(router.call(req)).expect("Should have been able to run router call");
+        assert_eq!(
+            Some("/user/:user_id"),
+            response
+                .extensions()
+                .get::<PathParams>()
+                .map(PathParams::route)
+        );
+    }
+
+    #[test]
+    fn test_matched_route() {
+        let mut router = FnRouteBuilder::new()
+            .get("/user/:user_id", get_bar_handler)
+            .expect("Should have been able to add route")
+            .service_builder()
+            .post("/user/:user_id", StringHandler::new("Post"))
+            .expect("Should have been able to add route")
+            .build();
+        router
+            .csrf_exempt("/user/:user_id")
+            .expect("Should have been able to exempt route");
+        let matched = |method: Method, uri: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Empty::<Bytes>::new())
+                .expect("Should have been able to build request");
+            let response =
+                block_on(router.call(req)).expect("Should have been able to run router call");
+            response
+                .extensions()
+                .get::<MatchedRoute>()
+                .cloned()
+                .expect("Should have had the matched route")
+        };
+
+        let get = matched(Method::GET, "/user/7");
+        assert_eq!(Some("/user/:user_id"), get.route());
+        assert!(get.is_csrf_exempt());
+        // the allowed methods are only looked up when they are needed
+        assert!(get.allowed_methods().is_empty());
+        let unmatched = matched(Method::DELETE, "/user/7");
+        assert_eq!(None, unmatched.route());
+        assert!(!unmatched.is_csrf_exempt());
+        assert_eq!(&[Method::GET, Method::POST], unmatched.allowed_methods());
+    }
+
+    #[test]
+    fn test_guards() {
+        let router = ServiceRouteBuilder::new()
+            .get_guarded(
+                "/foo",
+                Accept::new("application/vnd.foo.v2+json"),
+                StringHandler::new("V2"),
+            )
+            .expect("Should have been able to add route")
+            .get_guarded(
+                "/foo",
+                Accept::new("application/vnd.foo.v1+json"),
+                StringHandler::new("V1"),
+            )
+            .expect("Should have been able to add route")
+            .post_guarded(
+                "/foo",
+                ContentType::new("application/json"),
+                StringHandler::new("Json"),
+            )
+            .expect("Should have been able to add route")
+            .build();
+
+        assert_guarded(&router, Method::GET, "application/vnd.foo.v1+json", "V1");
+        assert_guarded(&router, Method::GET, "*/*", "V2");
+        assert_guarded(&router, Method::POST, "application/json", "Json");
+        assert_status(
+            &router,
+            Method::GET,
+            "/foo?accept=text/html",
+            StatusCode::OK,
+        );
+
+        let req = Request::builder()
+            .uri("/foo")
+            .header(ACCEPT, "text/html")
+            .body(luminal_handler::empty())
+            .expect("Should have been able to build request");
+        let response = block_on(router.call(req)).expect("Should have been able to run call");
+        assert_eq!(StatusCode::NOT_ACCEPTABLE, response.status());
+
+        assert_status(
+            &router,
+            Method::POST,
+            "/foo",
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        );
+    }
+
+    #[test]
+    fn test_guard_fallback() {
+        let mut router = FnRouteBuilder::new()
+            .get_guarded(
+                "/foo",
+                |req: &Request<LuminalBody>| req.headers().contains_key(ACCEPT),
+                get_bar_handler,
+            )
+            .expect("Should have been able to add route")
+            .build();
+        assert_status(&router, Method::GET, "/foo", StatusCode::NOT_FOUND);
+
+        router
+            .add(Method::GET, "/foo", StringHandler::new("Fallback"))
+            .expect("Should have been able to add route");
+        router
+            .add(Method::GET, "/foo", StringHandler::new("Replaced"))
+            .expect("Should have been able to add route");
+        assert_call(&router, Method::GET, "/foo", "Replaced");
+        assert_guarded(&router, Method::GET, "text/html", "Get bar");
     }
 
     #[test]
@@ -156,50 +530,91 @@ mod tests {
             ..Default::default()
         };
 
-        let uri = "/foo"
-            .parse()
-            .expect("Should have been able to convert to uri");
-        let req: Request<Body> = Request::new(Method::Get, uri);
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/foo")
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
 
         let work = router.call(req);
 
-        let mut core = Core::new().expect("Should have been able to create core");
-
-        let response = core.run(work)
-            .expect("Should have been able to run router call");
+        let response = block_on(work).expect("Should have been able to run router call");
 
         assert_eq!(
-            StatusCode::NotFound,
+            StatusCode::NOT_FOUND,
             response.status(),
             "Should have received not found status."
         );
     }
 
+    fn assert_status(router: &Router, method: Method, uri: &str, expected: StatusCode) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+
+        let response =
+            block_on(router.call(req)).expect("Should have been able to run router call");
+
+        assert_eq!(
+            expected,
+            response.status(),
+            "Should have received {} status.",
+            expected
+        );
+    }
+
+    fn assert_guarded(router: &Router, method: Method, value: &str, expected: &str) {
+        let name = if method == Method::GET {
+            ACCEPT
+        } else {
+            CONTENT_TYPE
+        };
+        let req = Request::builder()
+            .method(method)
+            .uri("/foo")
+            .header(name, value)
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+
+        let response =
+            block_on(router.call(req)).expect("Should have been able to run router call");
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+
+        assert_eq!(
+            expected.as_bytes(),
+            &body[..],
+            "Should have received correct body content"
+        );
+    }
+
     fn assert_call(router: &Router, method: Method, uri: &str, expected: &str) {
-        let uri = uri.parse()
-            .expect("Should have been able to convert to uri");
-        let req: Request<Body> = Request::new(method, uri);
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
 
         let work = router.call(req);
 
-        let mut core = Core::new().expect("Should have been able to create core");
-
-        let response = core.run(work)
-            .expect("Should have been able to run router call");
+        let response = block_on(work).expect("Should have been able to run router call");
 
         assert_eq!(
-            StatusCode::Ok,
+            StatusCode::OK,
             response.status(),
             "Should have received Ok status."
         );
 
-        let body = core.run(response.body().concat2())
-            .expect("Should have been able to resolve body concat");
-        let body: &[u8] = &body.to_vec();
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
 
         assert_eq!(
             expected.as_bytes(),
-            body,
+            &body[..],
             "Should have received correct body content"
         );
     }
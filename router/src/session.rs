@@ -0,0 +1,549 @@
+//! Sessions, kept in a `SessionStore` and tied to the client by a private cookie.
+//!
+//! `SessionService` loads the session named by the request's cookie and puts it in the request
+//! extensions as a `Session`. Once the response is ready, a session that was changed is saved and
+//! its cookie set; one that was destroyed is removed along with its cookie. Untouched sessions
+//! cost nothing and new ones aren't saved until something is put in them.
+//!
+//! Call `Session::renew` when a user logs in, so the session gets a new ID and one planted in a
+//! victim's browser beforehand is of no use.
+//!
+//! The session cookie is a private cookie, so `SessionService` has to be wrapped by a
+//! `CookieService` with at least one key. Without one, every request fails rather than sessions
+//! silently never being kept.
+use bytes::Bytes;
+use http::{Request, Response};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::boxed;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::cookies::{Cookie, Cookies, SameSite};
+use crate::timeout::{Clock, TokioClock};
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// The values in a session.
+pub type SessionData = Map<String, Value>;
+
+/// A future resolving to the result of a `SessionStore` operation.
+pub type SessionFuture<T> = Pin<Box<dyn Future<Output = Result<T, BoxError>> + Send>>;
+
+/// Where sessions are kept, named by the value of the session cookie.
+pub trait SessionStore: Send + Sync {
+    /// The session the cookie names, if it is still there.
+    fn load(&self, cookie: &str) -> SessionFuture<Option<SessionData>>;
+
+    /// Save the session, new when there is no cookie, returning the value for the cookie. When
+    /// renewing, the session must move to a new name and the old one stop working.
+    fn save(&self, cookie: Option<&str>, data: &SessionData, renew: bool) -> SessionFuture<String>;
+
+    fn destroy(&self, cookie: &str) -> SessionFuture<()>;
+}
+
+/// Sessions held in memory under random IDs, expiring when they haven't been used for a while.
+///
+/// Loading a session, which happens for every request that sends its cookie, pushes its expiry
+/// back as much as saving it does.
+///
+/// An expired session is dropped when it is next loaded, and the rest are swept out as the map
+/// grows.
+pub struct MemorySessions {
+    sessions: Mutex<Entries>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct Entries {
+    sessions: HashMap<String, (Instant, SessionData)>,
+    sweep_at: usize,
+}
+
+impl MemorySessions {
+    /// Sessions that expire after `ttl` without a request.
+    pub fn new(ttl: Duration) -> MemorySessions {
+        MemorySessions::with_clock(ttl, TokioClock)
+    }
+
+    pub fn with_clock<C: Clock + 'static>(ttl: Duration, clock: C) -> MemorySessions {
+        MemorySessions {
+            sessions: Mutex::new(Entries::default()),
+            ttl,
+            clock: Arc::new(clock),
+        }
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, Entries> {
+        self.sessions
+            .lock()
+            .expect("Should not have poisoned the sessions")
+    }
+}
+
+impl SessionStore for MemorySessions {
+    fn load(&self, cookie: &str) -> SessionFuture<Option<SessionData>> {
+        let now = self.clock.now();
+        let sessions = &mut self.sessions().sessions;
+        let data = match sessions.get_mut(cookie) {
+            Some((expires, data)) if *expires > now => {
+                *expires = now + self.ttl;
+                Some(data.clone())
+            }
+            Some(_) => {
+                sessions.remove(cookie);
+                None
+            }
+            None => None,
+        };
+        Box::pin(future::ready(Ok(data)))
+    }
+
+    fn save(&self, cookie: Option<&str>, data: &SessionData, renew: bool) -> SessionFuture<String> {
+        let now = self.clock.now();
+        let mut entries = self.sessions();
+        let Entries { sessions, sweep_at } = &mut *entries;
+        let id = match cookie {
+            Some(id) if !renew => id.to_owned(),
+            _ => {
+                if let Some(old) = cookie {
+                    sessions.remove(old);
+                }
+                uuid::Uuid::new_v4().simple().to_string()
+            }
+        };
+        sessions.insert(id.clone(), (now + self.ttl, data.clone()));
+        if sessions.len() > *sweep_at {
+            sessions.retain(|_, (expires, _)| *expires > now);
+            *sweep_at = (sessions.len() * 2).max(1024);
+        }
+        Box::pin(future::ready(Ok(id)))
+    }
+
+    fn destroy(&self, cookie: &str) -> SessionFuture<()> {
+        self.sessions().sessions.remove(cookie);
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Sessions kept entirely in the encrypted session cookie, so no state is held on the server.
+///
+/// Browsers limit cookies to around 4KB, so sessions that grow past that fail to save. A copy of
+/// the cookie stays valid until it expires, even after the session is renewed or destroyed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CookieSessions;
+
+impl SessionStore for CookieSessions {
+    fn load(&self, cookie: &str) -> SessionFuture<Option<SessionData>> {
+        Box::pin(future::ready(Ok(serde_json::from_str(cookie).ok())))
+    }
+
+    fn save(
+        &self,
+        _cookie: Option<&str>,
+        data: &SessionData,
+        _renew: bool,
+    ) -> SessionFuture<String> {
+        let saved = serde_json::to_string(data)
+            .map_err(BoxError::from)
+            .and_then(|json| match json.len() {
+                // leaves room for the encryption overhead and the cookie's attributes
+                len if len > 3072 => Err(BoxError::from("Session is too large for a cookie")),
+                _ => Ok(json),
+            });
+        Box::pin(future::ready(saved))
+    }
+
+    fn destroy(&self, _cookie: &str) -> SessionFuture<()> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+#[derive(Default)]
+struct SessionState {
+    data: SessionData,
+    changed: bool,
+    renew: bool,
+    destroy: bool,
+}
+
+/// The session of a request, from the request extensions.
+///
+/// Clones share the same session.
+#[derive(Clone, Default)]
+pub struct Session(Arc<Mutex<SessionState>>);
+
+impl Session {
+    fn state(&self) -> MutexGuard<'_, SessionState> {
+        self.0.lock().expect("Should not have poisoned the session")
+    }
+
+    /// The value, if there is one and it deserializes to the type.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.state().data.get(key)?.clone();
+        serde_json::from_value(value).ok()
+    }
+
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) -> serde_json::Result<()> {
+        let value = serde_json::to_value(value)?;
+        let mut state = self.state();
+        state.data.insert(key.to_owned(), value);
+        state.changed = true;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        let mut state = self.state();
+        state.changed = true;
+        state.data.remove(key)
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.data.clear();
+        state.changed = true;
+    }
+
+    /// Move the session to a new ID, keeping its values, as when a user logs in.
+    pub fn renew(&self) {
+        let mut state = self.state();
+        state.renew = true;
+        state.changed = true;
+    }
+
+    /// Remove the session from the store and the client, as when a user logs out.
+    pub fn destroy(&self) {
+        let mut state = self.state();
+        state.data.clear();
+        state.destroy = true;
+    }
+}
+
+/// A session policy, built fluently.
+///
+/// The session cookie is called `luminal.sid` and is `HttpOnly`, `Secure` and `SameSite=Lax` for
+/// the whole site, lasting until the browser closes, unless set otherwise.
+pub struct Sessions {
+    store: Box<dyn SessionStore>,
+    cookie: Cookie<'static>,
+}
+
+impl Sessions {
+    pub fn new<S: SessionStore + 'static>(store: S) -> Sessions {
+        let cookie = Cookie::build(("luminal.sid", ""))
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Lax)
+            .build();
+        Sessions {
+            store: Box::new(store),
+            cookie,
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie.set_name(name.to_owned());
+        self
+    }
+
+    /// Whether the cookie is only sent over HTTPS, turned off for local development.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.cookie.set_secure(secure);
+        self
+    }
+
+    /// Keep the cookie for this long, rather than until the browser closes.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.cookie
+            .set_max_age(cookie::time::Duration::try_from(max_age).ok());
+        self
+    }
+
+    pub fn wrap<S>(self, inner: S) -> SessionService<S> {
+        SessionService {
+            sessions: Arc::new(self),
+            inner,
+        }
+    }
+
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = self.cookie.clone();
+        cookie.set_value(value);
+        cookie
+    }
+}
+
+/// A service giving its requests sessions, see `Sessions`.
+pub struct SessionService<S> {
+    sessions: Arc<Sessions>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for SessionService<S> {
+    fn clone(&self) -> Self {
+        SessionService {
+            sessions: Arc::clone(&self.sessions),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for SessionService<S>
+where
+    S: Service<
+            Request<LuminalBody>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Clone
+        + Send
+        + 'static,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let cookies = match req.extensions().get::<Cookies>() {
+            Some(cookies) => cookies.clone(),
+            None => {
+                return Box::pin(future::ready(Err(BoxError::from(
+                    "Sessions need a CookieService wrapped around them",
+                ))))
+            }
+        };
+        if !cookies.can_sign() {
+            return Box::pin(future::ready(Err(BoxError::from(
+                "Sessions need a key to encrypt their cookie with",
+            ))));
+        }
+        let sessions = Arc::clone(&self.sessions);
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let name = sessions.cookie.name();
+            let existing = cookies
+                .get_private(name)
+                .map(|cookie| cookie.value().to_owned());
+            let loaded = match &existing {
+                Some(existing) => sessions.store.load(existing).await?,
+                None => None,
+            };
+            // a cookie for a session that has since expired is as good as none
+            let existing = loaded.as_ref().and(existing);
+            let session = Session::default();
+            session.state().data = loaded.unwrap_or_default();
+            req.extensions_mut().insert(session.clone());
+
+            let response = inner.call(req).await?;
+
+            let (data, renew, destroy, changed) = {
+                let state = session.state();
+                (
+                    state.data.clone(),
+                    state.renew,
+                    state.destroy,
+                    state.changed,
+                )
+            };
+            if destroy {
+                if let Some(existing) = existing {
+                    sessions.store.destroy(&existing).await?;
+                    cookies.remove(sessions.cookie(String::new()));
+                }
+            } else if changed {
+                let value = sessions
+                    .store
+                    .save(existing.as_deref(), &data, renew)
+                    .await?;
+                cookies.add_private(sessions.cookie(value));
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use http::header::{COOKIE, SET_COOKIE};
+    use http::StatusCode;
+    use luminal_handler::{empty, full};
+
+    use super::*;
+    use crate::cookies::{CookieManager, CookieService, Key};
+    use crate::timeout::MockClock;
+    use crate::{FnRouteBuilder, Router};
+
+    fn session(req: &Request<LuminalBody>) -> Session {
+        req.extensions()
+            .get::<Session>()
+            .cloned()
+            .expect("Should have had a session")
+    }
+
+    fn router() -> Router {
+        FnRouteBuilder::new()
+            .get("/", |req| async move {
+                let user = session(&req).get::<String>("user").unwrap_or_default();
+                Ok(Response::new(full(user)))
+            })
+            .expect("Should have been able to add route")
+            .get("/login", |req| async move {
+                let session = session(&req);
+                session.renew();
+                session
+                    .insert("user", "alice")
+                    .expect("Should have been able to insert");
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .get("/logout", |req| async move {
+                session(&req).destroy();
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    type Sessioned = CookieService<SessionService<Router>>;
+
+    fn service<S: SessionStore + 'static>(store: S) -> Sessioned {
+        CookieManager::new()
+            .key(Key::generate())
+            .wrap(Sessions::new(store).wrap(router()))
+    }
+
+    // The body and the session cookie set, if any, without its attributes.
+    fn call(service: &Sessioned, uri: &str, cookie: Option<&str>) -> (String, Option<String>) {
+        let mut req = Request::builder().uri(uri);
+        if let Some(cookie) = cookie {
+            req = req.header(COOKIE, cookie);
+        }
+        let req = req
+            .body(empty())
+            .expect("Should have been able to build request");
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        assert_eq!(StatusCode::OK, response.status());
+        let set = response.headers().get(SET_COOKIE).map(|cookie| {
+            let cookie = cookie.to_str().expect("Should have been a string");
+            assert!(cookie.contains("HttpOnly"));
+            cookie.split(';').next().unwrap_or_default().to_owned()
+        });
+        let body = block_on(http_body_util::BodyExt::collect(response.into_body()))
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        (
+            String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body"),
+            set,
+        )
+    }
+
+    #[test]
+    fn test_memory_sessions() {
+        let clock = MockClock::new();
+        let service = service(MemorySessions::with_clock(
+            Duration::from_secs(60),
+            clock.clone(),
+        ));
+
+        // nothing is saved for a session that is never used
+        let (user, anonymous) = call(&service, "/", None);
+        assert_eq!("", user);
+        assert_eq!(None, anonymous);
+
+        let (_, first) = call(&service, "/login", None);
+        let first = first.expect("Should have set a session cookie");
+        assert_eq!(
+            ("alice".to_owned(), None),
+            call(&service, "/", Some(&first))
+        );
+
+        // logging in again moves the session, and the old cookie stops working
+        let (_, second) = call(&service, "/login", Some(&first));
+        let second = second.expect("Should have set a session cookie");
+        assert_ne!(first, second);
+        assert_eq!("", call(&service, "/", Some(&first)).0);
+        assert_eq!("alice", call(&service, "/", Some(&second)).0);
+
+        let (_, removed) = call(&service, "/logout", Some(&second));
+        assert_eq!(Some(String::from("luminal.sid=")), removed);
+        assert_eq!("", call(&service, "/", Some(&second)).0);
+
+        // each request keeps the session alive, however long ago it was last changed
+        let (_, third) = call(&service, "/login", None);
+        let third = third.expect("Should have set a session cookie");
+        clock.advance(Duration::from_secs(40));
+        assert_eq!("alice", call(&service, "/", Some(&third)).0);
+        clock.advance(Duration::from_secs(40));
+        assert_eq!("alice", call(&service, "/", Some(&third)).0);
+        clock.advance(Duration::from_secs(61));
+        assert_eq!("", call(&service, "/", Some(&third)).0);
+    }
+
+    #[test]
+    fn test_memory_sweep() {
+        let clock = MockClock::new();
+        let store = MemorySessions::with_clock(Duration::from_secs(60), clock.clone());
+        let save = || block_on(store.save(None, &SessionData::new(), false));
+        let expired = save().expect("Should have been able to save");
+        for _ in 0..1023 {
+            save().expect("Should have been able to save");
+        }
+        clock.advance(Duration::from_secs(61));
+
+        // loading drops only the expired session it finds
+        assert_eq!(
+            None,
+            block_on(store.load(&expired)).expect("Should have been able to load")
+        );
+        assert_eq!(1023, store.sessions().sessions.len());
+        // saving sweeps the rest once there are enough of them
+        save().expect("Should have been able to save");
+        assert_eq!(1024, store.sessions().sessions.len());
+        save().expect("Should have been able to save");
+        assert_eq!(2, store.sessions().sessions.len());
+    }
+
+    #[test]
+    fn test_cookie_sessions() {
+        let service = service(CookieSessions);
+        let (_, cookie) = call(&service, "/login", None);
+        let cookie = cookie.expect("Should have set a session cookie");
+        assert!(!cookie.contains("alice"));
+        assert_eq!("alice", call(&service, "/", Some(&cookie)).0);
+
+        let tampered = format!("{}x", cookie);
+        assert_eq!("", call(&service, "/", Some(&tampered)).0);
+    }
+
+    #[test]
+    fn test_needs_cookies() {
+        let service = Sessions::new(CookieSessions).wrap(router());
+        let req = Request::builder()
+            .uri("/")
+            .body(empty())
+            .expect("Should have been able to build request");
+        assert!(block_on(service.call(req)).is_err());
+    }
+
+    #[test]
+    fn test_needs_key() {
+        let service = CookieManager::new().wrap(Sessions::new(CookieSessions).wrap(router()));
+        let req = Request::builder()
+            .uri("/login")
+            .body(empty())
+            .expect("Should have been able to build request");
+        assert!(block_on(service.call(req)).is_err());
+    }
+}
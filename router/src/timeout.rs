@@ -0,0 +1,571 @@
+//! Deadlines for handling requests.
+//!
+//! `TimeoutService` gives every request a `Deadline`, either the global timeout or one set for the
+//! route the `Router` inside matched, even behind other middleware, counted from when the request
+//! arrived. It answers with 503 when the response is not ready by then.
+//! The handler's future is dropped at that point, so whatever it was waiting on is cancelled with
+//! it.
+//!
+//! The deadline is in the request extensions so handlers can pass what is left of it on to the
+//! services they call. A request that already carries an earlier `Deadline`, from an outer
+//! `TimeoutService`, keeps it.
+use bytes::Bytes;
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::{Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::service::Service;
+use luminal_handler::{boxed, full};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::route::{MatchedRoute, RouteLayers};
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+/// A future completing at an instant, from `Clock::sleep_until`.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The source of time for deadlines, replaceable by a `MockClock` in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+/// The system clock, with timers from the tokio runtime driving the service.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+struct MockState {
+    now: Instant,
+    sleepers: Vec<Waker>,
+}
+
+/// A clock that only moves when it is advanced, waking any sleeps it passes.
+///
+/// Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            state: Arc::new(Mutex::new(MockState {
+                now: Instant::now(),
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let sleepers = {
+            let mut state = self
+                .state
+                .lock()
+                .expect("Should not have poisoned the clock");
+            state.now += duration;
+            std::mem::take(&mut state.sleepers)
+        };
+        // every sleeper checks the new time and registers again if it is still early
+        for sleeper in sleepers {
+            sleeper.wake();
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.state
+            .lock()
+            .expect("Should not have poisoned the clock")
+            .now
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let state = Arc::clone(&self.state);
+        Box::pin(poll_fn(move |cx| {
+            let mut state = state.lock().expect("Should not have poisoned the clock");
+            if state.now >= deadline {
+                Poll::Ready(())
+            } else {
+                state.sleepers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }))
+    }
+}
+
+/// The instant by which a request should have its response, from the request extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn new(at: Instant) -> Deadline {
+        Deadline(at)
+    }
+
+    pub fn at(&self) -> Instant {
+        self.0
+    }
+
+    /// The time left as of `now`, zero once the deadline has passed.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.0.saturating_duration_since(now)
+    }
+}
+
+/// A timeout policy, built fluently.
+///
+/// Timeouts set for a route template apply to every method routed there and override the global
+/// timeout. Requests answered late get 503 with an empty body unless configured otherwise.
+pub struct Timeout {
+    timeout: Option<Duration>,
+    routes: HashMap<String, Duration>,
+    status: StatusCode,
+    body: Bytes,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Timeout")
+            .field("timeout", &self.timeout)
+            .field("routes", &self.routes)
+            .field("status", &self.status)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl Timeout {
+    /// A policy giving every request the timeout.
+    pub fn new(timeout: Duration) -> Timeout {
+        Timeout {
+            timeout: Some(timeout),
+            ..Timeout::per_route()
+        }
+    }
+
+    /// A policy only limiting requests to routes given a timeout with `route`.
+    pub fn per_route() -> Timeout {
+        Timeout {
+            timeout: None,
+            routes: HashMap::new(),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            body: Bytes::new(),
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// Give requests matching the route template, as it was added to the router, their own
+    /// timeout.
+    pub fn route(mut self, route: &str, timeout: Duration) -> Self {
+        self.routes.insert(route.to_owned(), timeout);
+        self
+    }
+
+    /// Answer late requests with this status instead, such as 504 for a gateway.
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Answer late requests with this plain text body.
+    pub fn body<T: Into<Bytes>>(mut self, body: T) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn wrap<S>(self, inner: S) -> TimeoutService<S> {
+        TimeoutService {
+            timeout: Arc::new(self),
+            inner,
+        }
+    }
+
+    // The deadline for a request that arrived at start given the timeout, unless it already has
+    // an earlier one.
+    fn deadline(outer: Option<Deadline>, start: Instant, timeout: Duration) -> Deadline {
+        match outer {
+            Some(outer) if outer.at() <= start + timeout => outer,
+            _ => Deadline::new(start + timeout),
+        }
+    }
+
+    // Answer with the timed out response if the response isn't ready by the deadline, dropping it.
+    //
+    // Once the router has matched the request the deadline is left to the route's layer, which
+    // may have chosen a later one.
+    fn bound(
+        config: Arc<Timeout>,
+        mut response: LuminalFuture,
+        deadline: Deadline,
+        routed: Option<Arc<OnceLock<MatchedRoute>>>,
+    ) -> LuminalFuture {
+        Box::pin(async move {
+            // the timer is made on the first poll, inside whatever runtime drives the service
+            let mut sleep = Some(config.clock.sleep_until(deadline.at()));
+            poll_fn(|cx| {
+                if let Poll::Ready(result) = response.as_mut().poll(cx) {
+                    return Poll::Ready(result);
+                }
+                let expired = match sleep.as_mut() {
+                    Some(sleep) => sleep.as_mut().poll(cx).is_ready(),
+                    None => false,
+                };
+                if !expired {
+                    return Poll::Pending;
+                }
+                if routed.as_ref().is_some_and(|routed| routed.get().is_some()) {
+                    sleep = None;
+                    return Poll::Pending;
+                }
+                Poll::Ready(Ok(config.timed_out()))
+            })
+            .await
+        })
+    }
+
+    fn timed_out(&self) -> Response<LuminalBody> {
+        let mut response = Response::new(full(self.body.clone()));
+        *response.status_mut() = self.status;
+        if !self.body.is_empty() {
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+        }
+        response
+    }
+}
+
+/// A service bounding the time its inner service takes to respond, see `Timeout`.
+pub struct TimeoutService<S> {
+    timeout: Arc<Timeout>,
+    inner: S,
+}
+
+impl<S: Clone> Clone for TimeoutService<S> {
+    fn clone(&self) -> Self {
+        TimeoutService {
+            timeout: Arc::clone(&self.timeout),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for TimeoutService<S>
+where
+    S: Service<
+        Request<LuminalBody>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(boxed);
+        let start = self.timeout.clock.now();
+        let outer = req.extensions().get::<Deadline>().copied();
+        let deadline = self
+            .timeout
+            .timeout
+            .map(|timeout| Timeout::deadline(outer, start, timeout));
+        if let Some(deadline) = deadline {
+            req.extensions_mut().insert(deadline);
+        }
+        let routed = if self.timeout.routes.is_empty() {
+            None
+        } else {
+            // the route's own timeout replaces the global one once the router matched it
+            let config = Arc::clone(&self.timeout);
+            Some(RouteLayers::push(
+                &mut req,
+                move |matched, mut req, next| {
+                    let timeout = matched
+                        .route()
+                        .and_then(|route| config.routes.get(route))
+                        .copied()
+                        .or(config.timeout);
+                    let timeout = match timeout {
+                        Some(timeout) => timeout,
+                        None => return next(req),
+                    };
+                    let deadline = Timeout::deadline(outer, start, timeout);
+                    req.extensions_mut().insert(deadline);
+                    Timeout::bound(Arc::clone(&config), next(req), deadline, None)
+                },
+            ))
+        };
+
+        let response = self.inner.call(req);
+        match deadline {
+            Some(deadline) => Timeout::bound(Arc::clone(&self.timeout), response, deadline, routed),
+            None => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::task::noop_waker;
+    use http_body_util::BodyExt;
+    use hyper::service::service_fn;
+    use luminal_handler::empty;
+
+    use std::future::pending;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Context;
+
+    use super::*;
+    use crate::{ConcurrencyLimit, FnRouteBuilder, Router};
+
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn router(dropped: Arc<AtomicBool>) -> Router {
+        FnRouteBuilder::new()
+            .get("/fast", |req| async move {
+                let deadline = req
+                    .extensions()
+                    .get::<Deadline>()
+                    .copied()
+                    .expect("Should have had a deadline");
+                Ok(Response::new(full(format!("{:?}", deadline.at()))))
+            })
+            .expect("Should have been able to add route")
+            .get("/slow", move |_req| {
+                let flag = DropFlag(Arc::clone(&dropped));
+                async move {
+                    let _flag = flag;
+                    pending::<()>().await;
+                    Ok(Response::new(empty()))
+                }
+            })
+            .expect("Should have been able to add route")
+            .get("/report/:id", |_req| async {
+                pending::<()>().await;
+                Ok(Response::new(empty()))
+            })
+            .expect("Should have been able to add route")
+            .build()
+    }
+
+    fn get(uri: &str) -> Request<LuminalBody> {
+        Request::builder()
+            .uri(uri)
+            .body(empty())
+            .expect("Should have been able to build request")
+    }
+
+    fn body(response: Response<LuminalBody>) -> String {
+        let body = block_on(response.into_body().collect())
+            .expect("Should have been able to resolve body concat")
+            .to_bytes();
+        String::from_utf8(body.to_vec()).expect("Should have been a utf-8 body")
+    }
+
+    #[test]
+    fn test_deadline() {
+        let clock = MockClock::new();
+        let service = Timeout::new(Duration::from_secs(5))
+            .clock(clock.clone())
+            .wrap(router(Arc::default()));
+
+        let response =
+            block_on(service.call(get("/fast"))).expect("Should have been able to run call");
+        assert_eq!(StatusCode::OK, response.status());
+        let expected = clock.now() + Duration::from_secs(5);
+        assert_eq!(format!("{:?}", expected), body(response));
+
+        // an earlier deadline from further out is kept
+        let mut req = get("/fast");
+        let outer = Deadline::new(clock.now() + Duration::from_secs(1));
+        req.extensions_mut().insert(outer);
+        let response = block_on(service.call(req)).expect("Should have been able to run call");
+        assert_eq!(format!("{:?}", outer.at()), body(response));
+        assert_eq!(Duration::from_secs(1), outer.remaining(clock.now()));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let clock = MockClock::new();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let service = Timeout::new(Duration::from_secs(5))
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .body("Timed out")
+            .clock(clock.clone())
+            .wrap(router(Arc::clone(&dropped)));
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut response = service.call(get("/slow"));
+        assert!(response.as_mut().poll(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(4));
+        assert!(response.as_mut().poll(&mut cx).is_pending());
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        clock.advance(Duration::from_secs(1));
+        match response.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(response)) => {
+                assert!(dropped.load(Ordering::SeqCst));
+                assert_eq!(StatusCode::GATEWAY_TIMEOUT, response.status());
+                assert_eq!(
+                    "text/plain; charset=utf-8",
+                    response.headers()[CONTENT_TYPE]
+                );
+                assert_eq!("Timed out", body(response));
+            }
+            _ => panic!("Should have timed out"),
+        }
+    }
+
+    #[test]
+    fn test_per_route() {
+        let clock = MockClock::new();
+        let service = Timeout::per_route()
+            .route("/report/:id", Duration::from_secs(30))
+            .clock(clock.clone())
+            .wrap(router(Arc::default()));
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut report = service.call(get("/report/1"));
+        let mut slow = service.call(get("/slow"));
+        assert!(report.as_mut().poll(&mut cx).is_pending());
+        assert!(slow.as_mut().poll(&mut cx).is_pending());
+
+        clock.advance(Duration::from_secs(30));
+        match report.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(response)) => {
+                assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+                assert!(!response.headers().contains_key(CONTENT_TYPE));
+            }
+            _ => panic!("Should have timed out"),
+        }
+        // without a global timeout the other routes are left alone
+        assert!(slow.as_mut().poll(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn test_route_outlasts_global() {
+        let clock = MockClock::new();
+        let service = Timeout::new(Duration::from_secs(5))
+            .route("/report/:id", Duration::from_secs(30))
+            .clock(clock.clone())
+            .wrap(router(Arc::default()));
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut report = service.call(get("/report/1"));
+        let mut slow = service.call(get("/slow"));
+        assert!(report.as_mut().poll(&mut cx).is_pending());
+        assert!(slow.as_mut().poll(&mut cx).is_pending());
+
+        clock.advance(Duration::from_secs(5));
+        assert!(slow.as_mut().poll(&mut cx).is_ready());
+        assert!(report.as_mut().poll(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(25));
+        assert!(report.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_route_outlasts_global_deferred() {
+        let clock = MockClock::new();
+        // the limiter only calls the router from within its future
+        let service = Timeout::new(Duration::from_secs(5))
+            .route("/report/:id", Duration::from_secs(30))
+            .clock(clock.clone())
+            .wrap(ConcurrencyLimit::new(10).wrap(router(Arc::default())));
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut report = service.call(get("/report/1"));
+        let mut slow = service.call(get("/slow"));
+        assert!(report.as_mut().poll(&mut cx).is_pending());
+        assert!(slow.as_mut().poll(&mut cx).is_pending());
+
+        clock.advance(Duration::from_secs(5));
+        assert!(slow.as_mut().poll(&mut cx).is_ready());
+        assert!(report.as_mut().poll(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(24));
+        assert!(report.as_mut().poll(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(1));
+        assert!(report.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_plain_service() {
+        let clock = MockClock::new();
+        let service = Timeout::new(Duration::from_secs(5))
+            .route("/slow", Duration::from_secs(30))
+            .clock(clock.clone())
+            .wrap(service_fn(|_req: Request<LuminalBody>| -> LuminalFuture {
+                Box::pin(async {
+                    pending::<()>().await;
+                    Ok(Response::new(empty()))
+                })
+            }));
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut response = service.call(get("/slow"));
+        assert!(response.as_mut().poll(&mut cx).is_pending());
+        // with no router to match the route, only the global timeout applies
+        clock.advance(Duration::from_secs(5));
+        assert!(response.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_tokio_clock() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("Should have been able to build a runtime");
+        let service = Timeout::new(Duration::from_millis(10)).wrap(router(Arc::default()));
+        let response = runtime
+            .block_on(service.call(get("/slow")))
+            .expect("Should have been able to run call");
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+    }
+}
@@ -1,15 +1,27 @@
+//! A path trie mapping route templates, such as `/user/:user_id`, to values.
+//!
+//! `RouteTree` is what the `Router` uses to find a service for each method, but it holds any value
+//! so it is just as useful for permission maps or for routing messages that aren't HTTP at all.
 use std::collections::BTreeMap;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::str::Split;
 
-use error::*;
+use crate::error::*;
+use crate::params::Params;
 
 /// Route mapping as a radix tree.
+#[derive(Clone, Debug)]
 pub struct RouteTree<T> {
     root: PathNode<T>,
 }
 
+impl<T> Default for RouteTree<T> {
+    fn default() -> Self {
+        RouteTree::empty_root()
+    }
+}
+
 impl<T> RouteTree<T> {
     /// Create a new route mapping with an index node with no value.
     pub fn empty_root() -> Self {
@@ -23,19 +35,24 @@ impl<T> RouteTree<T> {
     /// This method will update the internal tree used to store searchable routes. It will append
     /// any unknown path components in the route and assign the value to the new, full route.
     pub fn add(&mut self, route: &str, value: T) -> Result<&mut Self> {
-        let path = route.trim_right_matches('/');
+        let path = route.trim_end_matches('/');
         let tokens: Vec<&str> = path.split('/').collect();
-        if tokens[0] != "" {
-            bail!("Paths must start with a slash (/)")
+        if !tokens[0].is_empty() {
+            bail!(ErrorKind::InvalidRoute(route.to_owned()))
+        }
+        if tokens[..tokens.len() - 1]
+            .iter()
+            .any(|token| token.starts_with('*'))
+        {
+            bail!(ErrorKind::CatchAll(route.to_owned()))
         }
 
         // updating the root route value is a special case that doesn't require any trie
         // traversal
         if tokens.len() == 1 {
-            self.root.value = Some(value);
+            self.root.assign(route, value);
             return Ok(self);
         }
-
         // limit the borrow of self needed to update the internal tree so that this method can
         // return a reference to this struct to support fluent calling
         {
@@ -52,14 +69,31 @@ impl<T> RouteTree<T> {
                 // start with the first non-root component of the route
                 .skip(1)
                 .fold(Vec::new(), |mut created, token| {
-                    let last = last_existing.pop().expect("Should always have a last component");
-                    if token.starts_with(':') {
+                    let last = last_existing
+                        .pop()
+                        .expect("Should always have a last component");
+                    // once a component is new, everything after it is new as well; the children
+                    // of the last existing component aren't the children of the new one
+                    if !created.is_empty() {
+                        last_existing.push(last);
+                        created.push(PathNode::for_token(token));
+                        return created;
+                    }
+                    if token.starts_with('*') {
+                        if last.catch_all.deref_mut().is_none() {
+                            last_existing.push(last);
+                            created.push(PathNode::for_token(token));
+                            return created;
+                        }
+                        let next = last.catch_all.deref_mut().as_mut().unwrap();
+                        last_existing.push(next);
+                    } else if token.starts_with(':') {
                         // this is a guard because if it was an if..else then the borrow from
                         // last.params would live for the expression, both branches, not only the
                         // one where the dereferenced option contains Some
                         if last.params.deref_mut().is_none() {
                             last_existing.push(last);
-                            created.push(PathNode::new("*", None));
+                            created.push(PathNode::for_token(token));
                             return created;
                         }
                         let next = last.params.deref_mut().as_mut().unwrap();
@@ -76,7 +110,7 @@ impl<T> RouteTree<T> {
                     // components to wire together
                     } else {
                         last_existing.push(last);
-                        created.push(PathNode::new(token, None));
+                        created.push(PathNode::for_token(token));
                     }
                     created
                 });
@@ -87,23 +121,70 @@ impl<T> RouteTree<T> {
         Ok(self)
     }
 
-    /// Find the value for the specific route.
+    /// Find the value for the requested path along with the path parameters it captured.
     ///
-    /// Traverses the routing tree to find the matching value. The outer `Option` is `None` if
-    /// thethe requested path is not found at all. The inner `Option` reference will be None if the
-    /// route is found but no value is assigned. The value will not be assigned in the
-    /// requested path is only a partial match for a longer path added to the route tree.
-    pub fn dispatch<'a>(&'a self, request_path: &str) -> Option<&'a Option<T>> {
-        let path = request_path.trim_left_matches('/');
-        if path == "" {
-            return Some(&self.root.value);
-        }
-        let mut tokens = path.split('/');
-        let iter = self.iter(&mut tokens);
-        if let Some(found) = iter.last() {
-            Some(&found.value)
+    /// Only a route that consumes the whole requested path and has a value assigned is a match,
+    /// and a dead end under a literal or a parameter falls back to the next best sibling, so a
+    /// catch-all still matches when a more specific route doesn't.
+    pub fn dispatch<'a, 'p>(&'a self, request_path: &'p str) -> Option<Match<'a, 'p, T>> {
+        let path = request_path.trim_start_matches('/');
+        let node = if path.is_empty() {
+            &self.root
         } else {
-            None
+            self.root.lookup(path.split('/'))?
+        };
+        match (&node.route, &node.value) {
+            (Some(route), Some(value)) => Some(Match {
+                route,
+                value,
+                params: Params::new(route, request_path),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Get the value assigned to exactly this route.
+    ///
+    /// Path parameters are matched by position, so `/user/:id` and `/user/:user_id` name the same
+    /// route.
+    pub fn get(&self, route: &str) -> Option<&T> {
+        let mut node = &self.root;
+        for token in RouteTree::<T>::route_tokens(route)? {
+            node = if token.starts_with('*') {
+                node.catch_all.deref().as_ref()?
+            } else if token.starts_with(':') {
+                node.params.deref().as_ref()?
+            } else {
+                node.next.get(token)?
+            };
+        }
+        node.value.as_ref()
+    }
+
+    /// Get a mutable reference to the value assigned to exactly this route.
+    pub fn get_mut(&mut self, route: &str) -> Option<&mut T> {
+        self.node_mut(route)?.value.as_mut()
+    }
+
+    /// Remove the value assigned to exactly this route, returning it.
+    ///
+    /// Any nodes that are left with neither a value nor further routes below them are pruned from
+    /// the tree, so removing every route leaves an empty tree behind.
+    pub fn remove(&mut self, route: &str) -> Option<T> {
+        let tokens: Vec<&str> = RouteTree::<T>::route_tokens(route)?.collect();
+        self.root.remove(&tokens)
+    }
+
+    /// Whether there are no values assigned to any route.
+    pub fn is_empty(&self) -> bool {
+        self.root.is_vacant()
+    }
+
+    /// Iterate over every route that has a value, yielding the route as it was added along with
+    /// its value.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![&self.root],
         }
     }
 
@@ -118,35 +199,27 @@ impl<T> RouteTree<T> {
         // the route isn't new, only the value is
         if created.is_empty() {
             if let Some(last) = last_existing.pop() {
-                last.value = Some(value);
+                last.assign(route, value);
             }
         // the route is new in part or total and needs to be connected into the existing routing
         // trie
         } else {
             if let Some(mut last) = created.pop() {
-                last.value = Some(value);
+                last.assign(route, value);
                 created.push(last);
             }
             while !created.is_empty() {
                 let node = created.pop();
                 if let Some(node) = node {
                     if let Some(last) = created.last_mut() {
-                        if node.segment == "*" {
-                            last.params = Box::new(Some(node));
-                        } else {
-                            last.next.insert(node.segment.clone(), node);
-                        }
+                        last.attach(node);
                     } else if let Some(last) = last_existing.pop() {
-                        if node.segment == "*" {
-                            last.params = Box::new(Some(node));
-                        } else {
-                            last.next.insert(node.segment.clone(), node);
-                        }
+                        last.attach(node);
                     } else {
-                        bail!("Could not fully wire up route {}", route);
+                        bail!(ErrorKind::Wiring(route.to_owned()));
                     }
                 } else {
-                    bail!("Created stack ran dry too soon!");
+                    bail!(ErrorKind::Wiring(route.to_owned()));
                 }
             }
         }
@@ -154,39 +227,64 @@ impl<T> RouteTree<T> {
         Ok(())
     }
 
-    fn iter<'a, 'b>(&'a self, tokens: &'b mut Split<'b, char>) -> Iter<'a, 'b, T> {
-        Iter {
-            tokens,
-            previous: &self.root,
+    // Split a route into the components below the root, if it is a valid route at all.
+    fn route_tokens(route: &str) -> Option<impl Iterator<Item = &str>> {
+        let mut tokens = route.trim_end_matches('/').split('/');
+        if tokens.next() != Some("") {
+            return None;
+        }
+        Some(tokens)
+    }
+
+    fn node_mut(&mut self, route: &str) -> Option<&mut PathNode<T>> {
+        let mut node = &mut self.root;
+        for token in RouteTree::<T>::route_tokens(route)? {
+            node = if token.starts_with('*') {
+                node.catch_all.deref_mut().as_mut()?
+            } else if token.starts_with(':') {
+                node.params.deref_mut().as_mut()?
+            } else {
+                node.next.get_mut(token)?
+            };
         }
+        Some(node)
     }
 }
 
-// An internal struct used for fast traversal during dispatching.
-struct Iter<'a, 'b, T: 'a> {
-    // Working directly with the `Split` is more efficient than collecting it into a `Vec`, based
-    // on benchmarking both approaches.
-    tokens: &'b mut Split<'b, char>,
-    previous: &'a PathNode<T>,
+/// A successful `RouteTree::dispatch`.
+#[derive(Debug)]
+pub struct Match<'a, 'p, T> {
+    /// The route the value was added with.
+    pub route: &'a str,
+    /// The value assigned to the route.
+    pub value: &'a T,
+    /// The path parameters captured from the requested path.
+    pub params: Params<'a, 'p>,
+}
+
+/// Iterator over the routes in a `RouteTree` that have values, see `RouteTree::iter`.
+pub struct Iter<'a, T> {
+    stack: Vec<&'a PathNode<T>>,
 }
 
-// An impl that uses references to traversal the routing tree as fast and as cheaply as possible.
-impl<'a, 'b, T> Iterator for Iter<'a, 'b, T> {
-    type Item = &'a PathNode<T>;
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (&'a str, &'a T);
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(token) = self.tokens.next() {
-            if let Some(next) = self.previous.next.get(token) {
-                self.previous = next;
-                Some(next)
-            } else if let Some(next) = self.previous.params.deref().as_ref() {
-                self.previous = next;
-                Some(next)
-            } else {
-                None
+        while let Some(node) = self.stack.pop() {
+            // push in reverse so that routes come out in the order of their segments, with a
+            // parameter after any literal siblings and a catch-all last of all
+            if let Some(catch_all) = node.catch_all.deref() {
+                self.stack.push(catch_all);
+            }
+            if let Some(params) = node.params.deref() {
+                self.stack.push(params);
+            }
+            self.stack.extend(node.next.values().rev());
+            if let (Some(route), Some(value)) = (&node.route, &node.value) {
+                return Some((route, value));
             }
-        } else {
-            None
         }
+        None
     }
 }
 
@@ -196,7 +294,7 @@ impl<'a, 'b, T> Iterator for Iter<'a, 'b, T> {
 // to connect the nodes. The params field handles path parameter links, allowing one value for
 // routes ending with a parameter and more routes to be added with additional path parameters
 // beyond this node.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 struct PathNode<T> {
     // The specific segment within the original path for this node, will be "*" for a path
     // parameter as a convenience.
@@ -206,6 +304,11 @@ struct PathNode<T> {
     // A node representing a value for a path parameter may also have connected edges to further
     // nodes.
     params: Box<Option<PathNode<T>>>,
+    // A catch-all parameter, with the segment "**", matches every remaining segment so it never
+    // has edges of its own.
+    catch_all: Box<Option<PathNode<T>>>,
+    // The full route the value was added with, which keeps the names of any path parameters.
+    route: Option<String>,
     // An optional value.
     value: Option<T>,
 }
@@ -216,9 +319,106 @@ impl<T> PathNode<T> {
             segment: segment.to_owned(),
             next: BTreeMap::new(),
             params: Box::new(None),
+            catch_all: Box::new(None),
+            route: None,
             value,
         }
     }
+
+    // A new, empty node for a component of a route being added.
+    fn for_token(token: &str) -> PathNode<T> {
+        if token.starts_with('*') {
+            PathNode::new("**", None)
+        } else if token.starts_with(':') {
+            PathNode::new("*", None)
+        } else {
+            PathNode::new(token, None)
+        }
+    }
+
+    // Connect a child node below this one, according to the kind of segment it is for.
+    fn attach(&mut self, node: PathNode<T>) {
+        match node.segment.as_str() {
+            "*" => *self.params = Some(node),
+            "**" => *self.catch_all = Some(node),
+            _ => {
+                self.next.insert(node.segment.clone(), node);
+            }
+        }
+    }
+
+    fn assign(&mut self, route: &str, value: T) {
+        self.route = Some(route.to_owned());
+        self.value = Some(value);
+    }
+
+    // Take the value at the end of the remaining tokens, pruning any child left vacant on the way
+    // back up.
+    fn remove(&mut self, tokens: &[&str]) -> Option<T> {
+        let (token, rest) = match tokens.split_first() {
+            Some(split) => split,
+            None => {
+                self.route = None;
+                return self.value.take();
+            }
+        };
+        if token.starts_with('*') {
+            let child = self.catch_all.deref_mut().as_mut()?;
+            let value = child.remove(rest);
+            if child.is_vacant() {
+                *self.catch_all = None;
+            }
+            value
+        } else if token.starts_with(':') {
+            let child = self.params.deref_mut().as_mut()?;
+            let value = child.remove(rest);
+            if child.is_vacant() {
+                *self.params = None;
+            }
+            value
+        } else {
+            let child = self.next.get_mut(*token)?;
+            let value = child.remove(rest);
+            if child.is_vacant() {
+                self.next.remove(*token);
+            }
+            value
+        }
+    }
+
+    fn is_vacant(&self) -> bool {
+        self.value.is_none()
+            && self.next.is_empty()
+            && self.params.is_none()
+            && self.catch_all.is_none()
+    }
+
+    // Find the node with a value for the remaining tokens, trying a literal, then a parameter and
+    // then a catch-all at each level.
+    fn lookup(&self, mut tokens: Split<'_, char>) -> Option<&PathNode<T>> {
+        if self.segment == "**" {
+            return Some(self);
+        }
+        let token = match tokens.next() {
+            Some(token) => token,
+            None => return self.value.as_ref().map(|_| self),
+        };
+        self.next
+            .get(token)
+            .and_then(|node| node.lookup(tokens.clone()))
+            .or_else(|| {
+                self.params
+                    .deref()
+                    .as_ref()
+                    .and_then(|node| node.lookup(tokens.clone()))
+            })
+            .or_else(|| {
+                self.catch_all
+                    .deref()
+                    .as_ref()
+                    .filter(|node| node.value.is_some())
+            })
+    }
 }
 
 #[cfg(test)]
@@ -260,7 +460,7 @@ mod tests {
     pub fn test_add() {
         let mut expected = PathNode::new("", None);
         let mut foo = PathNode::new("foo", None);
-        let bar = PathNode::new("bar", Some(String::from("Bar")));
+        let bar = leaf("bar", "/foo/bar", "Bar");
         foo.next.insert(String::from("bar"), bar);
         expected.next.insert(String::from("foo"), foo);
         let mut route = RouteTree::empty_root();
@@ -289,11 +489,9 @@ mod tests {
     #[test]
     pub fn test_add_extend() {
         let mut expected = PathNode::new("", None);
-        let mut foo = PathNode::new("foo", Some(String::from("Foo")));
-        foo.next.insert(
-            String::from("bar"),
-            PathNode::new("bar", Some(String::from("Bar"))),
-        );
+        let mut foo = leaf("foo", "/foo/", "Foo");
+        foo.next
+            .insert(String::from("bar"), leaf("bar", "/foo/bar/", "Bar"));
         expected.next.insert(String::from("foo"), foo);
         let mut route = RouteTree::empty_root();
         route
@@ -369,117 +567,346 @@ mod tests {
         assert_dispatch(&route, "/foo", "");
     }
 
-    // Test iterating partially into the tree.
+    // Test dispatching a path that is only a prefix of a route.
     #[test]
-    pub fn test_iter_partial() {
+    pub fn test_dispatch_partial() {
         let mut route = RouteTree::empty_root();
         route
             .add("/foo/bar/baz", String::from("Baz"))
             .expect("Should have been able to add route.");
+        assert!(route.dispatch("/foo").is_none());
+        assert!(route.dispatch("/foo/bar").is_none());
+    }
 
-        let mut tokens = "foo".split('/');
-
-        let mut iter = route.iter(&mut tokens);
-        assert_eq!(
-            Some(&String::from("foo")),
-            iter.next().map(|node| &node.segment)
-        );
-        assert_eq!(None, iter.next());
+    // Test dispatching with a requested path that misses.
+    #[test]
+    pub fn test_dispatch_miss() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/foo/bar/baz", String::from("Baz"))
+            .expect("Should have been able to add route.");
+        assert!(route.dispatch("/foo/baz").is_none());
+        assert!(route.dispatch("/foo/bar/baz/qux").is_none());
     }
 
-    // Test iterating with a requested path that misses.
+    // Test dispatching a requested path that is in the tree.
     #[test]
-    pub fn test_iter_miss() {
+    pub fn test_dispatch_hit() {
         let mut route = RouteTree::empty_root();
         route
             .add("/foo/bar/baz", String::from("Baz"))
             .expect("Should have been able to add route.");
+        let found = route
+            .dispatch("/foo/bar/baz")
+            .expect("Should have found route");
+        assert_eq!("/foo/bar/baz", found.route);
+        assert_eq!("Baz", found.value);
+        assert!(found.params.is_empty());
+    }
+
+    // Test dispatching with a path parameter.
+    #[test]
+    pub fn test_dispatch_path() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/foo/:bar", String::from("Foo"))
+            .expect("Should have been able to add route.");
+        let found = route.dispatch("/foo/123").expect("Should have found route");
+        assert_eq!("Foo", found.value);
+        assert_eq!(Some("123"), found.params.get("bar"));
+    }
 
-        let mut tokens = "foo/baz".split('/');
+    // Test finding a route along with its captured parameters.
+    #[test]
+    pub fn test_dispatch_params() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/company/:company/user/:user", String::from("User"))
+            .expect("Should have added route without error")
+            .add("/company/:id", String::from("Company"))
+            .expect("Should have added route without error");
 
-        let mut iter = route.iter(&mut tokens);
+        let found = route
+            .dispatch("/company/123/user/456")
+            .expect("Should have found route");
+        assert_eq!("User", found.value);
+        assert_eq!("/company/:company/user/:user", found.route);
+        assert_eq!(Some("123"), found.params.get("company"));
+        assert_eq!(Some("456"), found.params.get("user"));
         assert_eq!(
-            Some(&String::from("foo")),
-            iter.next().map(|node| &node.segment)
+            vec![("company", "123"), ("user", "456")],
+            found.params.iter().collect::<Vec<_>>()
         );
-        assert_eq!(None, iter.next());
+
+        let found = route
+            .dispatch("/company/789")
+            .expect("Should have found route");
+        assert_eq!("Company", found.value);
+        assert_eq!(Some("789"), found.params.get("id"));
+        assert_eq!(None, found.params.get("company"));
     }
 
-    // Test iterating a requested path that is in the tree.
+    // Test that dispatch only matches a complete path to a route with a value.
     #[test]
-    pub fn test_iter_hit() {
+    pub fn test_dispatch_incomplete() {
         let mut route = RouteTree::empty_root();
         route
+            .add("/foo", String::from("Foo"))
+            .expect("Should have added route without error")
             .add("/foo/bar/baz", String::from("Baz"))
-            .expect("Should have been able to add route.");
+            .expect("Should have added route without error");
 
-        let mut tokens = "foo/bar/baz".split('/');
+        assert!(route.dispatch("/foo/qux").is_none());
+        assert!(route.dispatch("/foo/bar").is_none());
+        assert!(route.dispatch("/").is_none());
+        assert!(route
+            .dispatch("/foo")
+            .map(|found| found.params.is_empty())
+            .unwrap_or(false));
+    }
 
-        let mut iter = route.iter(&mut tokens);
+    // Test a catch-all parameter capturing the rest of the path, behind literals and parameters.
+    #[test]
+    pub fn test_dispatch_catch_all() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/static/*path", String::from("Static"))
+            .expect("Should have added route without error")
+            .add("/static/:file", String::from("File"))
+            .expect("Should have added route without error")
+            .add("/static/index", String::from("Index"))
+            .expect("Should have added route without error");
+
+        let found = route
+            .dispatch("/static/css/site/main.css")
+            .expect("Should have found catch-all route");
+        assert_eq!("Static", found.value);
+        assert_eq!(Some("css/site/main.css"), found.params.get("path"));
+        assert_eq!("File", route.dispatch("/static/main.css").unwrap().value);
+        assert_eq!("Index", route.dispatch("/static/index").unwrap().value);
+        assert!(route.dispatch("/static").is_none());
+
+        assert_eq!(Some(String::from("Static")), route.remove("/static/*rest"));
+        assert!(route.dispatch("/static/css/main.css").is_none());
+    }
+
+    // Test that a catch-all has to be the end of a route.
+    #[test]
+    pub fn test_catch_all_last() {
+        let mut route = RouteTree::empty_root();
+        match route.add("/static/*path/more", String::from("Static")) {
+            Err(Error(ErrorKind::CatchAll(_), _)) => (),
+            _ => panic!("Should have rejected a catch-all in the middle of a route"),
+        }
+    }
+
+    // Test looking up and changing a value in place by its route.
+    #[test]
+    pub fn test_get_mut() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/foo/:foo/bar", String::from("Bar"))
+            .expect("Should have added route without error");
+
+        route
+            .get_mut("/foo/:other/bar")
+            .expect("Should have found route")
+            .push_str("Baz");
+
+        assert_eq!(Some(&String::from("BarBaz")), route.get("/foo/:foo/bar"));
+        assert_eq!(None, route.get_mut("/foo/:foo"));
+        assert_eq!(None, route.get_mut("foo"));
+    }
+
+    // Test removing a value from the tree.
+    #[test]
+    pub fn test_remove() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/foo/bar", String::from("Bar"))
+            .expect("Should have added route without error")
+            .add("/foo/baz", String::from("Baz"))
+            .expect("Should have added route without error");
+
+        assert_eq!(Some(String::from("Bar")), route.remove("/foo/bar"));
+        assert_eq!(None, route.remove("/foo/bar"));
+        assert!(route.dispatch("/foo/bar").is_none());
+        assert_dispatch(&route, "/foo/baz", "Baz");
+    }
+
+    // Test that removing routes prunes nodes that no longer lead to a value.
+    #[test]
+    pub fn test_remove_prune() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/foo", String::from("Foo"))
+            .expect("Should have added route without error")
+            .add("/foo/:foo/bar/baz", String::from("Baz"))
+            .expect("Should have added route without error")
+            .add("/qux/quux", String::from("Quux"))
+            .expect("Should have added route without error");
+
+        assert_eq!(Some(String::from("Baz")), route.remove("/foo/:foo/bar/baz"));
+        assert_eq!(Some(String::from("Quux")), route.remove("/qux/quux"));
+
+        let mut expected = PathNode::new("", None);
+        expected
+            .next
+            .insert(String::from("foo"), leaf("foo", "/foo", "Foo"));
+        assert_eq!(expected, route.root);
+
+        assert_eq!(Some(String::from("Foo")), route.remove("/foo/"));
+        assert!(route.is_empty());
+        assert_eq!(PathNode::new("", None), route.root);
+    }
+
+    // Test that removing a route that was never added leaves the tree alone.
+    #[test]
+    pub fn test_remove_miss() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/foo/bar", String::from("Bar"))
+            .expect("Should have added route without error");
+
+        assert_eq!(None, route.remove("/foo"));
+        assert_eq!(None, route.remove("/foo/bar/baz"));
+        assert_eq!(None, route.remove("/foo/:foo"));
+        assert_eq!(None, route.remove("foo/bar"));
+        assert_dispatch(&route, "/foo/bar", "Bar");
+    }
+
+    // Test iterating over every route in segment order.
+    #[test]
+    pub fn test_iter_routes() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/foo/:foo", String::from("Param"))
+            .expect("Should have added route without error")
+            .add("/foo/bar", String::from("Bar"))
+            .expect("Should have added route without error")
+            .add("/", String::from("Root"))
+            .expect("Should have added route without error")
+            .add("/baz", String::from("Baz"))
+            .expect("Should have added route without error");
+
+        let routes = route
+            .iter()
+            .map(|(route, value)| (route, value.as_str()))
+            .collect::<Vec<_>>();
         assert_eq!(
-            Some(&String::from("foo")),
-            iter.next().map(|node| &node.segment)
+            vec![
+                ("/", "Root"),
+                ("/baz", "Baz"),
+                ("/foo/bar", "Bar"),
+                ("/foo/:foo", "Param"),
+            ],
+            routes
         );
+    }
+
+    // Test that a cloned tree is independent of the original.
+    #[test]
+    pub fn test_clone() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/foo", String::from("Foo"))
+            .expect("Should have added route without error");
+        let mut cloned = route.clone();
+        cloned.remove("/foo");
+
+        assert_dispatch(&route, "/foo", "Foo");
+        assert!(cloned.dispatch("/foo").is_none());
+    }
+
+    // Test the typed error for a route without a leading slash.
+    #[test]
+    pub fn test_invalid_route() {
+        let mut route = RouteTree::empty_root();
+        match route.add("foo", String::from("Foo")) {
+            Err(Error(ErrorKind::InvalidRoute(ref bad), _)) => assert_eq!("foo", bad),
+            _ => panic!("Should have failed with an invalid route"),
+        }
+    }
+
+    // Test adding a route whose tail shares a segment with a route at the root.
+    #[test]
+    pub fn test_add_below_new_segment() {
+        let mut route = RouteTree::empty_root();
+        route
+            .add("/b", String::from("B"))
+            .expect("Should have added route without error")
+            .add("/x/b", String::from("XB"))
+            .expect("Should have added route without error");
         assert_eq!(
-            Some(&String::from("bar")),
-            iter.next().map(|node| &node.segment)
+            Some("XB"),
+            route.dispatch("/x/b").map(|found| found.value.as_str())
         );
         assert_eq!(
-            Some(String::from("Baz")),
-            iter.next().as_ref().and_then(|node| node.value.clone())
+            Some("B"),
+            route.dispatch("/b").map(|found| found.value.as_str())
         );
-        assert_eq!(None, iter.next());
+        assert!(route.dispatch("/b/x").is_none());
+        assert_eq!(Some(&String::from("XB")), route.get("/x/b"));
     }
 
-    // Test iterating with a path parameter.
+    // Test adding a route whose tail shares a parameter with a route at the root.
     #[test]
-    pub fn test_iter_path() {
+    pub fn test_add_param_below_new_segment() {
         let mut route = RouteTree::empty_root();
         route
-            .add("/foo/:bar", String::from("Foo"))
-            .expect("Should have been able to add route.");
-
-        let mut tokens = "foo/123".split('/');
-
-        let mut iter = route.iter(&mut tokens);
-        assert!(
-            iter.next().map(|node| node.params.is_some()).unwrap(),
-            "First component should have had a params opt"
-        );
+            .add("/:id", String::from("ID"))
+            .expect("Should have added route without error")
+            .add("/x/:id", String::from("XID"))
+            .expect("Should have added route without error");
+        let found = route.dispatch("/x/1").expect("Should have found /x/:id");
+        assert_eq!("XID", found.value);
+        assert_eq!(Some("1"), found.params.get("id"));
         assert_eq!(
-            Some(&Some(String::from("Foo"))),
-            iter.next().map(|node| &node.value)
+            Some("ID"),
+            route.dispatch("/1").map(|found| found.value.as_str())
         );
+        assert!(route.dispatch("/1/x").is_none());
+        assert_eq!(Some(&String::from("XID")), route.get("/x/:id"));
     }
 
     fn sub_route2(parent: &str, first: &str, second: &str) -> PathNode<String> {
         let mut node = PathNode::new(parent, None);
         node.next.insert(
             first.to_owned(),
-            PathNode::new(first, Some(String::from(first.to_uppercase()))),
+            leaf(
+                first,
+                &format!("/{}/{}", parent, first),
+                &first.to_uppercase(),
+            ),
         );
         node.next.insert(
             second.to_owned(),
-            PathNode::new(second, Some(String::from(second.to_uppercase()))),
+            leaf(
+                second,
+                &format!("/{}/{}", parent, second),
+                &second.to_uppercase(),
+            ),
         );
         node
     }
 
+    fn leaf(segment: &str, route: &str, value: &str) -> PathNode<String> {
+        let mut node = PathNode::new(segment, None);
+        node.assign(route, value.to_owned());
+        node
+    }
+
+    // An empty value asserts that the path is only part of a longer route, so nothing is found.
     fn assert_dispatch(route: &RouteTree<String>, route_path: &str, value: &str) {
-        let found = route.dispatch(route_path);
-        if let Some(found) = found {
+        let found = route.dispatch(route_path).map(|found| found.value.as_str());
+        if value.is_empty() {
             assert_eq!(
-                if value.is_empty() {
-                    None
-                } else {
-                    Some(value.to_owned())
-                },
-                *found,
-                "Could not find value, {:?}",
+                None, found,
+                "Should not have found a value, {:?}",
                 route.root
             );
         } else {
-            panic!("Not found");
+            assert_eq!(Some(value), found, "Could not find value, {:?}", route.root);
         }
     }
 }
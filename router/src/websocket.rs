@@ -0,0 +1,821 @@
+//! WebSocket routes.
+//!
+//! `WebSocketUpgrade` is a route target that checks the opening handshake of RFC 6455, picks a
+//! subprotocol, answers 101 and, once hyper hands over the connection, runs its function with a
+//! `WebSocket`, a `Stream` and `Sink` of messages, along with the request's parts, so whatever
+//! middleware put in the extensions is still at hand. Pings are answered and close frames
+//! returned on their own, and messages over the size limit end the stream with an error.
+//!
+//! Browsers send cookies along with cross-site WebSocket handshakes, so a handshake whose
+//! `Origin` is neither the host it was sent to nor an allowed origin is turned away with 403.
+//!
+//! Upgrades need HTTP/1.1 and a server that serves connections with upgrades. `loopback` connects
+//! to a service over an in-memory connection, which is all tests need.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::{Sink, Stream};
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_TYPE, ORIGIN, SEC_WEBSOCKET_ACCEPT,
+    SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use http::request::Parts;
+use http::{Method, Request, Response, StatusCode, Version};
+use hyper::body::Incoming;
+use hyper::service::Service;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use log::debug;
+use luminal_handler::full;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Instant, Sleep};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::csrf::allowed_origin;
+use crate::{BoxError, LuminalBody, LuminalFuture};
+
+pub use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+pub use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+pub use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+
+// Whatever a WebSocket runs over, an upgraded connection or one end of an in-memory pipe.
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+// Sends pings while the connection is quiet and gives up on it when they go unanswered.
+struct Keepalive {
+    every: Duration,
+    sleep: Pin<Box<Sleep>>,
+    waiting: bool,
+}
+
+/// An open WebSocket, reading messages as a `Stream` and sending them as a `Sink`.
+///
+/// Close it by sending a `Message::Close`, or with `SinkExt::close`, and keep reading until the
+/// stream ends to see the close handshake through.
+pub struct WebSocket {
+    stream: WebSocketStream<Box<dyn Io>>,
+    protocol: Option<String>,
+    keepalive: Option<Keepalive>,
+}
+
+impl WebSocket {
+    fn new(
+        stream: WebSocketStream<Box<dyn Io>>,
+        protocol: Option<String>,
+        ping_every: Option<Duration>,
+    ) -> WebSocket {
+        WebSocket {
+            stream,
+            protocol,
+            keepalive: ping_every.map(|every| Keepalive {
+                every,
+                sleep: Box::pin(tokio::time::sleep(every)),
+                waiting: false,
+            }),
+        }
+    }
+
+    /// The subprotocol agreed on in the handshake.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> Poll<WebSocketError> {
+        let keepalive = match &mut self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return Poll::Pending,
+        };
+        while keepalive.sleep.as_mut().poll(cx).is_ready() {
+            if keepalive.waiting {
+                return Poll::Ready(WebSocketError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "WebSocket ping went unanswered",
+                )));
+            }
+            let stream = Pin::new(&mut self.stream);
+            match stream.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    if let Err(error) = Pin::new(&mut self.stream).start_send(Message::Ping(
+                        tokio_tungstenite::tungstenite::Bytes::from_static(b"luminal"),
+                    )) {
+                        return Poll::Ready(error);
+                    }
+                    keepalive.waiting = true;
+                }
+                Poll::Ready(Err(error)) => return Poll::Ready(error),
+                // the sink is busy, which says as much about the connection as a ping would
+                Poll::Pending => {}
+            }
+            keepalive
+                .sleep
+                .as_mut()
+                .reset(Instant::now() + keepalive.every);
+        }
+        if let Poll::Ready(Err(error)) = Pin::new(&mut self.stream).poll_flush(cx) {
+            return Poll::Ready(error);
+        }
+        Poll::Pending
+    }
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Message, WebSocketError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Poll::Ready(error) = this.poll_keepalive(cx) {
+            return Poll::Ready(Some(Err(error)));
+        }
+        let next = Pin::new(&mut this.stream).poll_next(cx);
+        if let (Poll::Ready(Some(Ok(_))), Some(keepalive)) = (&next, &mut this.keepalive) {
+            keepalive.waiting = false;
+            let next_ping = Instant::now() + keepalive.every;
+            keepalive.sleep.as_mut().reset(next_ping);
+        }
+        next
+    }
+}
+
+impl Sink<Message> for WebSocket {
+    type Error = WebSocketError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.get_mut().stream).start_send(message)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_close(cx)
+    }
+}
+
+type Callback = dyn Fn(WebSocket, Parts) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync;
+
+/// A WebSocket route target, built fluently around the function that runs each connection.
+///
+/// Messages and frames are limited to 1 MiB by default, and no pings are sent unless asked for.
+/// Browsers may only connect from the host the request was sent to, unless their origin is
+/// allowed.
+#[derive(Clone)]
+pub struct WebSocketUpgrade {
+    callback: Arc<Callback>,
+    protocols: Vec<String>,
+    allowed_origins: Vec<String>,
+    max_message_size: usize,
+    max_frame_size: usize,
+    ping_every: Option<Duration>,
+}
+
+impl WebSocketUpgrade {
+    pub fn new<F, R>(function: F) -> WebSocketUpgrade
+    where
+        F: Fn(WebSocket, Parts) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        WebSocketUpgrade {
+            callback: Arc::new(move |socket, parts| Box::pin(function(socket, parts))),
+            protocols: Vec::new(),
+            allowed_origins: Vec::new(),
+            max_message_size: 1 << 20,
+            max_frame_size: 1 << 20,
+            ping_every: None,
+        }
+    }
+
+    /// The subprotocols spoken, in order of preference.
+    ///
+    /// The first of them the client offers is picked, and clients offering only others are
+    /// turned away with 400. Clients offering none get no subprotocol.
+    pub fn protocols<I, P>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Accept connections from other origins, such as `https://app.example.com`.
+    ///
+    /// Requests whose `Origin` is neither one of these nor the host they were sent to are turned
+    /// away with 403. Requests without an `Origin` come from outside browsers and are accepted.
+    pub fn allowed_origins<I, P>(mut self, origins: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<str>,
+    {
+        self.allowed_origins = origins
+            .into_iter()
+            .map(|origin| origin.as_ref().trim_end_matches('/').to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    pub fn max_message_size(mut self, max: usize) -> Self {
+        self.max_message_size = max;
+        self
+    }
+
+    pub fn max_frame_size(mut self, max: usize) -> Self {
+        self.max_frame_size = max;
+        self
+    }
+
+    /// Ping the client whenever it has been quiet this long, and end the stream with an error
+    /// when it doesn't answer before the next ping is due.
+    pub fn ping_every(mut self, every: Duration) -> Self {
+        self.ping_every = Some(every);
+        self
+    }
+
+    fn config(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .max_message_size(Some(self.max_message_size))
+            .max_frame_size(Some(self.max_frame_size))
+    }
+
+    // The accept key and subprotocol of a valid handshake, or the response turning it away.
+    fn handshake(
+        &self,
+        parts: &Parts,
+    ) -> ::std::result::Result<(String, Option<String>), Response<LuminalBody>> {
+        if parts.method != Method::GET || parts.version != Version::HTTP_11 {
+            return Err(reject(
+                StatusCode::BAD_REQUEST,
+                "WebSocket upgrades need a GET over HTTP/1.1",
+            ));
+        }
+        if !has_token(&parts.headers, &CONNECTION, "upgrade")
+            || !has_token(&parts.headers, &UPGRADE, "websocket")
+        {
+            let mut response = reject(StatusCode::UPGRADE_REQUIRED, "Expected a WebSocket upgrade");
+            response
+                .headers_mut()
+                .insert(UPGRADE, HeaderValue::from_static("websocket"));
+            response
+                .headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            return Err(response);
+        }
+        if parts
+            .headers
+            .get(SEC_WEBSOCKET_VERSION)
+            .map(HeaderValue::as_bytes)
+            != Some(b"13")
+        {
+            let mut response = reject(
+                StatusCode::UPGRADE_REQUIRED,
+                "Unsupported WebSocket version",
+            );
+            response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+            return Err(response);
+        }
+        let key = match parts.headers.get(SEC_WEBSOCKET_KEY) {
+            Some(key) if STANDARD.decode(key.as_bytes()).map(|key| key.len()) == Ok(16) => key,
+            _ => {
+                return Err(reject(
+                    StatusCode::BAD_REQUEST,
+                    "Missing or malformed Sec-WebSocket-Key",
+                ))
+            }
+        };
+        if let Some(origin) = parts.headers.get(ORIGIN) {
+            if !allowed_origin(origin, &parts.uri, &parts.headers, &self.allowed_origins) {
+                return Err(reject(StatusCode::FORBIDDEN, "Origin not allowed"));
+            }
+        }
+
+        let offered = tokens(&parts.headers, &SEC_WEBSOCKET_PROTOCOL);
+        let protocol = if offered.is_empty() || self.protocols.is_empty() {
+            None
+        } else {
+            match self
+                .protocols
+                .iter()
+                .find(|protocol| offered.iter().any(|offer| offer == *protocol))
+            {
+                Some(protocol) => Some(protocol.clone()),
+                None => {
+                    return Err(reject(
+                        StatusCode::BAD_REQUEST,
+                        "None of the offered subprotocols are supported",
+                    ))
+                }
+            }
+        };
+        Ok((derive_accept_key(key.as_bytes()), protocol))
+    }
+}
+
+fn reject(status: StatusCode, reason: &'static str) -> Response<LuminalBody> {
+    let mut response = Response::new(full(reason));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+// The comma separated tokens of every instance of a header.
+fn tokens(headers: &HeaderMap, name: &HeaderName) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_owned())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+fn has_token(headers: &HeaderMap, name: &HeaderName, token: &str) -> bool {
+    tokens(headers, name)
+        .iter()
+        .any(|found| found.eq_ignore_ascii_case(token))
+}
+
+impl Service<Request<LuminalBody>> for WebSocketUpgrade {
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, req: Request<LuminalBody>) -> Self::Future {
+        let (mut parts, _) = req.into_parts();
+        let (accept, protocol) = match self.handshake(&parts) {
+            Ok(handshake) => handshake,
+            Err(response) => return Box::pin(async move { Ok(response) }),
+        };
+        let on_upgrade = match parts.extensions.remove::<OnUpgrade>() {
+            Some(on_upgrade) => on_upgrade,
+            None => {
+                return Box::pin(async {
+                    Ok(reject(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "The server can't upgrade this connection",
+                    ))
+                })
+            }
+        };
+
+        let mut response = Response::new(luminal_handler::empty());
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let headers = response.headers_mut();
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        let accept = HeaderValue::from_str(&accept);
+        if let Some(protocol) = &protocol {
+            // offered by the client, so it is a valid header value
+            if let Ok(protocol) = HeaderValue::from_str(protocol) {
+                headers.insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+            }
+        }
+
+        let callback = Arc::clone(&self.callback);
+        let config = self.config();
+        let ping_every = self.ping_every;
+        Box::pin(async move {
+            response.headers_mut().insert(SEC_WEBSOCKET_ACCEPT, accept?);
+            tokio::spawn(async move {
+                let upgraded = match on_upgrade.await {
+                    Ok(upgraded) => upgraded,
+                    Err(error) => {
+                        debug!("WebSocket upgrade failed: {}", error);
+                        return;
+                    }
+                };
+                let io: Box<dyn Io> = Box::new(TokioIo::new(upgraded));
+                let stream = WebSocketStream::from_raw_socket(io, Role::Server, Some(config)).await;
+                callback(WebSocket::new(stream, protocol, ping_every), parts).await;
+            });
+            Ok(response)
+        })
+    }
+}
+
+/// Connect to a WebSocket route of a service over an in-memory connection, as a test client
+/// would.
+///
+/// The request is anything tungstenite makes a client request of, such as
+/// `"ws://localhost/events"`, with any headers the route needs added to it.
+pub async fn loopback<S, R>(
+    service: S,
+    request: R,
+) -> ::std::result::Result<(WebSocket, Response<Option<Vec<u8>>>), WebSocketError>
+where
+    S: Service<
+            Request<Incoming>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Send
+        + 'static,
+    R: IntoClientRequest + Unpin,
+{
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        let connection = hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(server), service)
+            .with_upgrades();
+        if let Err(error) = connection.await {
+            debug!("Error serving loopback connection: {}", error);
+        }
+    });
+    let io: Box<dyn Io> = Box::new(client);
+    let (stream, response) = tokio_tungstenite::client_async(request, io).await?;
+    let protocol = response
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocol| protocol.to_str().ok())
+        .map(str::to_owned);
+    Ok((WebSocket::new(stream, protocol, None), response))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::{SinkExt, StreamExt};
+    use http::header::HOST;
+    use http_body_util::BodyExt;
+    use luminal_handler::empty;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::{FnRouteBuilder, Router};
+
+    async fn echo(mut socket: WebSocket, _: Parts) {
+        while let Some(message) = socket.next().await {
+            match message {
+                Ok(message @ (Message::Text(_) | Message::Binary(_))) => {
+                    if socket.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(WebSocketError::Capacity(_)) => {
+                    let frame = CloseFrame {
+                        code: CloseCode::Size,
+                        reason: "Too big".into(),
+                    };
+                    let _ = socket.send(Message::Close(Some(frame))).await;
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn router(upgrade: WebSocketUpgrade) -> Router {
+        FnRouteBuilder::new()
+            .get("/", |_| async { Ok(Response::new(empty())) })
+            .expect("Should have added a route")
+            .websocket("/echo", upgrade)
+            .expect("Should have added a WebSocket route")
+            .build()
+    }
+
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Should have been able to build a runtime")
+    }
+
+    fn upgrade_request() -> http::request::Builder {
+        Request::get("/echo")
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    fn call(upgrade: &WebSocketUpgrade, req: http::request::Builder) -> Response<LuminalBody> {
+        let req = req.body(empty()).expect("Should have built a request");
+        block_on(upgrade.call(req)).expect("Should have answered the handshake")
+    }
+
+    async fn text(response: Response<LuminalBody>) -> String {
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .expect("Should have read the body")
+            .to_bytes();
+        String::from_utf8(body.to_vec()).expect("Should have had a UTF-8 body")
+    }
+
+    #[test]
+    fn test_echo() {
+        runtime().block_on(async {
+            let upgrade = WebSocketUpgrade::new(echo).protocols(["chat.v2", "chat.v1"]);
+            let mut request = "ws://localhost/echo"
+                .into_client_request()
+                .expect("Should have built a client request");
+            request.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static("chat.v1, chat.v2"),
+            );
+            let (mut socket, response) = loopback(router(upgrade), request)
+                .await
+                .expect("Should have connected");
+            assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+            assert_eq!(socket.protocol(), Some("chat.v2"));
+
+            socket
+                .send(Message::text("hello"))
+                .await
+                .expect("Should have sent a message");
+            let reply = socket.next().await.expect("Should have had a reply");
+            assert_eq!(
+                reply.expect("Should have read the reply"),
+                Message::text("hello")
+            );
+            socket
+                .send(Message::binary(vec![1, 2, 3]))
+                .await
+                .expect("Should have sent a message");
+            let reply = socket.next().await.expect("Should have had a reply");
+            assert_eq!(
+                reply.expect("Should have read the reply"),
+                Message::binary(vec![1, 2, 3])
+            );
+        });
+    }
+
+    #[test]
+    fn test_ping_and_close() {
+        runtime().block_on(async {
+            let upgrade = WebSocketUpgrade::new(echo);
+            let (mut socket, _) = loopback(router(upgrade), "ws://localhost/echo")
+                .await
+                .expect("Should have connected");
+            assert_eq!(socket.protocol(), None);
+
+            socket
+                .send(Message::Ping("are you there".into()))
+                .await
+                .expect("Should have sent a ping");
+            let pong = socket.next().await.expect("Should have had a pong");
+            assert_eq!(
+                pong.expect("Should have read the pong"),
+                Message::Pong("are you there".into())
+            );
+
+            let frame = CloseFrame {
+                code: CloseCode::Away,
+                reason: "Bye".into(),
+            };
+            socket
+                .send(Message::Close(Some(frame)))
+                .await
+                .expect("Should have sent a close frame");
+            let close = socket.next().await.expect("Should have had a close frame");
+            match close.expect("Should have read the close frame") {
+                Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+                message => panic!("Should have had a close frame, not {:?}", message),
+            }
+            assert!(socket.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        runtime().block_on(async {
+            let upgrade = WebSocketUpgrade::new(echo).max_message_size(16);
+            let (mut socket, _) = loopback(router(upgrade), "ws://localhost/echo")
+                .await
+                .expect("Should have connected");
+
+            socket
+                .send(Message::text("small"))
+                .await
+                .expect("Should have sent a message");
+            let reply = socket.next().await.expect("Should have had a reply");
+            assert_eq!(
+                reply.expect("Should have read the reply"),
+                Message::text("small")
+            );
+
+            socket
+                .send(Message::text("x".repeat(64)))
+                .await
+                .expect("Should have sent a message");
+            let close = socket.next().await.expect("Should have had a close frame");
+            match close.expect("Should have read the close frame") {
+                Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Size),
+                message => panic!("Should have had a close frame, not {:?}", message),
+            }
+        });
+    }
+
+    #[test]
+    fn test_keepalive() {
+        runtime().block_on(async {
+            let upgrade = WebSocketUpgrade::new(echo).ping_every(Duration::from_millis(10));
+            let (mut socket, _) = loopback(router(upgrade), "ws://localhost/echo")
+                .await
+                .expect("Should have connected");
+
+            // reading the ping answers it, so the connection stays open for more
+            for _ in 0..3 {
+                let ping = socket.next().await.expect("Should have had a ping");
+                assert!(matches!(
+                    ping.expect("Should have read the ping"),
+                    Message::Ping(_)
+                ));
+            }
+            socket
+                .send(Message::text("still here"))
+                .await
+                .expect("Should have sent a message");
+            loop {
+                let reply = socket.next().await.expect("Should have had a reply");
+                match reply.expect("Should have read the reply") {
+                    Message::Ping(_) => continue,
+                    message => {
+                        assert_eq!(message, Message::text("still here"));
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_unanswered_pings() {
+        runtime().block_on(async {
+            let (sender, receiver) = tokio::sync::oneshot::channel();
+            let sender = std::sync::Mutex::new(Some(sender));
+            let upgrade = WebSocketUpgrade::new(move |mut socket: WebSocket, _| {
+                let sender = sender.lock().expect("Should have locked").take();
+                async move {
+                    let ended = socket.next().await;
+                    if let Some(sender) = sender {
+                        let _ = sender.send(ended.map(|ended| ended.is_err()));
+                    }
+                }
+            })
+            .ping_every(Duration::from_millis(10));
+            // the client never reads, so it never answers
+            let (_socket, _) = loopback(router(upgrade), "ws://localhost/echo")
+                .await
+                .expect("Should have connected");
+            let ended = receiver.await.expect("Should have heard from the handler");
+            assert_eq!(ended, Some(true));
+        });
+    }
+
+    #[test]
+    fn test_plain_routes() {
+        runtime().block_on(async {
+            let result = loopback(router(WebSocketUpgrade::new(echo)), "ws://localhost/").await;
+            match result {
+                Err(WebSocketError::Http(response)) => {
+                    assert_eq!(response.status(), StatusCode::OK)
+                }
+                _ => panic!("Should have been refused by a plain route"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_rejected_handshakes() {
+        let upgrade = WebSocketUpgrade::new(echo).protocols(["chat.v1"]);
+
+        let response = call(&upgrade, Request::get("/echo"));
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.headers()[UPGRADE], "websocket");
+
+        let mut req = upgrade_request();
+        req.headers_mut()
+            .expect("Should have had headers")
+            .insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("8"));
+        let response = call(&upgrade, req);
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.headers()[SEC_WEBSOCKET_VERSION], "13");
+
+        let mut req = upgrade_request();
+        req.headers_mut()
+            .expect("Should have had headers")
+            .insert(SEC_WEBSOCKET_KEY, HeaderValue::from_static("c2hvcnQ="));
+        let response = call(&upgrade, req);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            block_on(text(response)),
+            "Missing or malformed Sec-WebSocket-Key"
+        );
+
+        let response = call(
+            &upgrade,
+            upgrade_request().header(SEC_WEBSOCKET_PROTOCOL, "chat.v9"),
+        );
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = call(&upgrade, upgrade_request().method(Method::POST));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = call(
+            &upgrade,
+            upgrade_request().header(ORIGIN, "https://evil.example.com"),
+        );
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // a valid handshake, but nothing to upgrade
+        let response = call(&upgrade, upgrade_request());
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_origins() {
+        let upgrade = WebSocketUpgrade::new(echo).allowed_origins(["https://App.example.com/"]);
+        let handshake = |origin: &str, host: &str| {
+            let req = upgrade_request()
+                .header(ORIGIN, origin)
+                .header(HOST, host)
+                .body(())
+                .expect("Should have built a request");
+            let (parts, _) = req.into_parts();
+            upgrade
+                .handshake(&parts)
+                .map_err(|response| response.status())
+        };
+
+        assert!(handshake("https://example.com", "example.com").is_ok());
+        assert!(handshake("http://example.com:8080", "example.com:8080").is_ok());
+        assert!(handshake("https://app.example.com", "example.com").is_ok());
+        assert_eq!(
+            handshake("https://evil.example.com", "example.com").err(),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            handshake("http://example.com:8080", "example.com").err(),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            handshake("null", "example.com").err(),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        runtime().block_on(async {
+            let upgrade = WebSocketUpgrade::new(echo);
+            let mut request = "ws://localhost/echo"
+                .into_client_request()
+                .expect("Should have built a client request");
+            request
+                .headers_mut()
+                .insert(ORIGIN, HeaderValue::from_static("http://localhost"));
+            let (_, response) = loopback(router(upgrade.clone()), request)
+                .await
+                .expect("Should have connected");
+            assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+            let mut request = "ws://localhost/echo"
+                .into_client_request()
+                .expect("Should have built a client request");
+            request
+                .headers_mut()
+                .insert(ORIGIN, HeaderValue::from_static("https://evil.example.com"));
+            match loopback(router(upgrade), request).await {
+                Err(WebSocketError::Http(response)) => {
+                    assert_eq!(response.status(), StatusCode::FORBIDDEN)
+                }
+                _ => panic!("Should have been refused for its origin"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_accept_key() {
+        let upgrade = WebSocketUpgrade::new(echo).protocols(["chat.v1"]);
+        let req = upgrade_request()
+            .header(SEC_WEBSOCKET_PROTOCOL, "chat.v1")
+            .body(())
+            .expect("Should have built a request");
+        let (parts, _) = req.into_parts();
+        let (accept, protocol) = upgrade
+            .handshake(&parts)
+            .expect("Should have accepted the handshake");
+        // the example from RFC 6455
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(protocol.as_deref(), Some("chat.v1"));
+    }
+}
@@ -0,0 +1,34 @@
+use luminal_router::{LuminalError, LuminalErrorKind};
+
+error_chain! {
+    links {
+        Luminal(LuminalError, LuminalErrorKind);
+    }
+
+    foreign_links {
+        Io(::std::io::Error);
+    }
+
+    errors {
+        /// A server with nothing to listen on.
+        NoAddresses {
+            description("no addresses")
+            display("Servers need at least one address or socket to listen on")
+        }
+        /// An address that could not be bound, already in use or not permitted.
+        Bind(addr: String) {
+            description("could not bind")
+            display("Could not bind server socket to {}", addr)
+        }
+        /// A certificate, key or CA that could not be loaded or doesn't fit together.
+        Tls(reason: String) {
+            description("invalid TLS configuration")
+            display("Could not load TLS configuration: {}", reason)
+        }
+        /// The tokio runtime or the signal handlers could not be set up.
+        Startup(reason: String) {
+            description("could not start")
+            display("Could not start server: {}", reason)
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Serve a luminal `Router`, or any service wrapping one, over HTTP/1 and HTTP/2.
+//!
+//! `Server` binds one or more TCP addresses or Unix domain sockets, or takes the sockets systemd
+//! passes with socket activation, builds the service from a factory and accepts connections on
+//! every one of them until it is told to stop, by SIGTERM or SIGINT or by calling
+//! `Running::shutdown`. It then stops accepting, lets the requests in flight finish for up to the
+//! shutdown timeout and closes whatever connections remain.
+//!
+//! With a `TlsConfig` the server terminates TLS itself, negotiating HTTP/2 through ALPN,
+//! optionally verifying client certificates and picking up renewed certificates from disk.
+//!
+//! Every request carries the `PeerAddr` of its connection in its extensions, along with the
+//! `SocketAddr` for TCP connections and `Secure` for those over TLS.
+//!
+//! Nothing in here panics on bad configuration; failing to bind, to start the runtime or to build
+//! the router is returned as an error.
+#[macro_use]
+extern crate error_chain;
+
+mod error;
+mod listener;
+mod server;
+mod tls;
+
+pub use error::Error as ServerError;
+pub use error::ErrorKind as ServerErrorKind;
+pub use error::Result as ServerResult;
+pub use server::{Running, Server};
+pub use tls::{PeerCertificate, TlsConfig};
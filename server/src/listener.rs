@@ -0,0 +1,369 @@
+//! The sockets a server accepts connections on: TCP, Unix domain sockets and those passed by
+//! systemd socket activation.
+use luminal_router::PeerAddr;
+use tokio::net::{TcpListener, TcpStream};
+
+use std::io;
+use std::net::SocketAddr;
+
+use crate::error::*;
+
+#[cfg(unix)]
+pub(crate) use self::unix::{Activation, UnixSocket};
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// A Unix domain socket, with the file to remove when it was bound rather than inherited.
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        socket: Option<UnixSocket>,
+    },
+}
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Listener {
+    pub(crate) async fn bind(addr: SocketAddr) -> Result<Listener> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .chain_err(|| ErrorKind::Bind(addr.to_string()))?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    /// The address of a TCP listener.
+    pub(crate) fn local_addr(&self) -> Option<io::Result<SocketAddr>> {
+        match self {
+            Listener::Tcp(listener) => Some(listener.local_addr()),
+            #[cfg(unix)]
+            Listener::Unix { .. } => None,
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| String::from("TCP")),
+            #[cfg(unix)]
+            Listener::Unix {
+                socket: Some(socket),
+                ..
+            } => format!("unix:{}", socket.0.display()),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => String::from("unix"),
+                },
+                Err(_) => String::from("unix"),
+            },
+        }
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<(Stream, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                let (stream, addr) = listener.accept().await?;
+                let cred = stream.peer_cred().ok();
+                let peer = PeerAddr::Unix {
+                    path: addr.as_pathname().map(|path| path.to_path_buf()),
+                    uid: cred.map(|cred| cred.uid()),
+                    gid: cred.map(|cred| cred.gid()),
+                };
+                Ok((Stream::Unix(stream), peer))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use log::debug;
+    use tokio::net::{TcpListener, UnixListener};
+
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::ops::Range;
+    use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use super::Listener;
+    use crate::error::*;
+
+    // The first descriptor systemd passes, after stdin, stdout and stderr.
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// The file of a Unix domain socket the server bound, removed again when its listener is
+    /// dropped.
+    pub(crate) struct UnixSocket(pub(super) PathBuf);
+
+    impl Drop for UnixSocket {
+        fn drop(&mut self) {
+            if let Err(error) = fs::remove_file(&self.0) {
+                debug!("Could not remove socket {}: {}", self.0.display(), error);
+            }
+        }
+    }
+
+    impl Listener {
+        /// Bind a Unix domain socket, replacing a stale socket file nothing listens on any more,
+        /// but nothing else.
+        pub(crate) fn bind_unix(path: &Path, mode: Option<u32>) -> Result<Listener> {
+            let bind_error = || ErrorKind::Bind(format!("unix:{}", path.display()));
+            if let Ok(meta) = fs::symlink_metadata(path) {
+                if !meta.file_type().is_socket() {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket"))
+                        .chain_err(bind_error);
+                }
+                match std::os::unix::net::UnixStream::connect(path) {
+                    Ok(_) => {
+                        return Err(io::Error::new(io::ErrorKind::AddrInUse, "in use"))
+                            .chain_err(bind_error)
+                    }
+                    Err(_) => fs::remove_file(path).chain_err(bind_error)?,
+                }
+            }
+            let listener = match mode {
+                Some(mode) => bind_private(path, mode),
+                None => UnixListener::bind(path),
+            }
+            .chain_err(bind_error)?;
+            let socket = UnixSocket(path.to_path_buf());
+            Ok(Listener::Unix {
+                listener,
+                socket: Some(socket),
+            })
+        }
+    }
+
+    // Bind a socket in a directory next to the path that only this user can enter, so nobody
+    // connects before its permissions are set, then move it into place.
+    fn bind_private(path: &Path, mode: u32) -> io::Result<UnixListener> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let private = parent.join(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let staged = private.join(name);
+        let bound = stage(&staged, path, mode);
+        if bound.is_err() {
+            let _ = fs::remove_file(&staged);
+        }
+        let _ = fs::remove_dir(&private);
+        bound
+    }
+
+    fn stage(staged: &Path, path: &Path, mode: u32) -> io::Result<UnixListener> {
+        let listener = UnixListener::bind(staged)?;
+        fs::set_permissions(staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(staged, path)?;
+        Ok(listener)
+    }
+
+    /// The sockets systemd passed this process, taken from `LISTEN_PID` and `LISTEN_FDS` while
+    /// the server is configured and handed to the first listener to start.
+    #[derive(Clone, Debug)]
+    pub(crate) struct Activation(Arc<Mutex<Option<Result<Range<RawFd>>>>>);
+
+    impl Activation {
+        /// Read the variables and remove them, so they aren't taken twice or passed on to
+        /// children.
+        pub(crate) fn from_env() -> Activation {
+            let fds = listen_fds(
+                env::var("LISTEN_PID").ok().as_deref(),
+                env::var("LISTEN_FDS").ok().as_deref(),
+                std::process::id(),
+            );
+            env::remove_var("LISTEN_PID");
+            env::remove_var("LISTEN_FDS");
+            env::remove_var("LISTEN_FDNAMES");
+            Activation(Arc::new(Mutex::new(Some(fds))))
+        }
+
+        /// The passed sockets, or none once they were taken.
+        pub(crate) fn listeners(&self) -> Result<Vec<Listener>> {
+            let fds = self
+                .0
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .take();
+            match fds {
+                Some(fds) => fds?.map(inherit).collect(),
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+
+    fn listen_fds(pid: Option<&str>, fds: Option<&str>, own: u32) -> Result<Range<RawFd>> {
+        let invalid = |what: &str| ErrorKind::Startup(format!("invalid {} from systemd", what));
+        let (pid, fds) = match (pid, fds) {
+            (Some(pid), Some(fds)) => (pid, fds),
+            _ => return Ok(0..0),
+        };
+        let pid: u32 = pid.parse().chain_err(|| invalid("LISTEN_PID"))?;
+        if pid != own {
+            return Ok(0..0);
+        }
+        let fds: RawFd = fds.parse().chain_err(|| invalid("LISTEN_FDS"))?;
+        if fds < 0 {
+            bail!(invalid("LISTEN_FDS"));
+        }
+        Ok(LISTEN_FDS_START..LISTEN_FDS_START + fds)
+    }
+
+    fn inherit(fd: RawFd) -> Result<Listener> {
+        let inherit_error =
+            || ErrorKind::Startup(format!("could not use socket {} from systemd", fd));
+        if socket_option(fd, libc::SO_TYPE).chain_err(inherit_error)? != libc::SOCK_STREAM
+            || socket_option(fd, libc::SO_ACCEPTCONN).chain_err(inherit_error)? == 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a listening stream socket",
+            ))
+            .chain_err(inherit_error);
+        }
+        // SAFETY: systemd hands these descriptors to this process alone, open and listening, and
+        // they are only taken once since the activation hands them out once.
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        if listener.local_addr().is_ok() {
+            listener.set_nonblocking(true).chain_err(inherit_error)?;
+            let listener = TcpListener::from_std(listener).chain_err(inherit_error)?;
+            return Ok(Listener::Tcp(listener));
+        }
+
+        // not an inet socket, so it should be a Unix domain socket
+        // SAFETY: the descriptor was just released by the TCP listener that owned it
+        let listener =
+            unsafe { std::os::unix::net::UnixListener::from_raw_fd(listener.into_raw_fd()) };
+        listener.local_addr().chain_err(inherit_error)?;
+        listener.set_nonblocking(true).chain_err(inherit_error)?;
+        let listener = UnixListener::from_std(listener).chain_err(inherit_error)?;
+        Ok(Listener::Unix {
+            listener,
+            socket: None,
+        })
+    }
+
+    fn socket_option(fd: RawFd, name: libc::c_int) -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len point to an int and its size, which is what these options fill in
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result == 0 {
+            Ok(value)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use tempfile::TempDir;
+
+        use std::os::fd::AsRawFd;
+
+        use super::*;
+
+        #[test]
+        fn test_listen_fds() {
+            assert_eq!(
+                0..0,
+                listen_fds(None, None, 42).expect("Should have parsed")
+            );
+            assert_eq!(
+                0..0,
+                listen_fds(Some("41"), Some("2"), 42).expect("Should have parsed")
+            );
+            assert_eq!(
+                3..5,
+                listen_fds(Some("42"), Some("2"), 42).expect("Should have parsed")
+            );
+            assert!(listen_fds(Some("42"), Some("two"), 42).is_err());
+        }
+
+        #[tokio::test]
+        async fn test_inherit() {
+            let tcp =
+                std::net::TcpListener::bind("127.0.0.1:0").expect("Should have bound TCP listener");
+            let addr = tcp.local_addr().expect("Should have local address");
+            match inherit(tcp.into_raw_fd()).expect("Should have inherited TCP listener") {
+                Listener::Tcp(listener) => assert_eq!(
+                    addr,
+                    listener.local_addr().expect("Should have local address")
+                ),
+                _ => panic!("Should have inherited a TCP listener"),
+            }
+
+            let dir = TempDir::new().expect("Should have created temp dir");
+            let unix = std::os::unix::net::UnixListener::bind(dir.path().join("activated.sock"))
+                .expect("Should have bound Unix listener");
+            match inherit(unix.into_raw_fd()).expect("Should have inherited Unix listener") {
+                Listener::Unix { socket, .. } => assert!(socket.is_none()),
+                _ => panic!("Should have inherited a Unix listener"),
+            }
+
+            let udp =
+                std::net::UdpSocket::bind("127.0.0.1:0").expect("Should have bound UDP socket");
+            assert!(inherit(udp.as_raw_fd()).is_err());
+
+            let tcp =
+                std::net::TcpListener::bind("127.0.0.1:0").expect("Should have bound TCP listener");
+            let stream =
+                std::net::TcpStream::connect(tcp.local_addr().expect("Should have local address"))
+                    .expect("Should have connected");
+            assert!(inherit(stream.as_raw_fd()).is_err());
+        }
+
+        #[tokio::test]
+        async fn test_bind_unix_permissions() {
+            let dir = TempDir::new().expect("Should have created temp dir");
+            let path = dir.path().join("private.sock");
+            let listener =
+                Listener::bind_unix(&path, Some(0o600)).expect("Should have bound Unix socket");
+            let meta = fs::metadata(&path).expect("Should have created the socket");
+            assert_eq!(0o600, meta.permissions().mode() & 0o777);
+            assert_eq!(format!("unix:{}", path.display()), listener.describe());
+            assert_eq!(
+                1,
+                fs::read_dir(dir.path())
+                    .expect("Should have listed the temp dir")
+                    .count()
+            );
+            std::os::unix::net::UnixStream::connect(&path).expect("Should have connected");
+            drop(listener);
+            assert!(!path.exists());
+        }
+    }
+}
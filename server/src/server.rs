@@ -0,0 +1,868 @@
+//! The server builder and its accept loops.
+use http::{Request, Response};
+use hyper::body::Incoming;
+use hyper::service::Service;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use log::{debug, info, warn};
+use luminal_router::{BoxError, LuminalBody, LuminalError, LuminalFuture, PeerAddr, Secure};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::*;
+#[cfg(unix)]
+use crate::listener::Activation;
+use crate::listener::{Listener, Stream};
+use crate::tls::{PeerCertificate, Tls, TlsConfig};
+
+// How long to back off after a failed accept, such as running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Protocols {
+    Auto,
+    Http1,
+    Http2,
+}
+
+/// Fluent builder for a server, consumed by `start`, `serve` or `run`.
+///
+/// By default HTTP/1 with keep-alive and HTTP/2 are both served, the runtime has a worker per
+/// core and the requests in flight get 30 seconds to finish on shutdown.
+#[derive(Clone, Debug)]
+pub struct Server {
+    addrs: Vec<SocketAddr>,
+    unix: Vec<PathBuf>,
+    unix_mode: Option<u32>,
+    #[cfg(unix)]
+    systemd: Option<Activation>,
+    protocols: Protocols,
+    keep_alive: bool,
+    header_read_timeout: Option<Duration>,
+    max_buf_size: Option<usize>,
+    max_concurrent_streams: Option<u32>,
+    http2_keep_alive: Option<(Duration, Duration)>,
+    workers: Option<usize>,
+    shutdown_timeout: Duration,
+    tls: Option<TlsConfig>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            addrs: Vec::new(),
+            unix: Vec::new(),
+            unix_mode: None,
+            #[cfg(unix)]
+            systemd: None,
+            protocols: Protocols::Auto,
+            keep_alive: true,
+            header_read_timeout: None,
+            max_buf_size: None,
+            max_concurrent_streams: None,
+            http2_keep_alive: None,
+            workers: None,
+            shutdown_timeout: Duration::from_secs(30),
+            tls: None,
+        }
+    }
+
+    /// Add an address to listen on, port 0 picks a free port.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Add a Unix domain socket to listen on.
+    ///
+    /// A stale socket file left behind by an earlier run is replaced, and the file is removed again
+    /// on shutdown.
+    #[cfg(unix)]
+    pub fn bind_unix<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.unix.push(path.into());
+        self
+    }
+
+    /// The permissions of the Unix domain socket files, such as `0o660` to let a reverse proxy
+    /// in the same group connect.
+    #[cfg(unix)]
+    pub fn unix_permissions(mut self, mode: u32) -> Self {
+        self.unix_mode = Some(mode);
+        self
+    }
+
+    /// Also listen on the sockets systemd passes through socket activation, if there are any
+    /// for this process.
+    ///
+    /// The `LISTEN_*` variables are read and removed from the environment right away, so call
+    /// this before starting any threads, and in particular before a runtime.
+    #[cfg(unix)]
+    pub fn systemd(mut self) -> Self {
+        self.systemd = Some(Activation::from_env());
+        self
+    }
+
+    /// Only serve HTTP/1.
+    pub fn http1_only(mut self) -> Self {
+        self.protocols = Protocols::Http1;
+        self
+    }
+
+    /// Only serve HTTP/2, with prior knowledge on plain connections.
+    pub fn http2_only(mut self) -> Self {
+        self.protocols = Protocols::Http2;
+        self
+    }
+
+    /// Whether HTTP/1 connections are kept open between requests.
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// How long HTTP/1 clients get to send the headers of a request, 30 seconds unless set.
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.header_read_timeout = Some(timeout);
+        self
+    }
+
+    /// The most an HTTP/1 connection buffers, which also caps the size of the headers.
+    pub fn max_buf_size(mut self, max: usize) -> Self {
+        self.max_buf_size = Some(max);
+        self
+    }
+
+    /// The most streams an HTTP/2 client may have open at once, 200 unless set.
+    pub fn max_concurrent_streams(mut self, max: u32) -> Self {
+        self.max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Ping HTTP/2 clients every `interval`, closing the connection when a ping isn't answered
+    /// within `timeout`.
+    pub fn http2_keep_alive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.http2_keep_alive = Some((interval, timeout));
+        self
+    }
+
+    /// The number of worker threads of the runtime `run` builds.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// How long the requests in flight get to finish once shutdown starts.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Serve HTTPS rather than plain HTTP on every address, offering HTTP/2 and HTTP/1.1 through
+    /// ALPN as far as they are enabled.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Build a runtime with the configured workers and serve on it until SIGTERM or SIGINT.
+    pub fn run<F, S>(self, factory: F) -> Result<()>
+    where
+        F: FnOnce() -> ::std::result::Result<S, LuminalError>,
+        S: Service<
+                Request<Incoming>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        let mut runtime = tokio::runtime::Builder::new_multi_thread();
+        if let Some(workers) = self.workers {
+            runtime.worker_threads(workers);
+        }
+        let runtime = runtime
+            .enable_all()
+            .build()
+            .chain_err(|| ErrorKind::Startup(String::from("could not build the runtime")))?;
+        runtime.block_on(self.serve(factory))
+    }
+
+    /// Serve on the current runtime until SIGTERM or SIGINT, then shut down gracefully.
+    pub async fn serve<F, S>(self, factory: F) -> Result<()>
+    where
+        F: FnOnce() -> ::std::result::Result<S, LuminalError>,
+        S: Service<
+                Request<Incoming>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        self.start(factory).await?.wait().await
+    }
+
+    /// Bind every address, build the service and start accepting on the current runtime.
+    pub async fn start<F, S>(self, factory: F) -> Result<Running>
+    where
+        F: FnOnce() -> ::std::result::Result<S, LuminalError>,
+        S: Service<
+                Request<Incoming>,
+                Response = Response<LuminalBody>,
+                Error = BoxError,
+                Future = LuminalFuture,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        let tls = match &self.tls {
+            Some(tls) => Some(Arc::new(Tls::load(tls.clone(), self.alpn())?)),
+            None => None,
+        };
+        let mut listeners = Vec::new();
+        for addr in &self.addrs {
+            listeners.push(Listener::bind(*addr).await?);
+        }
+        #[cfg(unix)]
+        {
+            for path in &self.unix {
+                listeners.push(Listener::bind_unix(path, self.unix_mode)?);
+            }
+            if let Some(activation) = &self.systemd {
+                listeners.extend(activation.listeners()?);
+            }
+        }
+        if listeners.is_empty() {
+            bail!(ErrorKind::NoAddresses);
+        }
+        let local_addrs = listeners
+            .iter()
+            .filter_map(Listener::local_addr)
+            .collect::<::std::io::Result<Vec<_>>>()?;
+        let service = Arc::new(factory()?);
+
+        let http = Arc::new(self.connection_builder());
+        let graceful = Arc::new(GracefulShutdown::new());
+        let (stop, stopped) = watch::channel(());
+        let (close, closed) = watch::channel(());
+        for listener in &listeners {
+            info!("Listening on {}", listener.describe());
+        }
+        let mut tasks: Vec<JoinHandle<()>> = listeners
+            .into_iter()
+            .map(|listener| {
+                let accept = Accept {
+                    service: Arc::clone(&service),
+                    http: Arc::clone(&http),
+                    tls: tls.clone(),
+                    graceful: Arc::clone(&graceful),
+                    stopped: stopped.clone(),
+                    closed: closed.clone(),
+                };
+                tokio::spawn(accept.run(listener))
+            })
+            .collect();
+        if let Some(tls) = tls {
+            if let Some(every) = tls.reload_every() {
+                tasks.push(tokio::spawn(reload(tls, every, stopped.clone())));
+            }
+        }
+
+        Ok(Running {
+            local_addrs,
+            stop,
+            close,
+            tasks,
+            graceful,
+            shutdown_timeout: self.shutdown_timeout,
+        })
+    }
+
+    fn alpn(&self) -> Vec<&'static [u8]> {
+        match self.protocols {
+            Protocols::Auto => vec![b"h2", b"http/1.1"],
+            Protocols::Http1 => vec![b"http/1.1"],
+            Protocols::Http2 => vec![b"h2"],
+        }
+    }
+
+    fn connection_builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        // hyper's own defaults, such as its 30 second header read timeout, stay unless overridden
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .keep_alive(self.keep_alive);
+        if let Some(timeout) = self.header_read_timeout {
+            builder.http1().header_read_timeout(timeout);
+        }
+        if let Some(max) = self.max_buf_size {
+            builder.http1().max_buf_size(max);
+        }
+        builder.http2().timer(TokioTimer::new());
+        if let Some(max) = self.max_concurrent_streams {
+            builder.http2().max_concurrent_streams(max);
+        }
+        if let Some((interval, timeout)) = self.http2_keep_alive {
+            builder
+                .http2()
+                .keep_alive_interval(interval)
+                .keep_alive_timeout(timeout);
+        }
+        match self.protocols {
+            Protocols::Auto => builder,
+            Protocols::Http1 => builder.http1_only(),
+            Protocols::Http2 => builder.http2_only(),
+        }
+    }
+}
+
+struct Accept<S> {
+    service: Arc<S>,
+    http: Arc<auto::Builder<TokioExecutor>>,
+    tls: Option<Arc<Tls>>,
+    graceful: Arc<GracefulShutdown>,
+    stopped: watch::Receiver<()>,
+    closed: watch::Receiver<()>,
+}
+
+impl<S> Accept<S>
+where
+    S: Service<
+            Request<Incoming>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Send
+        + Sync
+        + 'static,
+{
+    async fn run(mut self, listener: Listener) {
+        loop {
+            let (stream, peer) = tokio::select! {
+                _ = self.stopped.changed() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        warn!("Could not accept connection: {}", error);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                },
+            };
+            match stream {
+                Stream::Tcp(stream) => {
+                    if let Err(error) = stream.set_nodelay(true) {
+                        debug!("Could not set TCP_NODELAY for {}: {}", peer, error);
+                    }
+                    self.spawn(stream, peer);
+                }
+                #[cfg(unix)]
+                Stream::Unix(stream) => self.spawn(stream, peer),
+            }
+        }
+    }
+
+    fn spawn<I>(&self, io: I, peer: PeerAddr)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let connection = Connection {
+            service: Arc::clone(&self.service),
+            http: Arc::clone(&self.http),
+            watcher: self.graceful.watcher(),
+            closed: self.closed.clone(),
+            peer,
+        };
+        match &self.tls {
+            Some(tls) => {
+                tokio::spawn(connection.serve_tls(io, Arc::clone(tls)));
+            }
+            None => {
+                tokio::spawn(connection.serve(io, false, None));
+            }
+        }
+    }
+}
+
+// A connection accepted but not yet served, which the graceful shutdown is already waiting for.
+struct Connection<S> {
+    service: Arc<S>,
+    http: Arc<auto::Builder<TokioExecutor>>,
+    watcher: Watcher,
+    closed: watch::Receiver<()>,
+    peer: PeerAddr,
+}
+
+impl<S> Connection<S>
+where
+    S: Service<
+            Request<Incoming>,
+            Response = Response<LuminalBody>,
+            Error = BoxError,
+            Future = LuminalFuture,
+        > + Send
+        + Sync
+        + 'static,
+{
+    async fn serve_tls<I>(self, stream: I, tls: Arc<Tls>)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let accepted =
+            tokio::time::timeout(tls.handshake_timeout(), tls.acceptor().accept(stream)).await;
+        let stream = match accepted {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => {
+                debug!("TLS handshake with {} failed: {}", self.peer, error);
+                return;
+            }
+            Err(_) => {
+                debug!("TLS handshake with {} timed out", self.peer);
+                return;
+            }
+        };
+        let peer_certificate = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(PeerCertificate::from_chain);
+        self.serve(stream, true, peer_certificate).await
+    }
+
+    async fn serve<I>(self, io: I, secure: bool, peer_certificate: Option<PeerCertificate>)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Connection {
+            service,
+            http,
+            watcher,
+            mut closed,
+            peer,
+        } = self;
+        let service = Connected {
+            service,
+            peer: peer.clone(),
+            secure,
+            peer_certificate,
+        };
+        let connection =
+            watcher.watch(http.serve_connection_with_upgrades(TokioIo::new(io), service));
+        tokio::select! {
+            served = connection => if let Err(error) = served {
+                debug!("Error serving connection from {}: {}", peer, error);
+            },
+            _ = closed.changed() => debug!("Closed connection from {} on shutdown", peer),
+        }
+    }
+}
+
+// Adds what is known about the connection to the extensions of each of its requests.
+struct Connected<S> {
+    service: Arc<S>,
+    peer: PeerAddr,
+    secure: bool,
+    peer_certificate: Option<PeerCertificate>,
+}
+
+impl<S> Service<Request<Incoming>> for Connected<S>
+where
+    S: Service<
+        Request<Incoming>,
+        Response = Response<LuminalBody>,
+        Error = BoxError,
+        Future = LuminalFuture,
+    >,
+{
+    type Response = Response<LuminalBody>;
+    type Error = BoxError;
+    type Future = LuminalFuture;
+
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        if let PeerAddr::Tcp(addr) = self.peer {
+            req.extensions_mut().insert(addr);
+        }
+        req.extensions_mut().insert(self.peer.clone());
+        if self.secure {
+            req.extensions_mut().insert(Secure);
+        }
+        if let Some(peer_certificate) = &self.peer_certificate {
+            req.extensions_mut().insert(peer_certificate.clone());
+        }
+        self.service.call(req)
+    }
+}
+
+async fn reload(tls: Arc<Tls>, every: Duration, mut stopped: watch::Receiver<()>) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        tokio::select! {
+            _ = stopped.changed() => break,
+            _ = interval.tick() => tls.reload(),
+        }
+    }
+}
+
+/// A started server, accepting connections until it is shut down.
+pub struct Running {
+    local_addrs: Vec<SocketAddr>,
+    stop: watch::Sender<()>,
+    close: watch::Sender<()>,
+    tasks: Vec<JoinHandle<()>>,
+    graceful: Arc<GracefulShutdown>,
+    shutdown_timeout: Duration,
+}
+
+impl Running {
+    /// The TCP addresses actually bound, with the ports picked for any bound to port 0.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Wait for SIGTERM or SIGINT, then shut down gracefully.
+    pub async fn wait(self) -> Result<()> {
+        shutdown_signal().await?;
+        info!("Shutting down");
+        self.shutdown().await
+    }
+
+    /// Stop accepting, wait up to the shutdown timeout for the requests in flight and close the
+    /// connections still open after that.
+    pub async fn shutdown(self) -> Result<()> {
+        let Running {
+            stop,
+            close,
+            tasks,
+            graceful,
+            shutdown_timeout,
+            ..
+        } = self;
+        let _ = stop.send(());
+        for task in tasks {
+            if let Err(error) = task.await {
+                warn!("Server task ended abnormally: {}", error);
+            }
+        }
+
+        // every accept loop has ended and dropped its handle
+        let graceful = Arc::try_unwrap(graceful)
+            .unwrap_or_else(|_| panic!("Should have stopped accepting before draining"));
+        let open = graceful.count();
+        if tokio::time::timeout(shutdown_timeout, graceful.shutdown())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Closing connections still open after {:?}, of {} when shutdown started",
+                shutdown_timeout, open
+            );
+            let _ = close.send(());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())
+        .chain_err(|| ErrorKind::Startup(String::from("could not listen for SIGTERM")))?;
+    let mut interrupt = signal(SignalKind::interrupt())
+        .chain_err(|| ErrorKind::Startup(String::from("could not listen for SIGINT")))?;
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    tokio::signal::ctrl_c()
+        .await
+        .chain_err(|| ErrorKind::Startup(String::from("could not listen for Ctrl-C")))
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use hyper::client::conn::http2;
+    use luminal_handler::full;
+    use luminal_router::{FnRouteBuilder, LuminalErrorKind, Router};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use std::time::Instant;
+
+    use super::*;
+
+    fn routes() -> ::std::result::Result<Router, LuminalError> {
+        Ok(FnRouteBuilder::new()
+            .get("/hello", |_req| async { Ok(Response::new(full("hello"))) })?
+            .get("/peer", |req| async move {
+                let peer = req
+                    .extensions()
+                    .get::<PeerAddr>()
+                    .map(PeerAddr::to_string)
+                    .unwrap_or_default();
+                let tcp = req.extensions().get::<SocketAddr>().is_some();
+                Ok(Response::new(full(format!("{} {}", peer, tcp))))
+            })?
+            .get("/slow", |_req| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok(Response::new(full("done")))
+            })?
+            .get("/stuck", |_req| async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(Response::new(full("never")))
+            })?
+            .build())
+    }
+
+    fn localhost() -> SocketAddr {
+        "127.0.0.1:0".parse().expect("Should have parsed address")
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> ::std::io::Result<String> {
+        request(TcpStream::connect(addr).await?, path).await
+    }
+
+    async fn request<I>(mut stream: I, path: &str) -> ::std::io::Result<String>
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    path
+                )
+                .as_bytes(),
+            )
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_http1_and_http2() {
+        let running = Server::new()
+            .bind(localhost())
+            .bind(localhost())
+            .start(routes)
+            .await
+            .expect("Should have started server");
+        assert_eq!(2, running.local_addrs().len());
+        for addr in running.local_addrs() {
+            let response = get(*addr, "/hello")
+                .await
+                .expect("Should have been able to make request");
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.ends_with("hello"));
+        }
+        let stream = TcpStream::connect(running.local_addrs()[0])
+            .await
+            .expect("Should have been able to connect");
+        let peer = stream.local_addr().expect("Should have local address");
+        let response = request(stream, "/peer")
+            .await
+            .expect("Should have been able to make request");
+        assert!(response.ends_with(&format!("{} true", peer)));
+
+        let stream = TcpStream::connect(running.local_addrs()[0])
+            .await
+            .expect("Should have been able to connect");
+        let (mut sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .expect("Should have been able to handshake");
+        tokio::spawn(connection);
+        let req = Request::builder()
+            .uri(format!("http://{}/hello", running.local_addrs()[0]))
+            .body(Empty::<Bytes>::new())
+            .expect("Should have been able to build request");
+        let response = sender
+            .send_request(req)
+            .await
+            .expect("Should have been able to make request");
+        assert_eq!(http::Version::HTTP_2, response.version());
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .expect("Should have been able to read body")
+            .to_bytes();
+        assert_eq!("hello", body);
+
+        running
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix() {
+        use std::fs;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        use tokio::net::UnixStream;
+
+        let dir = tempfile::TempDir::new().expect("Should have created temp dir");
+        let path = dir.path().join("luminal.sock");
+
+        // a socket file left behind by a server that is gone is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).expect("Should have bound socket"));
+        let running = Server::new()
+            .bind_unix(&path)
+            .unix_permissions(0o660)
+            .start(routes)
+            .await
+            .expect("Should have started server");
+        assert!(running.local_addrs().is_empty());
+        let meta = fs::metadata(&path).expect("Should have created socket file");
+        assert_eq!(0o660, meta.permissions().mode() & 0o777);
+
+        let stream = UnixStream::connect(&path)
+            .await
+            .expect("Should have been able to connect");
+        let response = request(stream, "/peer")
+            .await
+            .expect("Should have been able to make request");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(&format!("unix:uid={} false", meta.uid())));
+
+        // a socket something listens on is left alone, as is a file that isn't a socket
+        let error = Server::new()
+            .bind_unix(&path)
+            .start(routes)
+            .await
+            .err()
+            .expect("Should have failed to bind a socket in use");
+        assert!(matches!(error.kind(), ErrorKind::Bind(_)));
+        let file = dir.path().join("not.sock");
+        fs::write(&file, "data").expect("Should have written file");
+        let error = Server::new()
+            .bind_unix(&file)
+            .start(routes)
+            .await
+            .err()
+            .expect("Should have failed to bind over a file");
+        assert!(matches!(error.kind(), ErrorKind::Bind(_)));
+        assert_eq!(
+            "data",
+            fs::read_to_string(&file).expect("Should have kept file")
+        );
+
+        running
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let running = Server::new()
+            .bind(localhost())
+            .start(routes)
+            .await
+            .expect("Should have started server");
+        let addr = running.local_addrs()[0];
+        let in_flight = tokio::spawn(get(addr, "/slow"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        running
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+        let response = in_flight
+            .await
+            .expect("Should have joined request")
+            .expect("Should have finished request in flight");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("done"));
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout() {
+        let running = Server::new()
+            .bind(localhost())
+            .shutdown_timeout(Duration::from_millis(100))
+            .start(routes)
+            .await
+            .expect("Should have started server");
+        let in_flight = tokio::spawn(get(running.local_addrs()[0], "/stuck"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        running
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let response = in_flight.await.expect("Should have joined request");
+        assert!(response.map(|response| response.is_empty()).unwrap_or(true));
+    }
+
+    #[tokio::test]
+    async fn test_startup_errors() {
+        let error = Server::new()
+            .start(routes)
+            .await
+            .err()
+            .expect("Should have refused to start without addresses");
+        assert!(matches!(error.kind(), ErrorKind::NoAddresses));
+
+        let running = Server::new()
+            .bind(localhost())
+            .start(routes)
+            .await
+            .expect("Should have started server");
+        let error = Server::new()
+            .bind(running.local_addrs()[0])
+            .start(routes)
+            .await
+            .err()
+            .expect("Should have failed to bind an address in use");
+        assert!(matches!(error.kind(), ErrorKind::Bind(_)));
+
+        let error = Server::new()
+            .bind(localhost())
+            .start(|| {
+                FnRouteBuilder::new()
+                    .get("no-slash", |_req| async { Ok(Response::new(full(""))) })
+                    .map(FnRouteBuilder::build)
+            })
+            .await
+            .err()
+            .expect("Should have failed to build the router");
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::Luminal(LuminalErrorKind::InvalidRoute(_))
+        ));
+
+        running
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+    }
+}
@@ -0,0 +1,546 @@
+//! TLS termination with rustls, with optional client certificates and certificates reloaded from
+//! disk when they change.
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::*;
+
+/// Where to find the server's certificate chain and key, and how to treat client certificates.
+///
+/// The files are PEM, the chain leaf first. They are checked for changes every 10 seconds by
+/// default and swapped in for new connections once they load; connections already open keep the
+/// certificate they were made with.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert_chain: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    client_required: bool,
+    reload: Option<Duration>,
+    handshake_timeout: Duration,
+}
+
+impl TlsConfig {
+    pub fn new<C: AsRef<Path>, K: AsRef<Path>>(cert_chain: C, key: K) -> TlsConfig {
+        TlsConfig {
+            cert_chain: cert_chain.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+            client_ca: None,
+            client_required: false,
+            reload: Some(Duration::from_secs(10)),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Require clients to present a certificate issued by one of the CAs in the PEM file.
+    pub fn client_auth<P: AsRef<Path>>(mut self, ca: P) -> Self {
+        self.client_ca = Some(ca.as_ref().to_path_buf());
+        self.client_required = true;
+        self
+    }
+
+    /// Verify client certificates against the CAs in the PEM file when clients present one, but
+    /// let those without one connect too.
+    pub fn optional_client_auth<P: AsRef<Path>>(mut self, ca: P) -> Self {
+        self.client_ca = Some(ca.as_ref().to_path_buf());
+        self.client_required = false;
+        self
+    }
+
+    /// How often to check the files for changes.
+    pub fn reload_every(mut self, every: Duration) -> Self {
+        self.reload = Some(every);
+        self
+    }
+
+    /// Load the files once, at startup.
+    pub fn no_reload(mut self) -> Self {
+        self.reload = None;
+        self
+    }
+
+    /// How long clients get to complete the handshake.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    fn files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cert_chain.as_path(), self.key.as_path()];
+        files.extend(self.client_ca.as_deref());
+        files
+    }
+
+    fn load(&self, alpn: &[&[u8]]) -> Result<ServerConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|error| tls_error(&error))?;
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(ca)? {
+                    roots.add(cert).map_err(|error| tls_error(&error))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if self.client_required {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+                builder
+                    .with_client_cert_verifier(verifier.build().map_err(|error| tls_error(&error))?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|error| tls_error(&format!("{}: {}", self.key.display(), error)))?;
+        let mut config = builder
+            .with_single_cert(read_certs(&self.cert_chain)?, key)
+            .map_err(|error| tls_error(&error))?;
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Ok(config)
+    }
+}
+
+fn tls_error<E: ToString + ?Sized>(error: &E) -> Error {
+    ErrorKind::Tls(error.to_string()).into()
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<::std::result::Result<Vec<_>, _>>())
+        .map_err(|error| tls_error(&format!("{}: {}", path.display(), error)))?;
+    if certs.is_empty() {
+        return Err(tls_error(&format!("{}: no certificates", path.display())));
+    }
+    Ok(certs)
+}
+
+fn modified(files: &[&Path]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| file.metadata().and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+/// The loaded configuration of a running server, replaced as the files change.
+pub(crate) struct Tls {
+    config: TlsConfig,
+    alpn: Vec<&'static [u8]>,
+    current: ArcSwap<ServerConfig>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl Tls {
+    pub(crate) fn load(config: TlsConfig, alpn: Vec<&'static [u8]>) -> Result<Tls> {
+        let modified = modified(&config.files());
+        let current = config.load(&alpn)?;
+        Ok(Tls {
+            config,
+            alpn,
+            current: ArcSwap::from_pointee(current),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.load_full())
+    }
+
+    pub(crate) fn reload_every(&self) -> Option<Duration> {
+        self.config.reload
+    }
+
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.config.handshake_timeout
+    }
+
+    /// Load the files again if any of them changed, keeping the current configuration when they
+    /// don't load, such as halfway through replacing them.
+    pub(crate) fn reload(&self) {
+        let modified = modified(&self.config.files());
+        let mut last = self
+            .modified
+            .lock()
+            .expect("Should have been able to lock TLS file times");
+        if *last == modified {
+            return;
+        }
+        match self.config.load(&self.alpn) {
+            Ok(config) => {
+                self.current.store(Arc::new(config));
+                *last = modified;
+                info!(
+                    "Reloaded TLS certificate {}",
+                    self.config.cert_chain.display()
+                );
+            }
+            Err(error) => warn!("Keeping the current TLS certificate: {}", error),
+        }
+    }
+}
+
+/// The certificate a client authenticated with, in the request extensions of every request on
+/// the connection.
+#[derive(Clone, Debug)]
+pub struct PeerCertificate {
+    chain: Vec<CertificateDer<'static>>,
+    subject: String,
+    issuer: String,
+    serial: String,
+    dns_names: Vec<String>,
+    not_after: SystemTime,
+}
+
+impl PeerCertificate {
+    pub(crate) fn from_chain(chain: &[CertificateDer<'static>]) -> Option<PeerCertificate> {
+        let (_, leaf) = x509_parser::parse_x509_certificate(chain.first()?).ok()?;
+        let dns_names = match leaf.subject_alternative_name() {
+            Ok(Some(names)) => names
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let not_after = leaf.validity().not_after.timestamp();
+        Some(PeerCertificate {
+            subject: leaf.subject().to_string(),
+            issuer: leaf.issuer().to_string(),
+            serial: leaf.raw_serial_as_string(),
+            dns_names,
+            not_after: UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64),
+            chain: chain.to_vec(),
+        })
+    }
+
+    /// The subject's distinguished name, such as `CN=client, O=Example`.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// The issuer's distinguished name.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// The serial number as colon separated hex.
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    /// The DNS names among the subject alternative names.
+    pub fn dns_names(&self) -> &[String] {
+        &self.dns_names
+    }
+
+    pub fn not_after(&self) -> SystemTime {
+        self.not_after
+    }
+
+    /// The DER of the whole chain the client sent, leaf first.
+    pub fn chain(&self) -> &[CertificateDer<'static>] {
+        &self.chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Request, Response, Version};
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use hyper::client::conn::{http1, http2};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use luminal_handler::full;
+    use luminal_router::{BoxError, FnRouteBuilder, LuminalError, Router};
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose,
+        IsCa, KeyPair,
+    };
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConfig;
+    use tempfile::TempDir;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    use std::fs;
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::{Running, Server};
+
+    struct Pki {
+        dir: TempDir,
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl Pki {
+        fn new() -> Pki {
+            let mut params =
+                CertificateParams::new(Vec::new()).expect("Should have created CA params");
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "luminal test CA");
+            let key = KeyPair::generate().expect("Should have generated CA key");
+            let ca = CertifiedIssuer::self_signed(params, key)
+                .expect("Should have self-signed CA certificate");
+            let dir = TempDir::new().expect("Should have created temp dir");
+            fs::write(dir.path().join("ca.pem"), ca.pem()).expect("Should have written CA");
+            Pki { dir, ca }
+        }
+
+        fn issue(&self, name: &str, purpose: ExtendedKeyUsagePurpose) -> (String, String) {
+            let mut params = CertificateParams::new(vec![String::from("localhost")])
+                .expect("Should have created params");
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![purpose];
+            let key = KeyPair::generate().expect("Should have generated key");
+            let cert = params
+                .signed_by(&key, &self.ca)
+                .expect("Should have signed certificate");
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn write_server(&self, name: &str) {
+            let (cert, key) = self.issue(name, ExtendedKeyUsagePurpose::ServerAuth);
+            fs::write(self.path("key.pem"), key).expect("Should have written key");
+            fs::write(self.path("cert.pem"), cert).expect("Should have written cert");
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.dir.path().join(file)
+        }
+
+        fn roots(&self) -> RootCertStore {
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(self.ca.der().clone())
+                .expect("Should have added CA");
+            roots
+        }
+    }
+
+    fn routes() -> ::std::result::Result<Router, LuminalError> {
+        Ok(FnRouteBuilder::new()
+            .get("/whoami", |req| async move {
+                assert!(req.extensions().get::<luminal_router::Secure>().is_some());
+                let subject = req
+                    .extensions()
+                    .get::<PeerCertificate>()
+                    .map(|peer| peer.subject().to_owned())
+                    .unwrap_or_else(|| String::from("anonymous"));
+                Ok(Response::new(full(subject)))
+            })?
+            .build())
+    }
+
+    async fn start(tls: TlsConfig) -> Running {
+        Server::new()
+            .bind("127.0.0.1:0".parse().expect("Should have parsed address"))
+            .tls(tls)
+            .start(routes)
+            .await
+            .expect("Should have started server")
+    }
+
+    // Ask who the server thinks the client is, returning the protocol, the answer and the subject
+    // of the server's certificate.
+    async fn whoami(
+        addr: SocketAddr,
+        pki: &Pki,
+        client: Option<(&str, &str)>,
+        alpn: &[u8],
+    ) -> ::std::result::Result<(Version, String, String), BoxError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(pki.roots());
+        let mut config = match client {
+            Some((cert, key)) => config.with_client_auth_cert(
+                CertificateDer::pem_slice_iter(cert.as_bytes())
+                    .collect::<::std::result::Result<_, _>>()?,
+                PrivateKeyDer::from_pem_slice(key.as_bytes())?,
+            )?,
+            None => config.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![alpn.to_vec()];
+
+        let stream = TcpStream::connect(addr).await?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        let server = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(PeerCertificate::from_chain)
+            .map(|peer| peer.subject().to_owned())
+            .unwrap_or_default();
+        let req = Request::builder()
+            .uri("https://localhost/whoami")
+            .body(Empty::<Bytes>::new())?;
+        let response = if alpn == b"h2" {
+            let (mut sender, connection) =
+                http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+            tokio::spawn(connection);
+            sender.send_request(req).await?
+        } else {
+            let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+            tokio::spawn(connection);
+            sender.send_request(req).await?
+        };
+        let version = response.version();
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok((version, String::from_utf8(body.to_vec())?, server))
+    }
+
+    #[tokio::test]
+    async fn test_https() {
+        let pki = Pki::new();
+        pki.write_server("server");
+        let running = start(TlsConfig::new(pki.path("cert.pem"), pki.path("key.pem"))).await;
+        let addr = running.local_addrs()[0];
+
+        let (version, answer, server) = whoami(addr, &pki, None, b"h2")
+            .await
+            .expect("Should have made request over HTTP/2");
+        assert_eq!(Version::HTTP_2, version);
+        assert_eq!("anonymous", answer);
+        assert_eq!("CN=server", server);
+        let (version, ..) = whoami(addr, &pki, None, b"http/1.1")
+            .await
+            .expect("Should have made request over HTTP/1.1");
+        assert_eq!(Version::HTTP_11, version);
+
+        running
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+    }
+
+    #[tokio::test]
+    async fn test_client_auth() {
+        let pki = Pki::new();
+        pki.write_server("server");
+        let (cert, key) = pki.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        let required = start(
+            TlsConfig::new(pki.path("cert.pem"), pki.path("key.pem"))
+                .client_auth(pki.path("ca.pem")),
+        )
+        .await;
+        let addr = required.local_addrs()[0];
+        assert!(whoami(addr, &pki, None, b"h2").await.is_err());
+        let (_, answer, _) = whoami(addr, &pki, Some((&cert, &key)), b"h2")
+            .await
+            .expect("Should have made request with client certificate");
+        assert_eq!("CN=client", answer);
+
+        // a certificate from some other CA is refused
+        let other = Pki::new();
+        let (cert, key) = other.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        assert!(whoami(addr, &pki, Some((&cert, &key)), b"h2")
+            .await
+            .is_err());
+
+        let optional = start(
+            TlsConfig::new(pki.path("cert.pem"), pki.path("key.pem"))
+                .optional_client_auth(pki.path("ca.pem")),
+        )
+        .await;
+        let (_, answer, _) = whoami(optional.local_addrs()[0], &pki, None, b"h2")
+            .await
+            .expect("Should have made request without client certificate");
+        assert_eq!("anonymous", answer);
+
+        required
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+        optional
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let pki = Pki::new();
+        pki.write_server("one");
+        let running = start(
+            TlsConfig::new(pki.path("cert.pem"), pki.path("key.pem"))
+                .reload_every(Duration::from_millis(20)),
+        )
+        .await;
+        let addr = running.local_addrs()[0];
+
+        // a key that doesn't load leaves the current certificate in place
+        fs::write(pki.path("key.pem"), "not a key").expect("Should have written key");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (_, _, server) = whoami(addr, &pki, None, b"h2")
+            .await
+            .expect("Should have made request");
+        assert_eq!("CN=one", server);
+
+        pki.write_server("two");
+        let mut server = String::new();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            (_, _, server) = whoami(addr, &pki, None, b"h2")
+                .await
+                .expect("Should have made request");
+            if server != "CN=one" {
+                break;
+            }
+        }
+        assert_eq!("CN=two", server);
+
+        running
+            .shutdown()
+            .await
+            .expect("Should have been able to shut down");
+    }
+
+    #[tokio::test]
+    async fn test_invalid() {
+        let pki = Pki::new();
+        pki.write_server("server");
+        let error = Server::new()
+            .bind("127.0.0.1:0".parse().expect("Should have parsed address"))
+            .tls(TlsConfig::new(pki.path("missing.pem"), pki.path("key.pem")))
+            .start(routes)
+            .await
+            .err()
+            .expect("Should have failed to load certificate");
+        assert!(matches!(error.kind(), ErrorKind::Tls(_)));
+
+        // a key that doesn't go with the certificate
+        let (_, key) = pki.issue("other", ExtendedKeyUsagePurpose::ServerAuth);
+        fs::write(pki.path("key.pem"), key).expect("Should have written key");
+        let error = Server::new()
+            .bind("127.0.0.1:0".parse().expect("Should have parsed address"))
+            .tls(TlsConfig::new(pki.path("cert.pem"), pki.path("key.pem")))
+            .start(routes)
+            .await
+            .err()
+            .expect("Should have refused mismatched key");
+        assert!(matches!(error.kind(), ErrorKind::Tls(_)));
+    }
+}